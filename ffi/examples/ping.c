@@ -0,0 +1,24 @@
+/*
+ * Minimal monitoring check against quic_echo_server:
+ *   cc -Iffi ffi/examples/ping.c -Lffi/target/release -lquic_echo_ffi -o ping
+ *   LD_LIBRARY_PATH=ffi/target/release ./ping localhost 12806
+ * Exits with the status, like quic_echo_client --probe.
+ */
+#include <stdio.h>
+#include <stdlib.h>
+
+#include "quic_echo.h"
+
+int main(int argc, char **argv) {
+  const char *host = argc > 1 ? argv[1] : "localhost";
+  uint16_t port = argc > 2 ? (uint16_t)atoi(argv[2]) : 12806;
+
+  quic_echo_result result;
+  if (quic_echo_ping(host, port, 2000, &result) != QUIC_ECHO_OK) {
+    fprintf(stderr, "%s:%u: FAIL (%d) %s\n", host, port, result.status, result.error);
+    return result.status;
+  }
+  printf("%s:%u: %u B echoed in %.3f ms (handshake %.3f ms)\n", host, port, result.bytes,
+         result.rtt_us / 1000.0, result.handshake_us / 1000.0);
+  return 0;
+}
@@ -0,0 +1,50 @@
+/*
+ * C interface to quic_echo's reachability check (libquic_echo_ffi).
+ *
+ * quic_echo_ping() connects to a quic_echo server in echo mode, sends one
+ * ping on a stream and waits for the echo, all within timeout_ms. The
+ * server certificate is not verified, as with quic_echo_client. The call
+ * blocks and is safe to make from any thread.
+ *
+ * Build: cargo build --release (in ffi/), then link with
+ *   -Lffi/target/release -lquic_echo_ffi
+ */
+#ifndef QUIC_ECHO_H
+#define QUIC_ECHO_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Status codes; 1-3 match quic_echo_client --probe's exit codes. */
+#define QUIC_ECHO_OK 0
+/* unresolvable host, handshake failure or connection lost */
+#define QUIC_ECHO_NO_CONNECTION 1
+#define QUIC_ECHO_TIMEOUT 2
+/* the echo differs from the ping */
+#define QUIC_ECHO_MISMATCH 3
+/* null or non-UTF-8 host, or a zero timeout */
+#define QUIC_ECHO_BAD_ARGUMENT (-1)
+
+typedef struct quic_echo_result {
+  int32_t status;
+  /* from sending the ping to the complete echo */
+  uint64_t rtt_us;
+  /* resolving the host plus the handshake */
+  uint64_t handshake_us;
+  /* bytes echoed */
+  uint32_t bytes;
+  /* NUL-terminated failure description, empty on success */
+  char error[256];
+} quic_echo_result;
+
+/* Ping host:port; fills *out (if not NULL) and returns out->status. */
+int32_t quic_echo_ping(const char *host, uint16_t port, uint32_t timeout_ms, quic_echo_result *out);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,133 @@
+//! C ABI for the client's reachability check, so monitoring agents written
+//! in other languages can link it instead of running `quic_echo_client
+//! --probe` and parsing its output. See `quic_echo.h` for the C side.
+//!
+//! [`quic_echo_ping`] resolves the host, opens a connection (skipping
+//! certificate verification, like the client binary), pings the echo
+//! server over a stream and fills in a [`QuicEchoResult`]. Calls block;
+//! they run on a small tokio runtime started on first use and may come from
+//! any thread.
+
+use quic_echo::{
+  client::{EchoClient, Failure, Mode, PingResult},
+  crypto::{self, Provider, SkipServerVerification},
+};
+use std::{
+  ffi::{CStr, c_char},
+  sync::OnceLock,
+  time::Duration,
+};
+
+/// The echo came back.
+pub const QUIC_ECHO_OK: i32 = 0;
+/// No connection (unresolvable host, handshake failure, connection lost).
+pub const QUIC_ECHO_NO_CONNECTION: i32 = Failure::Connect as i32;
+/// No echo within the timeout.
+pub const QUIC_ECHO_TIMEOUT: i32 = Failure::Timeout as i32;
+/// The echo differs from what was sent.
+pub const QUIC_ECHO_MISMATCH: i32 = Failure::Mismatch as i32;
+/// A null pointer, a host that is not UTF-8, or a zero timeout.
+pub const QUIC_ECHO_BAD_ARGUMENT: i32 = -1;
+
+const PAYLOAD: &[u8] = b"ping";
+
+/// The outcome of [`quic_echo_ping`]; `status` is also its return value.
+#[repr(C)]
+#[derive(Debug)]
+pub struct QuicEchoResult {
+  pub status: i32,
+  /// Time from sending the ping to the complete echo
+  pub rtt_us: u64,
+  /// Time to resolve the host and complete the handshake
+  pub handshake_us: u64,
+  /// Bytes echoed
+  pub bytes: u32,
+  /// NUL-terminated description of the failure; empty on success
+  pub error: [c_char; 256],
+}
+
+/// Ping the echo server at `host`:`port` with everything bounded by
+/// `timeout_ms`, and fill in `out` (which may be null if only the status is
+/// wanted). Returns `out->status`.
+///
+/// # Safety
+///
+/// `host` must be null or point to a NUL-terminated string, and `out` must
+/// be null or point to writable memory for a `QuicEchoResult`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn quic_echo_ping(host: *const c_char, port: u16, timeout_ms: u32, out: *mut QuicEchoResult) -> i32 {
+  let mut result = QuicEchoResult { status: QUIC_ECHO_OK, rtt_us: 0, handshake_us: 0, bytes: 0, error: [0; 256] };
+  let host = if host.is_null() { None } else { unsafe { CStr::from_ptr(host) }.to_str().ok() };
+  match host {
+    Some(host) if timeout_ms > 0 => ping(host, port, Duration::from_millis(timeout_ms.into()), &mut result),
+    _ => fail(&mut result, QUIC_ECHO_BAD_ARGUMENT, "host must be a UTF-8 string and timeout_ms nonzero"),
+  }
+  if !out.is_null() {
+    unsafe { out.write(result) };
+    return unsafe { (*out).status };
+  }
+  result.status
+}
+
+fn ping(host: &str, port: u16, timeout: Duration, result: &mut QuicEchoResult) {
+  // the timer needs the runtime, so it is created inside it
+  let outcome = runtime().block_on(async { tokio::time::timeout(timeout, attempt(host, port, timeout)).await });
+  match outcome {
+    Ok(Ok((ping, resolved))) => {
+      result.rtt_us = ping.rtt.as_micros() as u64;
+      result.handshake_us = (resolved + ping.handshake.unwrap_or_default()).as_micros() as u64;
+      result.bytes = ping.bytes as u32;
+    }
+    Ok(Err((status, error))) => fail(result, status, &error),
+    Err(_) => fail(result, QUIC_ECHO_TIMEOUT, &format!("no echo within {timeout:?}")),
+  }
+}
+
+/// One ping, returning it with the time spent resolving `host`, or the
+/// status and error text to report.
+async fn attempt(host: &str, port: u16, timeout: Duration) -> Result<(PingResult, Duration), (i32, String)> {
+  let started = tokio::time::Instant::now();
+  let addr = tokio::net::lookup_host((host, port))
+    .await
+    .ok()
+    .and_then(|mut addrs| addrs.next())
+    .ok_or_else(|| (QUIC_ECHO_NO_CONNECTION, format!("cannot resolve {host}")))?;
+  let resolved = started.elapsed();
+  let tls = rustls::ClientConfig::builder()
+    .dangerous()
+    .with_custom_certificate_verifier(SkipServerVerification::new())
+    .with_no_client_auth();
+  let client = EchoClient::new(addr, host, tls)
+    .map_err(|e| (QUIC_ECHO_NO_CONNECTION, format!("{e:#}")))?
+    .with_timeout(timeout.saturating_sub(started.elapsed()));
+  let ping = client.ping(PAYLOAD, Mode::Stream).await;
+  // the runtime lives on, so the close can finish after we return
+  tokio::spawn(async move { client.close().await });
+  let ping = ping.map_err(|e| {
+    let status = e.downcast_ref::<Failure>().map_or(QUIC_ECHO_NO_CONNECTION, |failure| *failure as i32);
+    (status, format!("{e:#}"))
+  })?;
+  Ok((ping, resolved))
+}
+
+fn fail(result: &mut QuicEchoResult, status: i32, error: &str) {
+  result.status = status;
+  // truncated to fit, keeping the terminating NUL
+  for (to, from) in result.error.iter_mut().zip(error.bytes().take(255)) {
+    *to = from as c_char;
+  }
+}
+
+fn runtime() -> &'static tokio::runtime::Runtime {
+  static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+  RUNTIME.get_or_init(|| {
+    // the host program may have installed a provider already; either will do
+    let _ = crypto::install(Provider::Ring);
+    tokio::runtime::Builder::new_multi_thread()
+      .worker_threads(1)
+      .thread_name("quic-echo")
+      .enable_all()
+      .build()
+      .expect("start the tokio runtime")
+  })
+}
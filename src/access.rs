@@ -0,0 +1,188 @@
+//! Per-connection access log for the server (`--access-log`): one JSON
+//! object per line for every established connection, written when it
+//! closes:
+//!
+//! ```json
+//! {"ts_ms":1760000000000,"remote":"192.0.2.7:50123","sni":"localhost","alpn":"freven-quic-test",
+//!  "country":"SE","asn":64512,"as_org":"Example Net","duration_ms":1503.217,"streams":3,"uni_streams":0,"bytes_in":12,"bytes_out":12,
+//!  "datagrams_in":1,"datagrams_out":1,"udp_bytes_in":4120,"udp_bytes_out":6210,
+//!  "close_reason":"closed by peer: 0"}
+//! ```
+//!
+//! `ts_ms` is the Unix time of the close; `country`, `asn` and `as_org` come
+//! from `--geoip` and are `null` without it. `streams`, `bytes_*` (stream
+//! payload read from and echoed to the client) and `datagrams_*` are counted
+//! by the echo mode and `null` in the other modes; `udp_bytes_*` come from
+//! quinn and cover every mode.
+
+use crate::geoip::Origin;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::{
+  fs::{File, OpenOptions},
+  io::{BufWriter, Write},
+  net::SocketAddr,
+  path::Path,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex, OnceLock,
+  },
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// The log file, shared by every connection.
+#[derive(Debug)]
+pub struct AccessLog {
+  out: Mutex<BufWriter<File>>,
+}
+
+impl AccessLog {
+  /// Open `path` for appending, so restarts keep the earlier records.
+  pub fn open(path: &Path) -> Result<Self> {
+    let file = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(path)
+      .with_context(|| format!("open access log {}", path.display()))?;
+    Ok(Self { out: Mutex::new(BufWriter::new(file)) })
+  }
+
+  pub fn write(&self, record: &Record) -> Result<()> {
+    let mut out = self.out.lock().unwrap();
+    serde_json::to_writer(&mut *out, record)?;
+    writeln!(out)?;
+    // flush per record so the file can be tailed
+    out.flush()?;
+    Ok(())
+  }
+}
+
+/// What the echo mode did on one connection.
+#[derive(Debug, Default)]
+pub struct Counters {
+  streams: AtomicU64,
+  uni_streams: AtomicU64,
+  bytes_in: AtomicU64,
+  bytes_out: AtomicU64,
+  datagrams_in: AtomicU64,
+  datagrams_out: AtomicU64,
+  /// Stream and datagram payload echoed, for `--max-bytes-per-conn`
+  echoed: AtomicU64,
+  /// [`monotonic_ms`] of the last stream or datagram, for `--reap-idle`
+  last_active_ms: AtomicU64,
+}
+
+impl Counters {
+  pub fn stream(&self) {
+    self.touch();
+    self.streams.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn uni_stream(&self) {
+    self.touch();
+    self.uni_streams.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Stream payload read from the client.
+  pub fn read(&self, bytes: usize) {
+    self.touch();
+    self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+  }
+
+  /// Stream payload echoed to the client.
+  pub fn wrote(&self, bytes: usize) {
+    self.touch();
+    self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    self.echoed.fetch_add(bytes as u64, Ordering::Relaxed);
+  }
+
+  pub fn datagram_in(&self) {
+    self.touch();
+    self.datagrams_in.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn datagram_out(&self, bytes: usize) {
+    self.touch();
+    self.datagrams_out.fetch_add(1, Ordering::Relaxed);
+    self.echoed.fetch_add(bytes as u64, Ordering::Relaxed);
+  }
+
+  /// Mark the connection active now.
+  pub fn touch(&self) {
+    self.last_active_ms.store(monotonic_ms(), Ordering::Relaxed);
+  }
+
+  /// Time since the last stream or datagram (or [`touch`](Self::touch)).
+  pub fn idle(&self) -> Duration {
+    Duration::from_millis(monotonic_ms().saturating_sub(self.last_active_ms.load(Ordering::Relaxed)))
+  }
+
+  /// Stream and datagram payload echoed so far.
+  pub fn echoed(&self) -> u64 {
+    self.echoed.load(Ordering::Relaxed)
+  }
+}
+
+/// One line of the access log.
+#[derive(Debug, Serialize)]
+pub struct Record {
+  pub ts_ms: u128,
+  pub remote: SocketAddr,
+  pub sni: Option<String>,
+  pub alpn: Option<String>,
+  /// From `--geoip`; all `null` without it
+  #[serde(flatten)]
+  pub origin: Origin,
+  pub duration_ms: f64,
+  pub streams: Option<u64>,
+  pub uni_streams: Option<u64>,
+  pub bytes_in: Option<u64>,
+  pub bytes_out: Option<u64>,
+  pub datagrams_in: Option<u64>,
+  pub datagrams_out: Option<u64>,
+  pub udp_bytes_in: u64,
+  pub udp_bytes_out: u64,
+  pub close_reason: String,
+}
+
+impl Record {
+  /// The record of `conn`, closed for `reason` after `duration`; `counters`
+  /// is `None` outside the echo mode.
+  pub fn new(
+    conn: &quinn::Connection,
+    sni: Option<String>,
+    alpn: Option<String>,
+    origin: Origin,
+    duration: Duration,
+    counters: Option<&Counters>,
+    reason: &quinn::ConnectionError,
+  ) -> Self {
+    let stats = conn.stats();
+    let count = |counter: fn(&Counters) -> &AtomicU64| counters.map(|c| counter(c).load(Ordering::Relaxed));
+    Record {
+      ts_ms: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis(),
+      remote: conn.remote_address(),
+      sni,
+      alpn,
+      origin,
+      // microsecond precision is plenty
+      duration_ms: (duration.as_secs_f64() * 1e6).round() / 1e3,
+      streams: count(|c| &c.streams),
+      uni_streams: count(|c| &c.uni_streams),
+      bytes_in: count(|c| &c.bytes_in),
+      bytes_out: count(|c| &c.bytes_out),
+      datagrams_in: count(|c| &c.datagrams_in),
+      datagrams_out: count(|c| &c.datagrams_out),
+      udp_bytes_in: stats.udp_rx.bytes,
+      udp_bytes_out: stats.udp_tx.bytes,
+      close_reason: reason.to_string(),
+    }
+  }
+}
+
+/// Milliseconds since the first call, on the monotonic clock: idle times
+/// must not jump when NTP steps the wall clock.
+fn monotonic_ms() -> u64 {
+  static BASE: OnceLock<Instant> = OnceLock::new();
+  BASE.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
@@ -0,0 +1,192 @@
+//! IP allow and deny lists for the server (`--allow`, `--deny`,
+//! `--acl-file`), checked against each connection attempt's source address
+//! before the handshake.
+//!
+//! A `deny` match always refuses. If there is any `allow` rule, an address
+//! must also match one of them; with none, everything not denied passes.
+//! The file holds one rule per line, `allow <cidr>` or `deny <cidr>` (a bare
+//! address is a single host, `#` starts a comment), and is re-read when its
+//! modification time changes; a file that no longer parses keeps the
+//! previous rules.
+
+use anyhow::{Context, Result};
+use std::{
+  fmt,
+  net::IpAddr,
+  path::{Path, PathBuf},
+  str::FromStr,
+  sync::{Arc, RwLock},
+  time::{Duration, SystemTime},
+};
+use tracing::{info, warn};
+
+/// How often the `--acl-file` is checked for changes.
+const RELOAD_POLL: Duration = Duration::from_secs(2);
+
+/// An address block like `10.0.0.0/8` or `2001:db8::/32`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+  addr: IpAddr,
+  prefix: u8,
+}
+
+impl Cidr {
+  pub fn contains(&self, ip: IpAddr) -> bool {
+    match (self.addr, ip.to_canonical()) {
+      (IpAddr::V4(net), IpAddr::V4(ip)) => {
+        let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+        u32::from(net) & mask == u32::from(ip) & mask
+      }
+      (IpAddr::V6(net), IpAddr::V6(ip)) => {
+        let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+        u128::from(net) & mask == u128::from(ip) & mask
+      }
+      _ => false,
+    }
+  }
+}
+
+impl FromStr for Cidr {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, String> {
+    let (addr, prefix) = s.split_once('/').map_or((s, None), |(addr, prefix)| (addr, Some(prefix)));
+    let addr = addr
+      .parse::<IpAddr>()
+      .map_err(|_| format!("expected an address or CIDR block like 10.0.0.0/8, got {s:?}"))?
+      .to_canonical();
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+      Some(prefix) => prefix.parse::<u8>().ok().filter(|&p| p <= max).ok_or_else(|| format!("bad prefix length in {s:?}"))?,
+      None => max,
+    };
+    Ok(Cidr { addr, prefix })
+  }
+}
+
+impl fmt::Display for Cidr {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}/{}", self.addr, self.prefix)
+  }
+}
+
+/// Allow and deny blocks.
+#[derive(Clone, Debug, Default)]
+pub struct Rules {
+  pub allow: Vec<Cidr>,
+  pub deny: Vec<Cidr>,
+}
+
+impl Rules {
+  /// Parse `--acl-file` contents.
+  pub fn parse(text: &str) -> Result<Self> {
+    let mut rules = Rules::default();
+    for (n, line) in text.lines().enumerate() {
+      let line = line.split('#').next().unwrap_or_default().trim();
+      if line.is_empty() {
+        continue;
+      }
+      let (list, cidr) = match line.split_once(char::is_whitespace) {
+        Some(("allow", cidr)) => (&mut rules.allow, cidr),
+        Some(("deny", cidr)) => (&mut rules.deny, cidr),
+        _ => anyhow::bail!("line {}: expected `allow <cidr>` or `deny <cidr>`, got {line:?}", n + 1),
+      };
+      list.push(cidr.trim().parse().map_err(|e| anyhow::anyhow!("line {}: {e}", n + 1))?);
+    }
+    Ok(rules)
+  }
+
+  fn merged(&self, other: &Rules) -> Rules {
+    Rules {
+      allow: [&self.allow[..], &other.allow[..]].concat(),
+      deny: [&self.deny[..], &other.deny[..]].concat(),
+    }
+  }
+
+  /// Why `ip` is refused, if it is.
+  pub fn check(&self, ip: IpAddr) -> Result<(), Refusal> {
+    if let Some(block) = self.deny.iter().find(|block| block.contains(ip)) {
+      return Err(Refusal::Denied(*block));
+    }
+    if !self.allow.is_empty() && !self.allow.iter().any(|block| block.contains(ip)) {
+      return Err(Refusal::NotAllowed);
+    }
+    Ok(())
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Refusal {
+  /// Matched this deny block
+  Denied(Cidr),
+  /// Matched no allow block
+  NotAllowed,
+}
+
+impl fmt::Display for Refusal {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Refusal::Denied(block) => write!(f, "denied by {block}"),
+      Refusal::NotAllowed => f.write_str("not in any allowed block"),
+    }
+  }
+}
+
+/// The rules in force: the command line's plus the file's.
+#[derive(Debug)]
+pub struct Acl {
+  flags: Rules,
+  file: Option<PathBuf>,
+  current: RwLock<Rules>,
+}
+
+impl Acl {
+  /// Combine `flags` with the rules of `file`, which must parse.
+  pub fn new(flags: Rules, file: Option<PathBuf>) -> Result<Arc<Self>> {
+    let current = match &file {
+      Some(path) => flags.merged(&load(path)?),
+      None => flags.clone(),
+    };
+    info!(allow = current.allow.len(), deny = current.deny.len(), "IP access rules loaded");
+    Ok(Arc::new(Acl { flags, file, current: RwLock::new(current) }))
+  }
+
+  pub fn check(&self, ip: IpAddr) -> Result<(), Refusal> {
+    self.current.read().unwrap().check(ip)
+  }
+
+  /// Re-read the file whenever it changes, until the process exits.
+  pub fn watch(self: Arc<Self>) {
+    let Some(path) = self.file.clone() else {
+      return;
+    };
+    tokio::spawn(async move {
+      let mut seen = modified(&path);
+      loop {
+        tokio::time::sleep(RELOAD_POLL).await;
+        let now = modified(&path);
+        if now == seen {
+          continue;
+        }
+        seen = now;
+        match load(&path) {
+          Ok(rules) => {
+            let rules = self.flags.merged(&rules);
+            info!(allow = rules.allow.len(), deny = rules.deny.len(), "reloaded {}", path.display());
+            *self.current.write().unwrap() = rules;
+          }
+          Err(e) => warn!("keeping the previous IP access rules: {e:#}"),
+        }
+      }
+    });
+  }
+}
+
+fn load(path: &Path) -> Result<Rules> {
+  let text = std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+  Rules::parse(&text).with_context(|| format!("parse {}", path.display()))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+  std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
@@ -0,0 +1,541 @@
+//! Automatic certificates for the server (`--acme <domain>`, needs the `acme`
+//! feature): a certificate is ordered from an ACME CA (Let's Encrypt by
+//! default) with the TLS-ALPN-01 challenge, cached on disk and renewed in the
+//! background, so a server on the public internet has a certificate that
+//! verifying clients (browsers, `EchoClient` with public roots) accept as is.
+//!
+//! TLS-ALPN-01 is answered on TCP (port 443 unless `--acme-port` says
+//! otherwise, the CA always connects to 443): the CA's handshake offers the
+//! `acme-tls/1` ALPN and gets a self-signed certificate carrying the
+//! challenge digest. Everything else gets the current certificate.
+
+use anyhow::Result;
+use rustls::server::ResolvesServerCert;
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+
+/// Where and how to obtain the certificate.
+#[derive(Debug, Clone)]
+pub struct Acme {
+  /// Names the certificate covers; the first one is its subject
+  pub domains: Vec<String>,
+  pub email: Option<String>,
+  /// ACME directory URL of the CA
+  pub directory: String,
+  /// Directory holding the account key and the issued certificate
+  pub cache: PathBuf,
+  /// TCP address answering TLS-ALPN-01 challenges
+  pub challenge_addr: SocketAddr,
+}
+
+/// Let's Encrypt's production directory.
+pub const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// Start answering challenges, load the cached certificate or order a new
+/// one, and keep it renewed; the returned resolver always serves the
+/// current certificate.
+#[cfg(feature = "acme")]
+pub async fn start(acme: Acme) -> Result<Arc<dyn ResolvesServerCert>> {
+  client::start(acme).await
+}
+
+#[cfg(not(feature = "acme"))]
+pub async fn start(_acme: Acme) -> Result<Arc<dyn ResolvesServerCert>> {
+  anyhow::bail!("--acme needs quic_echo built with `--features acme`")
+}
+
+#[cfg(feature = "acme")]
+mod client {
+  use super::Acme;
+  use anyhow::{Context, Result};
+  use rcgen::{CertificateParams, CustomExtension, DistinguishedName, KeyPair};
+  use ring::{
+    digest::{SHA256, digest},
+    rand::SystemRandom,
+    signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair as _},
+  };
+  use rustls::{
+    pki_types::{CertificateDer, DnsName, PrivateKeyDer, ServerName, pem::PemObject},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+  };
+  use serde::Deserialize;
+  use serde_json::{Value, json};
+  use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+  };
+  use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+  };
+  use tokio_rustls::{TlsAcceptor, TlsConnector};
+  use tracing::{debug, info, warn};
+
+  const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+  /// Let's Encrypt certificates live 90 days; renew with a month to spare
+  const RENEW_AFTER: Duration = Duration::from_secs(60 * 24 * 3600);
+  const RETRY: Duration = Duration::from_secs(3600);
+  const POLL: Duration = Duration::from_secs(2);
+  const POLL_TRIES: u32 = 60;
+
+  /// Serves the current certificate, or a challenge certificate to the CA.
+  #[derive(Debug, Default)]
+  struct Resolver {
+    current: RwLock<Option<Arc<CertifiedKey>>>,
+    /// Challenge certificate per domain while its authorization is pending
+    challenges: Mutex<HashMap<String, Arc<CertifiedKey>>>,
+  }
+
+  impl ResolvesServerCert for Resolver {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+      let challenge = hello.alpn().is_some_and(|mut alpn| alpn.any(|p| p == ACME_TLS_ALPN));
+      if challenge {
+        let sni = hello.server_name()?;
+        debug!(sni, "answering TLS-ALPN-01 challenge");
+        return self.challenges.lock().unwrap().get(sni).cloned();
+      }
+      self.current.read().unwrap().clone()
+    }
+  }
+
+  pub(super) async fn start(acme: Acme) -> Result<Arc<dyn ResolvesServerCert>> {
+    std::fs::create_dir_all(&acme.cache).with_context(|| format!("create {}", acme.cache.display()))?;
+    let resolver = Arc::new(Resolver::default());
+
+    let mut tls = rustls::ServerConfig::builder().with_no_client_auth().with_cert_resolver(resolver.clone());
+    tls.alpn_protocols = vec![ACME_TLS_ALPN.to_vec()];
+    let listener = TcpListener::bind(acme.challenge_addr)
+      .await
+      .with_context(|| format!("bind TLS-ALPN-01 challenge port {}", acme.challenge_addr))?;
+    info!("answering ACME TLS-ALPN-01 challenges on {} (TCP)", listener.local_addr()?);
+    tokio::spawn(answer_challenges(listener, TlsAcceptor::from(Arc::new(tls))));
+
+    let cert = acme.cache.join("cert.pem");
+    let issued = match load_cached(&acme) {
+      Ok(Some(issued)) => {
+        info!(cert = %cert.display(), "using cached ACME certificate");
+        issued
+      }
+      Ok(None) => order(&acme, &resolver).await?,
+      Err(e) => {
+        warn!("ignoring cached ACME certificate: {e:#}");
+        order(&acme, &resolver).await?
+      }
+    };
+    *resolver.current.write().unwrap() = Some(Arc::new(issued));
+
+    let renewing = resolver.clone();
+    tokio::spawn(async move {
+      loop {
+        let age = std::fs::metadata(&cert)
+          .and_then(|m| m.modified())
+          .ok()
+          .and_then(|t| SystemTime::now().duration_since(t).ok())
+          .unwrap_or_default();
+        tokio::time::sleep(RENEW_AFTER.saturating_sub(age)).await;
+        info!(domains = ?acme.domains, "renewing ACME certificate");
+        match order(&acme, &renewing).await {
+          Ok(issued) => *renewing.current.write().unwrap() = Some(Arc::new(issued)),
+          Err(e) => {
+            warn!("ACME renewal failed, retrying in {RETRY:?}: {e:#}");
+            tokio::time::sleep(RETRY).await;
+          }
+        }
+      }
+    });
+    Ok(resolver)
+  }
+
+  /// Complete the CA's handshakes; the challenge is answered by the
+  /// certificate alone, so the connection is dropped right after.
+  async fn answer_challenges(listener: TcpListener, acceptor: TlsAcceptor) {
+    loop {
+      let (tcp, remote) = match listener.accept().await {
+        Ok(accepted) => accepted,
+        Err(e) => {
+          warn!("challenge listener failed: {e}");
+          return;
+        }
+      };
+      let acceptor = acceptor.clone();
+      tokio::spawn(async move {
+        match tokio::time::timeout(POLL * 5, acceptor.accept(tcp)).await {
+          Ok(Ok(_)) => debug!(%remote, "challenge handshake done"),
+          Ok(Err(e)) => debug!(%remote, "challenge handshake failed: {e}"),
+          Err(_) => debug!(%remote, "challenge handshake timed out"),
+        }
+      });
+    }
+  }
+
+  /// The cached certificate, if there is one covering every domain.
+  fn load_cached(acme: &Acme) -> Result<Option<CertifiedKey>> {
+    let (cert, key) = (acme.cache.join("cert.pem"), acme.cache.join("key.pem"));
+    if !cert.exists() || !key.exists() {
+      return Ok(None);
+    }
+    let chain = CertificateDer::pem_file_iter(&cert)
+      .and_then(|it| it.collect::<Result<Vec<_>, _>>())
+      .with_context(|| format!("read {}", cert.display()))?;
+    let leaf = chain.first().context("no certificate in the cache")?;
+    let ee = webpki::EndEntityCert::try_from(leaf).context("parse cached certificate")?;
+    for domain in &acme.domains {
+      let name = ServerName::DnsName(DnsName::try_from(domain.as_str())?.to_owned());
+      if ee.verify_is_valid_for_subject_name(&name).is_err() {
+        info!(domain, "cached ACME certificate does not cover this name");
+        return Ok(None);
+      }
+    }
+    let key = PrivateKeyDer::from_pem_file(&key).with_context(|| format!("read {}", key.display()))?;
+    Ok(Some(CertifiedKey::from_der(chain, key, &crate::crypto::provider())?))
+  }
+
+  /// Order a certificate for `acme.domains`, store it in the cache and
+  /// return it.
+  async fn order(acme: &Acme, resolver: &Resolver) -> Result<CertifiedKey> {
+    info!(domains = ?acme.domains, directory = acme.directory, "requesting ACME certificate");
+    let mut client = Client::new(acme).await?;
+    client.register(acme.email.as_deref()).await?;
+
+    let identifiers: Vec<_> = acme.domains.iter().map(|d| json!({"type": "dns", "value": d})).collect();
+    let resp = client.post(&client.dir.new_order.clone(), Some(&json!({"identifiers": identifiers}))).await?;
+    let order_url = resp.header("location").context("order has no Location")?.to_string();
+    let order: Order = resp.json()?;
+
+    for authz_url in &order.authorizations {
+      client.authorize(authz_url, resolver).await?;
+    }
+
+    let order = client.poll(&order_url, |o: &Order| o.status != "pending").await?;
+    anyhow::ensure!(order.status == "ready", "order is {} instead of ready", order.status);
+    let key = KeyPair::generate()?;
+    let mut params = CertificateParams::new(acme.domains.clone())?;
+    params.distinguished_name = DistinguishedName::new();
+    let csr = params.serialize_request(&key)?;
+    client.post(&order.finalize, Some(&json!({"csr": base64url(csr.der())}))).await?;
+    let order = client.poll(&order_url, |o: &Order| o.status != "processing").await?;
+    anyhow::ensure!(order.status == "valid", "order is {} after finalizing", order.status);
+    let cert_url = order.certificate.context("valid order without certificate")?;
+    let pem = client.post(&cert_url, None).await?.body;
+
+    let (cert, key_file) = (acme.cache.join("cert.pem"), acme.cache.join("key.pem"));
+    write_private(&key_file, key.serialize_pem().as_bytes())?;
+    std::fs::write(&cert, &pem).with_context(|| format!("write {}", cert.display()))?;
+    let chain = CertificateDer::pem_slice_iter(&pem).collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::Pkcs8(key.serialize_der().into());
+    let issued = CertifiedKey::from_der(chain, key, &crate::crypto::provider())?;
+    info!(cert = %cert.display(), "ACME certificate issued");
+    Ok(issued)
+  }
+
+  #[derive(Debug, Deserialize)]
+  #[serde(rename_all = "camelCase")]
+  struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+  }
+
+  #[derive(Debug, Deserialize)]
+  struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+  }
+
+  #[derive(Debug, Deserialize)]
+  struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+  }
+
+  #[derive(Debug, Deserialize)]
+  struct Identifier {
+    value: String,
+  }
+
+  #[derive(Debug, Deserialize)]
+  struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: Option<String>,
+    error: Option<Value>,
+  }
+
+  /// An ACME account session: signs every request with the account key.
+  struct Client {
+    http: TlsConnector,
+    dir: Directory,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    /// Account URL once registered; requests before that carry the JWK
+    kid: Option<String>,
+    nonce: Option<String>,
+  }
+
+  impl Client {
+    async fn new(acme: &Acme) -> Result<Self> {
+      let roots = rustls::RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+      let tls = rustls::ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+      let http = TlsConnector::from(Arc::new(tls));
+      let dir = request(&http, "GET", &acme.directory, None).await?.json().context("ACME directory")?;
+      let key = account_key(&acme.cache.join("account.key"))?;
+      Ok(Self { http, dir, key, rng: SystemRandom::new(), kid: None, nonce: None })
+    }
+
+    /// Find or create the account for the key; agrees to the CA's terms.
+    async fn register(&mut self, email: Option<&str>) -> Result<()> {
+      let mut account = json!({"termsOfServiceAgreed": true});
+      if let Some(email) = email {
+        account["contact"] = json!([format!("mailto:{email}")]);
+      }
+      let resp = self.post(&self.dir.new_account.clone(), Some(&account)).await?;
+      let kid = resp.header("location").context("account has no Location")?.to_string();
+      debug!(account = kid, "ACME account ready");
+      self.kid = Some(kid);
+      Ok(())
+    }
+
+    /// Fulfil one authorization with TLS-ALPN-01.
+    async fn authorize(&mut self, url: &str, resolver: &Resolver) -> Result<()> {
+      let authz: Authorization = self.post(url, None).await?.json()?;
+      let domain = authz.identifier.value;
+      if authz.status == "valid" {
+        debug!(domain, "authorization already valid");
+        return Ok(());
+      }
+      let challenge = authz
+        .challenges
+        .iter()
+        .find(|c| c.kind == "tls-alpn-01")
+        .with_context(|| format!("CA offers no tls-alpn-01 challenge for {domain}"))?;
+      let token = challenge.token.as_deref().context("challenge without token")?;
+      let key_authorization = format!("{token}.{}", self.thumbprint());
+
+      let mut params = CertificateParams::new(vec![domain.clone()])?;
+      let digest = digest(&SHA256, key_authorization.as_bytes());
+      params.custom_extensions = vec![CustomExtension::new_acme_identifier(digest.as_ref())];
+      let key = KeyPair::generate()?;
+      let cert = params.self_signed(&key)?;
+      let certified = CertifiedKey::from_der(
+        vec![cert.der().clone()],
+        PrivateKeyDer::Pkcs8(key.serialize_der().into()),
+        &crate::crypto::provider(),
+      )?;
+      resolver.challenges.lock().unwrap().insert(domain.clone(), Arc::new(certified));
+
+      info!(domain, "answering tls-alpn-01 challenge");
+      let res = async {
+        self.post(&challenge.url, Some(&json!({}))).await?;
+        self.poll(url, |a: &Authorization| a.status != "pending").await
+      }
+      .await;
+      resolver.challenges.lock().unwrap().remove(&domain);
+      let authz = res?;
+      if authz.status != "valid" {
+        let error = authz.challenges.iter().find_map(|c| c.error.as_ref());
+        anyhow::bail!("authorization for {domain} is {}: {}", authz.status, error.unwrap_or(&Value::Null));
+      }
+      Ok(())
+    }
+
+    /// POST-as-GET `url` until `done` holds for the resource.
+    async fn poll<T: for<'de> Deserialize<'de>>(&mut self, url: &str, done: impl Fn(&T) -> bool) -> Result<T> {
+      for _ in 0..POLL_TRIES {
+        let resource: T = self.post(url, None).await?.json()?;
+        if done(&resource) {
+          return Ok(resource);
+        }
+        tokio::time::sleep(POLL).await;
+      }
+      anyhow::bail!("gave up waiting for {url}")
+    }
+
+    /// JWS-signed POST of `payload`, or POST-as-GET without one.
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<Response> {
+      let payload = payload.map_or_else(String::new, |p| base64url(p.to_string().as_bytes()));
+      for attempt in 0.. {
+        let nonce = match self.nonce.take() {
+          Some(nonce) => nonce,
+          None => {
+            let resp = request(&self.http, "HEAD", &self.dir.new_nonce, None).await?;
+            resp.header("replay-nonce").context("no Replay-Nonce")?.to_string()
+          }
+        };
+        let mut protected = json!({"alg": "ES256", "nonce": nonce, "url": url});
+        match &self.kid {
+          Some(kid) => protected["kid"] = json!(kid),
+          None => protected["jwk"] = self.jwk(),
+        }
+        let protected = base64url(protected.to_string().as_bytes());
+        let signature = self
+          .key
+          .sign(&self.rng, format!("{protected}.{payload}").as_bytes())
+          .map_err(|_| anyhow::anyhow!("cannot sign ACME request"))?;
+        let body = json!({"protected": protected, "payload": payload, "signature": base64url(signature.as_ref())});
+        let resp = request(&self.http, "POST", url, Some(body.to_string().as_bytes())).await?;
+        self.nonce = resp.header("replay-nonce").map(str::to_string);
+        if resp.status < 400 {
+          return Ok(resp);
+        }
+        let problem: Value = serde_json::from_slice(&resp.body).unwrap_or_default();
+        if problem["type"] == "urn:ietf:params:acme:error:badNonce" && attempt < 3 {
+          continue;
+        }
+        anyhow::bail!("{url}: HTTP {}: {}", resp.status, problem["detail"].as_str().unwrap_or("no detail"));
+      }
+      unreachable!()
+    }
+
+    fn jwk(&self) -> Value {
+      let point = self.key.public_key().as_ref();
+      json!({"crv": "P-256", "kty": "EC", "x": base64url(&point[1..33]), "y": base64url(&point[33..])})
+    }
+
+    /// RFC 7638 thumbprint of the account key.
+    fn thumbprint(&self) -> String {
+      // members in lexicographic order without whitespace, as RFC 7638 requires
+      let point = self.key.public_key().as_ref();
+      let jwk = format!(
+        r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+        base64url(&point[1..33]),
+        base64url(&point[33..])
+      );
+      base64url(digest(&SHA256, jwk.as_bytes()).as_ref())
+    }
+  }
+
+  /// The account key from `path`, created on first use.
+  fn account_key(path: &Path) -> Result<EcdsaKeyPair> {
+    let rng = SystemRandom::new();
+    let pkcs8 = match std::fs::read(path) {
+      Ok(pkcs8) => pkcs8,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+          .map_err(|_| anyhow::anyhow!("cannot generate ACME account key"))?;
+        write_private(path, pkcs8.as_ref())?;
+        info!(path = %path.display(), "created ACME account key");
+        pkcs8.as_ref().to_vec()
+      }
+      Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+    };
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+      .map_err(|e| anyhow::anyhow!("bad ACME account key {}: {e}", path.display()))
+  }
+
+  /// Write a key readable only by the owner (where the OS supports it).
+  fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path).with_context(|| format!("create {}", path.display()))?;
+    std::io::Write::write_all(&mut file, data)?;
+    Ok(())
+  }
+
+  struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+  }
+
+  impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+      self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    fn json<T: for<'de> Deserialize<'de>>(&self) -> Result<T> {
+      Ok(serde_json::from_slice(&self.body)?)
+    }
+  }
+
+  /// One HTTP/1.1 request over a fresh HTTPS connection; the ACME
+  /// exchange is a handful of small requests, so nothing is kept alive.
+  async fn request(http: &TlsConnector, method: &str, url: &str, body: Option<&[u8]>) -> Result<Response> {
+    let uri: http::Uri = url.parse().with_context(|| format!("parse URL {url}"))?;
+    anyhow::ensure!(uri.scheme_str() == Some("https"), "ACME URL {url} is not https");
+    let host = uri.host().context("URL without host")?.to_string();
+    let port = uri.port_u16().unwrap_or(443);
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+
+    let tcp = TcpStream::connect((host.as_str(), port)).await.with_context(|| format!("connect to {host}:{port}"))?;
+    let name = ServerName::try_from(host.clone())?;
+    let mut tls = http.connect(name, tcp).await.with_context(|| format!("TLS handshake with {host}"))?;
+    let body = body.unwrap_or_default();
+    let head = format!(
+      "{method} {path} HTTP/1.1\r\nHost: {host}\r\nUser-Agent: quic_echo/{}\r\nAccept: */*\r\n\
+       Content-Type: application/jose+json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+      env!("CARGO_PKG_VERSION"),
+      body.len(),
+    );
+    tls.write_all(head.as_bytes()).await?;
+    tls.write_all(body).await?;
+    tls.flush().await?;
+
+    let mut raw = Vec::new();
+    if let Err(e) = tls.read_to_end(&mut raw).await {
+      // some servers close without close_notify; the length checks below catch truncation
+      if e.kind() != std::io::ErrorKind::UnexpectedEof {
+        return Err(e).context("read HTTP response");
+      }
+    }
+    let split = raw.windows(4).position(|w| w == b"\r\n\r\n").context("truncated HTTP response")?;
+    let head = std::str::from_utf8(&raw[..split]).context("HTTP response head is not UTF-8")?;
+    let mut lines = head.split("\r\n");
+    let status = lines
+      .next()
+      .and_then(|l| l.split(' ').nth(1))
+      .and_then(|s| s.parse().ok())
+      .context("bad HTTP status line")?;
+    let headers: Vec<(String, String)> = lines
+      .filter_map(|l| l.split_once(':'))
+      .map(|(n, v)| (n.trim().to_string(), v.trim().to_string()))
+      .collect();
+    let mut resp = Response { status, headers, body: raw[split + 4..].to_vec() };
+    if method == "HEAD" {
+      resp.body.clear();
+    } else if resp.header("transfer-encoding").is_some_and(|te| te.eq_ignore_ascii_case("chunked")) {
+      resp.body = unchunk(&resp.body)?;
+    } else if let Some(len) = resp.header("content-length").and_then(|l| l.parse::<usize>().ok()) {
+      anyhow::ensure!(resp.body.len() >= len, "HTTP body cut off at {} of {len} bytes", resp.body.len());
+      resp.body.truncate(len);
+    }
+    Ok(resp)
+  }
+
+  fn unchunk(mut data: &[u8]) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+      let end = data.windows(2).position(|w| w == b"\r\n").context("bad chunked body")?;
+      let size = std::str::from_utf8(&data[..end])?.split(';').next().unwrap_or_default();
+      let size = usize::from_str_radix(size.trim(), 16).context("bad chunk size")?;
+      data = &data[end + 2..];
+      if size == 0 {
+        return Ok(body);
+      }
+      anyhow::ensure!(data.len() >= size + 2, "chunked body cut off");
+      body.extend_from_slice(&data[..size]);
+      data = &data[size + 2..];
+    }
+  }
+
+  /// Unpadded base64url, as JWS wants it.
+  fn base64url(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+      let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | u32::from(*b) << (16 - 8 * i));
+      for i in 0..=chunk.len() {
+        out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+      }
+    }
+    out
+  }
+}
@@ -0,0 +1,152 @@
+//! Anti-amplification check for the client (`amplification`): the client
+//! sends its first flight and then goes silent, so the server never gets to
+//! validate the client's address, and every byte the server sends in the
+//! meantime is counted. RFC 9000 section 8 caps that at three times what the
+//! client sent; anything above is a server an attacker could use to
+//! amplify traffic towards a spoofed address.
+//!
+//! The endpoint's socket is wrapped like the `--pcap` capture: sends pass
+//! until the first datagram from the server arrives and are dropped (but
+//! reported as sent) from then on, so no ACK or Handshake packet raises the
+//! server's budget.
+
+use anyhow::Result;
+use quinn::{
+  AsyncUdpSocket, ClientConfig, Endpoint, EndpointConfig, Runtime, TokioRuntime, UdpPoller,
+  udp::{RecvMeta, Transmit},
+};
+use std::{
+  fmt,
+  io::{self, IoSliceMut},
+  net::SocketAddr,
+  pin::Pin,
+  sync::{
+    Arc,
+    atomic::{AtomicBool, AtomicU64, Ordering},
+  },
+  task::{Context, Poll},
+  time::Duration,
+};
+
+/// Limit on the server's bytes per client byte before address validation.
+pub const LIMIT: f64 = 3.0;
+
+/// What went over the wire during the check.
+#[derive(Debug, Default)]
+pub struct Report {
+  pub client_datagrams: u64,
+  pub client_bytes: u64,
+  pub server_datagrams: u64,
+  pub server_bytes: u64,
+  /// The server answered with a Retry, validating the address statelessly
+  pub retry: bool,
+}
+
+impl Report {
+  /// Server bytes per client byte.
+  pub fn ratio(&self) -> f64 {
+    self.server_bytes as f64 / self.client_bytes.max(1) as f64
+  }
+}
+
+#[derive(Default)]
+struct Counters {
+  silent: AtomicBool,
+  retry: AtomicBool,
+  client_datagrams: AtomicU64,
+  client_bytes: AtomicU64,
+  server_datagrams: AtomicU64,
+  server_bytes: AtomicU64,
+}
+
+/// Send the first flight from `socket` to `server` with `config`, stay
+/// silent afterwards and count what the server sends for `window`.
+pub async fn measure(
+  socket: std::net::UdpSocket,
+  server: SocketAddr,
+  server_name: &str,
+  config: ClientConfig,
+  window: Duration,
+) -> Result<Report> {
+  let counters = Arc::new(Counters::default());
+  let inner = TokioRuntime.wrap_udp_socket(socket)?;
+  let local = inner.local_addr()?;
+  let socket = Arc::new(Silencer { inner, counters: counters.clone(), local });
+  let endpoint = Endpoint::new_with_abstract_socket(EndpointConfig::default(), None, socket, Arc::new(TokioRuntime))?;
+  let connecting = endpoint.connect_with(config, server, server_name)?;
+  // the handshake cannot complete while we are silent; only the window matters
+  let _ = tokio::time::timeout(window, connecting).await;
+  endpoint.close(0u32.into(), b"");
+
+  Ok(Report {
+    client_datagrams: counters.client_datagrams.load(Ordering::Relaxed),
+    client_bytes: counters.client_bytes.load(Ordering::Relaxed),
+    server_datagrams: counters.server_datagrams.load(Ordering::Relaxed),
+    server_bytes: counters.server_bytes.load(Ordering::Relaxed),
+    retry: counters.retry.load(Ordering::Relaxed),
+  })
+}
+
+/// Passes sends until the server is first heard from, then swallows them.
+struct Silencer {
+  inner: Arc<dyn AsyncUdpSocket>,
+  counters: Arc<Counters>,
+  local: SocketAddr,
+}
+
+impl fmt::Debug for Silencer {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Silencer").field("local", &self.local).finish_non_exhaustive()
+  }
+}
+
+impl AsyncUdpSocket for Silencer {
+  fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+    self.inner.clone().create_io_poller()
+  }
+
+  fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+    if self.counters.silent.load(Ordering::Relaxed) {
+      return Ok(());
+    }
+    self.inner.try_send(transmit)?;
+    let segment = transmit.segment_size.unwrap_or(transmit.contents.len()).max(1);
+    let datagrams = transmit.contents.len().div_ceil(segment);
+    self.counters.client_datagrams.fetch_add(datagrams as u64, Ordering::Relaxed);
+    self.counters.client_bytes.fetch_add(transmit.contents.len() as u64, Ordering::Relaxed);
+    Ok(())
+  }
+
+  fn poll_recv(&self, cx: &mut Context, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) -> Poll<io::Result<usize>> {
+    let n = std::task::ready!(self.inner.poll_recv(cx, bufs, meta))?;
+    for (buf, meta) in bufs.iter().zip(meta.iter()).take(n) {
+      let stride = if meta.stride == 0 { meta.len } else { meta.stride };
+      for datagram in buf[..meta.len].chunks(stride.max(1)) {
+        self.counters.server_datagrams.fetch_add(1, Ordering::Relaxed);
+        self.counters.server_bytes.fetch_add(datagram.len() as u64, Ordering::Relaxed);
+        // QUIC v1 long header with packet type 3
+        if datagram.first().is_some_and(|b| b & 0xf0 == 0xf0) {
+          self.counters.retry.store(true, Ordering::Relaxed);
+        }
+      }
+    }
+    self.counters.silent.store(true, Ordering::Relaxed);
+    Poll::Ready(Ok(n))
+  }
+
+  fn local_addr(&self) -> io::Result<SocketAddr> {
+    self.inner.local_addr()
+  }
+
+  fn max_transmit_segments(&self) -> usize {
+    self.inner.max_transmit_segments()
+  }
+
+  fn max_receive_segments(&self) -> usize {
+    self.inner.max_receive_segments()
+  }
+
+  fn may_fragment(&self) -> bool {
+    self.inner.may_fragment()
+  }
+}
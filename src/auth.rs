@@ -0,0 +1,56 @@
+//! Token authentication for the echo protocol (`--auth-token`): the client
+//! opens a unidirectional stream before anything else and sends the token
+//! on it, framed as a 2-byte big-endian length and the token bytes. The
+//! server waits for that stream before it echoes anything, and closes the
+//! connection with [`AUTH_FAILED`] if the token is missing, malformed or
+//! wrong, so a public server does not reflect traffic for strangers.
+//!
+//! The token travels inside the encrypted connection, but is a shared
+//! secret: anyone who has it can use the server.
+
+use anyhow::Result;
+use quinn::{Connection, VarInt};
+use ring::digest::{SHA256, digest};
+use std::time::Duration;
+
+/// Application close code for a failed authentication.
+pub const AUTH_FAILED: u32 = 401;
+/// Longest token accepted.
+pub const MAX_TOKEN: usize = 1024;
+
+/// Send `token` on a new unidirectional stream.
+pub async fn send(conn: &Connection, token: &str) -> Result<()> {
+  anyhow::ensure!(token.len() <= MAX_TOKEN, "the auth token is longer than {MAX_TOKEN} bytes");
+  let mut stream = conn.open_uni().await?;
+  let mut frame = (token.len() as u16).to_be_bytes().to_vec();
+  frame.extend_from_slice(token.as_bytes());
+  stream.write_all(&frame).await?;
+  stream.finish()?;
+  Ok(())
+}
+
+/// Wait up to `timeout` for the client's token and compare it with `token`;
+/// on any failure the connection is closed with [`AUTH_FAILED`] and the
+/// reason returned.
+pub async fn check(conn: &Connection, token: &str, timeout: Duration) -> Result<()> {
+  let presented = tokio::time::timeout(timeout, async {
+    let mut stream = conn.accept_uni().await?;
+    let frame = stream.read_to_end(2 + MAX_TOKEN).await?;
+    match frame.split_first_chunk::<2>() {
+      Some((len, token)) if usize::from(u16::from_be_bytes(*len)) == token.len() => Ok(token.to_vec()),
+      _ => anyhow::bail!("malformed token frame"),
+    }
+  })
+  .await;
+  let failure = match presented {
+    // compare digests, so the time taken says nothing about the token
+    Ok(Ok(presented)) if digest(&SHA256, &presented).as_ref() == digest(&SHA256, token.as_bytes()).as_ref() => {
+      return Ok(());
+    }
+    Ok(Ok(_)) => anyhow::anyhow!("wrong token"),
+    Ok(Err(e)) => e.context("no token"),
+    Err(_) => anyhow::anyhow!("no token within {timeout:?}"),
+  };
+  conn.close(VarInt::from_u32(AUTH_FAILED), b"authentication failed");
+  Err(anyhow::anyhow!("authentication failed: {failure:#}"))
+}
@@ -15,7 +15,7 @@ Both sides must match to negotiate the protocol.
 Certificate verification (IMPORTANT)
 ------------------------------------
 This client uses a custom verifier that *skips* server certificate validation
-(SkipServerVerification). That means:
+(quic_echo::crypto::SkipServerVerification). That means:
   - It will connect even if the server uses a self-signed cert.
   - It is NOT secure against man-in-the-middle attacks.
 Use this ONLY for local/dev testing. In production:
@@ -24,198 +24,2578 @@ Use this ONLY for local/dev testing. In production:
 
 Networking debug info
 ---------------------
-Before connecting, the client runs:
-  ip route get <remote-ip>
-to print the chosen source IP and interface. This is optional but useful when
+Before connecting, the client asks the OS which source IP and interface it
+would use for the remote (connected UDP socket + interface address lookup,
+no external commands) and logs it. This is optional but useful when
 debugging multi-homed hosts / VPNs / IPv4 vs IPv6 routing.
 
 How the client works (high level)
 ---------------------------------
-- Resolves host:port to a SocketAddr.
-- Creates a client Endpoint bound to 0.0.0.0:0 (ephemeral UDP port).
+- Resolves host:port (system resolver, or --resolver <ip[:port]> with
+  --resolver-proto udp|tls|https, and curl-style --resolve host:port:addr
+  overrides; restricted to one family with --ipv4 / --ipv6) and
+  orders the addresses Happy-Eyeballs style: IPv6 first, then alternating
+  families.
+- Creates a client Endpoint bound to --bind (default: 0.0.0.0:0 for an IPv4
+  remote, dual-stack [::]:0 for IPv6; ephemeral UDP port unless --local-port
+  picks one, for firewalls keyed on source ports), optionally pinned to --interface (SO_BINDTODEVICE on Linux,
+  IP_BOUND_IF on macOS) for multi-homed hosts and VPN vs default-route tests.
 - Applies TransportConfig datagram buffer tuning.
-- Connects to the server with SNI = host.
+- Connects to the server with SNI = host (or --sni <name>, to test
+  SNI-based routing or virtual hosting independently of the address
+  dialed; also used as the HTTP/3 :authority), racing all resolved addresses:
+  a new attempt starts every 250 ms (or as soon as the previous one fails)
+  and the first completed handshake wins. Each handshake attempt is bounded
+  by --connect-timeout (default 10 s). Each attempt's outcome and timing
+  is logged, along with the winning address family.
 - Prints negotiated ALPN.
-- Sends "ping" and waits up to 5 seconds for the echoed response:
+- With --reconnect, a failed handshake or a connection lost mid-test is
+  retried up to --max-retries times with exponential backoff (200 ms
+  doubling up to 10 s, with jitter); each failure reason and the total time
+  to success are logged.
+- With --connections N, N connections are opened at once (each with its own
+  handshake race, retries and test run, logged under a `worker{n}` span)
+  and every connection's result and time is printed along with the
+  aggregate: successes, failures, wall time and min/avg/max per connection.
+- Sends "ping" and waits up to --response-timeout (default 5 s) for the
+  echoed response:
   - datagram mode: send_datagram + read_datagram
   - stream mode: open_bi + write_all + finish + read_to_end
+  - HTTP/3 mode (--h3, ALPN "h3"): POST /echo with "ping" as the body and
+    check that the response is 200 with the same body
+  - WebTransport mode (--webtransport, ALPN "h3"): extended CONNECT to
+    /webtransport, then echo "ping" through a session stream (or a session
+    datagram with --datagram)
+  - MASQUE mode (--proxy-target host:port, ALPN "h3"): open a CONNECT-UDP
+    session through the server, send "ping" to the target as one UDP
+    datagram and print the first reply
+  - perf mode (--perf, ALPN "perf"): one quinn `perf` request uploading
+    --perf-upload bytes and downloading --perf-download bytes, reported as
+    throughput and time to first byte (not bounded by --response-timeout)
+    --warmup SECS first runs smaller requests of the same shape for that
+    long, so the measured request starts with congestion control out of
+    slow start; the warmup gets its own "not measured" line
+  - duplex mode (--duplex, ALPN "quic-echo-duplex"): against a --mode
+    duplex server, upload --duplex-size bytes while the server sends as
+    many back on the same stream; each direction's throughput is timed on
+    its own (upload until acknowledged, download until FIN), along with how
+    long both were moving at once
+  - DNS-over-QUIC mode (--doq, ALPN "doq"): send one --doq-name/--doq-type
+    query (RFC 9250 framing) and print the response
+  - file mode (--send-file PATH, ALPN "quic-echo-file"): stream the file to
+    a --mode file server, which echoes it back (or stores it with --store);
+    SHA-256 is computed on both ends and compared, and the transfer rate is
+    printed (not bounded by --response-timeout)
+  - UDP tunnel (--udp-listen ip:port): listen on a local UDP port and send
+    every packet as one QUIC datagram to a server started with --forward-to,
+    which relays it to its target; replies go back to the last local sender.
+    Runs until the connection closes. --standby host (--standby-port,
+    --standby-sni) keeps a second connection open and moves the tunnel to
+    it as soon as the primary's RTT passes --failover-rtt ms, its loss
+    passes --failover-loss %, it stops answering or it closes; a
+    --reconnect retry dials a standby that has closed again
+  - pipe mode (--pipe): netcat over QUIC, stdin is copied to one
+    bidirectional stream and the stream's response to stdout; EOF on stdin
+    finishes our side of the stream, and the client exits once the server
+    has finished its side too; --read-rate B/s reads the echo through a
+    token bucket, so the receive window fills and the server's sender stalls
+    on flow control
+  - dashboard mode (--tui, needs the `tui` feature): ping every --interval
+    seconds (a stream each, or a datagram with --datagram) and show a live
+    RTT sparkline, loss counters and connection stats until q / Esc / Ctrl-C
+  - interactive mode (--interactive): send every stdin line over its own
+    stream (or as a datagram; `=`/`~` prefixes pick per line) and print the
+    echoes as they arrive; /stats, /migrate (rebind to a new local port) and
+    /close <code> [reason] poke at the connection
+  - datagram size probe (--probe-datagram-size): binary-search the largest
+    datagram (up to Connection::max_datagram_size()) that actually comes
+    back, and print the advertised vs the working size; a gap between the
+    two points at an MTU black hole on the path (e.g. a tunnel)
+  - stream abort modes: --reset-after N writes N bytes on a stream and
+    resets it (RESET_STREAM, code 42), --stop-sending sends STOP_SENDING
+    (code 42) and keeps writing pings; the server mirrors either with the
+    same code, which the client checks
+  - fuzz mode (--fuzz [--seed N] [--fuzz-iterations N]): a seeded random
+    mix of random-size datagrams (zero and maximum included), streams written
+    in zero-length/one-byte/huge pieces, streams reset mid-write or stopped
+    before the echo, empty and unidirectional streams and abandoned streams;
+    afterwards a ping must still come back. The seed is printed to
+    reproduce a failure
+  - reliable datagrams (--reliable-dgram): send --reliable-count messages at
+    --reliable-rate per second as datagrams with sequence numbers; the echo
+    of each DATA is acknowledged with a selective ACK datagram, whose echo
+    acknowledges it to the sender, and unacknowledged messages are resent
+    with backoff up to --reliable-retries times. Prints the delivery rate
+    and the latency recovery added
+
+Payload verification
+--------------------
+Every echo is compared with what was sent, byte for byte: the stream,
+datagram, HTTP/3 and WebTransport pings, and --pipe while it runs (sent
+bytes are kept until their echo is compared). A mismatch or a short echo
+fails the run with the offset of the first differing byte and a hex
+excerpt of both sides.
+
+Response transforms
+-------------------
+--transform upper|reverse|sha256 expects a server started with the same
+--transform: the stream and datagram pings then check that the response is
+that transform of "ping" (e.g. "PING"), which a middlebox reflecting
+packets could not produce.
+
+Sample export
+-------------
+--samples-out FILE writes a time series while the client runs: one row per
+ping (measured round trip) and one per --sample-interval (default 1 s) of
+the connection (quinn's smoothed path RTT, UDP bytes and lost packets since
+the previous row, cwnd, the phase: warmup or measure, and jitter_ms for --soak --datagram
+pings), as CSV or JSON Lines (--samples-format, default
+from the file extension). The interval rows sample Connection::rtt()
+independently of the test's own round trips, so long --tui, --perf or
+--send-file runs show path RTT drift; its first/last/min/max is logged at
+the end.
+
+Latency histogram
+-----------------
+--hdr-out FILE records the round trip of every echoed ping (each
+connection's with --connections, every soak ping with --soak) in an
+HdrHistogram and writes its percentile distribution to FILE at the end,
+in milliseconds, in the .hgrm text format HdrHistogram's own tools and
+plotters read. Modes without ping round trips (--perf, --pipe, ...) have
+nothing to write and only log a warning.
+
+Comparing servers
+-----------------
+Repeating --host (or --host a,b, or listing `host [port]` lines in a
+--targets file) runs the same echo test against every server, one after
+another or all at once with --parallel-targets: the handshake (including
+the Happy Eyeballs race), median/min RTT of 20 pings on one stream, and
+packet loss from Connection::stats(), printed as one table with the best
+server named at the end. Servers that fail get a FAIL row instead of
+stopping the run.
+
+Dual-stack report
+-----------------
+--dual-stack runs that test twice against one --host with both A and AAAA
+records, once over its first IPv6 and once over its first IPv4 address,
+and prints the two side by side with the family the OS would have picked:
+the family of the first address in the resolver's answer (getaddrinfo
+sorts by RFC 6724 and /etc/gai.conf; --resolver and --resolve keep the
+DNS or override order).
+
+Preferred address
+-----------------
+Given the server's --preferred-addr, --preferred-addr reports after the
+test whether the connection ended up there. quinn's client does not act on
+the preferred_address transport parameter, so expect "not migrated"; the
+flag is there to confirm that, and to compare with other clients.
+
+Latency under load
+------------------
+--loaded-latency [SECS] (default 10) measures bufferbloat: 20 pings on an
+idle path, then pings every 100 ms on the same connection while a second
+connection echoes bulk data on 4 streams for SECS, saturating the path both
+ways. It prints idle vs loaded RTT (median, p90, max), the load's
+throughput and the loaded median as round trips per minute (RPM) graded
+like Apple's networkQuality: low under 300, high over 1000. Pings in the
+first quarter of the load (at most 2 s) are left out while queues fill.
+
+TCP comparison
+--------------
+--compare-tcp runs the same test over QUIC and over TLS-over-TCP to a
+server started with --tcp-port (default: the same port number as QUIC):
+handshake time, median/min RTT of 4-byte pings on one stream/connection and
+echo throughput of a 16 MiB transfer, printed side by side. TCP_NODELAY is
+set so small pings aren't held back by Nagle. With --warmup SECS each
+transport echoes 1 MiB rounds for that long before its pings and transfer.
+
+0-RTT
+-----
+--0rtt connects once to get a session ticket, then reconnects with 0-RTT
+and sends the ping (stream, or datagram with --datagram) as early data,
+before the handshake completes. It fails unless the server accepts the early
+data, which needs the server's --0rtt; on success it prints how soon after
+connecting the echo arrived. It uses the first resolved address only.
+
+Auth token
+----------
+--auth-token SECRET sends the token on a unidirectional stream right after
+the handshake (in 0-RTT too), before any test traffic, for a server
+started with the same --auth-token; without it such a server closes the
+connection with error 401. The comparison, conformance, scenario, soak and
+probe connections send it as well.
+
+Close code and reason
+---------------------
+--close-code N and/or --close-reason TEXT end the test with an explicit
+application close carrying those values (default 0 and empty), and wait
+for the CONNECTION_CLOSE to go out; the server logs what it received, so
+this checks that close information survives a proxy intact. The other
+way round, an application close from the server (e.g. its --shutdown-code
+/ --shutdown-reason) is logged with its code and reason.
+
+Health probe
+------------
+--probe is made for Kubernetes liveness probes and cron monitoring: one
+handshake and one ping (stream, or datagram with --datagram), all bounded
+by --probe-timeout (default 2 s), printing nothing and logging nothing
+unless --verbose. The exit code says what happened:
+    0  the echo came back
+    1  handshake failure (or no usable address, or a broken connection)
+    2  timeout: no completed handshake and echo within --probe-timeout
+    3  echo mismatch
+
+Assertions
+----------
+--assert-max-rtt MS, --assert-max-loss PCT and --assert-min-throughput
+MBPS turn a single run into a pass/fail gate for CI. After the run the
+client prints one [assert] line per threshold. The RTT is the ping's
+round trip, or quinn's path RTT estimate in modes without a single ping.
+Loss is lost packets as a share of those sent. Throughput is the slower
+direction of --perf or --duplex, or the --send-file rate. Each failed
+threshold sets its own bit of the exit code (8 rtt, 16 loss, 32
+throughput), so 24 means both RTT and loss failed. Other errors still
+exit with 1.
+
+Config file
+-----------
+--config FILE reads options from a flat TOML table keyed by long flag names
+(`port = 4433`, `stream-window = 16777216`, switches as `true`); they are
+spliced in front of the command line, which therefore wins; options set
+in the environment are skipped.
+
+Environment
+-----------
+Every option also reads QUIC_ECHO_<FLAG> (e.g. QUIC_ECHO_PORT,
+QUIC_ECHO_STREAM_WINDOW; QUIC_ECHO_CONFIG for --config; SSLKEYLOGFILE
+for --keylog). Precedence: command line, then environment, then the
+config file, then defaults.
+
+Flow control
+------------
+--stream-window, --conn-window, --max-concurrent-bidi and --max-concurrent-uni
+work like on the server and set what we grant the server; for download-heavy
+tests (--perf, echoes) raise the windows on both ends.
+Whenever the server's stream limit leaves the client waiting to open a
+stream it logs a warning, and the time it waited once credit arrives.
+
+Datagram push
+-------------
+--listen sends nothing and only receives the datagrams a server started
+with --push-dgrams sends on its own, then prints how many arrived, the
+rate, gaps in the sequence numbers (lost), late arrivals (reordered),
+duplicates and the interarrival jitter. It runs until the server closes
+the connection, or for --listen-for seconds.
+
+Server-initiated streams
+------------------------
+Otherwise the client never accepts streams. --accept-stream waits up to
+--response-timeout for the one a server started with --push-stream opens,
+reads it to the end, checks the server's byte pattern and, on a
+bidirectional stream, echoes it back before closing.
+
+Rate limit
+----------
+--rate-limit MBPS caps what each connection sends with a token bucket
+shared by its stream writes and datagram sends, so a benchmark offers a
+chosen load instead of whatever the congestion controller allows. It
+applies to the bulk senders: --perf uploads, --send-file, --pipe and
+--udp-listen (tunneled packets wait their turn rather than being dropped).
+
+Loss report
+-----------
+After every run the client logs a "loss report" from Connection::stats():
+packets sent and lost, lost bytes (what quinn retransmits), congestion
+events, MTU black holes and flow-control BLOCKED frames in each direction,
+plus `limit` = none / loss / flow control, so a slow benchmark shows whether
+it was loss-limited or flow-control-limited. The server logs the same per
+connection when it closes. A "connection ID activity" line follows: IDs
+issued and received (NEW_CONNECTION_ID) and retired by either side
+(RETIRE_CONNECTION_ID), to see how a NAT or load balancer copes with
+rotations.
+
+Tuning advisor
+--------------
+With --advise the client inspects Connection::stats() after the run
+(flow-control BLOCKED frames, losses, congestion events, MTU black holes)
+and prints concrete TransportConfig suggestions instead of raw counters.
+
+Conformance battery
+-------------------
+`quic_echo_client --host <server> conformance` runs a fixed, versioned set of
+scenarios (handshake/ALPN, stream and datagram echo, empty and large payloads,
+concurrent streams, oversize datagram and wrong-ALPN error paths) and prints a
+pass/fail matrix. The exit status is non-zero if any scenario fails, so third
+parties can verify a deployment (and its firewall rules) with one command.
+Bump CONFORMANCE_VERSION whenever the set of scenarios changes.
+
+Anti-amplification check
+------------------------
+`quic_echo_client --host <server> amplification [--window SECS]` sends only
+the first flight from a wrapped socket that swallows every later send, so
+the server never validates the address, then counts the server's bytes for
+the window and fails if they exceed 3x the client's (RFC 9000 section 8).
+
+Version negotiation
+-------------------
+`quic_echo_client --host <server> version-negotiation [--offer VERSION]`
+sends a padded Initial with an unsupported (by default randomly greased)
+version over a plain UDP socket, prints the versions in the server's Version
+Negotiation reply, then makes a normal connection; a missing reply points at
+a middlebox dropping unknown versions.
+
+Record and replay
+-----------------
+--record FILE writes every payload the client sends (ping, --pipe input,
+--interactive lines, tunneled packets) with its offset from the start of
+the run, as JSON Lines; --replay FILE sends them again over the echo ALPN
+with the original spacing and reports what came back and how far behind
+the recorded timing the sends fell.
+
+Soak test
+---------
+--soak keeps pinging (every --soak-interval seconds, with --datagram as
+datagrams) for --duration hours. Lost connections are counted by reason and
+re-established, and every --soak-report seconds (hourly by default) a line
+reports availability, RTT p50/p90/p99/max, reconnects and the longest
+outage; the whole run is summarized at the end. Pings that fall due while
+the client is reconnecting count as failed. The connection sends
+keep-alives so long ping intervals do not hit the idle timeout.
+With --datagram the summaries add the interarrival jitter of the echoes
+(RFC 3550, smoothed over 16 pings) at the end of the interval and its
+maximum, to judge a path for real-time media; --samples-out records a
+row per echo with the jitter so far in a jitter_ms column.
+
+Scenario runner
+---------------
+--scenario FILE runs the TOML list of steps in FILE (connect, datagrams
+with count/rate/size, stream of N bytes, migrate, sleep, close with a code)
+against an echo server and prints one OK/FAIL line per step; like the
+conformance battery it exits non-zero if a step failed.
+
+Crypto provider
+---------------
+--crypto-provider ring|aws-lc-rs picks the rustls crypto backend (ring by
+default; aws-lc-rs needs --features aws-lc-rs). The client logs
+the provider and the negotiated cipher suite after each handshake.
+
+TLS session details
+-------------------
+--tls-info prints the TLS version, cipher suite, key exchange group,
+certificate signature scheme and whether the session was resumed, read off
+the server's handshake messages (handshake::Sniffing); with --0rtt for both
+connections, the second being the resumed one.
+
+TLS key log
+-----------
+--keylog FILE (or SSLKEYLOGFILE) appends every TLS secret in the NSS key
+log format so Wireshark can decrypt captured traffic; debugging only.
+
+Packet capture
+--------------
+--pcap FILE wraps the endpoint's UDP socket (also after /migrate or a
+migrate step) so each datagram sent or received is also written to a pcap file
+with a synthesized IP/UDP header; open it in Wireshark with the --keylog
+file to decrypt.
+
+UDP offload
+-----------
+--gso/--gro auto|on|off work like on the server. The batch limits are
+logged when the socket is set up, and "UDP offload stats" at exit shows the
+datagrams per send and receive call: around 1.00 means no batching.
+
+io_uring backend
+----------------
+--io-backend uring works like on the server (Linux, `--features io-uring`);
+a /migrate or migrate-step rebind gets a new ring as well.
+
+DSCP marking
+------------
+--dscp N|NAME works like on the server: every packet leaves marked, and the
+markings of the echoes that came back are logged at exit ("DSCP of
+received packets"), so a remarking or bleaching hop shows up there.
+
+Hop limit
+---------
+--ttl N sends every packet with IP TTL / IPv6 hop limit N, to keep probes
+within a few hops or check a TTL-based firewall rule. On Linux the ICMP
+errors routers send back are logged as they come in ("ICMP error"), and a
+handshake that times out names them, e.g. "connect 10.0.2.2:4433; ICMP
+time exceeded from 10.0.1.1 x 5 (--ttl too small to reach the server)".
+
+Logging
+-------
+Diagnostics (route probe, ALPN, ...) go through `tracing` to stderr inside a
+`conn` span; results stay on stdout. RUST_LOG controls filtering, including
+quinn's own events, and --log-format text|pretty|json selects the format.
 */
 
 use anyhow::{Context, Result};
 use bytes::Bytes;
-use clap::Parser;
-use quinn::{ClientConfig, Endpoint, TransportConfig};
-use regex::Regex;
-use std::{net::SocketAddr, process::Command, sync::Arc, time::Duration};
+use clap::{CommandFactory, Parser, Subcommand};
+use quinn::{
+  AsyncUdpSocket, ClientConfig, Connection, ConnectionError, ConnectionStats, Endpoint, EndpointConfig, ReadError,
+  SendDatagramError, TokioRuntime, TransportConfig, VarInt, WriteError,
+};
+use std::{
+  future::Future,
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+use rand::Rng;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{info, info_span, warn, Instrument};
 
-use quinn::crypto::rustls::{NoInitialCipherSuite, QuicClientConfig};
-use rustls::{
-  client::danger,
-  crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
-  pki_types::{CertificateDer, ServerName, UnixTime},
-  DigitallySignedStruct, SignatureScheme,
+use hickory_resolver::proto::rr::RecordType;
+use quic_echo::{
+  amplification, auth, bufferbloat,
+  cid::CidActivity,
+  config,
+  crypto::{self, Provider}, doq, duplex, fuzz, http3, interactive, keylog, masque, perf, push, reliable, soak, transfer,
+  flow::{self, FlowControl},
+  handshake::{self, Sniffing},
+  hdr::Latencies,
+  logging::{self, LogFormat},
+  loss::LossReport,
+  offload::{OffloadOptions, OffloadStats},
+  pace::{self, Pacer, RateLimit},
+  pcap::Pcap,
+  resolve::{ResolveOverride, Resolver, ResolverProto},
+  replay::{self, Recorder, Recording},
+  route::route_get,
+  samples::{SampleFormat, SampleWriter, Sampler},
+  scenario::{self, Scenario},
+  tcp,
+  transform::Transform,
+  ttl,
+  tui, tunnel,
+  verify::{self, EchoCheck},
+  versions,
+  socket::bind_udp,
+  webtransport,
 };
+use quinn::crypto::rustls::{NoInitialCipherSuite, QuicClientConfig};
+use rustls::KeyLog;
 
 const ALPN: &[u8] = b"freven-quic-test";
+const CONFORMANCE_VERSION: u32 = 1;
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+const SCENARIO_TIMEOUT: Duration = Duration::from_secs(5);
+const BACKOFF_BASE: Duration = Duration::from_millis(200);
+const BACKOFF_MAX: Duration = Duration::from_secs(10);
+const TUNNEL_KEEP_ALIVE: Duration = Duration::from_secs(10);
+const COMPARE_PINGS: usize = 20;
+const COMPARE_BYTES: usize = 16 * 1024 * 1024;
+/// Error code of --reset-after's RESET_STREAM and --stop-sending's STOP_SENDING.
+const ABORT_CODE: u32 = 42;
+/// Gap between pings while --stop-sending waits for the server's STOP_SENDING.
+const STOP_PING_GAP: Duration = Duration::from_millis(50);
+
+fn make_tls_config(alpn: &[u8], key_log: Option<Arc<dyn KeyLog>>) -> rustls::ClientConfig {
+  let mut tls = rustls::ClientConfig::builder()
+    .dangerous()
+    .with_custom_certificate_verifier(crypto::SkipServerVerification::new())
+    .with_no_client_auth();
 
-#[derive(Debug)]
-struct SkipServerVerification(Arc<CryptoProvider>);
-impl SkipServerVerification {
-  fn new() -> Arc<Self> {
-    Arc::new(Self(Arc::new(rustls::crypto::ring::default_provider())))
+  tls.alpn_protocols = vec![alpn.to_vec()];
+  if let Some(key_log) = key_log {
+    tls.key_log = key_log;
   }
+  tls
+}
+
+/// The quinn config for `tls`, recording each session's details (the
+/// logged cipher suite, --tls-info).
+fn quic_client_config(tls: rustls::ClientConfig) -> Result<ClientConfig, NoInitialCipherSuite> {
+  let quic = QuicClientConfig::try_from(tls)?;
+  Ok(ClientConfig::new(Arc::new(Sniffing::new(Arc::new(quic)))))
+}
+
+fn make_client_config(alpn: &[u8], key_log: Option<Arc<dyn KeyLog>>) -> Result<ClientConfig, NoInitialCipherSuite> {
+  quic_client_config(make_tls_config(alpn, key_log))
 }
-impl danger::ServerCertVerifier for SkipServerVerification {
-  fn verify_server_cert(
-    &self,
-    _end_entity: &CertificateDer<'_>,
-    _intermediates: &[CertificateDer<'_>],
-    _server_name: &ServerName<'_>,
-    _ocsp: &[u8],
-    _now: UnixTime,
-  ) -> std::result::Result<danger::ServerCertVerified, rustls::Error> {
-    Ok(danger::ServerCertVerified::assertion())
+
+#[derive(Parser, Debug)]
+// later flags win, so the command line overrides --config
+#[clap(args_override_self = true)]
+struct Opt {
+  /// Read options from this TOML file (flags on the command line win)
+  #[clap(long, env = "QUIC_ECHO_CONFIG")]
+  config: Option<PathBuf>,
+  /// Server to test; repeat it (or use --targets) to compare several servers
+  #[clap(long, env = "QUIC_ECHO_HOST", value_delimiter = ',', required_unless_present = "targets")]
+  host: Vec<String>,
+  /// File of servers to compare, one `host [port]` per line (`#` starts a comment)
+  #[clap(long, env = "QUIC_ECHO_TARGETS")]
+  targets: Option<PathBuf>,
+  /// Test the servers of a comparison at the same time instead of one after another
+  #[clap(long, env = "QUIC_ECHO_PARALLEL_TARGETS")]
+  parallel_targets: bool,
+  /// Test --host over IPv6 and over IPv4 and compare the two families
+  #[clap(long, env = "QUIC_ECHO_DUAL_STACK", conflicts_with_all = ["targets", "ipv4", "ipv6", "parallel_targets"])]
+  dual_stack: bool,
+  /// TLS server name to send instead of --host (an IP address sends no SNI)
+  #[clap(long, env = "QUIC_ECHO_SNI")]
+  sni: Option<String>,
+  #[clap(long, env = "QUIC_ECHO_PORT", default_value_t = 12806)]
+  port: u16,
+  #[clap(long, env = "QUIC_ECHO_DATAGRAM")]
+  datagram: bool,
+  /// Speak HTTP/3 (ALPN "h3") and POST the ping to /echo
+  #[clap(long, env = "QUIC_ECHO_H3", conflicts_with = "datagram")]
+  h3: bool,
+  /// Open a WebTransport session (ALPN "h3") and echo through it
+  #[clap(long, env = "QUIC_ECHO_WEBTRANSPORT", conflicts_with = "h3")]
+  webtransport: bool,
+  /// Use the server as a CONNECT-UDP proxy and send the ping to this UDP `host:port`
+  #[clap(long, env = "QUIC_ECHO_PROXY_TARGET", conflicts_with_all = ["datagram", "h3", "webtransport"])]
+  proxy_target: Option<String>,
+  /// Run a quinn `perf` request (ALPN "perf") instead of a ping
+  #[clap(long, env = "QUIC_ECHO_PERF", conflicts_with_all = ["datagram", "h3", "webtransport", "proxy_target", "doq"])]
+  perf: bool,
+  /// Bytes to upload with --perf
+  #[clap(long, env = "QUIC_ECHO_PERF_UPLOAD", default_value_t = 0, requires = "perf")]
+  perf_upload: u64,
+  /// Bytes to download with --perf
+  #[clap(long, env = "QUIC_ECHO_PERF_DOWNLOAD", default_value_t = 16 * 1024 * 1024, requires = "perf")]
+  perf_download: u64,
+  /// Upload and download at the same time on one stream (ALPN "quic-echo-duplex") against --mode duplex
+  #[clap(long, env = "QUIC_ECHO_DUPLEX", conflicts_with_all = ["datagram", "h3", "webtransport", "proxy_target", "perf", "doq", "send_file", "udp_listen", "listen", "accept_stream", "pipe", "interactive", "tui"])]
+  duplex: bool,
+  /// Bytes sent in each direction with --duplex
+  #[clap(long, env = "QUIC_ECHO_DUPLEX_SIZE", default_value_t = 16 * 1024 * 1024, requires = "duplex")]
+  duplex_size: u64,
+  /// Send a DNS-over-QUIC query (ALPN "doq") instead of a ping
+  #[clap(long, env = "QUIC_ECHO_DOQ", conflicts_with_all = ["datagram", "h3", "webtransport", "proxy_target"])]
+  doq: bool,
+  /// Name to look up with --doq
+  #[clap(long, env = "QUIC_ECHO_DOQ_NAME", default_value = "example.com", requires = "doq")]
+  doq_name: String,
+  /// Record type to look up with --doq
+  #[clap(long, env = "QUIC_ECHO_DOQ_TYPE", default_value = "A", requires = "doq")]
+  doq_type: RecordType,
+  /// Send this file (ALPN "quic-echo-file") and verify its SHA-256 on both ends
+  #[clap(long, env = "QUIC_ECHO_SEND_FILE", conflicts_with_all = ["datagram", "h3", "webtransport", "proxy_target", "perf", "doq"])]
+  send_file: Option<PathBuf>,
+  /// Ask the server to store --send-file instead of echoing it back
+  #[clap(long, env = "QUIC_ECHO_STORE", requires = "send_file")]
+  store: bool,
+  /// Tunnel UDP packets arriving on this local `ip:port` to the server's --forward-to target
+  #[clap(long, env = "QUIC_ECHO_UDP_LISTEN", conflicts_with_all = ["datagram", "h3", "webtransport", "proxy_target", "perf", "doq", "send_file", "pipe", "interactive", "tui"])]
+  udp_listen: Option<SocketAddr>,
+  /// Keep a warm standby connection to this server and move the --udp-listen tunnel to it when the primary degrades
+  #[clap(long, env = "QUIC_ECHO_STANDBY", requires = "udp_listen", conflicts_with = "connections")]
+  standby: Option<String>,
+  /// Port of --standby (default: --port)
+  #[clap(long, env = "QUIC_ECHO_STANDBY_PORT", requires = "standby")]
+  standby_port: Option<u16>,
+  /// TLS server name to send to --standby instead of its host (an IP address sends no SNI)
+  #[clap(long, env = "QUIC_ECHO_STANDBY_SNI", requires = "standby")]
+  standby_sni: Option<String>,
+  /// Fail over to --standby once the primary's RTT estimate exceeds this many ms
+  #[clap(long, env = "QUIC_ECHO_FAILOVER_RTT", default_value_t = 200.0, requires = "standby")]
+  failover_rtt: f64,
+  /// Fail over to --standby once more than this percentage of the primary's packets is lost within a second
+  #[clap(long, env = "QUIC_ECHO_FAILOVER_LOSS", default_value_t = 10.0, requires = "standby")]
+  failover_loss: f64,
+  /// Send nothing and report on the datagrams a --push-dgrams server sends us
+  #[clap(long, env = "QUIC_ECHO_LISTEN", conflicts_with_all = ["datagram", "h3", "webtransport", "proxy_target", "perf", "doq", "send_file", "udp_listen", "pipe", "interactive", "tui"])]
+  listen: bool,
+  /// Stop --listen after this many seconds (default: when the server closes the connection)
+  #[clap(long, env = "QUIC_ECHO_LISTEN_FOR", value_parser = parse_secs, requires = "listen")]
+  listen_for: Option<Duration>,
+  /// Accept the stream a --push-stream server opens, verify it and echo it back if bidirectional
+  #[clap(long, env = "QUIC_ECHO_ACCEPT_STREAM", conflicts_with_all = ["datagram", "h3", "webtransport", "proxy_target", "perf", "doq", "send_file", "udp_listen", "pipe", "interactive", "tui", "listen"])]
+  accept_stream: bool,
+  /// The server's advertised --preferred-addr: report whether the connection moved there
+  #[clap(long, env = "QUIC_ECHO_PREFERRED_ADDR")]
+  preferred_addr: Option<SocketAddr>,
+  /// Seconds of traffic before --perf or --compare-tcp start measuring, to get past slow start
+  #[clap(long, env = "QUIC_ECHO_WARMUP", value_parser = parse_secs)]
+  warmup: Option<Duration>,
+  /// Cap what each connection sends (--perf, --send-file, --pipe, --udp-listen) at this many Mbit/s
+  #[clap(long, env = "QUIC_ECHO_RATE_LIMIT")]
+  rate_limit: Option<f64>,
+  /// Read the --pipe echo at most this many bytes per second, like a slow receiver
+  #[clap(long, env = "QUIC_ECHO_READ_RATE", value_parser = clap::value_parser!(u64).range(1..), requires = "pipe")]
+  read_rate: Option<u64>,
+  /// Copy stdin to a stream and the response to stdout until both sides are done (like nc)
+  #[clap(long, env = "QUIC_ECHO_PIPE", conflicts_with_all = ["datagram", "h3", "webtransport", "proxy_target", "perf", "doq", "send_file", "interactive"])]
+  pipe: bool,
+  /// Send stdin lines to the server and print the echoes (commands: /stats, /migrate, /close, /help)
+  #[clap(long, env = "QUIC_ECHO_INTERACTIVE", conflicts_with_all = ["h3", "webtransport", "proxy_target", "perf", "doq", "send_file", "tui"])]
+  interactive: bool,
+  /// Ping continuously and show a live dashboard (needs the `tui` feature)
+  #[clap(long, env = "QUIC_ECHO_TUI", conflicts_with_all = ["pipe", "h3", "webtransport", "proxy_target", "perf", "doq", "send_file"])]
+  tui: bool,
+  /// Seconds between pings with --tui
+  #[clap(long, env = "QUIC_ECHO_INTERVAL", default_value = "1", value_parser = parse_secs, requires = "tui")]
+  interval: Duration,
+  /// Find the largest datagram that makes the round trip and compare it with the advertised maximum
+  #[clap(long, env = "QUIC_ECHO_PROBE_DATAGRAM_SIZE", conflicts_with_all = ["datagram", "h3", "webtransport", "proxy_target", "perf", "doq", "send_file", "tui", "interactive", "pipe", "udp_listen"])]
+  probe_datagram_size: bool,
+  /// Expect the server's --transform of the ping instead of the ping itself
+  #[clap(long, env = "QUIC_ECHO_TRANSFORM", value_enum, default_value_t = Transform::Echo)]
+  transform: Transform,
+  /// Send --reliable-count datagrams with sequence numbers, selective ACKs and retransmission
+  #[clap(long, env = "QUIC_ECHO_RELIABLE_DGRAM", conflicts_with_all = ["datagram", "h3", "webtransport", "proxy_target", "perf", "doq", "send_file", "udp_listen", "pipe", "interactive", "tui", "probe_datagram_size"])]
+  reliable_dgram: bool,
+  /// Messages to send with --reliable-dgram
+  #[clap(long, env = "QUIC_ECHO_RELIABLE_COUNT", default_value_t = 1000, requires = "reliable_dgram")]
+  reliable_count: u32,
+  /// Messages per second with --reliable-dgram
+  #[clap(long, env = "QUIC_ECHO_RELIABLE_RATE", default_value_t = 200.0, requires = "reliable_dgram")]
+  reliable_rate: f64,
+  /// Payload bytes per message with --reliable-dgram
+  #[clap(long, env = "QUIC_ECHO_RELIABLE_SIZE", default_value_t = 64, requires = "reliable_dgram")]
+  reliable_size: usize,
+  /// Retransmissions of one message before --reliable-dgram gives up on it
+  #[clap(long, env = "QUIC_ECHO_RELIABLE_RETRIES", default_value_t = 5, requires = "reliable_dgram")]
+  reliable_retries: u32,
+  /// Write this many bytes on a stream, reset it and check the server resets the echo with the same code
+  #[clap(long, env = "QUIC_ECHO_RESET_AFTER", conflicts_with_all = ["datagram", "h3", "webtransport", "proxy_target", "perf", "doq", "send_file", "udp_listen", "pipe", "interactive", "tui", "probe_datagram_size"])]
+  reset_after: Option<u64>,
+  /// Send STOP_SENDING on a stream and check the server answers with the same code
+  #[clap(long, env = "QUIC_ECHO_STOP_SENDING", conflicts_with_all = ["reset_after", "datagram", "h3", "webtransport", "proxy_target", "perf", "doq", "send_file", "udp_listen", "pipe", "interactive", "tui", "probe_datagram_size"])]
+  stop_sending: bool,
+  /// Send a seeded random mix of malformed and abusive traffic, then check the server still echoes
+  #[clap(long, env = "QUIC_ECHO_FUZZ", conflicts_with_all = ["datagram", "h3", "webtransport", "proxy_target", "perf", "doq", "send_file", "udp_listen", "pipe", "interactive", "tui", "probe_datagram_size", "reliable_dgram"])]
+  fuzz: bool,
+  /// Seed of --fuzz, to reproduce a run (default: random, printed)
+  #[clap(long, env = "QUIC_ECHO_SEED", requires = "fuzz")]
+  seed: Option<u64>,
+  /// Actions per --fuzz run
+  #[clap(long, env = "QUIC_ECHO_FUZZ_ITERATIONS", default_value_t = 200, requires = "fuzz")]
+  fuzz_iterations: u32,
+  /// Ping for --duration hours, reconnecting as needed, and print a stability summary every --soak-report
+  #[clap(long, env = "QUIC_ECHO_SOAK", conflicts_with_all = ["h3", "webtransport", "proxy_target", "perf", "doq", "send_file", "udp_listen", "pipe", "interactive", "tui", "probe_datagram_size", "reliable_dgram", "fuzz", "compare_tcp", "connections", "reconnect"])]
+  soak: bool,
+  /// Hours to run --soak for
+  #[clap(long, env = "QUIC_ECHO_DURATION", default_value = "1", value_parser = parse_hours, requires = "soak")]
+  duration: Duration,
+  /// Seconds between --soak pings
+  #[clap(long, env = "QUIC_ECHO_SOAK_INTERVAL", default_value = "1", value_parser = parse_secs, requires = "soak")]
+  soak_interval: Duration,
+  /// Seconds between --soak summaries
+  #[clap(long, env = "QUIC_ECHO_SOAK_REPORT", default_value = "3600", value_parser = parse_secs, requires = "soak")]
+  soak_report: Duration,
+  /// Run the steps of this TOML scenario file and report each one
+  #[clap(long, env = "QUIC_ECHO_SCENARIO", conflicts_with_all = ["datagram", "h3", "webtransport", "proxy_target", "perf", "doq", "send_file", "udp_listen", "pipe", "interactive", "tui", "probe_datagram_size", "compare_tcp", "connections"])]
+  scenario: Option<PathBuf>,
+  /// Write every payload the client sends, with timestamps, to this file
+  #[clap(long, env = "QUIC_ECHO_RECORD", conflicts_with_all = ["h3", "webtransport", "proxy_target", "perf", "doq", "send_file", "tui", "probe_datagram_size", "compare_tcp", "scenario", "connections", "replay"])]
+  record: Option<PathBuf>,
+  /// Resend the payloads of a --record file with their original timing
+  #[clap(long, env = "QUIC_ECHO_REPLAY", conflicts_with_all = ["datagram", "h3", "webtransport", "proxy_target", "perf", "doq", "send_file", "udp_listen", "pipe", "interactive", "tui", "probe_datagram_size", "compare_tcp", "scenario"])]
+  replay: Option<PathBuf>,
+  /// Open this many connections at once, each running the selected test
+  #[clap(long, env = "QUIC_ECHO_CONNECTIONS", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..),
+    conflicts_with_all = ["udp_listen", "pipe", "interactive", "tui"])]
+  connections: u32,
+  /// Ping while a second connection saturates the path for this many seconds, and report idle vs loaded RTT and RPM
+  #[clap(long, env = "QUIC_ECHO_LOADED_LATENCY", value_parser = parse_secs, num_args = 0..=1, default_missing_value = "10",
+    conflicts_with_all = ["datagram", "h3", "webtransport", "proxy_target", "perf", "doq", "send_file", "udp_listen", "pipe", "interactive", "tui", "probe_datagram_size", "compare_tcp", "scenario", "soak", "connections", "zero_rtt", "probe"])]
+  loaded_latency: Option<Duration>,
+  /// Run the ping/throughput test over QUIC and over TLS-over-TCP and compare them
+  #[clap(long, env = "QUIC_ECHO_COMPARE_TCP", conflicts_with_all = ["datagram", "h3", "webtransport", "proxy_target", "perf", "doq", "send_file", "udp_listen", "pipe", "interactive", "tui", "probe_datagram_size"])]
+  compare_tcp: bool,
+  /// Connect once for a session ticket, then reconnect with 0-RTT and send the ping as early data (server needs --0rtt)
+  #[clap(long = "0rtt", env = "QUIC_ECHO_0RTT", conflicts_with_all = ["h3", "webtransport", "proxy_target", "perf", "doq", "send_file", "udp_listen", "pipe", "interactive", "tui", "probe_datagram_size", "compare_tcp", "scenario", "soak", "connections"])]
+  zero_rtt: bool,
+  /// Send this token before anything else, for a server started with the same --auth-token
+  #[clap(long, env = "QUIC_ECHO_AUTH_TOKEN", conflicts_with_all = ["h3", "webtransport", "proxy_target", "perf", "doq", "send_file", "duplex"])]
+  auth_token: Option<String>,
+  /// Close the connection with this application error code after the test (default 0)
+  #[clap(long, env = "QUIC_ECHO_CLOSE_CODE", conflicts_with_all = ["scenario", "soak", "probe", "compare_tcp", "zero_rtt", "interactive"])]
+  close_code: Option<u32>,
+  /// Close the connection with this reason after the test (default empty)
+  #[clap(long, env = "QUIC_ECHO_CLOSE_REASON", conflicts_with_all = ["scenario", "soak", "probe", "compare_tcp", "zero_rtt", "interactive"])]
+  close_reason: Option<String>,
+  /// One handshake and ping, silent, exiting 0 (ok), 1 (handshake failure), 2 (timeout) or 3 (echo mismatch)
+  #[clap(long, env = "QUIC_ECHO_PROBE", conflicts_with_all = ["targets", "h3", "webtransport", "proxy_target", "perf", "doq", "send_file", "duplex", "udp_listen", "pipe", "interactive", "tui", "probe_datagram_size", "compare_tcp", "scenario", "soak", "connections", "record", "replay", "zero_rtt", "reconnect"])]
+  probe: bool,
+  /// Seconds --probe may take in all
+  #[clap(long, env = "QUIC_ECHO_PROBE_TIMEOUT", default_value = "2", value_parser = parse_secs, requires = "probe")]
+  probe_timeout: Duration,
+  /// Print the negotiated TLS version, cipher suite, key exchange group, signature scheme and resumption
+  #[clap(long, env = "QUIC_ECHO_TLS_INFO")]
+  tls_info: bool,
+  /// Log as usual and print the outcome of --probe
+  #[clap(long, env = "QUIC_ECHO_VERBOSE", requires = "probe")]
+  verbose: bool,
+  /// TCP port of the server's TLS echo for --compare-tcp (default: --port)
+  #[clap(long, env = "QUIC_ECHO_TCP_PORT", requires = "compare_tcp")]
+  tcp_port: Option<u16>,
+  /// Local address to bind: `ip` or `ip:port` (default: unspecified address of the remote's family)
+  #[clap(long, env = "QUIC_ECHO_BIND", value_parser = parse_bind)]
+  bind: Option<SocketAddr>,
+  /// UDP source port to bind instead of an ephemeral one (on --bind's address, if given)
+  #[clap(long, env = "QUIC_ECHO_LOCAL_PORT", value_parser = clap::value_parser!(u16).range(1..))]
+  local_port: Option<u16>,
+  /// Hop limit (IP TTL / IPv6 hop limit) of outgoing packets; ICMP time exceeded replies are reported
+  #[clap(long, env = "QUIC_ECHO_TTL", value_parser = clap::value_parser!(u8).range(1..))]
+  ttl: Option<u8>,
+  /// Only use IPv4 addresses of --host
+  #[clap(long, env = "QUIC_ECHO_IPV4", conflicts_with = "ipv6")]
+  ipv4: bool,
+  /// Only use IPv6 addresses of --host
+  #[clap(long, env = "QUIC_ECHO_IPV6")]
+  ipv6: bool,
+  /// DNS server to query instead of the system resolver: `ip` or `ip:port`
+  #[clap(long, env = "QUIC_ECHO_RESOLVER")]
+  resolver: Option<String>,
+  /// Transport for --resolver
+  #[clap(long, env = "QUIC_ECHO_RESOLVER_PROTO", value_enum, default_value_t = ResolverProto::Udp, requires = "resolver")]
+  resolver_proto: ResolverProto,
+  /// TLS name of the --resolver server (DNS over TLS/HTTPS)
+  #[clap(long, env = "QUIC_ECHO_RESOLVER_NAME", requires = "resolver")]
+  resolver_name: Option<String>,
+  /// Use these addresses for host:port instead of resolving: `host:port:addr[,addr...]` (repeatable)
+  #[clap(long, env = "QUIC_ECHO_RESOLVE")]
+  resolve: Vec<ResolveOverride>,
+  /// Retry failed handshakes and lost connections with exponential backoff
+  #[clap(long, env = "QUIC_ECHO_RECONNECT")]
+  reconnect: bool,
+  /// Retries before giving up (with --reconnect)
+  #[clap(long, env = "QUIC_ECHO_MAX_RETRIES", default_value_t = 5, requires = "reconnect")]
+  max_retries: u32,
+  /// Seconds to wait for each handshake attempt
+  #[clap(long, env = "QUIC_ECHO_CONNECT_TIMEOUT", default_value = "10", value_parser = parse_secs)]
+  connect_timeout: Duration,
+  /// Seconds to wait for the echoed response
+  #[clap(long, env = "QUIC_ECHO_RESPONSE_TIMEOUT", default_value = "5", value_parser = parse_secs)]
+  response_timeout: Duration,
+  /// Network interface to send from (SO_BINDTODEVICE on Linux)
+  #[clap(long, env = "QUIC_ECHO_INTERFACE")]
+  interface: Option<String>,
+  /// Write a per-ping / per-second time series to this file
+  #[clap(long, env = "QUIC_ECHO_SAMPLES_OUT")]
+  samples_out: Option<PathBuf>,
+  /// Format of --samples-out (default: jsonl for .jsonl/.json files, csv otherwise)
+  #[clap(long, env = "QUIC_ECHO_SAMPLES_FORMAT", value_enum, requires = "samples_out")]
+  samples_format: Option<SampleFormat>,
+  /// Seconds between the path-RTT `interval` rows of --samples-out
+  #[clap(long, env = "QUIC_ECHO_SAMPLE_INTERVAL", default_value = "1", value_parser = parse_secs, requires = "samples_out")]
+  sample_interval: Duration,
+  /// Write the round trips of echoed pings as an HdrHistogram percentile distribution (.hgrm)
+  #[clap(long, env = "QUIC_ECHO_HDR_OUT")]
+  hdr_out: Option<PathBuf>,
+  /// Fail (exit code bit 8) if the ping round trip, or the path RTT in other modes, exceeds this many ms
+  #[clap(long, env = "QUIC_ECHO_ASSERT_MAX_RTT", conflicts_with_all = ["targets", "dual_stack", "probe", "compare_tcp", "loaded_latency", "zero_rtt", "scenario", "soak", "connections"])]
+  assert_max_rtt: Option<f64>,
+  /// Fail (exit code bit 16) if more than this percentage of packets was lost
+  #[clap(long, env = "QUIC_ECHO_ASSERT_MAX_LOSS", conflicts_with_all = ["targets", "dual_stack", "probe", "compare_tcp", "loaded_latency", "zero_rtt", "scenario", "soak", "connections"])]
+  assert_max_loss: Option<f64>,
+  /// Fail (exit code bit 32) if --perf, --duplex or --send-file moved data slower than this many Mbit/s
+  #[clap(long, env = "QUIC_ECHO_ASSERT_MIN_THROUGHPUT", conflicts_with_all = ["targets", "dual_stack", "probe", "compare_tcp", "loaded_latency", "zero_rtt", "scenario", "soak", "connections"])]
+  assert_min_throughput: Option<f64>,
+  #[clap(flatten)]
+  flow: FlowControl,
+  #[clap(flatten)]
+  offload: OffloadOptions,
+  /// Append TLS secrets to this file (NSS key log format, for Wireshark)
+  #[clap(long, env = "SSLKEYLOGFILE")]
+  keylog: Option<PathBuf>,
+  /// rustls crypto backend (aws-lc-rs needs the `aws-lc-rs` feature)
+  #[clap(long, env = "QUIC_ECHO_CRYPTO_PROVIDER", value_enum, default_value_t = Provider::Ring)]
+  crypto_provider: Provider,
+  /// Write every UDP datagram sent or received to this pcap file
+  #[clap(long, env = "QUIC_ECHO_PCAP")]
+  pcap: Option<PathBuf>,
+  /// Print transport tuning suggestions derived from the connection stats
+  #[clap(long, env = "QUIC_ECHO_ADVISE")]
+  advise: bool,
+  /// Log output format
+  #[clap(long, env = "QUIC_ECHO_LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)]
+  log_format: LogFormat,
+  #[clap(subcommand)]
+  cmd: Option<Cmd>,
+}
+
+impl Opt {
+  /// The one server of a single-target run.
+  fn host(&self) -> &str {
+    &self.host[0]
   }
 
-  fn verify_tls12_signature(
-    &self,
-    message: &[u8],
-    cert: &CertificateDer<'_>,
-    dss: &DigitallySignedStruct,
-  ) -> std::result::Result<danger::HandshakeSignatureValid, rustls::Error> {
-    verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+  /// Name for SNI and certificate checks: --sni, else --host.
+  fn server_name(&self) -> &str {
+    self.sni.as_deref().unwrap_or(self.host())
   }
 
-  fn verify_tls13_signature(
-    &self,
-    message: &[u8],
-    cert: &CertificateDer<'_>,
-    dss: &DigitallySignedStruct,
-  ) -> std::result::Result<danger::HandshakeSignatureValid, rustls::Error> {
-    verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+  /// The server name of --standby: --standby-sni, or the --standby host.
+  fn standby_name(&self) -> Option<&str> {
+    self.standby_sni.as_deref().or(self.standby.as_deref())
   }
 
-  fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
-    self.0.signature_verification_algorithms.supported_schemes()
+  /// --host values plus the lines of --targets, each with its port.
+  fn targets(&self) -> Result<Vec<(String, u16)>> {
+    let mut targets: Vec<(String, u16)> = self.host.iter().map(|host| (host.clone(), self.port)).collect();
+    if let Some(path) = &self.targets {
+      let text = std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+      for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let mut words = line.split_whitespace();
+        let Some(host) = words.next() else {
+          continue;
+        };
+        let port = match words.next() {
+          Some(port) => port.parse().with_context(|| format!("{}:{}: invalid port {port:?}", path.display(), n + 1))?,
+          None => self.port,
+        };
+        anyhow::ensure!(words.next().is_none(), "{}:{}: expected `host [port]`", path.display(), n + 1);
+        targets.push((host.to_string(), port));
+      }
+    }
+    anyhow::ensure!(!targets.is_empty(), "no targets: give --host or list servers in --targets");
+    Ok(targets)
   }
-}
 
-fn make_client_config() -> Result<ClientConfig, NoInitialCipherSuite> {
-  let mut tls = rustls::ClientConfig::builder()
-    .dangerous()
-    .with_custom_certificate_verifier(SkipServerVerification::new())
-    .with_no_client_auth();
+  /// Whether the run is a single echo round trip rather than a transfer,
+  /// a long-running mode or a probe.
+  fn is_ping(&self) -> bool {
+    !(self.perf
+      || self.send_file.is_some()
+      || self.duplex
+      || self.udp_listen.is_some()
+      || self.listen
+      || self.accept_stream
+      || self.pipe
+      || self.interactive
+      || self.tui
+      || self.probe_datagram_size
+      || self.reliable_dgram
+      || self.fuzz
+      || self.reset_after.is_some()
+      || self.stop_sending
+      || self.replay.is_some())
+  }
 
-  tls.alpn_protocols = vec![ALPN.to_vec()];
+  /// Whether any --assert-* threshold is set.
+  fn asserting(&self) -> bool {
+    self.assert_max_rtt.is_some() || self.assert_max_loss.is_some() || self.assert_min_throughput.is_some()
+  }
+}
 
-  Ok(ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?)))
+#[derive(Subcommand, Debug)]
+enum Cmd {
+  /// Run the fixed conformance battery against the server and print a pass/fail matrix
+  Conformance,
+  /// Send only the first flight and check the server stays within the 3x anti-amplification limit
+  Amplification {
+    /// Seconds to count the server's bytes while staying silent
+    #[clap(long, default_value = "3", value_parser = parse_secs)]
+    window: Duration,
+  },
+  /// Offer an unsupported QUIC version to elicit Version Negotiation, then connect normally
+  VersionNegotiation {
+    /// Version to offer, e.g. 0x1a2a3a4a (default: a random reserved version)
+    #[clap(long, value_parser = parse_version)]
+    offer: Option<u32>,
+  },
 }
 
-fn route_get(remote_ip: &str) -> (Option<String>, Option<String>) {
-  let mut cmd = Command::new("ip");
-  cmd.arg("-o");
-  if remote_ip.contains(':') {
-    cmd.args(["-6", "route", "get", remote_ip]);
-  } else {
-    cmd.args(["-4", "route", "get", remote_ip]);
+/// RFC 8305 ordering: IPv6 first, then alternate address families.
+fn happy_eyeballs_order(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+  let (mut v6, mut v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+  v6.dedup();
+  v4.dedup();
+  let mut out = Vec::with_capacity(v6.len() + v4.len());
+  let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+  loop {
+    match (v6.next(), v4.next()) {
+      (None, None) => return out,
+      (a, b) => out.extend(a.into_iter().chain(b)),
+    }
   }
+}
 
-  let out = match cmd.output() {
-    Ok(o) => String::from_utf8_lossy(&o.stdout).to_string(),
-    Err(_) => return (None, None),
-  };
+/// Race handshakes to all candidates, starting a new attempt every
+/// ATTEMPT_DELAY (or right after a failure); the first success wins and
+/// the remaining attempts are dropped, which closes them.
+async fn connect_any(
+  endpoint: &Endpoint,
+  candidates: &[SocketAddr],
+  host: &str,
+  timeout: Duration,
+) -> Result<(Connection, SocketAddr)> {
+  let start = Instant::now();
+  let mut pending = candidates.iter().copied().peekable();
+  let mut attempts = tokio::task::JoinSet::new();
+  let mut next_at = tokio::time::Instant::now();
+  let mut last_err = None;
 
-  let re_dev = Regex::new(r"\bdev\s+(\S+)").unwrap();
-  let re_src = Regex::new(r"\bsrc\s+(\S+)").unwrap();
-  let re_from = Regex::new(r"\bfrom\s+(\S+)").unwrap();
+  loop {
+    if attempts.is_empty() && pending.peek().is_none() {
+      return Err(last_err.unwrap_or_else(|| anyhow::anyhow!("no addresses to connect to")));
+    }
 
-  let dev = re_dev
-    .captures(&out)
-    .and_then(|c| c.get(1))
-    .map(|m| m.as_str().to_string());
+    tokio::select! {
+      _ = tokio::time::sleep_until(next_at), if pending.peek().is_some() => {
+        let addr = pending.next().unwrap();
+        let started = start.elapsed();
+        let connecting = endpoint.connect(addr, host);
+        attempts.spawn(async move {
+          let res = async {
+            match tokio::time::timeout(timeout, connecting?).await {
+              Ok(conn) => Ok(conn?),
+              Err(_) => anyhow::bail!("handshake timed out after {timeout:?}"),
+            }
+          };
+          (addr, started, res.await)
+        });
+        next_at = tokio::time::Instant::now() + ATTEMPT_DELAY;
+      }
+      Some(joined) = attempts.join_next() => {
+        let (addr, started, res): (_, _, Result<Connection>) = joined?;
+        let took = start.elapsed() - started;
+        match res {
+          Ok(conn) => {
+            info!(%addr, started = ?started, handshake = ?took, "connect attempt succeeded");
+            info!(
+              family = if addr.is_ipv6() { "IPv6" } else { "IPv4" },
+              total = ?start.elapsed(),
+              "connected via {addr}"
+            );
+            let suite = handshake::info(&conn).and_then(|tls| tls.cipher_suite);
+            let suite = suite.map_or_else(|| "unknown".into(), |s| format!("{s:?}"));
+            info!(provider = crypto::installed().name(), suite, "TLS session");
+            attempts.abort_all();
+            return Ok((conn, addr));
+          }
+          Err(e) => {
+            info!(%addr, started = ?started, after = ?took, "connect attempt failed: {e:#}");
+            last_err = Some(match ttl::feedback() {
+              Some(icmp) => e.context(format!("connect {addr}; {icmp}")),
+              None => e.context(format!("connect {addr}")),
+            });
+            // don't wait out the delay once the in-flight attempt has failed
+            if attempts.is_empty() {
+              next_at = tokio::time::Instant::now();
+            }
+          }
+        }
+      }
+    }
+  }
+}
 
-  let mut src = re_src
-    .captures(&out)
-    .and_then(|c| c.get(1))
-    .map(|m| m.as_str().to_string());
+fn parse_secs(s: &str) -> Result<Duration, String> {
+  s.parse::<f64>()
+    .ok()
+    .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+    .ok_or_else(|| format!("expected a number of seconds, got {s:?}"))
+}
 
-  if src.is_none() {
-    src = re_from
-      .captures(&out)
-      .and_then(|c| c.get(1))
-      .map(|m| m.as_str().to_string());
-  }
+fn parse_hours(s: &str) -> Result<Duration, String> {
+  s.parse::<f64>()
+    .ok()
+    .and_then(|hours| Duration::try_from_secs_f64(hours * 3600.0).ok())
+    .ok_or_else(|| format!("expected a number of hours, got {s:?}"))
+}
 
-  (src, dev)
+fn parse_version(s: &str) -> Result<u32, String> {
+  match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+    Some(hex) => u32::from_str_radix(hex, 16),
+    None => s.parse(),
+  }
+  .map_err(|_| format!("expected a 32-bit version like 0x1a2a3a4a, got {s:?}"))
 }
 
-#[derive(Parser, Debug)]
-struct Opt {
-  #[clap(long)]
-  host: String,
-  #[clap(long, default_value_t = 12806)]
-  port: u16,
-  #[clap(long)]
-  datagram: bool,
+fn parse_bind(s: &str) -> Result<SocketAddr, String> {
+  s.parse::<SocketAddr>()
+    .or_else(|_| s.parse::<std::net::IpAddr>().map(|ip| SocketAddr::new(ip, 0)))
+    .map_err(|_| format!("expected `ip` or `ip:port`, got {s:?}"))
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-  let _ = rustls::crypto::ring::default_provider().install_default();
+  let opt = Opt::parse_from(config::args(&Opt::command())?);
+  if !opt.probe {
+    let Err(e) = client(opt).await else {
+      return Ok(());
+    };
+    // a failed --assert-* exits with its own code
+    if let Some(failed) = e.downcast_ref::<AssertionsFailed>() {
+      eprintln!("Error: {e}");
+      std::process::exit(failed.0);
+    }
+    return Err(e);
+  }
+  // a probe answers with its exit code alone
+  let verbose = opt.verbose;
+  let code = match client(opt).await {
+    Ok(()) => 0,
+    Err(e) => {
+      let failure = e.downcast_ref::<ProbeFailure>().copied().unwrap_or(ProbeFailure::Handshake);
+      if verbose {
+        eprintln!("probe failed: {e:#}");
+      }
+      failure as i32
+    }
+  };
+  std::process::exit(code)
+}
+
+async fn client(mut opt: Opt) -> Result<()> {
+  if !opt.probe || opt.verbose {
+    logging::init(opt.log_format);
+  }
+  crypto::install(opt.crypto_provider)?;
+  opt.flow.check()?;
+  anyhow::ensure!(
+    opt.warmup.is_none() || opt.perf || opt.compare_tcp,
+    "--warmup applies to --perf and --compare-tcp"
+  );
+  if opt.asserting() {
+    anyhow::ensure!(
+      opt.cmd.is_none() && opt.host.len() <= 1,
+      "--assert-* check a single run against one server, not a subcommand or several hosts"
+    );
+    for (name, value) in [
+      ("--assert-max-rtt", opt.assert_max_rtt),
+      ("--assert-max-loss", opt.assert_max_loss),
+      ("--assert-min-throughput", opt.assert_min_throughput),
+    ] {
+      anyhow::ensure!(value.is_none_or(|v| v >= 0.0 && v.is_finite()), "{name} must be a non-negative number");
+    }
+    anyhow::ensure!(
+      opt.assert_min_throughput.is_none() || opt.perf || opt.duplex || opt.send_file.is_some(),
+      "--assert-min-throughput needs --perf, --duplex or --send-file"
+    );
+  }
+  if let Some(mbps) = opt.rate_limit {
+    anyhow::ensure!(mbps > 0.0 && mbps.is_finite(), "--rate-limit must be a positive number of Mbit/s");
+  }
 
-  let opt = Opt::parse();
+  let targets = opt.targets()?;
+  if let [(host, port)] = &targets[..] {
+    (opt.host, opt.port) = (vec![host.clone()], *port);
+  }
+  if targets.len() > 1 || opt.dual_stack {
+    anyhow::ensure!(
+      opt.is_ping()
+        && !(opt.datagram || opt.h3 || opt.webtransport || opt.doq || opt.compare_tcp || opt.soak)
+        && opt.loaded_latency.is_none()
+        && opt.proxy_target.is_none()
+        && opt.scenario.is_none()
+        && opt.cmd.is_none()
+        && opt.connections == 1
+        && !opt.probe,
+      "{} runs its own stream echo test; drop the other test options",
+      if opt.dual_stack { "--dual-stack".to_string() } else { format!("comparing {} targets", targets.len()) }
+    );
+    anyhow::ensure!(targets.len() == 1 || !opt.dual_stack, "--dual-stack takes a single --host");
+  }
 
-  let mut addrs = tokio::net::lookup_host((opt.host.as_str(), opt.port))
-    .await
-    .context("resolve host")?;
-  let remote: SocketAddr = addrs.next().context("no resolved addresses")?;
-  let remote_ip = remote.ip().to_string();
+  let resolver = Resolver::new(
+    opt.resolve.clone(),
+    opt.resolver.as_deref(),
+    opt.resolver_proto,
+    opt.resolver_name.as_deref(),
+  )?;
+  let mut resolved = Vec::new();
+  for (host, port) in &targets {
+    resolved.push(resolve(&resolver, host, *port, &opt).await);
+  }
+  let candidates = match &mut resolved[..] {
+    [single] => std::mem::replace(single, Ok(Vec::new()))?,
+    all => all.iter().flatten().flatten().copied().collect(),
+  };
 
-  let mut endpoint = Endpoint::client("0.0.0.0:0".parse::<SocketAddr>()?)?;
+  let mut bind = match opt.bind {
+    Some(bind) => bind,
+    None if candidates.iter().any(SocketAddr::is_ipv6) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    None => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+  };
+  if let Some(port) = opt.local_port {
+    anyhow::ensure!(
+      bind.port() == 0 || bind.port() == port,
+      "--local-port {port} contradicts the port of --bind {bind}"
+    );
+    bind.set_port(port);
+  }
+  let socket = match bind_udp(bind, opt.interface.as_deref()) {
+    Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && opt.local_port.is_some() => {
+      anyhow::bail!("local UDP port {} is already in use on {}; pick another --local-port", bind.port(), bind.ip())
+    }
+    bound => bound.with_context(|| match &opt.interface {
+      Some(iface) => format!("bind {bind} on interface {iface}"),
+      None => format!("bind {bind}"),
+    })?,
+  };
+  let capture = opt.pcap.as_deref().map(Pcap::create).transpose()?;
+  let offload = Arc::new(OffloadStats::default());
+  let _offload_report = offload.report_on_drop();
+  // the endpoint's socket as configured, hop-limited with --ttl
+  let wrap = |socket: std::net::UdpSocket| -> Result<Arc<dyn AsyncUdpSocket>> {
+    let Some(hops) = opt.ttl else {
+      return opt.offload.wrap(socket, capture.as_ref(), &offload);
+    };
+    let raw = socket.try_clone()?;
+    Ok(ttl::limit(opt.offload.wrap(socket, capture.as_ref(), &offload)?, raw, hops)?)
+  };
+  let mut endpoint = Endpoint::new_with_abstract_socket(EndpointConfig::default(), None, wrap(socket)?, Arc::new(TokioRuntime))?;
 
   let transport = Arc::new({
     let mut t = TransportConfig::default();
     t.datagram_receive_buffer_size(Some(65_536));
     t.datagram_send_buffer_size(2 * 1024 * 1024);
+    opt.flow.apply(&mut t);
+    if opt.udp_listen.is_some() || opt.soak {
+      // a quiet tunnel, or a soak test with long ping intervals, must not hit the idle timeout
+      t.keep_alive_interval(Some(TUNNEL_KEEP_ALIVE));
+    }
     t
   });
 
-  let mut cfg = make_client_config()?;
-  cfg.transport_config(transport);
-  endpoint.set_default_client_config(cfg);
+  let alpn = if opt.h3 || opt.webtransport || opt.proxy_target.is_some() {
+    http3::ALPN
+  } else if opt.doq {
+    doq::ALPN
+  } else if opt.perf {
+    perf::ALPN
+  } else if opt.send_file.is_some() {
+    transfer::ALPN
+  } else if opt.duplex {
+    duplex::ALPN
+  } else {
+    ALPN
+  };
+  let key_log = opt.keylog.as_deref().map(keylog::open).transpose()?;
+  let mut cfg = make_client_config(alpn, key_log.clone())?;
+  cfg.transport_config(transport.clone());
+  endpoint.set_default_client_config(cfg.clone());
 
   let local_port = endpoint.local_addr()?.port();
-  let (src_ip, dev) = route_get(&remote_ip);
-  println!(
-    "[net] route probe: remote={}:{} local={}:{} iface={}",
-    remote_ip,
-    remote.port(),
-    src_ip.unwrap_or_else(|| "unknown".into()),
-    local_port,
-    dev.unwrap_or_else(|| "unknown".into())
+  for &remote in &candidates {
+    let (src_ip, dev) = route_get(remote, bind.ip(), opt.interface.as_deref());
+    info!(
+      remote = %remote,
+      src = %src_ip.map_or_else(|| "unknown".into(), |ip| ip.to_string()),
+      local_port,
+      iface = dev.as_deref().unwrap_or("unknown"),
+      "route probe"
+    );
+  }
+
+  if targets.len() > 1 {
+    return compare_targets(&endpoint, targets, resolved, &opt).await;
+  }
+  if opt.dual_stack {
+    return compare_families(&endpoint, &resolver, &candidates, &opt).await;
+  }
+  if opt.probe {
+    anyhow::ensure!(opt.cmd.is_none(), "--probe runs its own ping; drop the subcommand");
+    return probe(&endpoint, &candidates, &opt).await;
+  }
+  if let Some(Cmd::Conformance) = opt.cmd {
+    anyhow::ensure!(
+      alpn == ALPN,
+      "the conformance battery targets the echo ALPN; drop --h3/--webtransport/--proxy-target/--doq/--perf/--send-file"
+    );
+    return conformance(&endpoint, candidates[0], opt.server_name(), opt.auth_token.as_deref(), transport, key_log).await;
+  }
+  if let Some(Cmd::Amplification { window }) = opt.cmd {
+    return amplification_check(candidates[0], &opt, bind.ip(), cfg, window).await;
+  }
+  if let Some(Cmd::VersionNegotiation { offer }) = opt.cmd {
+    return version_negotiation(&endpoint, &candidates, &opt, bind.ip(), offer).await;
+  }
+  if opt.compare_tcp {
+    return compare_tcp(&endpoint, candidates[0], &opt, key_log).await;
+  }
+  if let Some(duration) = opt.loaded_latency {
+    return loaded_latency(&endpoint, candidates[0], &opt, duration).await;
+  }
+  if opt.zero_rtt {
+    return zero_rtt(&endpoint, candidates[0], &opt, transport, key_log).await;
+  }
+
+  let samples = match &opt.samples_out {
+    Some(path) => {
+      let format = opt.samples_format.unwrap_or_else(|| SampleFormat::from_path(path));
+      Some(Arc::new(SampleWriter::create(path, format)?))
+    }
+    None => None,
+  };
+  let latencies = opt.hdr_out.as_ref().map(|_| Arc::new(Latencies::default()));
+
+  // moves the connection to a fresh local port (client migration)
+  let rebind = || {
+    let socket = bind_udp(SocketAddr::new(bind.ip(), 0), opt.interface.as_deref())?;
+    endpoint.rebind_abstract(wrap(socket)?)?;
+    Ok(endpoint.local_addr()?)
+  };
+
+  if let Some(path) = &opt.scenario {
+    let scenario = Scenario::load(path)?;
+    println!("scenario {} ({} steps) against {}", path.display(), scenario.steps.len(), opt.host());
+    let connect = async || {
+      let (conn, _) = connect_any(&endpoint, &candidates, opt.server_name(), opt.connect_timeout).await?;
+      authenticate(&conn, opt.auth_token.as_deref()).await?;
+      Ok(conn)
+    };
+    return scenario::run(&scenario, connect, rebind, opt.response_timeout).await;
+  }
+  if opt.soak {
+    let params = soak::Params {
+      duration: opt.duration,
+      interval: opt.soak_interval,
+      report: opt.soak_report,
+      timeout: opt.response_timeout,
+      datagram: opt.datagram,
+      transform: opt.transform,
+      sample_interval: opt.sample_interval,
+    };
+    let connect = async || {
+      let (conn, _) = connect_any(&endpoint, &candidates, opt.server_name(), opt.connect_timeout).await?;
+      authenticate(&conn, opt.auth_token.as_deref()).await?;
+      Ok(conn)
+    };
+    let run = soak::run(connect, &params, samples.as_ref(), latencies.as_deref()).await?;
+    println!("soak done after {:.1?}: {run}", opt.duration);
+    write_hdr(opt.hdr_out.as_deref(), latencies.as_deref())?;
+    anyhow::ensure!(run.ok > 0, "no ping was echoed during the soak test");
+    return Ok(());
+  }
+  if opt.connections > 1 {
+    let hdr_out = opt.hdr_out.clone();
+    let res = parallel(endpoint.clone(), candidates, Arc::new(opt), samples, latencies.clone()).await;
+    write_hdr(hdr_out.as_deref(), latencies.as_deref())?;
+    return res;
+  }
+  let recorder = opt.record.as_deref().map(Recorder::create).transpose()?;
+  let standby = match &opt.standby {
+    Some(host) => Some(resolve(&resolver, host, opt.standby_port.unwrap_or(opt.port), &opt).await?),
+    None => None,
+  };
+  let outputs = Outputs { samples: samples.as_ref(), latencies: latencies.as_deref(), record: recorder.as_ref() };
+  let res = run(&endpoint, &candidates, &opt, outputs, &rebind, standby.as_deref()).await;
+  write_hdr(opt.hdr_out.as_deref(), latencies.as_deref())?;
+  let metrics = res?;
+  if opt.close_code.is_some() || opt.close_reason.is_some() {
+    // make sure the CONNECTION_CLOSE with our code goes out before exiting
+    endpoint.wait_idle().await;
+  }
+  check_assertions(&opt, &metrics)
+}
+
+/// The usable addresses of `host`, in the order to try them.
+async fn resolve(resolver: &Resolver, host: &str, port: u16, opt: &Opt) -> Result<Vec<SocketAddr>> {
+  let resolved: Vec<SocketAddr> = resolver
+    .lookup(host, port)
+    .await?
+    .into_iter()
+    .filter(|a| (!opt.ipv4 || a.is_ipv4()) && (!opt.ipv6 || a.is_ipv6()))
+    // an IPv4 bind address cannot reach IPv6 peers
+    .filter(|a| !(a.is_ipv6() && opt.bind.is_some_and(|b| b.is_ipv4())))
+    .collect();
+  let candidates = happy_eyeballs_order(resolved);
+  anyhow::ensure!(
+    !candidates.is_empty(),
+    "no usable {}address for {}",
+    match (opt.ipv4, opt.ipv6) {
+      (true, _) => "IPv4 ",
+      (_, true) => "IPv6 ",
+      _ => "",
+    },
+    host
   );
+  Ok(candidates)
+}
+
+/// Where a run writes what it measured and sent: --samples-out, --hdr-out
+/// and --record.
+#[derive(Clone, Copy, Default)]
+struct Outputs<'a> {
+  samples: Option<&'a Arc<SampleWriter>>,
+  latencies: Option<&'a Latencies>,
+  record: Option<&'a Recorder>,
+}
+
+/// Connect and run the test, retrying with backoff if --reconnect is set.
+/// `standby` holds the --standby addresses of a tunnel; a retry dials the
+/// standby again once its connection has closed.
+async fn run(
+  endpoint: &Endpoint,
+  candidates: &[SocketAddr],
+  opt: &Opt,
+  outputs: Outputs<'_>,
+  rebind: &(dyn Fn() -> Result<SocketAddr> + Sync),
+  standby: Option<&[SocketAddr]>,
+) -> Result<Metrics> {
+  let start = Instant::now();
+  let mut retries = 0;
+  let mut standby_conn: Option<Connection> = None;
+  loop {
+    let res = async {
+      if let Some(addrs) = standby
+        && standby_conn.as_ref().is_none_or(|conn| conn.close_reason().is_some())
+      {
+        standby_conn = Some(connect_standby(endpoint, addrs, opt).await?);
+      }
+      let (conn, remote) = connect_any(endpoint, candidates, opt.server_name(), opt.connect_timeout).await?;
+      authenticate(&conn, opt.auth_token.as_deref()).await?;
+      let span = info_span!("conn", %remote, id = conn.stable_id());
+      tokio::spawn(log_peer_close(conn.clone()).instrument(span.clone()));
+      ping(&conn, opt, outputs, rebind, standby_conn.as_ref()).instrument(span).await
+    }
+    .await;
+
+    match res {
+      Ok(metrics) => {
+        if retries > 0 {
+          info!(attempts = retries + 1, total = ?start.elapsed(), "succeeded after reconnecting");
+        }
+        return Ok(metrics);
+      }
+      Err(e) if opt.reconnect && retries < opt.max_retries => {
+        let delay = backoff(retries);
+        retries += 1;
+        warn!(attempt = retries, "attempt failed: {e:#}; retrying in {delay:?}");
+        tokio::time::sleep(delay).await;
+      }
+      Err(e) => return Err(e),
+    }
+  }
+}
 
-  let conn = endpoint.connect(remote, opt.host.as_str())?.await?;
+/// Connect the --standby server of a tunnel at one of `addrs`.
+async fn connect_standby(endpoint: &Endpoint, addrs: &[SocketAddr], opt: &Opt) -> Result<Connection> {
+  let name = opt.standby_name().expect("--standby is set");
+  let (conn, addr) =
+    connect_any(endpoint, addrs, name, opt.connect_timeout).await.context("connect the --standby server")?;
+  authenticate(&conn, opt.auth_token.as_deref()).await?;
+  info!(%addr, "standby connected");
+  Ok(conn)
+}
+
+/// Run the test on --connections connections at once and report each one
+/// plus the aggregate.
+async fn parallel(
+  endpoint: Endpoint,
+  candidates: Vec<SocketAddr>,
+  opt: Arc<Opt>,
+  samples: Option<Arc<SampleWriter>>,
+  latencies: Option<Arc<Latencies>>,
+) -> Result<()> {
+  let start = Instant::now();
+  let candidates: Arc<[SocketAddr]> = candidates.into();
+  let mut tasks = tokio::task::JoinSet::new();
+  for n in 0..opt.connections {
+    let (endpoint, candidates, opt, samples, latencies) =
+      (endpoint.clone(), candidates.clone(), opt.clone(), samples.clone(), latencies.clone());
+    tasks.spawn(
+      async move {
+        let started = Instant::now();
+        let no_rebind = || anyhow::bail!("migration is not available with --connections");
+        let outputs = Outputs { samples: samples.as_ref(), latencies: latencies.as_deref(), record: None };
+        let res = run(&endpoint, &candidates, &opt, outputs, &no_rebind, None).await;
+        (n, started.elapsed(), res)
+      }
+      .instrument(info_span!("worker", n)),
+    );
+  }
+
+  let mut results = Vec::new();
+  while let Some(joined) = tasks.join_next().await {
+    results.push(joined?);
+  }
+  results.sort_by_key(|(n, _, _)| *n);
+  let wall = start.elapsed();
 
-  let proto = conn
-    .handshake_data()
-    .and_then(|x| x.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
-    .and_then(|hd| hd.protocol.clone())
+  println!("{} connections:", opt.connections);
+  for (n, took, res) in &results {
+    let ms = took.as_secs_f64() * 1000.0;
+    match res {
+      Ok(_) => println!("  #{n:<4} OK    {ms:>8.1} ms"),
+      Err(e) => println!("  #{n:<4} FAIL  {ms:>8.1} ms  {e:#}"),
+    }
+  }
+  let ok: Vec<Duration> = results.iter().filter(|(_, _, r)| r.is_ok()).map(|(_, took, _)| *took).collect();
+  let failed = results.len() - ok.len();
+  if let (Some(min), Some(max)) = (ok.iter().min(), ok.iter().max()) {
+    let avg = ok.iter().sum::<Duration>() / ok.len() as u32;
+    println!(
+      "{} ok, {failed} failed in {wall:.1?}; per connection min/avg/max {min:.1?}/{avg:.1?}/{max:.1?}",
+      ok.len()
+    );
+  } else {
+    println!("0 ok, {failed} failed in {wall:.1?}");
+  }
+  anyhow::ensure!(failed == 0, "{failed} of {} connections failed", opt.connections);
+  Ok(())
+}
+
+/// Write --hdr-out, if any round trips were recorded.
+fn write_hdr(path: Option<&Path>, latencies: Option<&Latencies>) -> Result<()> {
+  let (Some(path), Some(latencies)) = (path, latencies) else {
+    return Ok(());
+  };
+  if latencies.is_empty() {
+    warn!("no round trips to write to {}", path.display());
+    return Ok(());
+  }
+  latencies.write(path)?;
+  info!(path = %path.display(), count = latencies.len(), "wrote latency histogram");
+  Ok(())
+}
+
+/// Log an application close from the server with its code and reason.
+async fn log_peer_close(conn: Connection) {
+  if let ConnectionError::ApplicationClosed(close) = conn.closed().await {
+    info!(code = close.error_code.into_inner(), reason = %String::from_utf8_lossy(&close.reason), "server closed the connection");
+  }
+}
+
+/// Exponential backoff with "equal jitter": half the step is fixed, the
+/// other half random, so simultaneous clients spread out.
+fn backoff(retry: u32) -> Duration {
+  let step = BACKOFF_BASE.saturating_mul(1 << retry.min(16)).min(BACKOFF_MAX);
+  step / 2 + step.mul_f64(rand::rng().random::<f64>() / 2.0)
+}
+
+async fn ping(
+  conn: &Connection,
+  opt: &Opt,
+  outputs: Outputs<'_>,
+  rebind: &(dyn Fn() -> Result<SocketAddr> + Sync),
+  standby: Option<&Connection>,
+) -> Result<Metrics> {
+  let Outputs { samples, latencies, record } = outputs;
+  let proto = handshake::data(conn)
+    .and_then(|hd| hd.protocol)
     .map(|p| String::from_utf8_lossy(&p).into_owned())
     .unwrap_or_else(|| "<none>".into());
-  println!("ALPN: {proto}");
+  info!(alpn = %proto, "connection established");
+  if opt.tls_info && let Some(info) = handshake::info(conn) {
+    println!("tls: {info}");
+  }
+
+  let sampler = samples.map(|w| Sampler::new(w.clone(), conn.clone()));
+  let _intervals = sampler.as_ref().map(|s| s.spawn_intervals(opt.sample_interval));
+  let start = Instant::now();
+  let limit = opt.rate_limit.map(RateLimit::mbps);
+  let limit = limit.as_ref();
+  // the slowest direction that carried data, for --assert-min-throughput
+  let mut throughput = None;
 
-  if opt.datagram {
+  if opt.h3 {
+    let data = tokio::time::timeout(
+      opt.response_timeout,
+      http3::echo(conn.clone(), opt.server_name(), Bytes::from_static(b"ping")),
+    )
+    .await
+    .with_context(|| format!("no HTTP/3 echo within {:?}", opt.response_timeout))??;
+    verify::compare("HTTP/3 echo", b"ping", &data)?;
+    println!("recv(h3): {:?}", data);
+  } else if opt.webtransport {
+    let data = tokio::time::timeout(
+      opt.response_timeout,
+      webtransport::echo(conn, opt.server_name(), Bytes::from_static(b"ping"), opt.datagram),
+    )
+    .await
+    .with_context(|| format!("no WebTransport echo within {:?}", opt.response_timeout))??;
+    verify::compare("WebTransport echo", b"ping", &data)?;
+    println!("recv(wt{}): {:?}", if opt.datagram { "-dgram" } else { "" }, data);
+  } else if let Some(target) = &opt.proxy_target {
+    let data = tokio::time::timeout(
+      opt.response_timeout,
+      masque::proxy_echo(conn, opt.server_name(), target, Bytes::from_static(b"ping")),
+    )
+    .await
+    .with_context(|| format!("no reply from {target} within {:?}", opt.response_timeout))??;
+    println!("recv(masque {target}): {:?}", data);
+  } else if opt.perf {
+    if let Some(warmup) = opt.warmup {
+      if let Some(sampler) = &sampler {
+        sampler.set_warmup(true);
+      }
+      let run = perf::warmup(conn, opt.perf_upload, opt.perf_download, warmup, limit).await?;
+      if let Some(sampler) = &sampler {
+        sampler.set_warmup(false);
+      }
+      println!("warmup (not measured): up {} B, down {} B in {:.1?}", run.upload, run.download, run.elapsed);
+    }
+    let run = perf::request(conn, opt.perf_upload, opt.perf_download, limit).await?;
+    println!(
+      "perf: up {} B ({:.2} Mbit/s), down {} B ({:.2} Mbit/s), ttfb {}, total {:?}",
+      run.upload,
+      run.mbps(run.upload),
+      run.download,
+      run.mbps(run.download),
+      run.ttfb.map_or_else(|| "-".into(), |t| format!("{t:?}")),
+      run.elapsed
+    );
+    throughput = [(run.upload, run.mbps(run.upload)), (run.download, run.mbps(run.download))]
+      .into_iter()
+      .filter(|(bytes, _)| *bytes > 0)
+      .map(|(_, mbps)| mbps)
+      .reduce(f64::min);
+  } else if opt.duplex {
+    let run = duplex::run(conn, opt.duplex_size, opt.duplex_size, limit).await?;
+    println!(
+      "duplex: up {} B in {:.1?} ({:.2} Mbit/s), down {} B in {:.1?} ({:.2} Mbit/s), both moving for {:.1?}",
+      run.upload,
+      run.upload_took,
+      run.upload_mbps(),
+      run.download,
+      run.download_took,
+      run.download_mbps(),
+      run.overlap
+    );
+    throughput = Some(run.upload_mbps().min(run.download_mbps()));
+  } else if opt.doq {
+    let response = tokio::time::timeout(
+      opt.response_timeout,
+      doq::query(conn, &opt.doq_name, opt.doq_type),
+    )
+    .await
+    .with_context(|| format!("no DoQ response within {:?}", opt.response_timeout))??;
+    println!("recv(doq):\n{response}");
+  } else if let Some(path) = &opt.send_file {
+    let op = if opt.store { transfer::OP_STORE } else { transfer::OP_ECHO };
+    let run = transfer::send_file(conn, path, op, limit).await?;
+    println!(
+      "{} {}: {} B in {:?} ({:.2} Mbit/s), sha256 {} verified",
+      if opt.store { "stored" } else { "echoed" },
+      path.display(),
+      run.size,
+      run.elapsed,
+      run.mbps(),
+      transfer::hex(&run.sha256)
+    );
+    throughput = Some(run.mbps());
+  } else if let Some(listen) = opt.udp_listen {
+    let failover = standby.map(|standby| tunnel::Failover {
+      standby: standby.clone(),
+      max_rtt: Duration::from_secs_f64(opt.failover_rtt / 1000.0),
+      max_loss: opt.failover_loss / 100.0,
+    });
+    tunnel::listen(conn, listen, record, limit, failover).await?;
+  } else if opt.listen {
+    let run = push::listen(conn, opt.listen_for).await?;
+    // tell the server to stop pushing right away rather than at the idle timeout
+    conn.close(0u32.into(), b"listen done");
+    anyhow::ensure!(run.received > 0, "no pushed datagrams received (is the server running with --push-dgrams?)");
+    println!("pushed: {run}");
+  } else if opt.accept_stream {
+    let accepted = push::accept_stream(conn, opt.response_timeout).await?;
+    println!(
+      "accepted {} stream from the server: {} B verified{} in {:.1?}",
+      accepted.kind.name(),
+      accepted.size,
+      if accepted.kind == push::StreamKind::Bi { " and echoed back" } else { "" },
+      accepted.elapsed
+    );
+  } else if opt.pipe {
+    let (mut send, mut recv) = flow::open_bi(conn).await?;
+    // reverse/sha256 answers can only be checked once the whole input is known
+    let check = opt.transform.per_chunk().then(|| Mutex::new(EchoCheck::default()));
+    // each direction half-closes on its own
+    let upload = async {
+      let id = record.map(Recorder::open_stream);
+      let (mut stdin, mut buf, mut n) = (tokio::io::stdin(), vec![0; 64 * 1024], 0);
+      loop {
+        let read = stdin.read(&mut buf).await?;
+        if let (Some(record), Some(id)) = (record, id) {
+          record.stream(id, &buf[..read], read == 0);
+        }
+        if read == 0 {
+          break;
+        }
+        if let Some(check) = &check {
+          check.lock().unwrap().sent(&opt.transform.apply(Bytes::copy_from_slice(&buf[..read])));
+        }
+        pace::write_all(&mut send, &buf[..read], limit).await?;
+        n += read as u64;
+      }
+      send.finish()?;
+      anyhow::Ok(n)
+    };
+    let download = async {
+      let (mut stdout, mut n) = (tokio::io::stdout(), 0);
+      let mut pacer = opt.read_rate.map(Pacer::new);
+      while let Some(chunk) = recv.read_chunk(pacer.as_ref().map_or(64 * 1024, Pacer::slice), true).await? {
+        if let Some(pacer) = &mut pacer {
+          pacer.take(chunk.bytes.len()).await;
+        }
+        if let Some(check) = &check {
+          check.lock().unwrap().received(&chunk.bytes)?;
+        }
+        stdout.write_all(&chunk.bytes).await?;
+        n += chunk.bytes.len() as u64;
+      }
+      stdout.flush().await?;
+      anyhow::Ok(n)
+    };
+    let (sent, received) = tokio::try_join!(upload, download)?;
+    if let Some(check) = check {
+      check.into_inner().unwrap().finish()?;
+    }
+    info!(sent, received, "pipe closed");
+  } else if opt.interactive {
+    interactive::run(conn, opt.datagram, rebind, record).await?;
+  } else if opt.tui {
+    tui::run(conn, opt.datagram, opt.interval, opt.response_timeout).await?;
+  } else if opt.probe_datagram_size {
+    let advertised = conn.max_datagram_size().context("peer does not support datagrams")?;
+    let working = probe_datagram_size(conn, advertised).await;
+    let now = conn.max_datagram_size().unwrap_or(advertised);
+    match working {
+      Some(size) if size == advertised => {
+        println!("datagram size: advertised {advertised} B, working {size} B (no black hole)");
+      }
+      Some(size) => println!(
+        "datagram size: advertised {advertised} B, working {size} B ({} B lost, MTU black hole?)",
+        advertised - size
+      ),
+      None => println!("datagram size: advertised {advertised} B, no datagram came back"),
+    }
+    if now != advertised {
+      info!(advertised, now, "max_datagram_size changed during the probe (MTU discovery)");
+    }
+  } else if let Some(bytes) = opt.reset_after {
+    reset_after(conn, bytes, opt.response_timeout).await?;
+  } else if opt.stop_sending {
+    stop_sending(conn, opt.response_timeout).await?;
+  } else if opt.fuzz {
+    let seed = opt.seed.unwrap_or_else(|| rand::rng().random());
+    println!("fuzzing with seed {seed} (reproduce with --seed {seed})");
+    let run = fuzz::run(conn, seed, opt.fuzz_iterations, opt.response_timeout).await?;
+    let actions: Vec<String> = run.actions.iter().map(|(name, n)| format!("{n} {name}")).collect();
+    println!(
+      "fuzz: {} actions ({}), {} echoes verified, server still echoing",
+      opt.fuzz_iterations,
+      actions.join(", "),
+      run.verified
+    );
+  } else if opt.reliable_dgram {
+    let params = reliable::Params {
+      count: opt.reliable_count,
+      rate: opt.reliable_rate,
+      size: opt.reliable_size,
+      retries: opt.reliable_retries,
+    };
+    let run = reliable::run(conn, params).await?;
+    println!(
+      "reliable datagrams: {}/{} delivered ({:.1}%), {} on the first try, {} retransmissions, {} ACKs, {} given up, in {:.1?} ({:.0} msg/s)",
+      run.delivered,
+      run.sent,
+      run.delivery_rate(),
+      run.first_try,
+      run.retransmissions,
+      run.acks,
+      run.gave_up,
+      run.elapsed,
+      run.goodput()
+    );
+    println!(
+      "  delivery latency min/p50/p99/max {:.1?}/{:.1?}/{:.1?}/{:.1?}, added by recovery p50/p99 {:.1?}/{:.1?}",
+      run.latency(0.0),
+      run.latency(50.0),
+      run.latency(99.0),
+      run.latency(100.0),
+      run.added(50.0),
+      run.added(99.0)
+    );
+    anyhow::ensure!(run.delivered == run.sent, "{} of {} messages were never delivered", run.sent - run.delivered, run.sent);
+  } else if let Some(path) = &opt.replay {
+    let recording = Recording::load(path)?;
+    println!("replaying {} payloads over {:.1?} from {}", recording.len(), recording.duration(), path.display());
+    let run = replay::replay(conn, &recording, opt.response_timeout).await?;
+    println!(
+      "replayed: {}/{} datagrams echoed, {} streams with {}/{} B echoed, in {:.1?} (max {:.1?} behind the recorded timing)",
+      run.datagrams_echoed,
+      run.datagrams,
+      run.streams,
+      run.stream_bytes_echoed,
+      run.stream_bytes,
+      run.elapsed,
+      run.max_late
+    );
+    anyhow::ensure!(
+      run.stream_bytes_echoed == run.stream_bytes,
+      "only {} of {} stream bytes came back",
+      run.stream_bytes_echoed,
+      run.stream_bytes
+    );
+  } else if opt.datagram {
+    if let Some(record) = record {
+      record.datagram(b"ping");
+    }
     conn.send_datagram(Bytes::from_static(b"ping"))?;
-    let data = tokio::time::timeout(Duration::from_secs(5), conn.read_datagram()).await??;
+    let data = tokio::time::timeout(opt.response_timeout, conn.read_datagram())
+      .await
+      .with_context(|| format!("no datagram echo within {:?}", opt.response_timeout))??;
     println!("recv(dgram): {:?}", data);
+    check_transform(opt.transform, &data)?;
   } else {
-    let (mut send, mut recv) = conn.open_bi().await?;
+    let (mut send, mut recv) = flow::open_bi(conn).await?;
+    if let Some(record) = record {
+      record.stream(record.open_stream(), b"ping", true);
+    }
     send.write_all(b"ping").await?;
     send.finish()?;
-    let data = tokio::time::timeout(Duration::from_secs(5), recv.read_to_end(64 * 1024)).await??;
+    let data = tokio::time::timeout(opt.response_timeout, recv.read_to_end(64 * 1024))
+      .await
+      .with_context(|| format!("no stream echo within {:?}", opt.response_timeout))??;
     println!("recv: {:?}", data);
+    check_transform(opt.transform, &data)?;
+  }
+
+  // the ping's round trip, or quinn's estimate of the path's where there was no single ping
+  let mut rtt = conn.rtt();
+  if opt.is_ping() {
+    rtt = start.elapsed();
+    if let Some(sampler) = &sampler {
+      sampler.ping(rtt);
+    }
+    if let Some(latencies) = latencies {
+      latencies.record(rtt);
+    }
+  }
+  if let Some(drift) = sampler.as_ref().and_then(Sampler::drift) {
+    info!(
+      samples = drift.samples,
+      first = ?drift.first,
+      last = ?drift.last,
+      min = ?drift.min,
+      max = ?drift.max,
+      "path RTT over the run"
+    );
+  }
+  if let Some(preferred) = opt.preferred_addr {
+    let remote = conn.remote_address();
+    if (remote.ip().to_canonical(), remote.port()) == (preferred.ip().to_canonical(), preferred.port()) {
+      println!("preferred address {preferred}: migrated");
+    } else {
+      // quinn-proto parses the transport parameter but its client never acts on it
+      println!("preferred address {preferred}: not migrated, still on {remote} (quinn's client does not migrate to it)");
+    }
+  }
+  let loss = LossReport::new(&conn.stats());
+  loss.log();
+  CidActivity::new(&conn.stats()).log();
+
+  if opt.advise {
+    let advice = advise(&conn.stats());
+    if advice.is_empty() {
+      println!("[advice] no tuning suggestions: no flow-control stalls or losses observed");
+    }
+    for line in advice {
+      println!("[advice] {line}");
+    }
+  }
+
+  if opt.close_code.is_some() || opt.close_reason.is_some() {
+    let (code, reason) = (opt.close_code.unwrap_or(0), opt.close_reason.as_deref().unwrap_or_default());
+    info!(code, reason, "closing the connection");
+    conn.close(VarInt::from_u32(code), reason.as_bytes());
+  } else if opt.h3 {
+    conn.close(http3::NO_ERROR.into(), b"");
+  }
+
+  Ok(Metrics { rtt, loss_percent: loss.loss_percent(), throughput })
+}
+
+/// What one run measured, for the --assert-* thresholds.
+#[derive(Clone, Copy, Debug)]
+struct Metrics {
+  rtt: Duration,
+  loss_percent: f64,
+  /// Mbit/s, in the modes that move bulk data
+  throughput: Option<f64>,
+}
+
+/// Exit-code bits of the --assert-* thresholds, OR'ed together when
+/// several fail.
+const ASSERT_RTT: i32 = 8;
+const ASSERT_LOSS: i32 = 16;
+const ASSERT_THROUGHPUT: i32 = 32;
+
+/// The --assert-* thresholds a run broke, as the exit code to leave with.
+#[derive(Clone, Copy, Debug)]
+struct AssertionsFailed(i32);
+
+impl std::fmt::Display for AssertionsFailed {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let names: Vec<&str> = [(ASSERT_RTT, "rtt"), (ASSERT_LOSS, "loss"), (ASSERT_THROUGHPUT, "throughput")]
+      .into_iter()
+      .filter(|(bit, _)| self.0 & bit != 0)
+      .map(|(_, name)| name)
+      .collect();
+    write!(f, "assertion failed: {} (exit code {})", names.join(", "), self.0)
+  }
+}
+
+impl std::error::Error for AssertionsFailed {}
+
+/// Print one `[assert]` line per threshold given and fail with the bits of
+/// those that were broken.
+fn check_assertions(opt: &Opt, metrics: &Metrics) -> Result<()> {
+  let mut failed = 0;
+  let mut check = |bit: i32, ok: bool, line: String| {
+    println!("[assert] {line}: {}", if ok { "ok" } else { "FAIL" });
+    if !ok {
+      failed |= bit;
+    }
+  };
+  if let Some(max) = opt.assert_max_rtt {
+    let rtt = metrics.rtt.as_secs_f64() * 1000.0;
+    check(ASSERT_RTT, rtt <= max, format!("rtt {rtt:.2} ms <= {max} ms"));
+  }
+  if let Some(max) = opt.assert_max_loss {
+    let loss = metrics.loss_percent;
+    check(ASSERT_LOSS, loss <= max, format!("loss {loss:.2}% <= {max}%"));
+  }
+  if let Some(min) = opt.assert_min_throughput {
+    let mbps = metrics.throughput.unwrap_or(0.0);
+    check(ASSERT_THROUGHPUT, mbps >= min, format!("throughput {mbps:.2} Mbit/s >= {min} Mbit/s"));
+  }
+  if failed != 0 {
+    return Err(AssertionsFailed(failed).into());
+  }
+  Ok(())
+}
+
+/// Check that `response` is the server's `transform` of our "ping", byte
+/// for byte.
+fn check_transform(transform: Transform, response: &[u8]) -> Result<()> {
+  let expected = transform.apply(Bytes::from_static(b"ping"));
+  verify::compare(&format!("--transform {}", transform.name()), &expected, response)
+    .map_err(|e| anyhow::anyhow!("{e}; is the server running with the same --transform?"))
+}
+
+/// Turn the end-of-run counters into actionable TransportConfig suggestions.
+fn advise(stats: &ConnectionStats) -> Vec<String> {
+  let mut out = Vec::new();
+  let path = &stats.path;
+  // Aim for twice the bandwidth-delay product the congestion controller reached.
+  let window = (2 * path.cwnd).max(1024 * 1024).next_power_of_two();
+
+  if stats.frame_tx.stream_data_blocked > 0 {
+    out.push(format!(
+      "sender stalled on stream flow control {} times: raise the server's --stream-window (stream_receive_window) to >= {} bytes",
+      stats.frame_tx.stream_data_blocked, window
+    ));
+  }
+  if stats.frame_tx.data_blocked > 0 {
+    out.push(format!(
+      "sender stalled on connection flow control {} times: raise the server's --conn-window (receive_window) to >= {} bytes",
+      stats.frame_tx.data_blocked, window
+    ));
+  }
+  if stats.frame_rx.stream_data_blocked > 0 || stats.frame_rx.data_blocked > 0 {
+    out.push(format!(
+      "server stalled on our flow control (stream {}, conn {}): raise the client's --stream-window/--conn-window to >= {} bytes",
+      stats.frame_rx.stream_data_blocked, stats.frame_rx.data_blocked, window
+    ));
+  }
+  if stats.frame_tx.streams_blocked_bidi > 0 {
+    out.push(format!(
+      "blocked on stream credit {} times: raise the server's --max-concurrent-bidi",
+      stats.frame_tx.streams_blocked_bidi
+    ));
+  }
+
+  if path.sent_packets > 0 && path.lost_packets > 0 {
+    let loss = 100.0 * path.lost_packets as f64 / path.sent_packets as f64;
+    if loss >= 1.0 {
+      out.push(format!(
+        "{loss:.1}% packet loss with {} congestion events: the run was loss-limited; consider BBR (congestion_controller_factory(BbrConfig)) which backs off less on random loss",
+        path.congestion_events
+      ));
+    } else if path.congestion_events > 0 {
+      out.push(format!(
+        "{} congestion events at {loss:.2}% loss: likely queue overflow; raise the UDP socket send/receive buffers (net.core.rmem_max/wmem_max)",
+        path.congestion_events
+      ));
+    }
+  }
+
+  if path.black_holes_detected > 0 {
+    out.push(format!(
+      "{} MTU black holes detected (current MTU {}): lower initial_mtu or disable mtu_discovery_config on this path",
+      path.black_holes_detected, path.current_mtu
+    ));
+  }
+
+  out
+}
+
+type Matrix = Vec<(&'static str, Duration, std::result::Result<(), String>)>;
+
+/// Run one scenario with a timeout and record it in the matrix.
+async fn check<T>(
+  matrix: &mut Matrix,
+  name: &'static str,
+  fut: impl Future<Output = Result<T>>,
+) -> Option<T> {
+  let start = Instant::now();
+  let res = match tokio::time::timeout(SCENARIO_TIMEOUT, fut).await {
+    Ok(Ok(v)) => Ok(v),
+    Ok(Err(e)) => Err(format!("{e:#}")),
+    Err(_) => Err(format!("timed out after {SCENARIO_TIMEOUT:?}")),
+  };
+  let elapsed = start.elapsed();
+  match res {
+    Ok(v) => {
+      matrix.push((name, elapsed, Ok(())));
+      Some(v)
+    }
+    Err(e) => {
+      matrix.push((name, elapsed, Err(e)));
+      None
+    }
+  }
+}
+
+async fn stream_roundtrip(conn: &Connection, payload: &[u8]) -> Result<()> {
+  let (mut send, mut recv) = flow::open_bi(conn).await?;
+  let (written, echoed) = tokio::join!(
+    async {
+      send.write_all(payload).await?;
+      send.finish()?;
+      anyhow::Ok(())
+    },
+    recv.read_to_end(payload.len() + 1)
+  );
+  written?;
+  let echoed = echoed?;
+  anyhow::ensure!(
+    echoed == payload,
+    "echo mismatch: sent {} bytes, got {} back",
+    payload.len(),
+    echoed.len()
+  );
+  Ok(())
+}
+
+/// Send --auth-token, if given, before anything else on `conn`.
+async fn authenticate(conn: &Connection, token: Option<&str>) -> Result<()> {
+  match token {
+    Some(token) => auth::send(conn, token).await.context("send the auth token"),
+    None => Ok(()),
+  }
+}
+
+/// Connect to `remote` for a session ticket, then resume with 0-RTT and send
+/// the ping before the handshake completes; fails unless the server accepts
+/// the early data.
+async fn zero_rtt(
+  endpoint: &Endpoint,
+  remote: SocketAddr,
+  opt: &Opt,
+  transport: Arc<TransportConfig>,
+  key_log: Option<Arc<dyn KeyLog>>,
+) -> Result<()> {
+  let mut tls = make_tls_config(ALPN, key_log);
+  tls.enable_early_data = true;
+  // both connections share the config, and with it the session store
+  let mut cfg = quic_client_config(tls)?;
+  cfg.transport_config(transport);
+
+  let first = tokio::time::timeout(opt.connect_timeout, endpoint.connect_with(cfg.clone(), remote, opt.server_name())?)
+    .await
+    .with_context(|| format!("handshake with {remote} timed out"))??;
+  authenticate(&first, opt.auth_token.as_deref()).await?;
+  if opt.tls_info && let Some(info) = handshake::info(&first) {
+    println!("tls (first connection): {info}");
+  }
+  // the session ticket follows the handshake; one echo makes sure it has arrived
+  stream_roundtrip(&first, b"ping").await?;
+  first.close(0u32.into(), b"0-RTT ticket received");
+
+  let start = Instant::now();
+  let (conn, accepted) = match endpoint.connect_with(cfg, remote, opt.server_name())?.into_0rtt() {
+    Ok(early) => early,
+    Err(_) => anyhow::bail!("no 0-RTT: the server issued no session ticket that allows early data"),
+  };
+  let ping = async {
+    // in 0-RTT too, ahead of the ping
+    authenticate(&conn, opt.auth_token.as_deref()).await?;
+    if opt.datagram {
+      dgram_roundtrip(&conn, Bytes::from_static(b"ping")).await
+    } else {
+      stream_roundtrip(&conn, b"ping").await
+    }
+  };
+  let (echoed, accepted) = tokio::join!(tokio::time::timeout(opt.response_timeout, ping), accepted);
+  anyhow::ensure!(accepted, "0-RTT rejected by the server (is it running with --0rtt?)");
+  echoed.with_context(|| format!("no echo within {:?}", opt.response_timeout))??;
+  if opt.tls_info && let Some(info) = handshake::info(&conn) {
+    println!("tls (0-RTT connection): {info}");
+  }
+  println!("0-RTT accepted: ping echoed {:.1?} after connecting, rtt {:.1?}", start.elapsed(), conn.rtt());
+  conn.close(0u32.into(), b"0-RTT done");
+  Ok(())
+}
+
+/// How a --probe failed; the process exits with its value.
+#[derive(Clone, Copy, Debug)]
+enum ProbeFailure {
+  Handshake = 1,
+  Timeout = 2,
+  Mismatch = 3,
+}
+
+impl std::fmt::Display for ProbeFailure {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(match self {
+      ProbeFailure::Handshake => "no connection",
+      ProbeFailure::Timeout => "timeout",
+      ProbeFailure::Mismatch => "wrong echo",
+    })
+  }
+}
+
+/// One handshake and ping within --probe-timeout; errors carry the
+/// [`ProbeFailure`] as context.
+async fn probe(endpoint: &Endpoint, candidates: &[SocketAddr], opt: &Opt) -> Result<()> {
+  let start = Instant::now();
+  let attempt = async {
+    let (conn, remote) = connect_any(endpoint, candidates, opt.server_name(), opt.probe_timeout)
+      .await
+      .context(ProbeFailure::Handshake)?;
+    authenticate(&conn, opt.auth_token.as_deref()).await.context(ProbeFailure::Handshake)?;
+    let handshake = start.elapsed();
+    let echoed = async {
+      if opt.datagram {
+        conn.send_datagram(Bytes::from_static(b"ping"))?;
+        Ok(conn.read_datagram().await?)
+      } else {
+        let (mut send, mut recv) = flow::open_bi(&conn).await?;
+        send.write_all(b"ping").await?;
+        send.finish()?;
+        anyhow::Ok(Bytes::from(recv.read_to_end(64 * 1024).await?))
+      }
+    };
+    let data = echoed.await.context(ProbeFailure::Handshake)?;
+    check_transform(opt.transform, &data).context(ProbeFailure::Mismatch)?;
+    conn.close(0u32.into(), b"probe done");
+    anyhow::Ok((remote, handshake, conn.rtt()))
+  };
+  let (remote, handshake, rtt) = match tokio::time::timeout(opt.probe_timeout, attempt).await {
+    Ok(probed) => probed?,
+    Err(_) => {
+      return Err(anyhow::anyhow!("no handshake and echo within {:?}", opt.probe_timeout).context(ProbeFailure::Timeout));
+    }
+  };
+  if opt.verbose {
+    println!("probe ok: {remote} echoed after {:.1?} (handshake {handshake:.1?}, rtt {rtt:.1?})", start.elapsed());
+  }
+  Ok(())
+}
+
+/// Datagrams are unreliable: retry a few times and skip stale echoes.
+async fn dgram_roundtrip(conn: &Connection, payload: Bytes) -> Result<()> {
+  for _ in 0..3 {
+    conn.send_datagram(payload.clone())?;
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(1);
+    while let Ok(data) = tokio::time::timeout_at(deadline, conn.read_datagram()).await {
+      if data? == payload {
+        return Ok(());
+      }
+    }
+  }
+  anyhow::bail!("no matching echo after 3 attempts")
+}
+
+/// Write `bytes` bytes on a new stream and reset it; the server must reset
+/// its echo with the same error code.
+async fn reset_after(conn: &Connection, bytes: u64, timeout: Duration) -> Result<()> {
+  let code = VarInt::from_u32(ABORT_CODE);
+  let (mut send, mut recv) = flow::open_bi(conn).await?;
+  // read while writing, or a large write stalls on the echo we don't take
+  let write = async {
+    let chunk = vec![0x5a; 64 * 1024];
+    let mut left = bytes;
+    while left > 0 {
+      let n = left.min(chunk.len() as u64) as usize;
+      send.write_all(&chunk[..n]).await?;
+      left -= n as u64;
+    }
+    send.reset(code)?;
+    anyhow::Ok(())
+  };
+  let mut echoed = 0;
+  let read = async {
+    loop {
+      match recv.read_chunk(usize::MAX, true).await {
+        Ok(Some(chunk)) => echoed += chunk.bytes.len() as u64,
+        Ok(None) => return Ok(None),
+        Err(e) => return Ok(Some(e)),
+      }
+    }
+  };
+  let ((), end) = tokio::time::timeout(timeout, async { tokio::try_join!(write, read) })
+    .await
+    .with_context(|| format!("the echo was neither reset nor finished within {timeout:?}"))??;
+  match end {
+    Some(ReadError::Reset(got)) => {
+      anyhow::ensure!(got == code, "server reset the echo with code {got}, expected {code}");
+      println!("reset after {bytes} B with code {code}: server reset the echo with code {got} ({echoed} B echoed before)");
+      Ok(())
+    }
+    Some(e) => Err(e).context("reading the echo"),
+    None => anyhow::bail!("server finished the echo ({echoed} B) instead of resetting it"),
+  }
+}
+
+/// Send STOP_SENDING on a new stream and keep writing pings until the
+/// server, failing to echo them, asks us to stop with the same code.
+async fn stop_sending(conn: &Connection, timeout: Duration) -> Result<()> {
+  let code = VarInt::from_u32(ABORT_CODE);
+  let (mut send, mut recv) = flow::open_bi(conn).await?;
+  recv.stop(code)?;
+  let stopped = send.stopped();
+  tokio::pin!(stopped);
+  let got = tokio::time::timeout(timeout, async {
+    loop {
+      // the server only notices our STOP_SENDING when it writes an echo
+      match send.write_all(b"ping").await {
+        Ok(()) => {}
+        Err(WriteError::Stopped(got)) => return anyhow::Ok(Some(got)),
+        Err(e) => return Err(e.into()),
+      }
+      tokio::select! {
+        res = &mut stopped => return Ok(res?),
+        _ = tokio::time::sleep(STOP_PING_GAP) => {}
+      }
+    }
+  })
+  .await
+  .with_context(|| format!("server sent no STOP_SENDING within {timeout:?}"))??;
+  match got {
+    Some(got) => {
+      anyhow::ensure!(got == code, "server sent STOP_SENDING with code {got}, expected {code}");
+      println!("sent STOP_SENDING with code {code}: server answered with STOP_SENDING code {got}");
+      Ok(())
+    }
+    None => anyhow::bail!("the stream completed without a STOP_SENDING from the server"),
+  }
+}
+
+/// Binary-search the largest datagram in `1..=max` that is echoed back.
+async fn probe_datagram_size(conn: &Connection, max: usize) -> Option<usize> {
+  let (mut lo, mut hi) = (0, max);
+  while lo < hi {
+    let size = lo + (hi - lo).div_ceil(2);
+    let ok = dgram_roundtrip(conn, Bytes::from(vec![0x5a; size])).await.is_ok();
+    info!(size, ok, "datagram probe");
+    if ok {
+      lo = size;
+    } else {
+      hi = size - 1;
+    }
+  }
+  (lo > 0).then_some(lo)
+}
+
+/// Same pings and bulk echo over one QUIC stream and one TLS-over-TCP
+/// connection, printed as a table.
+async fn compare_tcp(
+  endpoint: &Endpoint,
+  remote: SocketAddr,
+  opt: &Opt,
+  key_log: Option<Arc<dyn KeyLog>>,
+) -> Result<()> {
+  let tcp_addr = SocketAddr::new(remote.ip(), opt.tcp_port.unwrap_or(opt.port));
+  println!("QUIC {remote} (UDP) vs TLS-over-TCP {tcp_addr} ({})", opt.host());
+  if let Some(warmup) = opt.warmup {
+    println!("each transport echoes for {warmup:?} first; the table only covers what follows");
+  }
+
+  let start = Instant::now();
+  let conn = tokio::time::timeout(opt.connect_timeout, endpoint.connect(remote, opt.server_name())?)
+    .await
+    .context("QUIC handshake timed out")?
+    .context("QUIC handshake")?;
+  let quic_handshake = start.elapsed();
+  authenticate(&conn, opt.auth_token.as_deref()).await?;
+  let (mut send, mut recv) = flow::open_bi(&conn).await?;
+  if let Some(warmup) = opt.warmup {
+    let echoed = tcp::warmup(&mut recv, &mut send, warmup).await.context("QUIC warmup")?;
+    info!(echoed, cwnd = conn.stats().path.cwnd, "QUIC warmup done");
+  }
+  let quic_rtts = tcp::ping(&mut recv, &mut send, COMPARE_PINGS).await.context("QUIC ping")?;
+  let quic_bulk = tcp::bulk(&mut recv, &mut send, COMPARE_BYTES).await.context("QUIC bulk echo")?;
+  send.finish()?;
+  conn.close(0u32.into(), b"compare done");
+
+  let (stream, tcp_handshake) = tokio::time::timeout(
+    opt.connect_timeout,
+    tcp::connect(tcp_addr, opt.server_name(), Arc::new(make_tls_config(ALPN, key_log))),
+  )
+  .await
+  .context("TCP+TLS handshake timed out")??;
+  let (mut recv, mut send) = tokio::io::split(stream);
+  if let Some(warmup) = opt.warmup {
+    let echoed = tcp::warmup(&mut recv, &mut send, warmup).await.context("TCP warmup")?;
+    info!(echoed, "TCP warmup done");
+  }
+  let tcp_rtts = tcp::ping(&mut recv, &mut send, COMPARE_PINGS).await.context("TCP ping")?;
+  let tcp_bulk = tcp::bulk(&mut recv, &mut send, COMPARE_BYTES).await.context("TCP bulk echo")?;
+  let _ = send.shutdown().await;
+
+  let ms = |d: Duration| format!("{:.2} ms", d.as_secs_f64() * 1000.0);
+  let median = |mut rtts: Vec<Duration>| {
+    rtts.sort();
+    (rtts[rtts.len() / 2], rtts[0])
+  };
+  let mbps = |d: Duration| format!("{:.1} Mbit/s", COMPARE_BYTES as f64 * 8.0 / d.as_secs_f64() / 1e6);
+  let ((quic_med, quic_min), (tcp_med, tcp_min)) = (median(quic_rtts), median(tcp_rtts));
+  println!("  {:<20} {:>14} {:>14}", "", "QUIC", "TCP+TLS");
+  println!("  {:<20} {:>14} {:>14}", "handshake", ms(quic_handshake), ms(tcp_handshake));
+  println!("  {:<20} {:>14} {:>14}", format!("rtt median ({COMPARE_PINGS})"), ms(quic_med), ms(tcp_med));
+  println!("  {:<20} {:>14} {:>14}", "rtt min", ms(quic_min), ms(tcp_min));
+  println!("  {:<20} {:>14} {:>14}", format!("echo {} MiB", COMPARE_BYTES >> 20), mbps(quic_bulk), mbps(tcp_bulk));
+  Ok(())
+}
+
+/// Ping over one connection while another loads the path, and print the
+/// idle and loaded RTTs with the responsiveness score.
+async fn loaded_latency(endpoint: &Endpoint, remote: SocketAddr, opt: &Opt, duration: Duration) -> Result<()> {
+  let connect = || async {
+    let conn = tokio::time::timeout(opt.connect_timeout, endpoint.connect(remote, opt.server_name())?)
+      .await
+      .context("handshake timed out")?
+      .context("handshake")?;
+    authenticate(&conn, opt.auth_token.as_deref()).await?;
+    anyhow::Ok(conn)
+  };
+  let (probe, load) = (connect().await?, connect().await?);
+  println!(
+    "latency under load to {remote}: {} idle pings, then pings while loading the path for {duration:?}",
+    bufferbloat::IDLE_PINGS
+  );
+  let report = bufferbloat::measure(&probe, &load, duration).await?;
+  println!("{report}");
+  LossReport::new(&load.stats()).log();
+  probe.close(0u32.into(), b"loaded latency done");
+  load.close(0u32.into(), b"loaded latency done");
+  Ok(())
+}
+
+/// One server's results in a multi-target comparison.
+struct TargetRun {
+  addr: SocketAddr,
+  handshake: Duration,
+  rtt_median: Duration,
+  rtt_min: Duration,
+  loss: LossReport,
+}
+
+/// Run the same echo test against every target (one after another, or all
+/// at once with --parallel-targets) and print them side by side.
+async fn compare_targets(
+  endpoint: &Endpoint,
+  targets: Vec<(String, u16)>,
+  resolved: Vec<Result<Vec<SocketAddr>>>,
+  opt: &Opt,
+) -> Result<()> {
+  println!(
+    "comparing {} targets {}",
+    targets.len(),
+    if opt.parallel_targets { "in parallel" } else { "one after another" }
+  );
+  let runs: Vec<_> = targets
+    .into_iter()
+    .zip(resolved)
+    .map(|((host, port), addrs)| {
+      let endpoint = endpoint.clone();
+      let name = opt.sni.clone().unwrap_or_else(|| host.clone());
+      let (connect_timeout, response_timeout) = (opt.connect_timeout, opt.response_timeout);
+      let token = opt.auth_token.clone();
+      let label = format!("{host}:{port}");
+      let run = async move {
+        probe_target(&endpoint, addrs?, &name, token.as_deref(), connect_timeout, response_timeout).await
+      }
+        .instrument(info_span!("target", %label));
+      (label, run)
+    })
+    .collect();
+
+  let mut results = Vec::new();
+  if opt.parallel_targets {
+    let mut tasks = tokio::task::JoinSet::new();
+    for (n, (label, run)) in runs.into_iter().enumerate() {
+      tasks.spawn(async move { (n, label, run.await) });
+    }
+    while let Some(joined) = tasks.join_next().await {
+      results.push(joined?);
+    }
+    results.sort_by_key(|(n, _, _)| *n);
+  } else {
+    for (n, (label, run)) in runs.into_iter().enumerate() {
+      results.push((n, label, run.await));
+    }
+  }
+
+  let Some((label, _)) = print_runs("target", &results) else {
+    anyhow::bail!("none of the {} targets answered", results.len());
+  };
+  println!("best: {label} (fewest lost packets, then lowest median RTT)");
+  Ok(())
+}
+
+/// Test the first IPv6 and the first IPv4 address of --host with the same
+/// echo as [`compare_targets`], and name the family the OS would have used.
+async fn compare_families(endpoint: &Endpoint, resolver: &Resolver, candidates: &[SocketAddr], opt: &Opt) -> Result<()> {
+  let host = &opt.host[0];
+  let (v6, v4) = (candidates.iter().find(|a| a.is_ipv6()), candidates.iter().find(|a| a.is_ipv4()));
+  let (Some(&v6), Some(&v4)) = (v6, v4) else {
+    anyhow::bail!(
+      "--dual-stack needs both A and AAAA records, but {host} only has {} addresses",
+      if v6.is_some() { "IPv6" } else { "IPv4" }
+    );
+  };
+  // `candidates` is in Happy Eyeballs order, so ask again for the resolver's own order
+  let preferred = resolver.lookup(host, opt.port).await?.into_iter().find(|a| candidates.contains(a));
+
+  println!("comparing IPv6 and IPv4 for {host}:{}", opt.port);
+  let name = opt.sni.as_deref().unwrap_or(host);
+  let mut results = Vec::new();
+  for (n, (family, addr)) in [("IPv6", v6), ("IPv4", v4)].into_iter().enumerate() {
+    let run = probe_target(endpoint, vec![addr], name, opt.auth_token.as_deref(), opt.connect_timeout, opt.response_timeout)
+      .instrument(info_span!("family", family))
+      .await;
+    results.push((n, family.to_string(), run));
+  }
+  let best = print_runs("family", &results).map(|(label, _)| label.clone());
+  if let Some(preferred) = preferred {
+    println!(
+      "OS preference: {} ({preferred}, first in the resolver's answer)",
+      if preferred.is_ipv6() { "IPv6" } else { "IPv4" }
+    );
+  }
+  let Some(best) = best else {
+    anyhow::bail!("{host} answered over neither IPv6 nor IPv4");
+  };
+  println!("best: {best} (fewest lost packets, then lowest median RTT)");
+  Ok(())
+}
+
+/// Print one comparison row per run and return the best run: fewest lost
+/// packets, then lowest median RTT.
+fn print_runs<'a>(column: &str, results: &'a [(usize, String, Result<TargetRun>)]) -> Option<(&'a String, &'a TargetRun)> {
+  let ms = |d: Duration| format!("{:.2} ms", d.as_secs_f64() * 1000.0);
+  let width = results.iter().map(|(_, label, _)| label.len()).max().unwrap_or(0).max(6);
+  println!(
+    "  {:<width$} {:<22} {:>12} {:>12} {:>12} {:>8}",
+    column, "address", "handshake", "rtt median", "rtt min", "loss"
+  );
+  for (_, label, res) in results {
+    match res {
+      Ok(run) => println!(
+        "  {label:<width$} {:<22} {:>12} {:>12} {:>12} {:>7.2}%",
+        run.addr.to_string(),
+        ms(run.handshake),
+        ms(run.rtt_median),
+        ms(run.rtt_min),
+        run.loss.loss_percent()
+      ),
+      Err(e) => println!("  {label:<width$} FAIL  {e:#}"),
+    }
+  }
+  results
+    .iter()
+    .filter_map(|(_, label, res)| res.as_ref().ok().map(|run| (label, run)))
+    .min_by_key(|(_, run)| (run.loss.lost_packets, run.rtt_median))
+}
+
+/// Connect to one target, time the handshake and [`COMPARE_PINGS`] echoes
+/// on one stream, and read the connection's losses.
+async fn probe_target(
+  endpoint: &Endpoint,
+  addrs: Vec<SocketAddr>,
+  server_name: &str,
+  auth_token: Option<&str>,
+  connect_timeout: Duration,
+  response_timeout: Duration,
+) -> Result<TargetRun> {
+  let start = Instant::now();
+  let (conn, addr) = connect_any(endpoint, &addrs, server_name, connect_timeout).await?;
+  let handshake = start.elapsed();
+  authenticate(&conn, auth_token).await?;
+  let (mut send, mut recv) = flow::open_bi(&conn).await?;
+  let mut rtts = tokio::time::timeout(response_timeout, tcp::ping(&mut recv, &mut send, COMPARE_PINGS))
+    .await
+    .with_context(|| format!("{COMPARE_PINGS} echoes took longer than {response_timeout:?}"))??;
+  send.finish()?;
+  let loss = LossReport::new(&conn.stats());
+  conn.close(0u32.into(), b"compare done");
+  rtts.sort();
+  Ok(TargetRun { addr, handshake, rtt_median: rtts[rtts.len() / 2], rtt_min: rtts[0], loss })
+}
+
+/// Connect from a separate socket that goes silent after the first flight
+/// and compare what the server sends to the anti-amplification limit.
+async fn amplification_check(
+  remote: SocketAddr,
+  opt: &Opt,
+  bind: IpAddr,
+  config: ClientConfig,
+  window: Duration,
+) -> Result<()> {
+  println!(
+    "anti-amplification check against {remote}: silent after the first flight for {:.1} s",
+    window.as_secs_f64()
+  );
+  let socket = bind_udp(SocketAddr::new(bind, 0), opt.interface.as_deref())?;
+  let report = amplification::measure(socket, remote, opt.server_name(), config, window).await?;
+  println!("  client first flight  {:>3} datagrams {:>7} B", report.client_datagrams, report.client_bytes);
+  println!(
+    "  server, unvalidated  {:>3} datagrams {:>7} B  ({:.2}x)",
+    report.server_datagrams,
+    report.server_bytes,
+    report.ratio()
+  );
+  anyhow::ensure!(report.server_datagrams > 0, "no reply from {remote} within {window:?}");
+  if report.retry {
+    println!("  the server sent a Retry, validating the address before any handshake data");
+  }
+  if report.ratio() > amplification::LIMIT {
+    println!("  FAIL  the server exceeds the {}x anti-amplification limit", amplification::LIMIT);
+    anyhow::bail!("{:.2}x amplification", report.ratio());
+  }
+  println!("  OK    within the {}x anti-amplification limit", amplification::LIMIT);
+  Ok(())
+}
+
+/// Offer `offer` (or a greased version) from a separate socket, print the
+/// versions the server advertises in its Version Negotiation, then check
+/// that a normal connection still succeeds.
+async fn version_negotiation(
+  endpoint: &Endpoint,
+  candidates: &[SocketAddr],
+  opt: &Opt,
+  bind: IpAddr,
+  offer: Option<u32>,
+) -> Result<()> {
+  let remote = candidates[0];
+  let version = offer.unwrap_or_else(versions::greased);
+  let socket = bind_udp(SocketAddr::new(bind, 0), opt.interface.as_deref())?;
+  socket.set_nonblocking(true)?;
+  let socket = tokio::net::UdpSocket::from_std(socket)?;
+
+  println!("offering version {version:#010x} ({}) to {remote}", versions::name(version));
+  let negotiation = versions::probe(&socket, remote, version, 3, opt.response_timeout).await;
+  match &negotiation {
+    Ok(vn) => {
+      println!(
+        "  Version Negotiation after {:.1} ms (attempt {}), server supports:",
+        vn.rtt.as_secs_f64() * 1000.0,
+        vn.attempts
+      );
+      for &v in &vn.versions {
+        println!("    {v:#010x}  {}", versions::name(v));
+      }
+      if vn.versions.contains(&version) {
+        println!("  note: the server lists the offered version it just refused");
+      }
+    }
+    Err(e) => println!("  FAIL  {e:#}"),
+  }
+
+  let connected = connect_any(endpoint, candidates, opt.server_name(), opt.connect_timeout).await;
+  match &connected {
+    Ok((conn, addr)) => {
+      println!("connection with the default version via {addr}: OK");
+      conn.close(0u32.into(), b"done");
+    }
+    Err(e) => println!("connection with the default version: FAIL  {e:#}"),
+  }
+  endpoint.wait_idle().await;
+  negotiation?;
+  connected?;
+  Ok(())
+}
+
+async fn conformance(
+  endpoint: &Endpoint,
+  remote: SocketAddr,
+  host: &str,
+  auth_token: Option<&str>,
+  transport: Arc<TransportConfig>,
+  key_log: Option<Arc<dyn KeyLog>>,
+) -> Result<()> {
+  println!("conformance v{CONFORMANCE_VERSION} against {remote} ({host})");
+  let mut matrix = Matrix::new();
+
+  let conn = check(&mut matrix, "handshake", async {
+    let conn = endpoint.connect(remote, host)?.await?;
+    let proto = handshake::data(&conn).and_then(|hd| hd.protocol);
+    anyhow::ensure!(proto.as_deref() == Some(ALPN), "unexpected ALPN {proto:?}");
+    authenticate(&conn, auth_token).await?;
+    Ok(conn)
+  })
+  .await;
+
+  if let Some(conn) = &conn {
+    check(&mut matrix, "stream-ping", stream_roundtrip(conn, b"ping")).await;
+    check(&mut matrix, "stream-empty", stream_roundtrip(conn, b"")).await;
+    let large: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    check(&mut matrix, "stream-1MiB", stream_roundtrip(conn, &large)).await;
+    check(&mut matrix, "stream-concurrent-8", async {
+      let mut tasks = tokio::task::JoinSet::new();
+      for i in 0..8u8 {
+        let conn = conn.clone();
+        tasks.spawn(async move { stream_roundtrip(&conn, &[i; 4096]).await });
+      }
+      while let Some(res) = tasks.join_next().await {
+        res??;
+      }
+      Ok(())
+    })
+    .await;
+    check(
+      &mut matrix,
+      "datagram-ping",
+      dgram_roundtrip(conn, Bytes::from_static(b"conformance-dgram")),
+    )
+    .await;
+    check(&mut matrix, "datagram-max-size", async {
+      let max = conn.max_datagram_size().context("peer does not support datagrams")?;
+      dgram_roundtrip(conn, Bytes::from(vec![0x5a; max])).await
+    })
+    .await;
+    check(&mut matrix, "datagram-oversize-rejected", async {
+      let max = conn.max_datagram_size().context("peer does not support datagrams")?;
+      match conn.send_datagram(Bytes::from(vec![0; max + 1])) {
+        Err(SendDatagramError::TooLarge) => Ok(()),
+        Err(e) => anyhow::bail!("unexpected error {e}"),
+        Ok(()) => anyhow::bail!("oversize datagram was accepted"),
+      }
+    })
+    .await;
+    conn.close(0u32.into(), b"conformance done");
+  }
+
+  check(&mut matrix, "wrong-alpn-rejected", async {
+    let mut cfg = make_client_config(b"quic-echo-conformance-bogus", key_log)?;
+    cfg.transport_config(transport);
+    match endpoint.connect_with(cfg, remote, host)?.await {
+      Ok(_) => anyhow::bail!("handshake succeeded with an unknown ALPN"),
+      Err(_) => Ok(()),
+    }
+  })
+  .await;
+
+  let failed = matrix.iter().filter(|(_, _, r)| r.is_err()).count();
+  for (name, elapsed, res) in &matrix {
+    let ms = elapsed.as_secs_f64() * 1000.0;
+    match res {
+      Ok(()) => println!("  {name:<28} PASS  {ms:>8.1} ms"),
+      Err(e) => println!("  {name:<28} FAIL  {ms:>8.1} ms  {e}"),
+    }
   }
+  println!("{} passed, {failed} failed", matrix.len() - failed);
 
+  anyhow::ensure!(failed == 0, "{failed} conformance scenario(s) failed");
   Ok(())
 }
@@ -26,12 +26,29 @@ Notes:
 
 How the server works (high level)
 ---------------------------------
-- Creates a QUIC endpoint bound to host:port (UDP).
+- Creates a QUIC endpoint bound to host:port (UDP), or with --workers N,
+  N endpoints sharing the port through SO_REUSEPORT (Unix only) so the
+  kernel spreads connections, and the per-packet work, across cores.
+  The kernel picks the endpoint by the client's address, so a client
+  that migrates usually lands on an endpoint that doesn't know it; use
+  --disable-migration with workers.
+- With --listen ADDR:PORT (repeatable) it binds every address instead of
+  host:port, each with its own endpoint (times --workers); all of them feed
+  the same accept loop, so limits, ACLs, settings and stats are shared.
 - Accepts incoming connections in a loop.
 - For each connection:
-  - prints the negotiated ALPN and remote address,
+  - prints the negotiated ALPN, the client's SNI and the remote address,
   - spawns a task that reads incoming datagrams and echoes them back,
   - accepts bidirectional streams in a loop; each stream is echoed back in a spawned task.
+    The echo hands quinn's received `Bytes` chunks straight back to the send
+    side (read_chunks / write_all_chunks), no copy through a buffer; up to
+    --chunk-budget chunks (default 32) are moved per call.
+  - mirrors aborts: a stream the client resets gets its echo reset with
+    the same error code, and a STOP_SENDING from the client is answered
+    with a STOP_SENDING carrying the same code, both logged; the client's
+    --reset-after / --stop-sending check the codes that come back,
+  - reads and discards unidirectional streams (they aren't echoed), so the
+    client's stream credit comes back.
 
 Datagram buffer tuning
 ----------------------
@@ -39,134 +56,1765 @@ TransportConfig is tweaked to increase send/receive buffers for datagrams:
   - receive buffer: 64 KiB
   - send buffer:    2 MiB
 This helps avoid drops when sending bigger bursts of datagrams.
+
+Config file
+-----------
+--config FILE reads options from a flat TOML table keyed by long flag names
+(`port = 4433`, `stream-window = 16777216`, switches as `true`); they are
+spliced in front of the command line, which therefore wins; options set
+in the environment are skipped.
+
+Environment
+-----------
+Every option also reads QUIC_ECHO_<FLAG> (e.g. QUIC_ECHO_PORT,
+QUIC_ECHO_STREAM_WINDOW; QUIC_ECHO_CONFIG for --config; SSLKEYLOGFILE
+for --keylog). Precedence: command line, then environment, then the
+config file, then defaults.
+
+Reloading
+---------
+On SIGHUP, and whenever the --config file's modification time changes,
+the server parses its command line and config file again and logs each
+option whose value changed. Limits (--max-connections, --conn-rate,
+--conn-burst, --max-bytes-per-conn), the impairments (--echo-delay,
+--echo-jitter, --drop-rate, --drop-burst), --alpn and --log-level are
+applied at once: quotas and impairments to live connections too, ALPNs
+to new handshakes (by swapping the endpoints' ServerConfig). Changes to any
+other option are logged as needing a restart. If the new options don't
+parse or validate, nothing is applied and the old ones stay in force.
+
+Flow control
+------------
+--stream-window / --conn-window set the receive windows we grant clients
+(TransportConfig::stream_receive_window / receive_window) and
+--max-concurrent-bidi / --max-concurrent-uni (or --max-bidi-streams /
+--max-uni-streams) the per-connection stream limits; anything
+not given keeps quinn's default. The default stream window caps a single
+stream at roughly 100 Mbit/s per 100 ms of RTT.
+
+Client migration / NAT rebinding
+--------------------------------
+By default quinn lets a client continue a connection from a new address
+(NAT rebinding, Wi-Fi -> LTE, ...). Flags:
+  --disable-migration   drop packets that would move a connection to a new path
+  --log-path-changes    poll each connection's remote address and print every
+                        observed change (old -> new) and whether the new path
+                        survived validation or was rolled back
+
+Preferred address
+-----------------
+--preferred-addr IP:PORT advertises a second address in the transport
+parameters (RFC 9000, 9.6) and binds a socket to it, feeding the same
+endpoint as the main one. A client that honours the parameter moves there
+once the handshake is confirmed, which is logged as "peer moved to the
+preferred address". quinn's own client (including ours) ignores it, so
+this needs another QUIC stack to show a migration. Single endpoint only
+(no --workers), and not with --disable-migration.
+
+0-RTT
+-----
+--0rtt accepts early data: clients resuming a session with a ticket from an
+earlier connection can send their first streams and datagrams before the
+handshake completes (the client's --0rtt checks that). Early data can be
+replayed by an attacker, which does not matter for an echo but would for a
+real service.
+
+Connection IDs
+--------------
+For a QUIC-aware load balancer in front of one or more servers:
+  --cid-len N          length of the connection IDs we issue (4-20, default 8)
+  --server-id HEX      start every issued ID with these bytes (the rest is
+                       random) so the balancer can route by connection ID;
+                       packets for other prefixes are dropped
+  --cid-lifetime SECS  retire each ID after this long and issue a new one
+  --cid-count N        IDs each client holds at once, the handshake's
+                       included (2-8; default what the client accepts, up
+                       to 8)
+Every issued ID is logged at debug level (RUST_LOG=info,quic_echo::cid=debug)
+and after "connection closed" a "connection ID activity" line counts the
+NEW_CONNECTION_ID and RETIRE_CONNECTION_ID frames each way.
+
+Connection limit
+----------------
+--max-connections N caps the number of live connections. Once the limit is
+reached, new connection attempts are refused (Incoming::refuse, the client
+sees CONNECTION_REFUSED) and the refusal is logged.
+
+Per-source rate limiting
+------------------------
+--conn-rate R --conn-burst B keeps a token bucket per remote IP in the accept
+loop: each new connection attempt costs one token, buckets refill at R/s up
+to B. Attempts from an empty bucket are refused before the handshake and
+counted; refusals are logged with per-IP and total counters.
+
+IP allow/deny lists
+-------------------
+--allow CIDR and --deny CIDR (repeatable or comma-separated; a bare address
+is one host) are checked in the accept loop, before the rate limiter and
+the handshake. A deny match always refuses; once there is any allow rule,
+only matching addresses get in. --acl-file FILE adds `allow <cidr>` /
+`deny <cidr>` lines (`#` comments) and is re-read within 2 s of a change;
+a file that no longer parses keeps the previous rules and logs a warning.
+Refusals are logged with the matching rule and a total counter.
+
+Byte quota
+----------
+--max-bytes-per-conn N (echo mode) closes a connection with application
+error 429 ("byte quota exceeded") once the server has echoed N bytes on it,
+stream and datagram payload combined, so one client cannot hog a shared
+test server. The check runs after each echo, so the last write may take the
+total past N.
+
+Idle reaper
+-----------
+--reap-idle SECS (echo mode) closes connections that have opened no stream
+and sent or been echoed no data or datagram for SECS, with application
+error 408 ("idle"). Unlike the QUIC idle timeout this ignores keep-alive
+PINGs, so clients that connect and go quiet don't pile up during soak
+tests. Each sweep that closes something logs how many it reaped, the total
+so far and how many connections are still tracked.
+
+Graceful shutdown
+-----------------
+On SIGINT (Ctrl-C) or SIGTERM the server stops accepting, closes every live
+connection with an application close (--shutdown-code / --shutdown-reason,
+default 0 / "server shutting down"), then waits up to --drain-timeout seconds
+for Endpoint::wait_idle() so the CONNECTION_CLOSE frames actually go out.
+The other way round, "connection closed" carries close_code and close_reason
+when the client closed with an application close (the client's --close-code
+/ --close-reason), to check they arrive intact through a proxy.
+
+Token authentication
+--------------------
+With --auth-token SECRET (echo mode) every connection must first open a
+unidirectional stream carrying the token (2-byte length + bytes, as the
+client's --auth-token sends it). Nothing is echoed or pushed until it has
+arrived; a missing (within 5 s), malformed or wrong token closes the
+connection with application error 401, so a public server is no reflector
+for strangers.
+
+Access log
+----------
+--access-log FILE appends one JSON line per connection when it closes:
+remote address, SNI, ALPN, duration, streams opened (bi and uni), stream
+bytes read and echoed, datagrams read and echoed, UDP bytes each way and the
+close reason. The stream and datagram counts come from the echo mode and
+are null in the other modes.
+
+GeoIP
+-----
+--geoip FILE.mmdb (repeatable; needs `--features geoip`) loads MaxMind
+databases - GeoLite2/GeoIP2 Country, City or ASN - and looks up every
+client: the `conn` span carries `country` and `asn`, "connection
+established" adds the AS organization, and --access-log records get
+country, asn and as_org. Each field comes from the first database with it.
+
+Daemon mode
+-----------
+--daemonize --pidfile FILE detaches the server (fork, setsid, fork) so it
+can run on a test box without a supervisor; the command returns once the
+server listens, printing its pid, or fails if it exits before that. The
+daemon's output is appended to --log-file (default: discarded). --pidfile
+alone writes the pid without detaching; the file is removed on exit.
+--stop --pidfile FILE sends that process SIGTERM (a graceful shutdown) and
+waits up to --drain-timeout + 5 s for it to exit. Unix only.
+
+Latency injection
+-----------------
+--echo-delay MS sleeps before echoing each stream chunk or datagram, and
+--echo-jitter MS adds a uniformly random extra 0..=MS on top, so the echo
+server doubles as a latency simulator for client timeout testing (echo mode
+only). Stream chunks stay in order; delayed datagrams are sent from their own
+tasks, so jitter can reorder them like a real path would.
+
+TLS-over-TCP companion
+----------------------
+--tcp-port P additionally listens on TCP port P (same --host) with the same
+certificate and echoes every TLS connection byte for byte, so the client's
+--compare-tcp can run the same ping/throughput test over TCP+TLS and QUIC.
+The QUIC UDP port and the TCP port may have the same number.
+
+UDP tunnel
+----------
+With --forward-to host:port (echo mode) datagrams are no longer echoed but
+relayed: each connection gets its own UDP socket connected to the target,
+every QUIC datagram is sent to it as one packet and every reply comes back
+as one datagram. Paired with the client's --udp-listen this is a tiny
+QUIC-based UDP tunnel; streams are still echoed.
+
+Slow reader
+-----------
+--read-rate B (echo mode) reads each stream at most B bytes per second
+through a token bucket instead of as fast as data arrives. quinn only
+extends the client's flow-control credit as data is read, so the receive
+window fills up and the client's sender stalls, like behind a slow
+consumer. The client's --read-rate does the same to the server's echo.
+
+Datagram push
+-------------
+--push-dgrams R (echo mode) sends every client R unsolicited datagrams per
+second from the moment it connects until it leaves, each carrying a
+sequence number and the server's send time, --push-size bytes long
+(default 64). The client's --listen receives them and reports loss,
+reordering, duplicates and jitter. Pushed datagrams arrive alongside
+datagram echoes, so leave it off for the client's datagram tests.
+The rate comes off tokio's millisecond timer, catching up in bursts after
+a stall. For loss figures that mean something, --burst N sends N
+datagrams back to back per burst and --spacing US starts a burst every
+US microseconds (default N/R seconds). Either one moves the push to a
+thread of its own that sleeps until just before each burst and spins the
+rest of the way, so spacing holds to a few microseconds; short spacings
+keep that thread busy. Bursts the sender falls a whole spacing behind on
+are skipped and counted in a warning when the push stops.
+
+Server-initiated streams
+------------------------
+--push-stream bi|uni (echo mode) opens one stream of that kind toward each
+client right after the handshake and sends --push-stream-size bytes of a
+fixed pattern (default 64 KiB). A client running --accept-stream accepts
+and verifies it; on a bidirectional stream it echoes the data back and the
+server checks the echo. The outcome is logged per connection; clients that
+never accept the stream leave it pending until they disconnect.
+
+Datagram loss
+-------------
+--drop-rate P (0.0-1.0) silently skips the echo of that fraction of
+datagrams, to exercise client loss handling without tc/netem. With
+--drop-burst N every loss event swallows N datagrams in a row (still at
+an average loss of P), which is closer to what a congested queue does.
+
+Response transforms
+-------------------
+--transform echo|upper|reverse|sha256 (echo mode) answers each datagram and
+stream with a transform of it instead of the bytes themselves: ASCII
+uppercase, reversed, or the hex SHA-256. A middlebox reflecting packets
+can't produce that, so a client run with the same --transform proves the
+data reached the application. upper streams chunk by chunk; reverse and
+sha256 answer when the stream is finished (up to 64 MiB).
+
+Modes
+-----
+--mode echo|h3|doq|masque|perf|file|duplex selects what the server speaks (default: echo).
+
+HTTP/3 mode
+-----------
+With --mode h3 (or just --h3) the server advertises the "h3" ALPN instead of the custom one and
+speaks HTTP/3 (h3 + h3-quinn): `POST /echo` streams the request body back as
+the response body, other paths get 404 and other methods 405. An extended
+CONNECT with :protocol webtransport (any path) opens a WebTransport session
+(h3-webtransport) that echoes every bidirectional stream and datagram of the
+session, so browser clients can test against the server too.
+
+DNS-over-QUIC mode
+------------------
+With --mode doq the server advertises the "doq" ALPN and acts as an RFC 9250
+stub responder (usually run with --port 853): every query arrives on its own
+bidirectional stream with a 2-byte length prefix and gets a static answer
+(A -> 127.0.0.1, AAAA -> ::1, anything else an empty NOERROR). Malformed
+queries close the connection with DOQ_PROTOCOL_ERROR.
+
+MASQUE mode
+-----------
+With --mode masque the server is a minimal CONNECT-UDP proxy (RFC 9298,
+ALPN "h3"): an extended CONNECT to /.well-known/masque/udp/{host}/{port}/
+opens a UDP socket to the target, and HTTP datagrams (context ID 0) are
+relayed to it and back until the request stream ends. Any target is allowed,
+so don't expose this mode publicly.
+
+perf mode
+---------
+With --mode perf the server speaks the quinn `perf` protocol (ALPN "perf"),
+so existing perf clients can measure throughput against it: each
+bidirectional stream starts with the requested response size (u64, big
+endian), the rest of the request is drained and that many bytes are sent
+back; unidirectional streams are drained.
+
+File transfer mode
+------------------
+With --mode file the server (ALPN "quic-echo-file") receives one file per
+bidirectional stream, hashes it with SHA-256 on the fly and either streams it
+back or, if the client asks to store it, writes it into --store-dir (only the
+file name is kept). The server's digest is sent back so the client can check
+it against its own; the size, digest and rate of every file are logged.
+
+Full-duplex mode
+----------------
+With --mode duplex (ALPN "quic-echo-duplex") each bidirectional stream
+starts with the size the client wants back (u64, big endian); the server
+sends that many bytes while the client's upload is still arriving, instead
+of draining it first like perf. Both directions' rates are logged per stream.
+
+Certificates
+------------
+--cert/--key may be repeated (paired by position) and --cert-dir DIR loads
+every subdirectory holding fullchain.pem + privkey.pem or cert.pem +
+key.pem. Each handshake gets the first certificate whose names cover the
+client's SNI (the first one if none does) and the choice is logged.
+Files may be PEM, DER or PKCS#12 (by extension, else sniffed); --pkcs12
+FILE takes a bundle holding both halves, unlocked with --pkcs12-pass.
+Encrypted PKCS#8 keys are unlocked with --key-pass, --key-pass-file or a
+prompt when stdin is a terminal.
+
+--acme DOMAIN (repeatable, build with --features acme) instead orders a
+certificate from Let's Encrypt (or --acme-directory) with the TLS-ALPN-01
+challenge, answered on TCP --acme-port (443; the CA always dials 443). The
+account key and certificate are kept in --acme-cache and the certificate is
+renewed in the background once it is 60 days old.
+
+Crypto provider
+---------------
+--crypto-provider ring|aws-lc-rs picks the rustls crypto backend (ring by
+default; aws-lc-rs needs --features aws-lc-rs). Every "connection
+established" line carries the negotiated cipher suite.
+
+TLS session details
+-------------------
+--tls-info logs the TLS version, cipher suite, key exchange group,
+certificate signature scheme and whether the session was resumed for every
+connection, as the handshake messages go out (handshake::Sniffing).
+
+TLS key log
+-----------
+--keylog FILE (or SSLKEYLOGFILE) appends every TLS secret in the NSS key
+log format so Wireshark can decrypt captured traffic; debugging only.
+
+Packet capture
+--------------
+--pcap FILE wraps the endpoint's UDP socket (every worker's, on the
+server) so each datagram sent or received is also written to a pcap file
+with a synthesized IP/UDP header; open it in Wireshark with the --keylog
+file to decrypt.
+
+UDP offload
+-----------
+--gso and --gro (auto|on|off) control quinn's batched sends and receives:
+auto uses them where the OS offers them, on refuses to start without them,
+off sends and receives one datagram per call. Each socket's batch limits
+are logged at startup ("UDP offload"), and the datagrams per send/receive
+call and the largest batches over the whole run at shutdown ("UDP offload
+stats").
+
+DSCP marking
+------------
+--dscp N|NAME (0-63, or ef, af41, cs1, ...; Linux) marks every outgoing
+packet through IP_TOS / IPV6_TCLASS for QoS tests. quinn-udp overwrites the
+socket's TOS with its ECN control message, so the tokio backend sends
+through a wrapper that puts DSCP and ECN together (dscp::mark). The DSCP of
+received packets is counted and logged at shutdown ("DSCP of received
+packets"); not with --io-backend uring, which only gets the socket option.
+
+io_uring backend
+----------------
+--io-backend uring (Linux, built with `--features io-uring`) drives the
+socket through an io_uring instead of quinn's tokio socket: receives stay
+posted in the ring and sends are queued into it, one datagram per operation
+(no GSO/GRO, ECN or destination address). Startup logs "io_uring UDP
+backend" and whether a kernel submission thread (sqpoll) is in use.
+
+Socket activation
+-----------------
+Started by systemd socket activation (LISTEN_PID/LISTEN_FDS, as
+sd_listen_fds() reads them), the server builds one endpoint per inherited
+UDP socket instead of binding --host/--port, so it can be restarted while
+systemd keeps the port open and queues what arrives meanwhile. Without
+those variables it binds as usual. --workers does not apply; several
+ListenDatagram= sockets give as many endpoints.
+
+Loss report
+-----------
+When a connection closes the server logs its close reason and a "loss
+report" (packets sent/lost, lost bytes, congestion events, flow-control
+BLOCKED frames, and whether loss or flow control limited it), like the
+client does after each run.
+
+Logging
+-------
+Logs go through `tracing` to stderr; every connection runs in a `conn` span
+carrying the remote address and quinn's stable connection id. RUST_LOG
+controls filtering (including quinn's own events, e.g.
+RUST_LOG=info,quinn_proto=debug, or --log-level with the same syntax,
+which a reload can change) and --log-format text|pretty|json selects the
+output format (json is meant for log aggregators).
 */
 
 use anyhow::{Context, Result};
-use clap::Parser;
-use quinn::{Endpoint, Incoming, TransportConfig};
+use bytes::Bytes;
+use clap::{CommandFactory, Parser, ValueEnum};
+use quinn::{
+  ConnectionError, Endpoint, EndpointConfig, Incoming, ReadError, ReadToEndError, RecvStream, SendStream, TokioRuntime,
+  TransportConfig, VarInt, WriteError,
+};
 use quinn::crypto::rustls::QuicServerConfig;
-use rustls::pki_types::{CertificateDer, PrivateKeyDer};
-use rustls::pki_types::pem::PemObject;
-use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+use quic_echo::{
+  access::{AccessLog, Counters, Record},
+  acl::{Acl, Cidr, Rules},
+  acme::{self, Acme},
+  auth,
+  certs::{self, Passwords},
+  cid::{CidActivity, CidCount, CidOptions},
+  config,
+  crypto::{self, Provider},
+  daemon::{self, Pidfile, Ready}, doq, duplex, http3, keylog, masque, perf, push, tcp, transfer, tunnel,
+  transform::{self, Transform},
+  flow::FlowControl,
+  geoip::{GeoIp, Origin},
+  handshake::{self, Sniffing},
+  logging::{self, LogFormat, LogLevel},
+  loss::LossReport,
+  offload::{OffloadOptions, OffloadStats},
+  pace::{self, Pacer},
+  pcap::Pcap,
+  reaper::Reaper,
+  preferred::Dual,
+  push::{Pacing, StreamKind},
+  sni::SniResolver,
+  socket::{activated_udp, bind_udp_reuseport},
+};
+use rand::Rng;
+use rustls::{server::ResolvesServerCert, sign::CertifiedKey, KeyLog};
+use std::{
+  collections::HashMap,
+  ffi::OsString,
+  net::{IpAddr, SocketAddr},
+  path::PathBuf,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, RwLock,
+  },
+  time::{Duration, Instant},
+};
+use tracing::{debug, info, info_span, warn, Instrument};
 
 const ALPN: &[u8] = b"freven-quic-test";
+const PATH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a client has to send its --auth-token.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+/// Application close code for a connection over --max-bytes-per-conn.
+const QUOTA_EXCEEDED: u32 = 429;
+/// How much longer than --drain-timeout --stop waits for the server to exit.
+const STOP_GRACE: Duration = Duration::from_secs(5);
 
 #[derive(Parser, Debug)]
+// later flags win, so the command line overrides --config
+#[clap(args_override_self = true)]
 struct Opt {
-  #[clap(long, default_value = "0.0.0.0")]
+  /// Read options from this TOML file (flags on the command line win)
+  #[clap(long, env = "QUIC_ECHO_CONFIG")]
+  config: Option<PathBuf>,
+  #[clap(long, env = "QUIC_ECHO_HOST", default_value = "0.0.0.0")]
   host: String,
-  #[clap(long, default_value_t = 12806)]
+  #[clap(long, env = "QUIC_ECHO_PORT", default_value_t = 12806)]
   port: u16,
-  #[clap(long, default_value = "cert.pem")]
-  cert: PathBuf,
-  #[clap(long, default_value = "key.pem")]
-  key: PathBuf,
+  /// Listen on this ip:port instead of --host/--port; repeat it for several addresses
+  #[clap(long, env = "QUIC_ECHO_LISTEN", value_delimiter = ',', conflicts_with_all = ["host", "port"])]
+  listen: Vec<SocketAddr>,
+  /// Certificate chain (PEM, DER or PKCS#12); repeat with --key for SNI-based selection (default: cert.pem)
+  #[clap(long, env = "QUIC_ECHO_CERT")]
+  cert: Vec<PathBuf>,
+  /// Private key of the --cert at the same position (PEM, DER or PKCS#12; default: key.pem)
+  #[clap(long, env = "QUIC_ECHO_KEY")]
+  key: Vec<PathBuf>,
+  /// PKCS#12 bundle (.p12/.pfx) holding both a certificate chain and its key; repeatable
+  #[clap(long, env = "QUIC_ECHO_PKCS12")]
+  pkcs12: Vec<PathBuf>,
+  #[clap(flatten)]
+  passwords: Passwords,
+  /// Also load every subdirectory holding fullchain.pem + privkey.pem (or cert.pem + key.pem)
+  #[clap(long, env = "QUIC_ECHO_CERT_DIR")]
+  cert_dir: Option<PathBuf>,
+  /// Get a certificate for this domain from an ACME CA via TLS-ALPN-01 (repeatable; needs the `acme` feature)
+  #[clap(long, env = "QUIC_ECHO_ACME", conflicts_with_all = ["cert", "key", "pkcs12", "cert_dir"])]
+  acme: Vec<String>,
+  /// Contact address for the ACME account
+  #[clap(long, env = "QUIC_ECHO_ACME_EMAIL", requires = "acme")]
+  acme_email: Option<String>,
+  /// ACME directory URL (Let's Encrypt by default)
+  #[clap(long, env = "QUIC_ECHO_ACME_DIRECTORY", default_value = acme::LETS_ENCRYPT)]
+  acme_directory: String,
+  /// Directory for the ACME account key and the issued certificate
+  #[clap(long, env = "QUIC_ECHO_ACME_CACHE", default_value = "acme")]
+  acme_cache: PathBuf,
+  /// TCP port answering TLS-ALPN-01 challenges (the CA connects to 443)
+  #[clap(long, env = "QUIC_ECHO_ACME_PORT", default_value_t = 443)]
+  acme_port: u16,
+  /// Also run a TLS-over-TCP echo on this port (for the client's --compare-tcp)
+  #[clap(long, env = "QUIC_ECHO_TCP_PORT")]
+  tcp_port: Option<u16>,
+  /// Endpoints sharing the port via SO_REUSEPORT (one per core)
+  #[clap(long, env = "QUIC_ECHO_WORKERS", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+  workers: u32,
+  /// Refuse client address migration (packets from a new path are dropped)
+  #[clap(long, env = "QUIC_ECHO_DISABLE_MIGRATION")]
+  disable_migration: bool,
+  /// Accept 0-RTT early data from clients resuming a session
+  #[clap(long = "0rtt", env = "QUIC_ECHO_0RTT")]
+  zero_rtt: bool,
+  /// Advertise this ip:port as the preferred address and accept the same connections there too
+  #[clap(long, env = "QUIC_ECHO_PREFERRED_ADDR", conflicts_with = "disable_migration")]
+  preferred_addr: Option<SocketAddr>,
+  /// Log the negotiated TLS version, cipher suite, key exchange group, signature scheme and resumption per connection
+  #[clap(long, env = "QUIC_ECHO_TLS_INFO")]
+  tls_info: bool,
+  /// Log every observed remote address change per connection
+  #[clap(long, env = "QUIC_ECHO_LOG_PATH_CHANGES")]
+  log_path_changes: bool,
+  /// Protocol spoken on accepted connections
+  #[clap(long, env = "QUIC_ECHO_MODE", value_enum, default_value_t = Mode::Echo)]
+  mode: Mode,
+  /// Directory where --mode file stores uploads (storing is refused without it)
+  #[clap(long, env = "QUIC_ECHO_STORE_DIR")]
+  store_dir: Option<PathBuf>,
+  /// Shorthand for --mode h3
+  #[clap(long, env = "QUIC_ECHO_H3", conflicts_with = "mode")]
+  h3: bool,
+  /// Max received chunks echoed per read_chunks/write_all_chunks call
+  #[clap(long, env = "QUIC_ECHO_CHUNK_BUDGET", default_value_t = 32, value_parser = clap::value_parser!(u32).range(1..))]
+  chunk_budget: u32,
+  /// Milliseconds to wait before echoing each stream chunk or datagram
+  #[clap(long, env = "QUIC_ECHO_ECHO_DELAY", default_value_t = 0)]
+  echo_delay: u64,
+  /// Extra random delay of up to this many milliseconds per echo
+  #[clap(long, env = "QUIC_ECHO_ECHO_JITTER", default_value_t = 0)]
+  echo_jitter: u64,
+  /// Relay datagrams to this UDP `host:port` instead of echoing them
+  #[clap(long, env = "QUIC_ECHO_FORWARD_TO")]
+  forward_to: Option<String>,
+  /// Fraction of datagrams (0.0-1.0) not echoed
+  #[clap(long, env = "QUIC_ECHO_DROP_RATE", default_value_t = 0.0)]
+  drop_rate: f64,
+  /// Answer with this transform of each stream or datagram instead of echoing it
+  #[clap(long, env = "QUIC_ECHO_TRANSFORM", value_enum, default_value_t = Transform::Echo)]
+  transform: Transform,
+  /// Read each echoed stream at most this many bytes per second, like a slow receiver
+  #[clap(long, env = "QUIC_ECHO_READ_RATE", value_parser = clap::value_parser!(u64).range(1..))]
+  read_rate: Option<u64>,
+  /// Send every client this many unsolicited datagrams per second
+  #[clap(long, env = "QUIC_ECHO_PUSH_DGRAMS", conflicts_with = "forward_to")]
+  push_dgrams: Option<f64>,
+  /// Bytes per pushed datagram
+  #[clap(long, env = "QUIC_ECHO_PUSH_SIZE", default_value_t = 64, requires = "push_dgrams")]
+  push_size: usize,
+  /// Microseconds between pushed bursts, paced to sub-millisecond accuracy (instead of the --push-dgrams rate)
+  #[clap(long, env = "QUIC_ECHO_SPACING", requires = "push_dgrams", value_parser = clap::value_parser!(u64).range(1..))]
+  spacing: Option<u64>,
+  /// Pushed datagrams sent back to back per burst; enables precise pacing
+  #[clap(long, env = "QUIC_ECHO_BURST", requires = "push_dgrams", value_parser = clap::value_parser!(u32).range(1..))]
+  burst: Option<u32>,
+  /// Open a stream of this kind toward every client and send it data
+  #[clap(long, env = "QUIC_ECHO_PUSH_STREAM", value_enum)]
+  push_stream: Option<StreamKind>,
+  /// Bytes sent on the --push-stream stream
+  #[clap(long, env = "QUIC_ECHO_PUSH_STREAM_SIZE", default_value_t = 64 * 1024, requires = "push_stream")]
+  push_stream_size: usize,
+  /// Datagrams lost per loss event with --drop-rate
+  #[clap(long, env = "QUIC_ECHO_DROP_BURST", default_value_t = 1, requires = "drop_rate")]
+  drop_burst: u32,
+  /// Refuse new connections while this many are live
+  #[clap(long, env = "QUIC_ECHO_MAX_CONNECTIONS")]
+  max_connections: Option<usize>,
+  /// New connections per second allowed from a single source IP
+  #[clap(long, env = "QUIC_ECHO_CONN_RATE")]
+  conn_rate: Option<f64>,
+  /// Burst size for --conn-rate (default: the rate, rounded up)
+  #[clap(long, env = "QUIC_ECHO_CONN_BURST", requires = "conn_rate")]
+  conn_burst: Option<u32>,
+  /// Accept connections only from these addresses or CIDR blocks; repeatable
+  #[clap(long, env = "QUIC_ECHO_ALLOW", value_delimiter = ',')]
+  allow: Vec<Cidr>,
+  /// Refuse connections from these addresses or CIDR blocks; repeatable, wins over --allow
+  #[clap(long, env = "QUIC_ECHO_DENY", value_delimiter = ',')]
+  deny: Vec<Cidr>,
+  /// File of `allow <cidr>` / `deny <cidr>` lines, added to --allow/--deny and re-read when it changes
+  #[clap(long, env = "QUIC_ECHO_ACL_FILE")]
+  acl_file: Option<PathBuf>,
+  /// Close a connection with error 429 once it has been echoed this many bytes, streams and datagrams combined (echo mode)
+  #[clap(long, env = "QUIC_ECHO_MAX_BYTES_PER_CONN")]
+  max_bytes_per_conn: Option<u64>,
+  /// Close connections that have sent no stream or datagram for this many seconds, even if kept alive (echo mode)
+  #[clap(long, env = "QUIC_ECHO_REAP_IDLE")]
+  reap_idle: Option<f64>,
+  /// Application error code sent to live connections on shutdown
+  #[clap(long, env = "QUIC_ECHO_SHUTDOWN_CODE", default_value_t = 0)]
+  shutdown_code: u32,
+  /// Close reason sent to live connections on shutdown
+  #[clap(long, env = "QUIC_ECHO_SHUTDOWN_REASON", default_value = "server shutting down")]
+  shutdown_reason: String,
+  /// Seconds to wait for connections to drain after closing them
+  #[clap(long, env = "QUIC_ECHO_DRAIN_TIMEOUT", default_value_t = 5)]
+  drain_timeout: u64,
+  /// Detach into the background (Unix), writing --pidfile
+  #[clap(long, env = "QUIC_ECHO_DAEMONIZE", requires = "pidfile")]
+  daemonize: bool,
+  /// Write the server's pid to this file, removed on exit
+  #[clap(long, env = "QUIC_ECHO_PIDFILE")]
+  pidfile: Option<PathBuf>,
+  /// Append the daemon's output to this file (default: discard it)
+  #[clap(long, env = "QUIC_ECHO_LOG_FILE", requires = "daemonize")]
+  log_file: Option<PathBuf>,
+  /// Send SIGTERM to the server in --pidfile, wait for it to exit, and exit
+  #[clap(long, env = "QUIC_ECHO_STOP", requires = "pidfile", conflicts_with = "daemonize")]
+  stop: bool,
+  #[clap(flatten)]
+  flow: FlowControl,
+  #[clap(flatten)]
+  cids: CidOptions,
+  #[clap(flatten)]
+  offload: OffloadOptions,
+  /// Append TLS secrets to this file (NSS key log format, for Wireshark)
+  #[clap(long, env = "SSLKEYLOGFILE")]
+  keylog: Option<PathBuf>,
+  /// rustls crypto backend (aws-lc-rs needs the `aws-lc-rs` feature)
+  #[clap(long, env = "QUIC_ECHO_CRYPTO_PROVIDER", value_enum, default_value_t = Provider::Ring)]
+  crypto_provider: Provider,
+  /// Write every UDP datagram sent or received to this pcap file
+  #[clap(long, env = "QUIC_ECHO_PCAP")]
+  pcap: Option<PathBuf>,
+  /// Close connections that do not send this token first, before echoing anything (echo mode)
+  #[clap(long, env = "QUIC_ECHO_AUTH_TOKEN")]
+  auth_token: Option<String>,
+  /// Append a JSON line per closed connection (remote, SNI, ALPN, duration, streams, bytes, close reason) to this file
+  #[clap(long, env = "QUIC_ECHO_ACCESS_LOG")]
+  access_log: Option<PathBuf>,
+  /// MaxMind database (Country, City or ASN) to annotate connections with country and ASN; repeatable
+  #[clap(long, env = "QUIC_ECHO_GEOIP", value_delimiter = ',')]
+  geoip: Vec<PathBuf>,
+  /// Log output format
+  #[clap(long, env = "QUIC_ECHO_LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)]
+  log_format: LogFormat,
+  /// Log filter in RUST_LOG syntax, e.g. `debug` or `info,quinn_proto=trace` (default: RUST_LOG, else info)
+  #[clap(long, env = "QUIC_ECHO_LOG_LEVEL")]
+  log_level: Option<String>,
+  /// Also accept clients offering this ALPN in echo mode; repeatable
+  #[clap(long, env = "QUIC_ECHO_ALPN", value_delimiter = ',')]
+  alpn: Vec<String>,
 }
 
-fn read_certs(path: &PathBuf) -> Result<Vec<CertificateDer<'static>>> {
-  let it = CertificateDer::pem_file_iter(path)
-    .with_context(|| format!("read PEM cert {:?}", path))?;
-  let certs = it
-    .collect::<std::result::Result<Vec<_>, _>>()
-    .with_context(|| format!("parse PEM cert {:?}", path))?;
-  Ok(certs)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Mode {
+  /// Echo streams and datagrams (ALPN "freven-quic-test")
+  Echo,
+  /// HTTP/3 `POST /echo` and WebTransport sessions (ALPN "h3")
+  H3,
+  /// DNS-over-QUIC stub responder (ALPN "doq")
+  Doq,
+  /// MASQUE CONNECT-UDP proxy (ALPN "h3")
+  Masque,
+  /// quinn `perf` protocol server (ALPN "perf")
+  Perf,
+  /// File transfer with SHA-256 verification (ALPN "quic-echo-file")
+  File,
+  /// Simultaneous upload and download on one stream (ALPN "quic-echo-duplex")
+  Duplex,
 }
 
-fn read_key(path: &PathBuf) -> Result<PrivateKeyDer<'static>> {
-  PrivateKeyDer::from_pem_file(path)
-    .with_context(|| format!("read PEM key {:?}", path))
+impl Mode {
+  fn alpn(self) -> &'static [u8] {
+    match self {
+      Mode::Echo => ALPN,
+      Mode::H3 | Mode::Masque => http3::ALPN,
+      Mode::Doq => doq::ALPN,
+      Mode::Perf => perf::ALPN,
+      Mode::File => transfer::ALPN,
+      Mode::Duplex => duplex::ALPN,
+    }
+  }
 }
 
-fn make_server_config(cert: PathBuf, key: PathBuf) -> Result<quinn::ServerConfig> {
-  let certs = read_certs(&cert)?;
-  let key = read_key(&key)?;
+impl Opt {
+  fn mode(&self) -> Mode {
+    if self.h3 { Mode::H3 } else { self.mode }
+  }
+}
+
+/// What every accepted connection needs from the command line.
+struct ConnSettings {
+  mode: Mode,
+  log_path_changes: bool,
+  /// Shared with the reload in the accept loop
+  tuning: Arc<RwLock<Tuning>>,
+  chunk_budget: usize,
+  read_rate: Option<u64>,
+  transform: Transform,
+  store_dir: Option<Arc<PathBuf>>,
+  forward_to: Option<SocketAddr>,
+  /// --push-dgrams rate and --push-size
+  push: Option<(Pacing, usize)>,
+  /// --push-stream kind and --push-stream-size
+  push_stream: Option<(StreamKind, usize)>,
+  access_log: Option<Arc<AccessLog>>,
+  auth_token: Option<String>,
+  reaper: Option<Arc<Reaper>>,
+  /// Log every session's TLS details (--tls-info)
+  tls_info: bool,
+}
 
+/// The echo settings a reload also changes on live connections.
+#[derive(Clone, Copy, Debug)]
+struct Tuning {
+  impairment: Impairment,
+  /// --max-bytes-per-conn
+  byte_quota: Option<u64>,
+}
+
+impl Tuning {
+  fn new(opt: &Opt, mode: Mode) -> Result<Self> {
+    anyhow::ensure!(
+      opt.max_bytes_per_conn.is_none() || mode == Mode::Echo,
+      "--max-bytes-per-conn applies to --mode echo"
+    );
+    anyhow::ensure!((0.0..=1.0).contains(&opt.drop_rate), "--drop-rate must be between 0.0 and 1.0");
+    anyhow::ensure!(opt.drop_burst > 0, "--drop-burst must be at least 1");
+    let impairment = Impairment {
+      delay: Duration::from_millis(opt.echo_delay),
+      jitter: Duration::from_millis(opt.echo_jitter),
+      drop_rate: opt.drop_rate,
+      drop_burst: opt.drop_burst,
+    };
+    Ok(Tuning { impairment, byte_quota: opt.max_bytes_per_conn })
+  }
+}
+
+/// Artificial impairments applied by the echo loops.
+#[derive(Clone, Copy, Debug)]
+struct Impairment {
+  delay: Duration,
+  jitter: Duration,
+  drop_rate: f64,
+  drop_burst: u32,
+}
+
+impl Impairment {
+  fn delays(&self) -> bool {
+    !self.delay.is_zero() || !self.jitter.is_zero()
+  }
+
+  /// Sleep for the configured delay plus a random share of the jitter.
+  async fn wait(&self) {
+    if self.delays() {
+      let jitter = self.jitter.mul_f64(rand::rng().random::<f64>());
+      tokio::time::sleep(self.delay + jitter).await;
+    }
+  }
+
+  /// Whether to drop the next datagram; `burst_left` carries an ongoing loss
+  /// burst between calls.
+  fn drop(&self, burst_left: &mut u32) -> bool {
+    if *burst_left > 0 {
+      *burst_left -= 1;
+      return true;
+    }
+    // start bursts less often so the average loss stays at drop_rate: a
+    // burst of b starts on a fraction p of the datagrams outside one, so
+    // r = p*b / (p*b + 1 - p)
+    let (r, b) = (self.drop_rate, f64::from(self.drop_burst));
+    let p = r / (b * (1.0 - r) + r);
+    if self.drop_rate > 0.0 && rand::rng().random::<f64>() < p {
+      *burst_left = self.drop_burst - 1;
+      return true;
+    }
+    false
+  }
+}
+
+/// Token bucket per remote IP, only touched from the accept loop.
+struct RateLimiter {
+  rate: f64,
+  burst: f64,
+  buckets: HashMap<IpAddr, Bucket>,
+  refused_total: u64,
+}
+
+struct Bucket {
+  tokens: f64,
+  last: Instant,
+  refused: u64,
+}
+
+impl RateLimiter {
+  const MAX_TRACKED: usize = 4096;
+
+  fn new(rate: f64, burst: Option<u32>) -> Self {
+    let burst = burst.map_or(rate.ceil().max(1.0), f64::from);
+    Self { rate, burst, buckets: HashMap::new(), refused_total: 0 }
+  }
+
+  fn allow(&mut self, ip: IpAddr, now: Instant) -> bool {
+    if self.buckets.len() >= Self::MAX_TRACKED {
+      // forget sources whose bucket has refilled completely
+      let (rate, burst) = (self.rate, self.burst);
+      self.buckets.retain(|_, b| b.tokens + now.duration_since(b.last).as_secs_f64() * rate < burst);
+    }
+
+    let bucket = self.buckets.entry(ip).or_insert(Bucket { tokens: self.burst, last: now, refused: 0 });
+    bucket.tokens =
+      (bucket.tokens + now.duration_since(bucket.last).as_secs_f64() * self.rate).min(self.burst);
+    bucket.last = now;
+
+    if bucket.tokens >= 1.0 {
+      bucket.tokens -= 1.0;
+      true
+    } else {
+      bucket.refused += 1;
+      self.refused_total += 1;
+      false
+    }
+  }
+}
+
+/// Counts a live connection for as long as it is held.
+struct LiveConn(Arc<AtomicUsize>);
+
+impl LiveConn {
+  fn new(live: &Arc<AtomicUsize>) -> Self {
+    live.fetch_add(1, Ordering::Relaxed);
+    Self(live.clone())
+  }
+}
+
+impl Drop for LiveConn {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+/// The --cert/--key pairs and --pkcs12 bundles (cert.pem/key.pem if none
+/// are given) followed by the pairs found in --cert-dir, as
+/// `(cert path, key path)`.
+fn cert_paths(opt: &Opt) -> Result<Vec<(PathBuf, PathBuf)>> {
+  anyhow::ensure!(
+    opt.cert.len() == opt.key.len(),
+    "every --cert needs a --key (got {} and {})",
+    opt.cert.len(),
+    opt.key.len()
+  );
+  let mut pairs: Vec<_> = opt.cert.iter().cloned().zip(opt.key.iter().cloned()).collect();
+  pairs.extend(opt.pkcs12.iter().map(|bundle| (bundle.clone(), bundle.clone())));
+  if let Some(dir) = &opt.cert_dir {
+    let mut subdirs = std::fs::read_dir(dir)
+      .with_context(|| format!("read --cert-dir {}", dir.display()))?
+      .map(|entry| Ok(entry?.path()))
+      .collect::<Result<Vec<_>>>()?;
+    subdirs.sort();
+    let found = pairs.len();
+    for sub in subdirs.iter().filter(|p| p.is_dir()) {
+      for (cert, key) in [("fullchain.pem", "privkey.pem"), ("cert.pem", "key.pem")] {
+        if sub.join(cert).is_file() && sub.join(key).is_file() {
+          pairs.push((sub.join(cert), sub.join(key)));
+          break;
+        }
+      }
+    }
+    anyhow::ensure!(pairs.len() > found, "no certificates found in --cert-dir {}", dir.display());
+  } else if pairs.is_empty() {
+    pairs.push(("cert.pem".into(), "key.pem".into()));
+  }
+  Ok(pairs)
+}
+
+/// Load every certificate (or start ACME) into one resolver shared by QUIC
+/// and TCP.
+async fn load_certs(opt: &Opt, ip: IpAddr) -> Result<Arc<dyn ResolvesServerCert>> {
+  if !opt.acme.is_empty() {
+    return acme::start(Acme {
+      domains: opt.acme.clone(),
+      email: opt.acme_email.clone(),
+      directory: opt.acme_directory.clone(),
+      cache: opt.acme_cache.clone(),
+      challenge_addr: SocketAddr::new(ip, opt.acme_port),
+    })
+    .await;
+  }
+  let provider = crypto::provider();
+  let loaded = cert_paths(opt)?
+    .into_iter()
+    .map(|(cert, key)| {
+      let certified = CertifiedKey::from_der(
+        certs::read_certs(&cert, &opt.passwords)?,
+        certs::read_key(&key, &opt.passwords)?,
+        &provider,
+      )
+      .with_context(|| format!("load {} with {}", cert.display(), key.display()))?;
+      info!(cert = %cert.display(), "loaded certificate");
+      Ok((cert.display().to_string(), certified))
+    })
+    .collect::<Result<Vec<_>>>()?;
+  Ok(Arc::new(SniResolver::new(loaded)))
+}
+
+fn make_server_config(
+  opt: &Opt,
+  certs: Arc<dyn ResolvesServerCert>,
+  key_log: Option<Arc<dyn KeyLog>>,
+) -> Result<quinn::ServerConfig> {
   let mut tls = rustls::ServerConfig::builder()
     .with_no_client_auth()
-    .with_single_cert(certs, key)
-    .context("with_single_cert")?;
-  tls.alpn_protocols = vec![ALPN.to_vec()];
+    .with_cert_resolver(certs);
+  tls.alpn_protocols = std::iter::once(opt.mode().alpn().to_vec())
+    .chain(opt.alpn.iter().map(|alpn| alpn.as_bytes().to_vec()))
+    .collect();
+  if let Some(key_log) = key_log {
+    tls.key_log = key_log;
+  }
+  if opt.zero_rtt {
+    // quinn takes early data only with the limit at u32::MAX
+    tls.max_early_data_size = u32::MAX;
+  }
 
-  let mut server_config =
-    quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?));
+  let mut crypto: Arc<dyn quinn::crypto::ServerConfig> = Arc::new(QuicServerConfig::try_from(tls)?);
+  if let Some(count) = opt.cids.cid_count {
+    crypto = Arc::new(CidCount::new(crypto, count));
+  }
+  // always sniffed: every connection logs its cipher suite
+  crypto = Arc::new(Sniffing::new(crypto));
+  let mut server_config = quinn::ServerConfig::with_crypto(crypto);
 
   // datagrams tuning
   let transport: &mut TransportConfig = Arc::get_mut(&mut server_config.transport).unwrap();
   transport.datagram_receive_buffer_size(Some(65_536));
   transport.datagram_send_buffer_size(2 * 1024 * 1024);
+  opt.flow.apply(transport);
+
+  server_config.migration(!opt.disable_migration);
+  match opt.preferred_addr {
+    Some(SocketAddr::V4(addr)) => server_config.preferred_address_v4(Some(addr)),
+    Some(SocketAddr::V6(addr)) => server_config.preferred_address_v6(Some(addr)),
+    None => &mut server_config,
+  };
 
   Ok(server_config)
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-  let _ = rustls::crypto::ring::default_provider().install_default();
+/// Plain TLS config for the TCP companion listener.
+fn make_tcp_tls_config(certs: Arc<dyn ResolvesServerCert>, key_log: Option<Arc<dyn KeyLog>>) -> rustls::ServerConfig {
+  let mut tls = rustls::ServerConfig::builder()
+    .with_no_client_auth()
+    .with_cert_resolver(certs);
+  if let Some(key_log) = key_log {
+    tls.key_log = key_log;
+  }
+  tls
+}
 
-  let opt = Opt::parse();
-  let addr: SocketAddr = format!("{}:{}", opt.host, opt.port).parse()?;
+fn main() -> Result<()> {
+  let args = config::args(&Opt::command())?;
+  let opt = Opt::parse_from(&args);
+  if opt.stop {
+    let pidfile = opt.pidfile.as_deref().expect("--stop requires --pidfile");
+    // the server drains for up to --drain-timeout after the signal
+    return daemon::stop(pidfile, Duration::from_secs(opt.drain_timeout) + STOP_GRACE);
+  }
+  // fork before the runtime starts its threads
+  let (_pidfile, ready) = match &opt.pidfile {
+    Some(path) if opt.daemonize => {
+      let (pidfile, ready) = daemon::daemonize(path, opt.log_file.as_deref())?;
+      (Some(pidfile), Some(ready))
+    }
+    Some(path) => (Some(Pidfile::create(path)?), None),
+    None => (None, None),
+  };
+  tokio::runtime::Runtime::new()?.block_on(serve(opt, args, ready))
+}
+
+/// `args` is the command line `opt` was parsed from, kept to tell what a
+/// reload changes.
+async fn serve(opt: Opt, args: Vec<OsString>, ready: Option<Ready>) -> Result<()> {
+  let log_level = logging::init_reloadable(opt.log_format, opt.log_level.as_deref())?;
+  crypto::install(opt.crypto_provider)?;
+  let addrs = if opt.listen.is_empty() {
+    let ip: IpAddr = opt.host.parse().with_context(|| format!("parse --host {:?}", opt.host))?;
+    vec![SocketAddr::new(ip, opt.port)]
+  } else {
+    opt.listen.clone()
+  };
+  // ACME's challenge listener takes the first address
+  let ip = addrs[0].ip();
 
-  let server_config = make_server_config(opt.cert, opt.key)?;
-  let endpoint = Endpoint::server(server_config, addr)?;
-  println!("QUIC echo server listening on {} (UDP)", endpoint.local_addr()?);
+  opt.flow.check()?;
+  opt.cids.check()?;
+  anyhow::ensure!(
+    opt.preferred_addr.is_none() || (opt.workers == 1 && addrs.len() == 1),
+    "--preferred-addr needs a single endpoint; drop --workers and extra --listen addresses"
+  );
+  if let Some(preferred) = opt.preferred_addr {
+    anyhow::ensure!(!preferred.ip().is_unspecified() && preferred.port() != 0, "--preferred-addr must be a concrete ip:port");
+  }
+  let key_log = opt.keylog.as_deref().map(keylog::open).transpose()?;
+  let certs = load_certs(&opt, ip).await?;
+  let server_config = make_server_config(&opt, certs.clone(), key_log.clone())?;
+  let capture = opt.pcap.as_deref().map(Pcap::create).transpose()?;
+  let access_log = opt.access_log.as_deref().map(AccessLog::open).transpose()?.map(Arc::new);
+  let geoip = (!opt.geoip.is_empty()).then(|| GeoIp::open(&opt.geoip)).transpose()?;
+  let acl = if opt.allow.is_empty() && opt.deny.is_empty() && opt.acl_file.is_none() {
+    None
+  } else {
+    let rules = Rules { allow: opt.allow.clone(), deny: opt.deny.clone() };
+    Some(Acl::new(rules, opt.acl_file.clone())?)
+  };
+  let offload = Arc::new(OffloadStats::default());
+  let activated = activated_udp().context("take the socket-activated sockets")?;
+  let sockets = if activated.is_empty() {
+    addrs
+      .iter()
+      .flat_map(|&addr| (0..opt.workers).map(move |_| addr))
+      .map(|addr| {
+        if opt.workers > 1 {
+          bind_udp_reuseport(addr).with_context(|| format!("bind {addr} with SO_REUSEPORT"))
+        } else {
+          std::net::UdpSocket::bind(addr).with_context(|| format!("bind {addr}"))
+        }
+      })
+      .collect::<Result<Vec<_>>>()?
+  } else {
+    anyhow::ensure!(opt.workers == 1, "a socket-activated server gets its sockets from systemd; drop --workers");
+    anyhow::ensure!(
+      opt.preferred_addr.is_none() || activated.len() == 1,
+      "--preferred-addr needs a single endpoint; the unit passed {} sockets",
+      activated.len()
+    );
+    info!(sockets = activated.len(), "socket-activated: serving the inherited sockets, ignoring --host/--port/--listen");
+    activated
+  };
+  let endpoints = sockets
+    .into_iter()
+    .map(|socket| {
+      let mut socket = opt.offload.wrap(socket, capture.as_ref(), &offload)?;
+      if let Some(preferred) = opt.preferred_addr {
+        let second = std::net::UdpSocket::bind(preferred).with_context(|| format!("bind --preferred-addr {preferred}"))?;
+        socket = Dual::new(socket, opt.offload.wrap(second, capture.as_ref(), &offload)?)?;
+        info!(%preferred, "advertising a preferred address");
+      }
+      let mut config = EndpointConfig::default();
+      opt.cids.apply(&mut config);
+      Ok(Endpoint::new_with_abstract_socket(config, Some(server_config.clone()), socket, Arc::new(TokioRuntime))?)
+    })
+    .collect::<Result<Vec<_>>>()?;
+  let mut listening = endpoints.iter().map(Endpoint::local_addr).collect::<std::io::Result<Vec<_>>>()?;
+  listening.dedup();
+  let listening = listening.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ");
+  info!(workers = endpoints.len(), "QUIC echo server listening on {listening} (UDP)");
+  if let Some(ready) = ready {
+    ready.notify();
+  }
+  if opt.workers > 1 && !opt.disable_migration {
+    warn!("migrating clients may hit another worker's endpoint; consider --disable-migration");
+  }
 
-  while let Some(incoming) = endpoint.accept().await {
+  // one accept loop, fed by every endpoint
+  let (incoming_tx, mut incoming_rx) = tokio::sync::mpsc::channel(64);
+  for endpoint in &endpoints {
+    let (endpoint, tx) = (endpoint.clone(), incoming_tx.clone());
+    let local = endpoint.local_addr()?;
     tokio::spawn(async move {
-      if let Err(e) = handle_incoming(incoming).await {
-        eprintln!("connection failed: {e}");
+      while let Some(incoming) = endpoint.accept().await {
+        if tx.send((local, incoming)).await.is_err() {
+          break;
+        }
       }
     });
   }
+  drop(incoming_tx);
+  if opt.disable_migration {
+    info!("client migration disabled");
+  }
+  if let Some(port) = opt.tcp_port {
+    let tls = Arc::new(make_tcp_tls_config(certs.clone(), key_log.clone()));
+    let mut ips: Vec<_> = addrs.iter().map(SocketAddr::ip).collect();
+    ips.sort_unstable();
+    ips.dedup();
+    for ip in ips {
+      let listener = tokio::net::TcpListener::bind(SocketAddr::new(ip, port))
+        .await
+        .with_context(|| format!("bind TCP port {port} on {ip}"))?;
+      let tls = tls.clone();
+      tokio::spawn(async move {
+        if let Err(e) = tcp::serve(listener, tls).await {
+          warn!("TCP echo failed: {e:#}");
+        }
+      });
+    }
+  }
+  let mode = opt.mode();
+  match mode {
+    Mode::Echo => {}
+    Mode::H3 => info!("HTTP/3 mode: POST {} echoes the request body", http3::ECHO_PATH),
+    Mode::Doq => info!("DNS-over-QUIC mode: answering queries with stub records"),
+    Mode::Masque => warn!("MASQUE mode: relaying CONNECT-UDP to any target"),
+    Mode::Perf => info!("perf mode: serving the quinn perf protocol"),
+    Mode::Duplex => info!("duplex mode: sending and receiving at the same time on every stream"),
+    Mode::File => match &opt.store_dir {
+      Some(dir) => info!("file mode: echoing files, storing uploads in {}", dir.display()),
+      None => info!("file mode: echoing files (no --store-dir, storing disabled)"),
+    },
+  }
+  anyhow::ensure!(opt.alpn.is_empty() || mode == Mode::Echo, "--alpn applies to --mode echo");
+  anyhow::ensure!(
+    opt.auth_token.is_none() || mode == Mode::Echo,
+    "--auth-token applies to --mode echo; the other modes speak their own protocols"
+  );
+  if opt.auth_token.is_some() {
+    info!("clients must send the auth token within {AUTH_TIMEOUT:?}");
+  }
+  let tuning = Tuning::new(&opt, mode)?;
+  let reaper = match opt.reap_idle {
+    Some(secs) => {
+      anyhow::ensure!(mode == Mode::Echo, "--reap-idle applies to --mode echo");
+      let idle = Duration::try_from_secs_f64(secs).ok().filter(|idle| !idle.is_zero());
+      let idle = idle.context("--reap-idle must be a positive number of seconds")?;
+      info!(?idle, "closing connections idle at the application level");
+      let reaper = Reaper::new(idle);
+      reaper.clone().run();
+      Some(reaper)
+    }
+    None => None,
+  };
+  let impairment = tuning.impairment;
+  if impairment.delays() {
+    if mode == Mode::Echo {
+      info!(delay = ?impairment.delay, jitter = ?impairment.jitter, "delaying every echo");
+    } else {
+      warn!("--echo-delay/--echo-jitter only apply to --mode echo");
+    }
+  }
+  if impairment.drop_rate > 0.0 {
+    if mode == Mode::Echo {
+      info!(rate = impairment.drop_rate, burst = impairment.drop_burst, "dropping datagrams");
+    } else {
+      warn!("--drop-rate only applies to --mode echo");
+    }
+  }
+  if let Some(rate) = opt.read_rate {
+    if mode == Mode::Echo {
+      info!(rate, "reading each stream at most {rate} B/s");
+    } else {
+      warn!("--read-rate only applies to --mode echo");
+    }
+  }
+  if opt.transform != Transform::Echo {
+    if mode == Mode::Echo {
+      info!(transform = ?opt.transform, "transforming every response");
+    } else {
+      warn!("--transform only applies to --mode echo");
+    }
+  }
+  let forward_to = match &opt.forward_to {
+    Some(target) => {
+      anyhow::ensure!(mode == Mode::Echo, "--forward-to only applies to --mode echo");
+      let addr = tokio::net::lookup_host(target)
+        .await
+        .with_context(|| format!("resolve --forward-to {target:?}"))?
+        .next()
+        .with_context(|| format!("no address for --forward-to {target:?}"))?;
+      info!(%addr, "forwarding datagrams to {target}");
+      Some(addr)
+    }
+    None => None,
+  };
+
+  let push_pacing = match opt.push_dgrams {
+    Some(rate) => {
+      anyhow::ensure!(mode == Mode::Echo, "--push-dgrams only applies to --mode echo");
+      anyhow::ensure!(rate > 0.0, "--push-dgrams must be positive");
+      anyhow::ensure!(opt.push_size >= push::HEADER, "--push-size must be at least {} bytes", push::HEADER);
+      let pacing = match (opt.spacing, opt.burst) {
+        (None, None) => Pacing::Rate(rate),
+        (spacing, burst) => {
+          let burst = burst.unwrap_or(1);
+          let spacing = spacing.map_or_else(|| Duration::from_secs_f64(f64::from(burst) / rate), Duration::from_micros);
+          Pacing::Spaced { spacing, burst }
+        }
+      };
+      match pacing {
+        Pacing::Rate(rate) => info!(rate, size = opt.push_size, "pushing datagrams to every client"),
+        Pacing::Spaced { spacing, burst } => {
+          info!(rate = pacing.rate(), burst, ?spacing, size = opt.push_size, "pushing paced datagram bursts to every client")
+        }
+      }
+      Some(pacing)
+    }
+    None => None,
+  };
+  if let Some(kind) = opt.push_stream {
+    anyhow::ensure!(mode == Mode::Echo, "--push-stream only applies to --mode echo");
+    info!(kind = kind.name(), size = opt.push_stream_size, "opening a stream to every client");
+  }
+
+  let settings = Arc::new(ConnSettings {
+    mode,
+    log_path_changes: opt.log_path_changes,
+    tuning: Arc::new(RwLock::new(tuning)),
+    chunk_budget: opt.chunk_budget as usize,
+    read_rate: opt.read_rate,
+    transform: opt.transform,
+    store_dir: opt.store_dir.clone().map(Arc::new),
+    forward_to,
+    push: push_pacing.map(|pacing| (pacing, opt.push_size)),
+    push_stream: opt.push_stream.map(|kind| (kind, opt.push_stream_size)),
+    access_log,
+    auth_token: opt.auth_token.clone(),
+    reaper,
+    tls_info: opt.tls_info,
+  });
+  let live = Arc::new(AtomicUsize::new(0));
+  let mut limiter = opt.conn_rate.map(|rate| RateLimiter::new(rate, opt.conn_burst));
+  let mut reloads = config::reloads(config::file());
+  let mut reloading = true;
+  let mut reload = Reload {
+    tuning: settings.tuning.clone(),
+    opt,
+    args,
+    log_level,
+    endpoints: endpoints.clone(),
+    certs,
+    key_log,
+  };
+  if let Some(acl) = &acl {
+    acl.clone().watch();
+  }
+  let mut acl_refused = 0u64;
+  let shutdown = shutdown_signal();
+  tokio::pin!(shutdown);
+  loop {
+    let (local, incoming) = tokio::select! {
+      incoming = incoming_rx.recv() => match incoming {
+        Some(incoming) => incoming,
+        None => break,
+      },
+      signal = &mut shutdown => {
+        info!("{signal} received, shutting down");
+        break;
+      }
+      reason = reloads.recv(), if reloading => {
+        match reason {
+          Some(reason) => match reload.run(reason) {
+            Ok(applied) => {
+              if applied.iter().any(|option| matches!(option.as_str(), "conn-rate" | "conn-burst")) {
+                limiter = reload.opt.conn_rate.map(|rate| RateLimiter::new(rate, reload.opt.conn_burst));
+              }
+            }
+            Err(e) => warn!("{reason}: keeping the running configuration: {e:#}"),
+          },
+          None => reloading = false,
+        }
+        continue;
+      }
+    };
+
+    if let Some(acl) = &acl
+      && let Err(refusal) = acl.check(incoming.remote_address().ip())
+    {
+      acl_refused += 1;
+      warn!(remote = %incoming.remote_address(), refused_total = acl_refused, "refused connection: {refusal}");
+      incoming.refuse();
+      continue;
+    }
+
+    if let Some(limiter) = limiter.as_mut() {
+      let ip = incoming.remote_address().ip();
+      if !limiter.allow(ip, Instant::now()) {
+        warn!(
+          remote = %incoming.remote_address(),
+          refused_ip = limiter.buckets[&ip].refused,
+          refused_total = limiter.refused_total,
+          "refused connection: rate limited"
+        );
+        incoming.refuse();
+        continue;
+      }
+    }
+
+    if let Some(max) = reload.opt.max_connections
+      && live.load(Ordering::Relaxed) >= max
+    {
+      warn!(remote = %incoming.remote_address(), "refused connection: {max} connections live");
+      incoming.refuse();
+      continue;
+    }
+
+    let guard = LiveConn::new(&live);
+    let settings = settings.clone();
+    let origin = geoip.as_ref().map(|geoip| geoip.lookup(incoming.remote_address().ip())).unwrap_or_default();
+    let span = info_span!(
+      "conn",
+      remote = %incoming.remote_address(),
+      country = origin.country.as_deref(),
+      asn = origin.asn,
+      id = tracing::field::Empty,
+      listen = tracing::field::Empty
+    );
+    if addrs.len() > 1 {
+      span.record("listen", tracing::field::display(local));
+    }
+    tokio::spawn(
+      async move {
+        let _guard = guard;
+        if let Err(e) = handle_incoming(incoming, &settings, origin).await {
+          warn!("connection failed: {e}");
+        }
+      }
+      .instrument(span),
+    );
+  }
+
+  info!(
+    live = live.load(Ordering::Relaxed),
+    code = reload.opt.shutdown_code,
+    reason = %reload.opt.shutdown_reason,
+    "closing live connections"
+  );
+  for endpoint in &endpoints {
+    endpoint.close(VarInt::from_u32(reload.opt.shutdown_code), reload.opt.shutdown_reason.as_bytes());
+  }
+  let drain = Duration::from_secs(reload.opt.drain_timeout);
+  let idle = async {
+    for endpoint in &endpoints {
+      endpoint.wait_idle().await;
+    }
+  };
+  if tokio::time::timeout(drain, idle).await.is_err() {
+    warn!("connections did not drain within {drain:?}, exiting anyway");
+  }
+  offload.log();
   Ok(())
 }
 
-async fn handle_incoming(incoming: Incoming) -> Result<()> {
+/// Options a reload applies to the running server; the others take effect
+/// after a restart.
+const RELOADABLE: &[&str] = &[
+  "max-connections",
+  "conn-rate",
+  "conn-burst",
+  "max-bytes-per-conn",
+  "echo-delay",
+  "echo-jitter",
+  "drop-rate",
+  "drop-burst",
+  "alpn",
+  "log-level",
+];
+
+/// What a configuration reload changes, owned by the accept loop.
+struct Reload {
+  /// The running options: the startup ones with reloaded changes applied
+  opt: Opt,
+  /// The command line (config file included) last read
+  args: Vec<OsString>,
+  log_level: LogLevel,
+  tuning: Arc<RwLock<Tuning>>,
+  endpoints: Vec<Endpoint>,
+  certs: Arc<dyn ResolvesServerCert>,
+  key_log: Option<Arc<dyn KeyLog>>,
+}
+
+impl Reload {
+  /// Read the command line and config file again, log every option that
+  /// changed and apply those in [`RELOADABLE`]. Nothing is applied if the
+  /// new options are invalid. Returns the applied options' names.
+  fn run(&mut self, reason: &str) -> Result<Vec<String>> {
+    let cmd = Opt::command();
+    let args = config::args(&cmd)?;
+    let new = Opt::try_parse_from(&args)?;
+    let changes = config::diff(&cmd, &self.args, &args)?;
+    if changes.is_empty() {
+      info!("{reason}: configuration unchanged");
+      return Ok(Vec::new());
+    }
+    let mode = self.opt.mode();
+    let tuning = Tuning::new(&new, mode)?;
+    anyhow::ensure!(new.alpn.is_empty() || mode == Mode::Echo, "--alpn applies to --mode echo");
+    let changed = |option: &str| changes.iter().any(|change| change.option == option);
+    let server_config = if changed("alpn") {
+      let running = std::mem::replace(&mut self.opt.alpn, new.alpn.clone());
+      let config = make_server_config(&self.opt, self.certs.clone(), self.key_log.clone());
+      self.opt.alpn = running;
+      Some(config.context("rebuild the server config")?)
+    } else {
+      None
+    };
+    if changed("log-level") {
+      self.log_level.set(new.log_level.as_deref())?;
+    }
+    if let Some(config) = server_config {
+      for endpoint in &self.endpoints {
+        endpoint.set_server_config(Some(config.clone()));
+      }
+    }
+
+    let opt = &mut self.opt;
+    opt.max_connections = new.max_connections;
+    (opt.conn_rate, opt.conn_burst) = (new.conn_rate, new.conn_burst);
+    opt.max_bytes_per_conn = new.max_bytes_per_conn;
+    (opt.echo_delay, opt.echo_jitter) = (new.echo_delay, new.echo_jitter);
+    (opt.drop_rate, opt.drop_burst) = (new.drop_rate, new.drop_burst);
+    (opt.alpn, opt.log_level) = (new.alpn, new.log_level);
+    *self.tuning.write().unwrap() = tuning;
+    self.args = args;
+
+    let mut applied = Vec::new();
+    for change in changes {
+      if RELOADABLE.contains(&change.option.as_str()) {
+        info!("{reason}: {change}");
+        applied.push(change.option);
+      } else {
+        warn!("{reason}: {change} (takes effect after a restart)");
+      }
+    }
+    Ok(applied)
+  }
+}
+
+/// Resolves once SIGINT or (on Unix) SIGTERM is received.
+async fn shutdown_signal() -> &'static str {
+  #[cfg(unix)]
+  {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+      Ok(mut term) => tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = term.recv() => "SIGTERM",
+      },
+      Err(e) => {
+        warn!("cannot install SIGTERM handler: {e}");
+        let _ = tokio::signal::ctrl_c().await;
+        "SIGINT"
+      }
+    }
+  }
+  #[cfg(not(unix))]
+  {
+    let _ = tokio::signal::ctrl_c().await;
+    "Ctrl-C"
+  }
+}
+
+async fn handle_incoming(incoming: Incoming, settings: &ConnSettings, origin: Origin) -> Result<()> {
+  let started = Instant::now();
   let conn = incoming.await?;
+  tracing::Span::current().record("id", conn.stable_id());
 
-  let proto = conn
-    .handshake_data()
-    .and_then(|x| x.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+  let handshake = handshake::data(&conn);
+  let proto = handshake
+    .as_ref()
     .and_then(|hd| hd.protocol.clone())
-    .map(|p| String::from_utf8_lossy(&p).into_owned())
-    .unwrap_or_else(|| "<none>".into());
-  println!("ALPN: {proto} from {}", conn.remote_address());
+    .map(|p| String::from_utf8_lossy(&p).into_owned());
+  let sni = handshake.and_then(|hd| hd.server_name);
+  let tls = handshake::info(&conn);
+  info!(
+    alpn = proto.as_deref().unwrap_or("<none>"),
+    sni = sni.as_deref().unwrap_or("<none>"),
+    suite = tls.as_ref().and_then(|tls| tls.cipher_suite).map(|suite| format!("{suite:?}")),
+    as_org = origin.as_org.as_deref(),
+    "connection established"
+  );
+  if settings.tls_info
+    && let Some(tls) = tls
+  {
+    info!(%tls, "tls session");
+  }
 
-  // datagram echo loop
-  let dgram_conn = conn.clone();
-  tokio::spawn(async move {
-    while let Ok(data) = dgram_conn.read_datagram().await {
-      if let Err(e) = dgram_conn.send_datagram(data) {
-        eprintln!("datagram send failed: {e}");
+  let counters = Arc::new(Counters::default());
+  let closing = conn.clone();
+  let (access_log, echo_counters) = (settings.access_log.clone(), (settings.mode == Mode::Echo).then(|| counters.clone()));
+  let reaper = settings.reaper.clone();
+  if let Some(reaper) = &reaper {
+    reaper.track(&conn, counters.clone());
+  }
+  tokio::spawn(
+    async move {
+      let reason = closing.closed().await;
+      if let Some(reaper) = reaper {
+        reaper.forget(&closing);
+      }
+      let stats = closing.stats();
+      let (close_code, close_reason) = match &reason {
+        ConnectionError::ApplicationClosed(close) => {
+          (Some(close.error_code.into_inner()), Some(String::from_utf8_lossy(&close.reason).into_owned()))
+        }
+        _ => (None, None),
+      };
+      info!(
+        %reason,
+        close_code,
+        close_reason,
+        "connection closed"
+      );
+      LossReport::new(&stats).log();
+      CidActivity::new(&stats).log();
+      if let Some(log) = access_log {
+        let record = Record::new(&closing, sni, proto, origin, started.elapsed(), echo_counters.as_deref(), &reason);
+        if let Err(e) = log.write(&record) {
+          warn!("access log write failed: {e:#}");
+        }
       }
     }
-  });
+    .in_current_span(),
+  );
+
+  if settings.log_path_changes {
+    tokio::spawn(watch_path_changes(conn.clone()).in_current_span());
+  }
+
+  match settings.mode {
+    Mode::Echo => {}
+    Mode::H3 => return http3::serve(conn).await,
+    Mode::Doq => return doq::serve(conn).await,
+    Mode::Masque => return masque::serve(conn).await,
+    Mode::Perf => return perf::serve(conn).await,
+    Mode::File => return transfer::serve(conn, settings.store_dir.clone()).await,
+    Mode::Duplex => return duplex::serve(conn).await,
+  }
+
+  if let Some(token) = &settings.auth_token {
+    auth::check(&conn, token, AUTH_TIMEOUT).await?;
+    debug!("client authenticated");
+  }
+
+  // datagram echo loop, or relay with --forward-to
+  let (dgram_conn, dgram_counters) = (conn.clone(), counters.clone());
+  let (tuning, forward_to, transform) = (settings.tuning.clone(), settings.forward_to, settings.transform);
+  let dgram_tuning = tuning.clone();
+  tokio::spawn(
+    async move {
+      if let Some(target) = forward_to {
+        if let Err(e) = tunnel::relay(&dgram_conn, target).await {
+          warn!("datagram relay to {target} failed: {e:#}");
+        }
+        return;
+      }
+      let (mut burst_left, mut dropped) = (0, 0u64);
+      while let Ok(data) = dgram_conn.read_datagram().await {
+        dgram_counters.datagram_in();
+        let Tuning { impairment, byte_quota: quota } = *dgram_tuning.read().unwrap();
+        if impairment.drop(&mut burst_left) {
+          dropped += 1;
+          debug!(dropped, "datagram dropped");
+          continue;
+        }
+        let data = transform.apply(data);
+        let len = data.len();
+        if impairment.delays() {
+          // delay each datagram on its own so one doesn't hold up the next
+          let (conn, counters) = (dgram_conn.clone(), dgram_counters.clone());
+          tokio::spawn(
+            async move {
+              impairment.wait().await;
+              match conn.send_datagram(data) {
+                Ok(()) => {
+                  counters.datagram_out(len);
+                  enforce_quota(&conn, &counters, quota);
+                }
+                Err(e) => warn!("datagram send failed: {e}"),
+              }
+            }
+            .in_current_span(),
+          );
+        } else {
+          match dgram_conn.send_datagram(data) {
+            Ok(()) => {
+              dgram_counters.datagram_out(len);
+              enforce_quota(&dgram_conn, &dgram_counters, quota);
+            }
+            Err(e) => warn!("datagram send failed: {e}"),
+          }
+        }
+      }
+    }
+    .in_current_span(),
+  );
+
+  if let Some((pacing, size)) = settings.push {
+    let push_conn = conn.clone();
+    tokio::spawn(
+      async move {
+        match push::send(&push_conn, pacing, size).await {
+          Ok(pushed) if pushed.skipped > 0 => {
+            warn!(sent = pushed.sent, skipped_bursts = pushed.skipped, "datagram push stopped; it fell behind --spacing")
+          }
+          Ok(pushed) => info!(sent = pushed.sent, "datagram push stopped"),
+          Err(e) => warn!("datagram push failed: {e:#}"),
+        }
+      }
+      .in_current_span(),
+    );
+  }
+
+  if let Some((kind, size)) = settings.push_stream {
+    let push_conn = conn.clone();
+    tokio::spawn(
+      async move {
+        match push::open_stream(&push_conn, kind, size).await {
+          Ok(took) if kind == StreamKind::Bi => info!(size, ?took, "pushed stream echoed back and verified"),
+          Ok(took) => info!(size, ?took, "pushed stream acknowledged"),
+          Err(e) => warn!(kind = kind.name(), "pushed stream failed: {e:#}"),
+        }
+      }
+      .in_current_span(),
+    );
+  }
+
+  // unidirectional streams aren't echoed; drain them so the client gets its stream credit back
+  let (uni_conn, uni_counters) = (conn.clone(), counters.clone());
+  tokio::spawn(
+    async move {
+      while let Ok(mut recv) = uni_conn.accept_uni().await {
+        uni_counters.uni_stream();
+        let counters = uni_counters.clone();
+        tokio::spawn(async move {
+          while let Ok(Some(chunk)) = recv.read_chunk(usize::MAX, false).await {
+            counters.read(chunk.bytes.len());
+          }
+        });
+      }
+    }
+    .in_current_span(),
+  );
 
   // stream echo loop
   loop {
     let (mut send, mut recv) = match conn.accept_bi().await {
       Ok(s) => s,
-      Err(quinn::ConnectionError::ApplicationClosed { .. }) => return Ok(()),
+      Err(ConnectionError::ApplicationClosed { .. } | ConnectionError::LocallyClosed) => {
+        return Ok(())
+      }
       Err(e) => return Err(e.into()),
     };
+    counters.stream();
 
-    tokio::spawn(async move {
-      let mut buf = [0u8; 16 * 1024];
-      loop {
-        match tokio::io::AsyncReadExt::read(&mut recv, &mut buf).await {
-          Ok(0) => {
-            let _ = send.finish();
-            break;
+    let (chunk_budget, read_rate, counters, conn) = (settings.chunk_budget, settings.read_rate, counters.clone(), conn.clone());
+    let tuning = tuning.clone();
+    tokio::spawn(
+      async move {
+        let mut pacer = read_rate.map(Pacer::new);
+        if !transform.per_chunk() {
+          // the whole stream is needed before the answer can be computed
+          match pace::read_to_end(&mut recv, transform::STREAM_LIMIT, pacer.as_mut()).await {
+            Ok(data) => {
+              counters.read(data.len());
+              let Tuning { impairment, byte_quota: quota } = *tuning.read().unwrap();
+              impairment.wait().await;
+              let echo = transform.apply(data.into());
+              let len = echo.len();
+              match send.write_chunk(echo).await {
+                Ok(()) => {
+                  counters.wrote(len);
+                  drop(send.finish());
+                  enforce_quota(&conn, &counters, quota);
+                }
+                Err(WriteError::Stopped(code)) => mirror_stop(&mut recv, code),
+                Err(_) => {}
+              }
+            }
+            Err(ReadToEndError::Read(ReadError::Reset(code))) => mirror_reset(&mut send, code),
+            Err(_) => {}
           }
-          Ok(n) => {
-            if tokio::io::AsyncWriteExt::write_all(&mut send, &buf[..n])
-              .await
-              .is_err()
-            {
+          return;
+        }
+        let mut chunks = vec![Bytes::new(); chunk_budget];
+        loop {
+          match pace::read_chunks(&mut recv, &mut chunks, pacer.as_mut()).await {
+            Ok(None) => {
+              let _ = send.finish();
+              break;
+            }
+            Ok(Some(n)) => {
+              counters.read(chunks[..n].iter().map(Bytes::len).sum());
+              let Tuning { impairment, byte_quota: quota } = *tuning.read().unwrap();
+              impairment.wait().await;
+              for chunk in &mut chunks[..n] {
+                *chunk = transform.apply(std::mem::take(chunk));
+              }
+              let len = chunks[..n].iter().map(Bytes::len).sum();
+              match send.write_all_chunks(&mut chunks[..n]).await {
+                Ok(_) => {
+                  counters.wrote(len);
+                  if enforce_quota(&conn, &counters, quota) {
+                    break;
+                  }
+                }
+                Err(WriteError::Stopped(code)) => {
+                  mirror_stop(&mut recv, code);
+                  break;
+                }
+                Err(_) => break,
+              }
+            }
+            Err(ReadError::Reset(code)) => {
+              mirror_reset(&mut send, code);
               break;
             }
+            Err(_) => break,
           }
-          Err(_) => break,
         }
       }
-    });
+      .in_current_span(),
+    );
+  }
+}
+
+/// Close `conn` with [`QUOTA_EXCEEDED`] once it has been echoed at least
+/// `quota` bytes; true if it is over.
+fn enforce_quota(conn: &quinn::Connection, counters: &Counters, quota: Option<u64>) -> bool {
+  let Some(quota) = quota else {
+    return false;
+  };
+  let echoed = counters.echoed();
+  if echoed < quota {
+    return false;
+  }
+  if conn.close_reason().is_none() {
+    warn!(echoed, quota, "closing connection: byte quota exceeded");
+    conn.close(VarInt::from_u32(QUOTA_EXCEEDED), b"byte quota exceeded");
+  }
+  true
+}
+
+/// The client reset its send side: reset the echo with the same code, so
+/// the client can check which code arrived.
+fn mirror_reset(send: &mut SendStream, code: VarInt) {
+  info!(%code, "stream reset by the client, resetting the echo");
+  let _ = send.reset(code);
+}
+
+/// The client sent STOP_SENDING: ask it to stop sending too, with the same
+/// code.
+fn mirror_stop(recv: &mut RecvStream, code: VarInt) {
+  info!(%code, "STOP_SENDING from the client, stopping its send side");
+  let _ = recv.stop(code);
+}
+
+/// quinn has no path-change events, so poll the remote address. A migration
+/// that fails validation is rolled back to the previous path by quinn after
+/// ~3 PTO, which shows up here as the old address reappearing.
+async fn watch_path_changes(conn: quinn::Connection) {
+  let mut current = conn.remote_address();
+  // (old, new, validation deadline)
+  let mut pending: Option<(SocketAddr, SocketAddr, Instant)> = None;
+  let mut tick = tokio::time::interval(PATH_POLL_INTERVAL);
+
+  loop {
+    tokio::select! {
+      _ = conn.closed() => return,
+      _ = tick.tick() => {}
+    }
+
+    let addr = conn.remote_address();
+    if let Some((old, new, deadline)) = pending {
+      if addr == old {
+        info!(%old, %new, "path validation failed, rolled back");
+        pending = None;
+        current = addr;
+        continue;
+      }
+      if addr == new && Instant::now() >= deadline {
+        info!(%new, "path validated");
+        pending = None;
+      }
+    }
+
+    if addr != current {
+      info!(old = %current, new = %addr, "path change");
+      let window = (conn.rtt() * 9 + Duration::from_millis(75)).max(Duration::from_millis(500));
+      pending = Some((current, addr, Instant::now() + window));
+      current = addr;
+    }
   }
 }
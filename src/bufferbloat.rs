@@ -0,0 +1,193 @@
+//! Latency under load (`--loaded-latency`): ping an idle path, then ping it
+//! again while bulk echo streams keep it saturated in both directions, and
+//! score the loaded RTT in round trips per minute (RPM), like Apple's
+//! networkQuality responsiveness test.
+//!
+//! The pings run on their own connection, so they queue behind the load in
+//! the network (the bufferbloat being measured) but not behind the load's
+//! own stream data in the sender.
+
+use crate::tcp;
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use quinn::Connection;
+use std::{
+  fmt,
+  sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+  },
+  time::Duration,
+};
+use tokio::time::Instant;
+
+/// Pings on the idle path
+pub const IDLE_PINGS: usize = 20;
+/// Pause between pings
+const PING_INTERVAL: Duration = Duration::from_millis(100);
+/// Bulk streams keeping the path busy
+const LOAD_STREAMS: usize = 4;
+const LOAD_CHUNK: usize = 64 * 1024;
+/// Longest start of the load left out of the loaded RTT, while queues fill
+const MAX_RAMP: Duration = Duration::from_secs(2);
+
+/// How responsive a path stays under load, graded like networkQuality.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Grade {
+  /// Under 300 RPM
+  Low,
+  /// 300 to 1000 RPM
+  Medium,
+  /// Over 1000 RPM
+  High,
+}
+
+impl fmt::Display for Grade {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Grade::Low => "low",
+      Grade::Medium => "medium",
+      Grade::High => "high",
+    })
+  }
+}
+
+/// Idle and loaded round trips, each sorted.
+#[derive(Clone, Debug)]
+pub struct Report {
+  pub idle: Vec<Duration>,
+  pub loaded: Vec<Duration>,
+  /// Bytes the load streams got back
+  pub load_bytes: u64,
+  pub load_time: Duration,
+}
+
+impl Report {
+  pub fn idle_median(&self) -> Duration {
+    median(&self.idle)
+  }
+
+  pub fn loaded_median(&self) -> Duration {
+    median(&self.loaded)
+  }
+
+  /// Round trips per minute at the loaded median RTT.
+  pub fn rpm(&self) -> f64 {
+    60.0 / self.loaded_median().as_secs_f64().max(1e-6)
+  }
+
+  pub fn grade(&self) -> Grade {
+    match self.rpm() {
+      rpm if rpm < 300.0 => Grade::Low,
+      rpm if rpm <= 1000.0 => Grade::Medium,
+      _ => Grade::High,
+    }
+  }
+
+  /// Echo throughput of the load, per direction.
+  pub fn load_mbps(&self) -> f64 {
+    self.load_bytes as f64 * 8.0 / self.load_time.as_secs_f64() / 1e6
+  }
+}
+
+impl fmt::Display for Report {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    let row = |f: &mut fmt::Formatter<'_>, name: &str, rtts: &[Duration]| {
+      writeln!(
+        f,
+        "  {name:<8} {:>10.2} ms {:>10.2} ms {:>10.2} ms {:>7}",
+        ms(median(rtts)),
+        ms(percentile(rtts, 0.9)),
+        ms(rtts[rtts.len() - 1]),
+        rtts.len()
+      )
+    };
+    writeln!(f, "  {:<8} {:>13} {:>13} {:>13} {:>7}", "", "rtt median", "rtt p90", "rtt max", "pings")?;
+    row(f, "idle", &self.idle)?;
+    row(f, "loaded", &self.loaded)?;
+    writeln!(
+      f,
+      "load: {} streams echoing {:.1} Mbit/s each way for {:.1?}",
+      LOAD_STREAMS,
+      self.load_mbps(),
+      self.load_time
+    )?;
+    writeln!(
+      f,
+      "latency under load: +{:.2} ms ({:.1}x idle)",
+      ms(self.loaded_median().saturating_sub(self.idle_median())),
+      self.loaded_median().as_secs_f64() / self.idle_median().as_secs_f64().max(1e-9)
+    )?;
+    write!(f, "responsiveness: {:.0} RPM ({})", self.rpm(), self.grade())
+  }
+}
+
+/// Ping over `probe` while idle, then for `duration` while `load` echoes
+/// bulk data on [`LOAD_STREAMS`] streams. Pings in the first quarter of the
+/// load (at most [`MAX_RAMP`]) are left out while the queues fill.
+pub async fn measure(probe: &Connection, load: &Connection, duration: Duration) -> Result<Report> {
+  let (mut send, mut recv) = probe.open_bi().await.context("open the ping stream")?;
+  let mut idle = Vec::with_capacity(IDLE_PINGS);
+  for _ in 0..IDLE_PINGS {
+    idle.push(tcp::ping(&mut recv, &mut send, 1).await.context("idle ping")?[0]);
+    tokio::time::sleep(PING_INTERVAL).await;
+  }
+
+  let start = Instant::now();
+  let until = start + duration;
+  let echoed = Arc::new(AtomicU64::new(0));
+  let mut streams = tokio::task::JoinSet::new();
+  for _ in 0..LOAD_STREAMS {
+    streams.spawn(saturate(load.clone(), until, echoed.clone()));
+  }
+  let ramp = (duration / 4).min(MAX_RAMP);
+  let mut loaded = Vec::new();
+  while Instant::now() < until {
+    let sent = Instant::now();
+    let rtt = tcp::ping(&mut recv, &mut send, 1).await.context("loaded ping")?[0];
+    if sent >= start + ramp {
+      loaded.push(rtt);
+    }
+    tokio::time::sleep(PING_INTERVAL).await;
+  }
+  send.finish()?;
+  while let Some(joined) = streams.join_next().await {
+    joined?.context("load stream")?;
+  }
+  let load_time = start.elapsed();
+  anyhow::ensure!(!loaded.is_empty(), "no ping finished under load; try a longer --loaded-latency");
+
+  idle.sort();
+  loaded.sort();
+  Ok(Report { idle, loaded, load_bytes: echoed.load(Ordering::Relaxed), load_time })
+}
+
+/// Write chunks on a new stream until `until` while counting what comes back.
+async fn saturate(conn: Connection, until: Instant, echoed: Arc<AtomicU64>) -> Result<()> {
+  let (mut send, mut recv) = conn.open_bi().await?;
+  let chunk = Bytes::from(vec![0x5a; LOAD_CHUNK]);
+  let writing = async {
+    while Instant::now() < until {
+      send.write_chunk(chunk.clone()).await?;
+    }
+    send.finish()?;
+    anyhow::Ok(())
+  };
+  let reading = async {
+    while let Some(chunk) = recv.read_chunk(usize::MAX, true).await? {
+      echoed.fetch_add(chunk.bytes.len() as u64, Ordering::Relaxed);
+    }
+    anyhow::Ok(())
+  };
+  tokio::try_join!(writing, reading)?;
+  Ok(())
+}
+
+fn median(sorted: &[Duration]) -> Duration {
+  sorted[sorted.len() / 2]
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+  sorted[((sorted.len() - 1) as f64 * p).round() as usize]
+}
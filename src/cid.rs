@@ -0,0 +1,327 @@
+//! Connection ID options of the server, for running it behind a QUIC-aware
+//! load balancer: `--cid-len` sets the length of the IDs the server issues,
+//! `--server-id` makes every one of them start with the given bytes (the
+//! rest stays random) so the balancer can route packets by connection ID
+//! alone, and `--cid-lifetime` retires and replaces them periodically.
+//! Without any of them quinn's default generator is kept.
+//!
+//! `--cid-count` caps how many IDs each client holds at once. quinn issues
+//! as many as the client's `active_connection_id_limit` allows, up to 8,
+//! and has no setting for fewer, so [`CidCount`] lowers the limit quinn
+//! reads from the client's transport parameters.
+//!
+//! Every ID issued is logged at debug level ("issued connection ID"). quinn
+//! does not tell the generator which connection an ID is for: the one chosen
+//! during the handshake is logged in the connection's span, the ones handed
+//! out later in NEW_CONNECTION_ID frames in the endpoint's.
+//!
+//! [`CidActivity`] counts the NEW_CONNECTION_ID and RETIRE_CONNECTION_ID
+//! frames of a connection; both binaries log it next to the loss report.
+
+use crate::{
+  http3::{get_varint, put_varint},
+  replay::unhex,
+};
+use anyhow::Result;
+use bytes::Bytes;
+use clap::Args;
+use quinn::{ConnectionId, ConnectionIdGenerator, ConnectionStats, EndpointConfig};
+use quinn_proto::{
+  HashedConnectionIdGenerator, InvalidCid, RandomConnectionIdGenerator, Side, TransportError,
+  crypto::{self, ExportKeyingMaterialError, HeaderKey, KeyPair, Keys, PacketKey, Session, UnsupportedVersion},
+  transport_parameters::TransportParameters,
+};
+use rand::RngCore;
+use std::{any::Any, sync::Arc, time::Duration};
+use tracing::{debug, info};
+
+/// quinn's connection ID length.
+const DEFAULT_LEN: u8 = 8;
+/// Random bytes each ID keeps after a `--server-id` prefix.
+const MIN_RANDOM: usize = 4;
+/// Longest connection ID QUIC allows.
+const MAX_LEN: usize = 20;
+/// Transport parameter ID of `active_connection_id_limit`.
+const ACTIVE_CONNECTION_ID_LIMIT: u64 = 0x0e;
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct CidOptions {
+  /// Length in bytes of the connection IDs the server issues (default 8)
+  #[clap(long, env = "QUIC_ECHO_CID_LEN", value_parser = clap::value_parser!(u8).range(4..=20))]
+  pub cid_len: Option<u8>,
+  /// Hex bytes every issued connection ID starts with, for load balancer routing (e.g. 0a01)
+  #[clap(long, env = "QUIC_ECHO_SERVER_ID", value_parser = parse_hex)]
+  pub server_id: Option<Bytes>,
+  /// Seconds after which each connection ID is retired and replaced by a new one
+  #[clap(long, env = "QUIC_ECHO_CID_LIFETIME", value_parser = parse_secs)]
+  pub cid_lifetime: Option<Duration>,
+  /// Connection IDs each client holds at once, counting the handshake's (default: what the client accepts, up to 8)
+  #[clap(long, env = "QUIC_ECHO_CID_COUNT", value_parser = clap::value_parser!(u8).range(2..=8))]
+  pub cid_count: Option<u8>,
+}
+
+impl CidOptions {
+  /// A `--server-id` must leave room for the random part.
+  pub fn check(&self) -> Result<()> {
+    if let Some(id) = &self.server_id {
+      let len = usize::from(self.cid_len.unwrap_or(DEFAULT_LEN));
+      anyhow::ensure!(
+        id.len() + MIN_RANDOM <= len,
+        "--server-id ({} bytes) must leave at least {MIN_RANDOM} random bytes in a {len}-byte connection ID; raise --cid-len",
+        id.len()
+      );
+    }
+    Ok(())
+  }
+
+  /// Install a generator for the options that were given on `config`.
+  pub fn apply(&self, config: &mut EndpointConfig) {
+    if self.cid_len.is_none() && self.server_id.is_none() && self.cid_lifetime.is_none() {
+      config.cid_generator(|| Box::new(Logged(HashedConnectionIdGenerator::new())));
+      return;
+    }
+    let (len, lifetime) = (usize::from(self.cid_len.unwrap_or(DEFAULT_LEN)), self.cid_lifetime);
+    match self.server_id.clone() {
+      Some(prefix) => config.cid_generator(move || Box::new(Logged(ServerId { prefix: prefix.clone(), len, lifetime }))),
+      None => config.cid_generator(move || {
+        let mut random = RandomConnectionIdGenerator::new(len);
+        if let Some(lifetime) = lifetime {
+          random.set_lifetime(lifetime);
+        }
+        Box::new(Logged(random))
+      }),
+    };
+  }
+}
+
+/// A quinn server crypto config that issues at most `count` connection IDs
+/// per client (`--cid-count`).
+pub struct CidCount<C: ?Sized> {
+  inner: Arc<C>,
+  count: u8,
+}
+
+impl<C: ?Sized> CidCount<C> {
+  pub fn new(inner: Arc<C>, count: u8) -> Self {
+    Self { inner, count }
+  }
+}
+
+impl<C: crypto::ServerConfig + ?Sized + 'static> crypto::ServerConfig for CidCount<C> {
+  fn initial_keys(&self, version: u32, dst_cid: &ConnectionId) -> Result<Keys, UnsupportedVersion> {
+    self.inner.initial_keys(version, dst_cid)
+  }
+
+  fn retry_tag(&self, version: u32, orig_dst_cid: &ConnectionId, packet: &[u8]) -> [u8; 16] {
+    self.inner.retry_tag(version, orig_dst_cid, packet)
+  }
+
+  fn start_session(self: Arc<Self>, version: u32, params: &TransportParameters) -> Box<dyn Session> {
+    Box::new(Capped { inner: self.inner.clone().start_session(version, params), count: self.count })
+  }
+}
+
+/// Passes everything through to the real session but shows quinn a client
+/// that accepts no more than `count` connection IDs.
+struct Capped {
+  inner: Box<dyn Session>,
+  count: u8,
+}
+
+impl Session for Capped {
+  fn initial_keys(&self, dst_cid: &ConnectionId, side: Side) -> Keys {
+    self.inner.initial_keys(dst_cid, side)
+  }
+
+  fn handshake_data(&self) -> Option<Box<dyn Any>> {
+    self.inner.handshake_data()
+  }
+
+  fn peer_identity(&self) -> Option<Box<dyn Any>> {
+    self.inner.peer_identity()
+  }
+
+  fn early_crypto(&self) -> Option<(Box<dyn HeaderKey>, Box<dyn PacketKey>)> {
+    self.inner.early_crypto()
+  }
+
+  fn early_data_accepted(&self) -> Option<bool> {
+    self.inner.early_data_accepted()
+  }
+
+  fn is_handshaking(&self) -> bool {
+    self.inner.is_handshaking()
+  }
+
+  fn read_handshake(&mut self, buf: &[u8]) -> Result<bool, TransportError> {
+    self.inner.read_handshake(buf)
+  }
+
+  fn transport_parameters(&self) -> Result<Option<TransportParameters>, TransportError> {
+    Ok(self.inner.transport_parameters()?.map(|params| cap_cids(params, self.count)))
+  }
+
+  fn write_handshake(&mut self, buf: &mut Vec<u8>) -> Option<Keys> {
+    self.inner.write_handshake(buf)
+  }
+
+  fn next_1rtt_keys(&mut self) -> Option<KeyPair<Box<dyn PacketKey>>> {
+    self.inner.next_1rtt_keys()
+  }
+
+  fn is_valid_retry(&self, orig_dst_cid: &ConnectionId, header: &[u8], payload: &[u8]) -> bool {
+    self.inner.is_valid_retry(orig_dst_cid, header, payload)
+  }
+
+  fn export_keying_material(
+    &self,
+    output: &mut [u8],
+    label: &[u8],
+    context: &[u8],
+  ) -> Result<(), ExportKeyingMaterialError> {
+    self.inner.export_keying_material(output, label, context)
+  }
+}
+
+/// The client's transport parameters with `active_connection_id_limit`
+/// lowered to `count`. quinn keeps the fields private, so they go through
+/// their wire encoding; should that fail they are returned unchanged.
+fn cap_cids(params: TransportParameters, count: u8) -> TransportParameters {
+  let mut wire = Vec::new();
+  params.write(&mut wire);
+  // absent, the limit is 2, the least allowed
+  let mut limit = 2;
+  let mut capped = Vec::with_capacity(wire.len());
+  let mut at = 0;
+  while at < wire.len() {
+    let Some((id, id_len)) = get_varint(&wire[at..]) else {
+      return params;
+    };
+    let Some((len, len_len)) = get_varint(&wire[at + id_len..]) else {
+      return params;
+    };
+    let end = at + id_len + len_len + len as usize;
+    let Some(param) = wire.get(at..end) else {
+      return params;
+    };
+    if id == ACTIVE_CONNECTION_ID_LIMIT {
+      limit = get_varint(&param[id_len + len_len..]).map_or(limit, |(limit, _)| limit);
+    } else {
+      capped.extend_from_slice(param);
+    }
+    at = end;
+  }
+  let mut value = Vec::new();
+  put_varint(&mut value, limit.min(u64::from(count)));
+  put_varint(&mut capped, ACTIVE_CONNECTION_ID_LIMIT);
+  put_varint(&mut capped, value.len() as u64);
+  capped.extend_from_slice(&value);
+  TransportParameters::read(Side::Server, &mut &capped[..]).unwrap_or(params)
+}
+
+/// NEW_CONNECTION_ID and RETIRE_CONNECTION_ID frames of one connection.
+#[derive(Clone, Copy, Debug)]
+pub struct CidActivity {
+  /// IDs we issued
+  pub issued: u64,
+  /// IDs the peer issued
+  pub received: u64,
+  /// The peer's IDs we retired
+  pub retired: u64,
+  /// Our IDs the peer retired
+  pub retired_by_peer: u64,
+}
+
+impl CidActivity {
+  pub fn new(stats: &ConnectionStats) -> Self {
+    Self {
+      issued: stats.frame_tx.new_connection_id,
+      received: stats.frame_rx.new_connection_id,
+      retired: stats.frame_tx.retire_connection_id,
+      retired_by_peer: stats.frame_rx.retire_connection_id,
+    }
+  }
+
+  /// Log the counts in the current span.
+  pub fn log(&self) {
+    info!(
+      issued = self.issued,
+      received = self.received,
+      retired = self.retired,
+      retired_by_peer = self.retired_by_peer,
+      "connection ID activity"
+    );
+  }
+}
+
+/// `prefix` followed by random bytes up to `len`.
+struct ServerId {
+  prefix: Bytes,
+  len: usize,
+  lifetime: Option<Duration>,
+}
+
+impl ConnectionIdGenerator for ServerId {
+  fn generate_cid(&mut self) -> ConnectionId {
+    let mut bytes = [0; MAX_LEN];
+    bytes[..self.prefix.len()].copy_from_slice(&self.prefix);
+    rand::rng().fill_bytes(&mut bytes[self.prefix.len()..self.len]);
+    ConnectionId::new(&bytes[..self.len])
+  }
+
+  /// Packets for another server's IDs are dropped instead of answered with a
+  /// stateless reset.
+  fn validate(&self, cid: &ConnectionId) -> Result<(), InvalidCid> {
+    match cid.starts_with(&self.prefix) {
+      true => Ok(()),
+      false => Err(InvalidCid),
+    }
+  }
+
+  fn cid_len(&self) -> usize {
+    self.len
+  }
+
+  fn cid_lifetime(&self) -> Option<Duration> {
+    self.lifetime
+  }
+}
+
+/// Logs every ID the wrapped generator issues.
+struct Logged<G>(G);
+
+impl<G: ConnectionIdGenerator> ConnectionIdGenerator for Logged<G> {
+  fn generate_cid(&mut self) -> ConnectionId {
+    let cid = self.0.generate_cid();
+    debug!(%cid, "issued connection ID");
+    cid
+  }
+
+  fn validate(&self, cid: &ConnectionId) -> Result<(), InvalidCid> {
+    self.0.validate(cid)
+  }
+
+  fn cid_len(&self) -> usize {
+    self.0.cid_len()
+  }
+
+  fn cid_lifetime(&self) -> Option<Duration> {
+    self.0.cid_lifetime()
+  }
+}
+
+fn parse_hex(s: &str) -> Result<Bytes, String> {
+  match unhex(s.strip_prefix("0x").unwrap_or(s)) {
+    Ok(bytes) if !bytes.is_empty() => Ok(bytes.into()),
+    Ok(_) => Err("expected at least one hex byte".into()),
+    Err(e) => Err(format!("expected hex bytes like 0a01, got {s:?}: {e}")),
+  }
+}
+
+fn parse_secs(s: &str) -> Result<Duration, String> {
+  s.parse::<f64>()
+    .ok()
+    .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+    .filter(|d| !d.is_zero())
+    .ok_or_else(|| format!("expected a positive number of seconds, got {s:?}"))
+}
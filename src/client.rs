@@ -0,0 +1,258 @@
+//! Echo checks for other Rust programs: [`EchoClient`] pings a quic_echo
+//! server (echo mode) over a stream or a datagram and returns a
+//! [`PingResult`] instead of printing, so a service can embed QUIC
+//! reachability checks; [`EchoClient::ping_stream`] keeps pinging at an
+//! interval and yields every result as a [`Stream`].
+//!
+//! The client keeps one connection and reuses it for every ping, connecting
+//! again (resuming the TLS session with 0-RTT where the server allows it)
+//! after a failure or a close.
+//!
+//! ```no_run
+//! # async fn check(ca: rustls::pki_types::CertificateDer<'static>) -> anyhow::Result<()> {
+//! use quic_echo::client::{EchoClient, Mode};
+//!
+//! let mut roots = rustls::RootCertStore::empty();
+//! roots.add(ca)?;
+//! let tls = rustls::ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+//! let client = EchoClient::new("192.0.2.7:4433".parse()?, "echo.example.com", tls)?;
+//! let result = client.ping(b"ping", Mode::Stream).await?;
+//! println!("echoed {} B in {:?}", result.bytes, result.rtt);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{auth, verify};
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use futures_core::Stream;
+use quinn::{ClientConfig, Connection, Endpoint, crypto::rustls::QuicClientConfig};
+use std::{
+  fmt,
+  net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+  pin::Pin,
+  sync::Arc,
+  task::{Context as TaskContext, Poll},
+  time::{Duration, Instant},
+};
+use tokio::{
+  sync::{mpsc, Mutex},
+  time::MissedTickBehavior,
+};
+
+/// The echo protocol's ALPN, as in both binaries.
+const ECHO_ALPN: &[u8] = b"freven-quic-test";
+/// How long a ping may take by default, connecting included.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a ping is sent as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+  /// A bidirectional stream, finished after the payload
+  Stream,
+  /// One unreliable datagram; a lost one fails the ping
+  Datagram,
+}
+
+/// Why a ping failed, attached as context to the error (find it with
+/// `downcast_ref`); the values are the client's `--probe` exit codes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+  /// No connection, or it was lost
+  Connect = 1,
+  /// No echo in time
+  Timeout = 2,
+  /// The echo differs from the payload
+  Mismatch = 3,
+}
+
+impl fmt::Display for Failure {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Failure::Connect => "no connection",
+      Failure::Timeout => "timeout",
+      Failure::Mismatch => "wrong echo",
+    })
+  }
+}
+
+/// One successful ping.
+#[derive(Clone, Copy, Debug)]
+pub struct PingResult {
+  /// From sending the payload to having all of the echo
+  pub rtt: Duration,
+  /// Payload bytes echoed
+  pub bytes: usize,
+  /// The connection resumed an earlier TLS session with accepted 0-RTT
+  pub resumed: bool,
+  /// Time spent connecting, if this ping had to open the connection
+  pub handshake: Option<Duration>,
+  /// The server's address on the connection
+  pub remote: SocketAddr,
+  /// quinn's smoothed RTT estimate for the connection
+  pub path_rtt: Duration,
+}
+
+/// Pings one server.
+#[derive(Debug)]
+pub struct EchoClient {
+  endpoint: Endpoint,
+  config: ClientConfig,
+  addr: SocketAddr,
+  server_name: String,
+  auth_token: Option<String>,
+  timeout: Duration,
+  conn: Mutex<Option<(Connection, bool)>>,
+}
+
+impl EchoClient {
+  /// A client for the server at `addr`, verified as `server_name` with
+  /// `tls` (whose ALPN is replaced and early data enabled). Binds an
+  /// unspecified address of `addr`'s family, so it needs a tokio runtime.
+  pub fn new(addr: SocketAddr, server_name: &str, mut tls: rustls::ClientConfig) -> Result<Self> {
+    tls.alpn_protocols = vec![ECHO_ALPN.to_vec()];
+    tls.enable_early_data = true;
+    let config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?));
+    let bind: SocketAddr = match addr {
+      SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+      SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let endpoint = Endpoint::client(bind).with_context(|| format!("bind {bind}"))?;
+    Ok(EchoClient {
+      endpoint,
+      config,
+      addr,
+      server_name: server_name.into(),
+      auth_token: None,
+      timeout: DEFAULT_TIMEOUT,
+      conn: Mutex::new(None),
+    })
+  }
+
+  /// Send this token on every new connection, for a server with `--auth-token`.
+  pub fn with_auth_token(mut self, token: impl Into<String>) -> Self {
+    self.auth_token = Some(token.into());
+    self
+  }
+
+  /// How long one ping may take, connecting included (default 5 s).
+  pub fn with_timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = timeout;
+    self
+  }
+
+  /// Send `payload` and wait for it to come back byte for byte. Errors
+  /// carry a [`Failure`] where the cause is known.
+  pub async fn ping(&self, payload: &[u8], mode: Mode) -> Result<PingResult> {
+    let mut cached = self.conn.lock().await;
+    let result = tokio::time::timeout(self.timeout, async {
+      let handshake = match &*cached {
+        Some((conn, _)) if conn.close_reason().is_none() => None,
+        _ => {
+          let start = Instant::now();
+          *cached = Some(self.connect().await.context(Failure::Connect)?);
+          Some(start.elapsed())
+        }
+      };
+      let (conn, resumed) = cached.as_ref().expect("connected above");
+      let start = Instant::now();
+      let bytes = match mode {
+        Mode::Stream => stream_roundtrip(conn, payload).await?,
+        Mode::Datagram => dgram_roundtrip(conn, payload).await?,
+      };
+      Ok(PingResult {
+        rtt: start.elapsed(),
+        bytes,
+        resumed: *resumed,
+        handshake,
+        remote: conn.remote_address(),
+        path_rtt: conn.rtt(),
+      })
+    })
+    .await
+    .unwrap_or_else(|_| Err(anyhow::anyhow!("no echo within {:?}", self.timeout).context(Failure::Timeout)));
+    if result.is_err() {
+      // start afresh next time rather than reuse a connection in doubt
+      if let Some((conn, _)) = cached.take() {
+        conn.close(0u32.into(), b"ping failed");
+      }
+    }
+    result
+  }
+
+  /// Ping every `interval` until the returned stream is dropped, yielding
+  /// each ping's result; a failed ping is an `Err` item, not the end.
+  pub fn ping_stream(self: Arc<Self>, payload: Bytes, mode: Mode, interval: Duration) -> PingStream {
+    let (tx, rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+      let mut tick = tokio::time::interval(interval);
+      tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+      loop {
+        tick.tick().await;
+        let result = self.ping(&payload, mode).await;
+        if tx.send(result).await.is_err() {
+          return;
+        }
+      }
+    });
+    PingStream { rx }
+  }
+
+  /// Close the connection and wait until the server has been told; pings
+  /// after this fail, including those of a running [`ping_stream`](Self::ping_stream).
+  pub async fn close(&self) {
+    self.endpoint.close(0u32.into(), b"done");
+    self.endpoint.wait_idle().await;
+  }
+
+  /// Connect, with 0-RTT if there is a ticket; returns whether the server
+  /// accepted it.
+  async fn connect(&self) -> Result<(Connection, bool)> {
+    let connecting = self.endpoint.connect_with(self.config.clone(), self.addr, &self.server_name)?;
+    let (conn, resumed) = match connecting.into_0rtt() {
+      // nothing is sent early, the ping waits for the handshake
+      Ok((conn, accepted)) => {
+        let resumed = accepted.await;
+        (conn, resumed)
+      }
+      Err(connecting) => (connecting.await.with_context(|| format!("connect to {}", self.addr))?, false),
+    };
+    if let Some(reason) = conn.close_reason() {
+      return Err(reason).with_context(|| format!("connect to {}", self.addr));
+    }
+    if let Some(token) = &self.auth_token {
+      auth::send(&conn, token).await.context("send the auth token")?;
+    }
+    Ok((conn, resumed))
+  }
+}
+
+/// The results of [`EchoClient::ping_stream`].
+#[derive(Debug)]
+pub struct PingStream {
+  rx: mpsc::Receiver<Result<PingResult>>,
+}
+
+impl Stream for PingStream {
+  type Item = Result<PingResult>;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+    self.rx.poll_recv(cx)
+  }
+}
+
+async fn stream_roundtrip(conn: &Connection, payload: &[u8]) -> Result<usize> {
+  let (mut send, mut recv) = conn.open_bi().await?;
+  send.write_all(payload).await?;
+  send.finish()?;
+  let echo = recv.read_to_end(payload.len()).await.context("read the echo")?;
+  verify::compare("stream echo", payload, &echo).context(Failure::Mismatch)?;
+  Ok(echo.len())
+}
+
+async fn dgram_roundtrip(conn: &Connection, payload: &[u8]) -> Result<usize> {
+  conn.send_datagram(Bytes::copy_from_slice(payload))?;
+  let echo = conn.read_datagram().await?;
+  verify::compare("datagram echo", payload, &echo).context(Failure::Mismatch)?;
+  Ok(echo.len())
+}
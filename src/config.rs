@@ -0,0 +1,189 @@
+//! `--config <file>` for both binaries: a flat TOML table whose keys are the
+//! long flag names (`port = 4433`, `stream-window = 16777216` or
+//! `stream_window = ...`, `datagram = true`, `resolve = ["a:1:1.2.3.4"]`).
+//!
+//! The table is turned back into flags placed in front of the real command
+//! line, so clap validates them like any other flag and a flag given on the
+//! command line wins (the binaries set `args_override_self`). List values
+//! add to the command line's instead of being replaced.
+//!
+//! Precedence is command line, then `QUIC_ECHO_*` environment variables,
+//! then the file, then the built-in defaults: keys whose variable is set
+//! are left out so the environment can override a shared file.
+//!
+//! For a running server, [`reloads`] reports SIGHUP and edits of the file,
+//! and [`diff`] lists the options a new [`args`] changes.
+
+use anyhow::{Context, Result};
+use std::{
+  ffi::OsString,
+  fmt,
+  path::{Path, PathBuf},
+  time::{Duration, SystemTime},
+};
+use tokio::sync::mpsc;
+use toml::Value;
+use tracing::warn;
+
+const FLAG: &str = "--config";
+const ENV: &str = "QUIC_ECHO_CONFIG";
+/// How often [`reloads`] checks the file's modification time
+const RELOAD_POLL: Duration = Duration::from_secs(2);
+
+/// The process arguments with the flags from `--config` (if given) spliced
+/// in after the program name. `cmd` is the binary's clap command, used to
+/// reject keys that are not one of its flags.
+pub fn args(cmd: &clap::Command) -> Result<Vec<OsString>> {
+  let mut args: Vec<OsString> = std::env::args_os().collect();
+  let Some(path) = config_path(&args) else {
+    return Ok(args);
+  };
+  let text = std::fs::read_to_string(&path).with_context(|| format!("read {}", path.display()))?;
+  let table: toml::Table = text.parse().with_context(|| format!("parse {}", path.display()))?;
+  let flags = to_flags(cmd, table).with_context(|| format!("in {}", path.display()))?;
+  args.splice(1..1, flags);
+  Ok(args)
+}
+
+/// The config file in use, if any.
+pub fn file() -> Option<PathBuf> {
+  config_path(&std::env::args_os().collect::<Vec<_>>())
+}
+
+/// One option whose value differs between two command lines.
+#[derive(Debug)]
+pub struct Change {
+  /// Long flag name, without the dashes
+  pub option: String,
+  pub old: String,
+  pub new: String,
+}
+
+impl fmt::Display for Change {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}: {} -> {}", self.option, self.old, self.new)
+  }
+}
+
+/// The options whose values (explicit or default) differ between `old` and
+/// `new`, both full argument lists like [`args`] returns.
+pub fn diff(cmd: &clap::Command, old: &[OsString], new: &[OsString]) -> Result<Vec<Change>> {
+  let old = cmd.clone().try_get_matches_from(old)?;
+  let new = cmd.clone().try_get_matches_from(new)?;
+  let value = |matches: &clap::ArgMatches, id: &str| match matches.get_raw(id) {
+    Some(raw) => raw.map(|v| v.to_string_lossy()).collect::<Vec<_>>().join(","),
+    None => "(unset)".to_string(),
+  };
+  Ok(
+    cmd
+      .get_arguments()
+      .filter_map(|arg| Some((arg.get_id().as_str(), arg.get_long()?)))
+      .filter(|&(_, long)| long != "config")
+      .map(|(id, long)| Change { option: long.to_string(), old: value(&old, id), new: value(&new, id) })
+      .filter(|change| change.old != change.new)
+      .collect(),
+  )
+}
+
+/// Reasons to reload the configuration: SIGHUP (on Unix) and, with a
+/// `file`, each change of its modification time.
+pub fn reloads(file: Option<PathBuf>) -> mpsc::Receiver<&'static str> {
+  let (tx, rx) = mpsc::channel(1);
+  #[cfg(unix)]
+  {
+    use tokio::signal::unix::{SignalKind, signal};
+    match signal(SignalKind::hangup()) {
+      Ok(mut hangup) => {
+        let tx = tx.clone();
+        tokio::spawn(async move {
+          while hangup.recv().await.is_some() && !tx.is_closed() {
+            // a reload already pending covers this one too
+            let _ = tx.try_send("SIGHUP");
+          }
+        });
+      }
+      Err(e) => warn!("cannot listen for SIGHUP: {e}"),
+    }
+  }
+  if let Some(path) = file {
+    tokio::spawn(async move {
+      let mut seen = modified(&path);
+      loop {
+        tokio::time::sleep(RELOAD_POLL).await;
+        let now = modified(&path);
+        if now != seen {
+          seen = now;
+          if tx.send("config file changed").await.is_err() {
+            return;
+          }
+        }
+      }
+    });
+  }
+  rx
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+  std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// `--config <file>` or `--config=<file>` up to a `--` separator, else
+/// `QUIC_ECHO_CONFIG`.
+fn config_path(args: &[OsString]) -> Option<PathBuf> {
+  let mut iter = args.iter().skip(1).take_while(|a| *a != "--");
+  while let Some(arg) = iter.next() {
+    if arg == FLAG {
+      return iter.next().map(PathBuf::from);
+    }
+    if let Some(path) = arg.to_str().and_then(|a| a.strip_prefix("--config=")) {
+      return Some(PathBuf::from(path));
+    }
+  }
+  std::env::var_os(ENV).map(PathBuf::from)
+}
+
+fn to_flags(cmd: &clap::Command, table: toml::Table) -> Result<Vec<OsString>> {
+  let mut flags = Vec::new();
+  for (key, value) in table {
+    let name = key.replace('_', "-");
+    let arg = cmd
+      .get_arguments()
+      .find(|a| a.get_long() == Some(name.as_str()))
+      .with_context(|| format!("unknown option {key:?}"))?;
+    anyhow::ensure!(name != "config", "a config file cannot include another one");
+    if arg.get_env().is_some_and(|var| std::env::var_os(var).is_some()) {
+      continue;
+    }
+    let flag = format!("--{name}");
+    let takes_value = arg.get_action().takes_values();
+    match value {
+      Value::Boolean(set) if !takes_value => {
+        if set {
+          flags.push(flag.into());
+        }
+      }
+      // `--flag=value` as one argument, so a value starting with `-` is not
+      // taken for a flag
+      Value::Array(items) => {
+        for item in items {
+          flags.push(format!("{flag}={}", scalar(&key, item)?).into());
+        }
+      }
+      value => {
+        anyhow::ensure!(takes_value, "{key:?} is a switch, set it to true or false");
+        flags.push(format!("{flag}={}", scalar(&key, value)?).into());
+      }
+    }
+  }
+  Ok(flags)
+}
+
+fn scalar(key: &str, value: Value) -> Result<String> {
+  Ok(match value {
+    Value::String(s) => s,
+    Value::Integer(n) => n.to_string(),
+    Value::Float(f) => f.to_string(),
+    Value::Boolean(b) => b.to_string(),
+    other => anyhow::bail!("{key:?}: expected a string, number or boolean, got {}", other.type_str()),
+  })
+}
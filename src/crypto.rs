@@ -0,0 +1,114 @@
+//! rustls crypto provider selection (`--crypto-provider`) for both binaries:
+//! ring is always built in, aws-lc-rs with the `aws-lc-rs` feature. The
+//! chosen provider is installed as the process default, which every rustls
+//! config in the crate (QUIC, TLS-over-TCP, ACME) picks up.
+
+use anyhow::Result;
+use clap::ValueEnum;
+use rustls::{
+  DigitallySignedStruct, SignatureScheme,
+  client::danger,
+  crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature},
+  pki_types::{CertificateDer, ServerName, UnixTime},
+};
+use std::sync::{Arc, OnceLock};
+use tracing::info;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Provider {
+  /// ring (always available)
+  #[default]
+  Ring,
+  /// AWS-LC (needs the `aws-lc-rs` feature)
+  AwsLcRs,
+}
+
+impl Provider {
+  pub fn name(self) -> &'static str {
+    match self {
+      Provider::Ring => "ring",
+      Provider::AwsLcRs => "aws-lc-rs",
+    }
+  }
+
+  fn build(self) -> Result<CryptoProvider> {
+    match self {
+      Provider::Ring => Ok(rustls::crypto::ring::default_provider()),
+      #[cfg(feature = "aws-lc-rs")]
+      Provider::AwsLcRs => Ok(rustls::crypto::aws_lc_rs::default_provider()),
+      #[cfg(not(feature = "aws-lc-rs"))]
+      Provider::AwsLcRs => anyhow::bail!("--crypto-provider aws-lc-rs needs quic_echo built with `--features aws-lc-rs`"),
+    }
+  }
+}
+
+static INSTALLED: OnceLock<Provider> = OnceLock::new();
+
+/// Install `provider` as the process-wide rustls default.
+pub fn install(provider: Provider) -> Result<()> {
+  let built = provider.build()?;
+  let fips = built.fips();
+  built
+    .install_default()
+    .map_err(|_| anyhow::anyhow!("a rustls crypto provider is already installed"))?;
+  INSTALLED.get_or_init(|| provider);
+  info!(provider = provider.name(), fips, "crypto provider installed");
+  Ok(())
+}
+
+/// The installed provider; [`install`] must have run.
+pub fn provider() -> Arc<CryptoProvider> {
+  CryptoProvider::get_default().expect("crypto provider installed at startup").clone()
+}
+
+/// Which provider [`install`] installed.
+pub fn installed() -> Provider {
+  INSTALLED.get().copied().unwrap_or_default()
+}
+
+/// A certificate verifier that accepts any server certificate (still
+/// checking the handshake signatures), so the client works against
+/// self-signed test servers. Not safe against a man in the middle.
+#[derive(Debug)]
+pub struct SkipServerVerification(Arc<CryptoProvider>);
+impl SkipServerVerification {
+  /// Uses the installed provider's signature algorithms; [`install`] must
+  /// have run.
+  pub fn new() -> Arc<Self> {
+    Arc::new(Self(provider()))
+  }
+}
+impl danger::ServerCertVerifier for SkipServerVerification {
+  fn verify_server_cert(
+    &self,
+    _end_entity: &CertificateDer<'_>,
+    _intermediates: &[CertificateDer<'_>],
+    _server_name: &ServerName<'_>,
+    _ocsp: &[u8],
+    _now: UnixTime,
+  ) -> std::result::Result<danger::ServerCertVerified, rustls::Error> {
+    Ok(danger::ServerCertVerified::assertion())
+  }
+
+  fn verify_tls12_signature(
+    &self,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+  ) -> std::result::Result<danger::HandshakeSignatureValid, rustls::Error> {
+    verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+  }
+
+  fn verify_tls13_signature(
+    &self,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+  ) -> std::result::Result<danger::HandshakeSignatureValid, rustls::Error> {
+    verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+  }
+
+  fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+    self.0.signature_verification_algorithms.supported_schemes()
+  }
+}
@@ -0,0 +1,176 @@
+//! Running the server without a supervisor (`--daemonize`, `--pidfile`,
+//! `--stop`): the classic double fork with `setsid`, stdout/stderr sent to
+//! a log file, and a pidfile that `--stop` reads to signal the daemon.
+//!
+//! [`daemonize`] must run before any thread is started (so before the tokio
+//! runtime), since only the calling thread survives a fork. The foreground
+//! process waits until the daemon reports [`Ready`] (or dies), so a startup
+//! error still gives a nonzero exit code.
+
+use anyhow::{Context, Result};
+use std::{
+  fs,
+  path::{Path, PathBuf},
+  time::Duration,
+};
+
+/// How often `--stop` checks whether the daemon is gone.
+#[cfg(unix)]
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+/// A pidfile holding this process's pid, removed again when dropped.
+#[derive(Debug)]
+pub struct Pidfile {
+  path: PathBuf,
+  pid: u32,
+}
+
+impl Pidfile {
+  /// Write our pid to `path`, unless a live process already owns it.
+  pub fn create(path: &Path) -> Result<Self> {
+    if let Some(pid) = read_pid(path).filter(|&pid| alive(pid)) {
+      anyhow::bail!("already running as pid {pid} (per {})", path.display());
+    }
+    let pid = std::process::id();
+    fs::write(path, format!("{pid}\n")).with_context(|| format!("write pidfile {}", path.display()))?;
+    Ok(Pidfile { path: std::path::absolute(path)?, pid })
+  }
+}
+
+impl Drop for Pidfile {
+  fn drop(&mut self) {
+    // a newer instance may have taken the file over
+    if read_pid(&self.path) == Some(self.pid) {
+      let _ = fs::remove_file(&self.path);
+    }
+  }
+}
+
+fn read_pid(path: &Path) -> Option<u32> {
+  fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Tells the waiting foreground process that the daemon is serving.
+#[derive(Debug)]
+pub struct Ready(std::io::PipeWriter);
+
+impl Ready {
+  pub fn notify(mut self) {
+    use std::io::Write;
+    let _ = writeln!(self.0, "{}", std::process::id());
+  }
+}
+
+/// Detach from the terminal and write `pidfile`. Returns in the daemon; the
+/// foreground process exits once the daemon calls [`Ready::notify`] (status
+/// 0) or exits first (status 1). Output goes to `log` (appended), or
+/// nowhere.
+#[cfg(unix)]
+pub fn daemonize(pidfile: &Path, log: Option<&Path>) -> Result<(Pidfile, Ready)> {
+  use std::{io::Read, os::fd::AsRawFd};
+
+  // fail in the foreground for what can be checked there
+  if let Some(pid) = read_pid(pidfile).filter(|&pid| alive(pid)) {
+    anyhow::bail!("already running as pid {pid} (per {})", pidfile.display());
+  }
+  let output = match log {
+    Some(path) => fs::OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(path)
+      .with_context(|| format!("open log file {}", path.display()))?,
+    None => fs::OpenOptions::new().write(true).open("/dev/null")?,
+  };
+  let null = fs::File::open("/dev/null")?;
+  let (mut started, ready) = std::io::pipe()?;
+
+  match unsafe { libc::fork() } {
+    -1 => return Err(std::io::Error::last_os_error()).context("fork"),
+    0 => {}
+    child => {
+      drop(ready);
+      let mut pid = String::new();
+      let _ = started.read_to_string(&mut pid);
+      // the first child exits right after forking the daemon
+      unsafe { libc::waitpid(child, std::ptr::null_mut(), 0) };
+      match pid.trim() {
+        "" => {
+          match log {
+            Some(path) => eprintln!("the server exited during startup; see {}", path.display()),
+            None => eprintln!("the server exited during startup; rerun with --log-file or without --daemonize"),
+          }
+          std::process::exit(1);
+        }
+        pid => {
+          println!("running in the background as pid {pid}");
+          std::process::exit(0);
+        }
+      }
+    }
+  }
+  drop(started);
+  // a new session without a controlling terminal; the second fork makes
+  // sure the daemon, no longer a session leader, never gets one again
+  if unsafe { libc::setsid() } == -1 {
+    return Err(std::io::Error::last_os_error()).context("setsid");
+  }
+  match unsafe { libc::fork() } {
+    -1 => return Err(std::io::Error::last_os_error()).context("fork"),
+    0 => {}
+    _ => unsafe { libc::_exit(0) },
+  }
+  for (from, to) in [(null.as_raw_fd(), 0), (output.as_raw_fd(), 1), (output.as_raw_fd(), 2)] {
+    if unsafe { libc::dup2(from, to) } == -1 {
+      return Err(std::io::Error::last_os_error()).context("redirect stdin/stdout/stderr");
+    }
+  }
+  Ok((Pidfile::create(pidfile)?, Ready(ready)))
+}
+
+#[cfg(not(unix))]
+pub fn daemonize(_pidfile: &Path, _log: Option<&Path>) -> Result<(Pidfile, Ready)> {
+  anyhow::bail!("--daemonize needs a Unix system")
+}
+
+/// Send SIGTERM to the process in `pidfile` and wait up to `wait` for it to
+/// exit. A pidfile whose process is gone is removed.
+#[cfg(unix)]
+pub fn stop(pidfile: &Path, wait: Duration) -> Result<()> {
+  let pid = read_pid(pidfile).with_context(|| format!("no pid in {}", pidfile.display()))?;
+  if !alive(pid) {
+    let _ = fs::remove_file(pidfile);
+    println!("pid {pid} is not running; removed the stale {}", pidfile.display());
+    return Ok(());
+  }
+  let target = libc::pid_t::try_from(pid).context("pid out of range")?;
+  if unsafe { libc::kill(target, libc::SIGTERM) } == -1 {
+    return Err(std::io::Error::last_os_error()).with_context(|| format!("signal pid {pid}"));
+  }
+  let deadline = std::time::Instant::now() + wait;
+  while alive(pid) {
+    anyhow::ensure!(std::time::Instant::now() < deadline, "pid {pid} is still running {wait:?} after SIGTERM");
+    std::thread::sleep(STOP_POLL);
+  }
+  println!("stopped pid {pid}");
+  Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn stop(_pidfile: &Path, _wait: Duration) -> Result<()> {
+  anyhow::bail!("--stop needs a Unix system")
+}
+
+#[cfg(unix)]
+fn alive(pid: u32) -> bool {
+  let Ok(pid) = libc::pid_t::try_from(pid) else {
+    return false;
+  };
+  // signal 0 only checks; EPERM means it exists under another user
+  let rc = unsafe { libc::kill(pid, 0) };
+  rc == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn alive(_pid: u32) -> bool {
+  false
+}
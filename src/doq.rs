@@ -0,0 +1,111 @@
+//! DNS-over-QUIC (RFC 9250) stub responder and query, for checking that DoQ
+//! traffic gets through to a host. Each query travels on its own
+//! bidirectional stream with a 2-byte length prefix and message ID 0.
+
+use crate::flow;
+use anyhow::{Context, Result};
+use hickory_resolver::proto::{
+  op::{Message, MessageType, OpCode, Query, ResponseCode},
+  rr::{rdata, Name, RData, Record, RecordType},
+};
+use quinn::{ConnectionError, VarInt};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use tracing::{info, warn, Instrument};
+
+pub const ALPN: &[u8] = b"doq";
+
+/// DOQ_PROTOCOL_ERROR, for malformed queries.
+const PROTOCOL_ERROR: u32 = 0x2;
+const STUB_TTL: u32 = 60;
+
+/// Answer every query on `conn` with the stub response until the client goes away.
+pub async fn serve(conn: quinn::Connection) -> Result<()> {
+  loop {
+    let (mut send, mut recv) = match conn.accept_bi().await {
+      Ok(s) => s,
+      Err(ConnectionError::ApplicationClosed { .. } | ConnectionError::LocallyClosed) => {
+        return Ok(())
+      }
+      Err(e) => return Err(e.into()),
+    };
+
+    let conn = conn.clone();
+    tokio::spawn(
+      async move {
+        let res = async {
+          let query = read_message(&mut recv).await?;
+          let response = stub_response(&query);
+          info!(
+            query = %query.queries().first().map_or_else(|| "<none>".into(), |q| q.to_string()),
+            rcode = %response.response_code(),
+            "DoQ query"
+          );
+          write_message(&mut send, &response).await
+        };
+        if let Err(e) = res.await {
+          warn!("DoQ query failed: {e:#}");
+          conn.close(VarInt::from_u32(PROTOCOL_ERROR), b"malformed query");
+        }
+      }
+      .in_current_span(),
+    );
+  }
+}
+
+/// A and AAAA get loopback addresses, other types an empty NOERROR.
+fn stub_response(query: &Message) -> Message {
+  let mut response = Message::error_msg(query.id(), query.op_code(), ResponseCode::NoError);
+  response.set_authoritative(true).set_recursion_desired(query.recursion_desired());
+  if query.op_code() != OpCode::Query || query.queries().len() != 1 {
+    response.set_response_code(ResponseCode::NotImp);
+    return response;
+  }
+
+  let q = &query.queries()[0];
+  response.add_query(q.clone());
+  let rdata = match q.query_type() {
+    RecordType::A => Some(RData::A(rdata::A(Ipv4Addr::LOCALHOST))),
+    RecordType::AAAA => Some(RData::AAAA(rdata::AAAA(Ipv6Addr::LOCALHOST))),
+    _ => None,
+  };
+  if let Some(rdata) = rdata {
+    response.add_answer(Record::from_rdata(q.name().clone(), STUB_TTL, rdata));
+  }
+  response
+}
+
+/// Send one `name`/`rtype` query on a new stream and return the response.
+pub async fn query(conn: &quinn::Connection, name: &str, rtype: RecordType) -> Result<Message> {
+  let name = Name::from_ascii(name).with_context(|| format!("invalid DNS name {name:?}"))?;
+  let mut query = Message::new();
+  query
+    .set_id(0)
+    .set_message_type(MessageType::Query)
+    .set_op_code(OpCode::Query)
+    .set_recursion_desired(true)
+    .add_query(Query::query(name, rtype));
+
+  let (mut send, mut recv) = flow::open_bi(conn).await?;
+  write_message(&mut send, &query).await?;
+  read_message(&mut recv).await
+}
+
+/// Writes the length-prefixed message and finishes the stream.
+async fn write_message(send: &mut quinn::SendStream, msg: &Message) -> Result<()> {
+  let body = msg.to_vec()?;
+  let len = u16::try_from(body.len()).context("DNS message too large")?;
+  send.write_all(&len.to_be_bytes()).await?;
+  send.write_all(&body).await?;
+  send.finish()?;
+  Ok(())
+}
+
+async fn read_message(recv: &mut quinn::RecvStream) -> Result<Message> {
+  let mut len = [0u8; 2];
+  recv.read_exact(&mut len).await.context("read length prefix")?;
+  let mut body = vec![0; usize::from(u16::from_be_bytes(len))];
+  recv.read_exact(&mut body).await.context("read DNS message")?;
+  let msg = Message::from_vec(&body).context("parse DNS message")?;
+  anyhow::ensure!(msg.id() == 0, "DoQ message ID must be 0, got {}", msg.id());
+  Ok(msg)
+}
@@ -0,0 +1,399 @@
+//! DSCP marking for both binaries (`--dscp`), to see how a network treats
+//! QUIC flows of a given class. The value goes into IP_TOS / IPV6_TCLASS of
+//! the endpoint's socket, ECN bits left to quinn.
+//!
+//! The socket option alone is not enough with quinn's own socket:
+//! quinn-udp attaches an IP_TOS / IPV6_TCLASS control message carrying just
+//! the ECN bits to every `sendmsg`, which overrides it. So on Linux
+//! [`mark`] wraps that socket and sends through `sendmsg` itself, with
+//! DSCP and ECN in one control message (plus quinn's segment size and
+//! source address). The io_uring backend sends without control messages
+//! and only needs the option.
+//!
+//! quinn-udp also keeps only the ECN bits of what arrives. The wrapper
+//! peeks at the next datagram's TOS before each receive and counts the
+//! datagrams the receive returns under that DSCP, a sample that is exact
+//! unless one receive batch mixes markings. The counts are logged with the
+//! UDP offload stats at the end ("DSCP of received packets"); with the
+//! io_uring backend nothing is observed.
+
+use crate::uring::IoBackend;
+use quinn::AsyncUdpSocket;
+use std::{
+  fmt, io,
+  sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+  },
+};
+use tracing::info;
+
+/// Per-hop behaviour names (RFC 2474, 2597, 3246, 5865, 8622).
+const NAMES: [(&str, u8); 23] = [
+  ("cs0", 0),
+  ("le", 1),
+  ("cs1", 8),
+  ("af11", 10),
+  ("af12", 12),
+  ("af13", 14),
+  ("cs2", 16),
+  ("af21", 18),
+  ("af22", 20),
+  ("af23", 22),
+  ("cs3", 24),
+  ("af31", 26),
+  ("af32", 28),
+  ("af33", 30),
+  ("cs4", 32),
+  ("af41", 34),
+  ("af42", 36),
+  ("af43", 38),
+  ("cs5", 40),
+  ("va", 44),
+  ("ef", 46),
+  ("cs6", 48),
+  ("cs7", 56),
+];
+
+/// A DSCP given as 0-63 or by name (`ef`, `af41`, `cs1`, ...).
+pub fn parse(s: &str) -> Result<u8, String> {
+  let lower = s.to_ascii_lowercase();
+  if let Some(&(_, dscp)) = NAMES.iter().find(|(name, _)| *name == lower) {
+    return Ok(dscp);
+  }
+  match s.parse::<u8>() {
+    Ok(dscp) if dscp < 64 => Ok(dscp),
+    _ => Err(format!("expected a DSCP of 0-63 or a name like ef, af41 or cs1, got {s:?}")),
+  }
+}
+
+/// A DSCP with its name, if it has one: `EF (46)`.
+#[derive(Clone, Copy, Debug)]
+pub struct Dscp(pub u8);
+
+impl fmt::Display for Dscp {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match NAMES.iter().find(|(_, dscp)| *dscp == self.0) {
+      Some((name, dscp)) => write!(f, "{} ({dscp})", name.to_ascii_uppercase()),
+      None => write!(f, "{}", self.0),
+    }
+  }
+}
+
+/// Datagrams received per DSCP.
+#[derive(Debug)]
+pub struct Marks([AtomicU64; 64]);
+
+impl Default for Marks {
+  fn default() -> Self {
+    Self(std::array::from_fn(|_| AtomicU64::new(0)))
+  }
+}
+
+impl Marks {
+  fn record(&self, dscp: u8, datagrams: u64) {
+    self.0[usize::from(dscp & 0x3f)].fetch_add(datagrams, Ordering::Relaxed);
+  }
+
+  /// Log the markings seen, if any, in the current span.
+  pub fn log(&self) {
+    let seen: Vec<String> = (0..64u8)
+      .filter_map(|dscp| {
+        let count = self.0[usize::from(dscp)].load(Ordering::Relaxed);
+        (count > 0).then(|| format!("{} x {count}", Dscp(dscp)))
+      })
+      .collect();
+    if !seen.is_empty() {
+      info!(marks = %seen.join(", "), "DSCP of received packets");
+    }
+  }
+}
+
+/// Mark everything `inner` sends with `dscp`; `socket` is another handle
+/// to the socket behind it. With quinn's tokio socket the returned one
+/// also counts the markings of received datagrams in `marks`.
+#[cfg(target_os = "linux")]
+pub fn mark(
+  inner: Arc<dyn AsyncUdpSocket>,
+  socket: std::net::UdpSocket,
+  dscp: u8,
+  backend: IoBackend,
+  marks: &Arc<Marks>,
+) -> io::Result<Arc<dyn AsyncUdpSocket>> {
+  let tos = dscp << 2;
+  linux::set_tos(&socket, tos)?;
+  let observed = backend == IoBackend::Tokio;
+  info!(dscp = %Dscp(dscp), observed, "DSCP marking");
+  if !observed {
+    return Ok(inner);
+  }
+  Ok(Arc::new(linux::Marked::new(inner, socket, tos, marks.clone())?))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn mark(
+  _inner: Arc<dyn AsyncUdpSocket>,
+  _socket: std::net::UdpSocket,
+  _dscp: u8,
+  _backend: IoBackend,
+  _marks: &Arc<Marks>,
+) -> io::Result<Arc<dyn AsyncUdpSocket>> {
+  Err(io::Error::new(io::ErrorKind::Unsupported, "--dscp is only supported on Linux"))
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+  use super::Marks;
+  use quinn::{
+    AsyncUdpSocket, UdpPoller,
+    udp::{RecvMeta, Transmit},
+  };
+  use socket2::SockAddr;
+  use std::{
+    fmt,
+    io::{self, IoSliceMut},
+    mem,
+    net::{IpAddr, SocketAddr},
+    os::fd::AsRawFd,
+    pin::Pin,
+    ptr,
+    sync::{
+      Arc,
+      atomic::{AtomicBool, Ordering},
+    },
+    task::{Context, Poll},
+  };
+  use tracing::{debug, info};
+
+  /// Room for the TOS, segment size and source address control messages.
+  const CONTROL_LEN: usize = 128;
+
+  #[repr(align(8))]
+  struct Control([u8; CONTROL_LEN]);
+
+  /// Set IP_TOS, and IPV6_TCLASS on an IPv6 socket, to `tos`.
+  pub fn set_tos(socket: &std::net::UdpSocket, tos: u8) -> io::Result<()> {
+    let set = |level, name| {
+      let value = libc::c_int::from(tos);
+      // SAFETY: an int option on a socket we own
+      let rc = unsafe {
+        libc::setsockopt(
+          socket.as_raw_fd(),
+          level,
+          name,
+          (&raw const value).cast(),
+          mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+      };
+      if rc == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+    };
+    if socket.local_addr()?.is_ipv6() {
+      set(libc::IPPROTO_IPV6, libc::IPV6_TCLASS)?;
+      // IPv4 peers of a dual-stack socket; fails on an IPv6-only one
+      let _ = set(libc::IPPROTO_IP, libc::IP_TOS);
+      Ok(())
+    } else {
+      set(libc::IPPROTO_IP, libc::IP_TOS)
+    }
+  }
+
+  /// Control messages appended to a [`Control`] buffer.
+  struct Encoder<'a> {
+    control: &'a mut Control,
+    len: usize,
+  }
+
+  impl Encoder<'_> {
+    fn push<T: Copy>(&mut self, level: libc::c_int, kind: libc::c_int, value: T) {
+      // SAFETY: pure size computations
+      let (space, len) = unsafe {
+        (libc::CMSG_SPACE(mem::size_of::<T>() as u32) as usize, libc::CMSG_LEN(mem::size_of::<T>() as u32))
+      };
+      assert!(self.len + space <= CONTROL_LEN, "control messages exceed their buffer");
+      // SAFETY: the header and value fit the buffer at an offset aligned
+      // like the previous messages, and the buffer is aligned for the header
+      unsafe {
+        let cmsg = self.control.0.as_mut_ptr().add(self.len).cast::<libc::cmsghdr>();
+        (*cmsg).cmsg_level = level;
+        (*cmsg).cmsg_type = kind;
+        (*cmsg).cmsg_len = len as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<T>(), value);
+      }
+      self.len += space;
+    }
+  }
+
+  /// quinn's socket with our own sends, marked with the DSCP.
+  pub struct Marked {
+    inner: Arc<dyn AsyncUdpSocket>,
+    socket: std::net::UdpSocket,
+    ipv6: bool,
+    /// DSCP in the upper six bits; quinn's ECN goes in the lower two
+    tos: u8,
+    /// `sendmsg` refused a segmented send, so quinn gets no more
+    gso_failed: AtomicBool,
+    marks: Arc<Marks>,
+  }
+
+  impl fmt::Debug for Marked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      f.debug_struct("Marked").field("inner", &self.inner).field("tos", &self.tos).finish_non_exhaustive()
+    }
+  }
+
+  impl Marked {
+    pub fn new(inner: Arc<dyn AsyncUdpSocket>, socket: std::net::UdpSocket, tos: u8, marks: Arc<Marks>) -> io::Result<Self> {
+      let ipv6 = socket.local_addr()?.is_ipv6();
+      Ok(Self { inner, socket, ipv6, tos, gso_failed: AtomicBool::new(false), marks })
+    }
+
+    /// Send `transmit` the way quinn-udp does, but with the DSCP.
+    fn send(&self, transmit: &Transmit) -> io::Result<()> {
+      let destination = match transmit.destination {
+        SocketAddr::V4(v4) if self.ipv6 => SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()),
+        destination => destination,
+      };
+      let to_ipv4 = match destination.ip() {
+        IpAddr::V4(_) => true,
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().is_some(),
+      };
+      let addr = SockAddr::from(destination);
+      let mut control = Control([0; CONTROL_LEN]);
+      let mut encoder = Encoder { control: &mut control, len: 0 };
+      let tos = libc::c_int::from(self.tos | transmit.ecn.map_or(0, |ecn| ecn as u8));
+      if to_ipv4 {
+        encoder.push(libc::IPPROTO_IP, libc::IP_TOS, tos);
+      } else {
+        encoder.push(libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos);
+      }
+      if let Some(segment) = transmit.segment_size.filter(|&segment| segment < transmit.contents.len()) {
+        encoder.push(libc::SOL_UDP, libc::UDP_SEGMENT, segment as u16);
+      }
+      match transmit.src_ip {
+        Some(IpAddr::V4(ip)) => encoder.push(
+          libc::IPPROTO_IP,
+          libc::IP_PKTINFO,
+          libc::in_pktinfo {
+            ipi_ifindex: 0,
+            ipi_spec_dst: libc::in_addr { s_addr: u32::from_ne_bytes(ip.octets()) },
+            ipi_addr: libc::in_addr { s_addr: 0 },
+          },
+        ),
+        Some(IpAddr::V6(ip)) => encoder.push(
+          libc::IPPROTO_IPV6,
+          libc::IPV6_PKTINFO,
+          libc::in6_pktinfo { ipi6_ifindex: 0, ipi6_addr: libc::in6_addr { s6_addr: ip.octets() } },
+        ),
+        None => {}
+      }
+      let control_len = encoder.len;
+
+      let mut iov = libc::iovec { iov_base: transmit.contents.as_ptr() as *mut libc::c_void, iov_len: transmit.contents.len() };
+      // SAFETY: all-zero is a valid msghdr
+      let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+      hdr.msg_name = addr.as_ptr() as *mut libc::c_void;
+      hdr.msg_namelen = addr.len();
+      hdr.msg_iov = &raw mut iov;
+      hdr.msg_iovlen = 1;
+      hdr.msg_control = control.0.as_mut_ptr().cast();
+      hdr.msg_controllen = control_len as _;
+      loop {
+        // SAFETY: `hdr` points at the address, payload and control messages, all alive for the call
+        if unsafe { libc::sendmsg(self.socket.as_raw_fd(), &hdr, libc::MSG_DONTWAIT) } >= 0 {
+          return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        match e.kind() {
+          io::ErrorKind::Interrupted => continue,
+          io::ErrorKind::WouldBlock => return Err(e),
+          // like quinn-udp, a lost datagram is no error; MTU probes are expected to be too big
+          _ => {
+            if transmit.segment_size.is_some()
+              && matches!(e.raw_os_error(), Some(libc::EIO | libc::EINVAL))
+              && !self.gso_failed.swap(true, Ordering::Relaxed)
+            {
+              info!("sendmsg failed with {e}; halting segmentation offload");
+            } else if e.raw_os_error() != Some(libc::EMSGSIZE) {
+              debug!(destination = %transmit.destination, "sendmsg failed: {e}");
+            }
+            return Ok(());
+          }
+        }
+      }
+    }
+  }
+
+  /// The TOS byte of the next datagram waiting on `socket`, which stays
+  /// queued.
+  fn peek_tos(socket: &std::net::UdpSocket) -> Option<u8> {
+    let mut control = Control([0; CONTROL_LEN]);
+    // SAFETY: all-zero is a valid msghdr
+    let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+    hdr.msg_control = control.0.as_mut_ptr().cast();
+    hdr.msg_controllen = CONTROL_LEN as _;
+    // no buffer: only the control messages are copied out
+    // SAFETY: `hdr` points at the control buffer, alive for the call
+    if unsafe { libc::recvmsg(socket.as_raw_fd(), &mut hdr, libc::MSG_PEEK | libc::MSG_DONTWAIT) } < 0 {
+      return None;
+    }
+    // SAFETY: the kernel filled in `msg_controllen` bytes of control messages
+    unsafe {
+      let mut cmsg = libc::CMSG_FIRSTHDR(&hdr);
+      while !cmsg.is_null() {
+        match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+          (libc::IPPROTO_IP, libc::IP_TOS) => return Some(*libc::CMSG_DATA(cmsg)),
+          (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+            return Some(ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<libc::c_int>()) as u8);
+          }
+          _ => cmsg = libc::CMSG_NXTHDR(&hdr, cmsg),
+        }
+      }
+    }
+    None
+  }
+
+  impl AsyncUdpSocket for Marked {
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+      self.inner.clone().create_io_poller()
+    }
+
+    fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+      match self.send(transmit) {
+        // quinn's socket must see the full buffer itself, or its poller
+        // never waits for room; should there be room again by now, this
+        // one datagram leaves unmarked
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => self.inner.try_send(transmit),
+        sent => sent,
+      }
+    }
+
+    fn poll_recv(&self, cx: &mut Context, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) -> Poll<io::Result<usize>> {
+      let tos = peek_tos(&self.socket);
+      let n = std::task::ready!(self.inner.poll_recv(cx, bufs, meta))?;
+      if let Some(tos) = tos {
+        let datagrams = meta[..n]
+          .iter()
+          .map(|meta| meta.len.div_ceil(if meta.stride == 0 { meta.len } else { meta.stride }.max(1)))
+          .sum::<usize>();
+        self.marks.record(tos >> 2, datagrams as u64);
+      }
+      Poll::Ready(Ok(n))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+      self.inner.local_addr()
+    }
+
+    fn max_transmit_segments(&self) -> usize {
+      if self.gso_failed.load(Ordering::Relaxed) { 1 } else { self.inner.max_transmit_segments() }
+    }
+
+    fn max_receive_segments(&self) -> usize {
+      self.inner.max_receive_segments()
+    }
+
+    fn may_fragment(&self) -> bool {
+      self.inner.may_fragment()
+    }
+  }
+}
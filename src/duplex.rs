@@ -0,0 +1,134 @@
+//! Full-duplex transfer test (ALPN "quic-echo-duplex"): client and server
+//! send bulk data to each other at the same time on one bidirectional
+//! stream, and each direction's throughput is measured on its own.
+//!
+//! ```text
+//! request:  size the server should send (u64) | client's upload until FIN
+//! response: that many bytes, sent while the upload is still arriving
+//! ```
+//!
+//! Unlike the perf protocol, where the server drains the upload before it
+//! answers, both directions run concurrently from the first byte, so flow
+//! control, ACKs and congestion control of the two directions interact.
+
+use crate::{flow, pace::RateLimit, perf};
+use anyhow::{Context, Result};
+use quinn::ConnectionError;
+use std::time::{Duration, Instant};
+use tracing::{info, warn, Instrument};
+
+pub const ALPN: &[u8] = b"quic-echo-duplex";
+
+/// Serve duplex requests on `conn` until the client goes away.
+pub async fn serve(conn: quinn::Connection) -> Result<()> {
+  loop {
+    let (send, recv) = match conn.accept_bi().await {
+      Ok(s) => s,
+      Err(ConnectionError::ApplicationClosed { .. } | ConnectionError::LocallyClosed) => return Ok(()),
+      Err(e) => return Err(e.into()),
+    };
+    tokio::spawn(
+      async move {
+        if let Err(e) = respond(send, recv).await {
+          warn!("duplex stream failed: {e:#}");
+        }
+      }
+      .in_current_span(),
+    );
+  }
+}
+
+async fn respond(mut send: quinn::SendStream, mut recv: quinn::RecvStream) -> Result<()> {
+  let mut header = [0u8; 8];
+  recv.read_exact(&mut header).await.context("read duplex request header")?;
+  let size = u64::from_be_bytes(header);
+  let start = Instant::now();
+  let sending = async {
+    perf::send_zeros(&mut send, size, None).await?;
+    send.finish()?;
+    anyhow::Ok(start.elapsed())
+  };
+  let receiving = async {
+    let mut total = 0;
+    while let Some(chunk) = recv.read_chunk(usize::MAX, false).await? {
+      total += chunk.bytes.len() as u64;
+    }
+    anyhow::Ok((total, start.elapsed()))
+  };
+  let (sent, received) = tokio::join!(sending, receiving);
+  let (uploaded, up_took) = received?;
+  let down_took = sent?;
+  info!(
+    received = uploaded,
+    receive_mbps = format_args!("{:.2}", mbps(uploaded, up_took)),
+    sent = size,
+    send_mbps = format_args!("{:.2}", mbps(size, down_took)),
+    "duplex stream done"
+  );
+  Ok(())
+}
+
+/// Outcome of one duplex transfer, as the client saw it.
+#[derive(Debug)]
+pub struct DuplexRun {
+  pub upload: u64,
+  /// Until the server had acknowledged all of the upload
+  pub upload_took: Duration,
+  pub download: u64,
+  /// Until the server's FIN arrived
+  pub download_took: Duration,
+  /// How long both directions were moving data at once
+  pub overlap: Duration,
+}
+
+impl DuplexRun {
+  pub fn upload_mbps(&self) -> f64 {
+    mbps(self.upload, self.upload_took)
+  }
+
+  pub fn download_mbps(&self) -> f64 {
+    mbps(self.download, self.download_took)
+  }
+}
+
+fn mbps(bytes: u64, took: Duration) -> f64 {
+  bytes as f64 * 8.0 / took.as_secs_f64().max(f64::EPSILON) / 1e6
+}
+
+/// Upload `upload` bytes (under `limit`, if given) while downloading
+/// `download` bytes on the same stream.
+pub async fn run(conn: &quinn::Connection, upload: u64, download: u64, limit: Option<&RateLimit>) -> Result<DuplexRun> {
+  let start = Instant::now();
+  let (mut send, mut recv) = flow::open_bi(conn).await?;
+
+  let sending = async {
+    send.write_all(&download.to_be_bytes()).await?;
+    perf::send_zeros(&mut send, upload, limit).await?;
+    send.finish()?;
+    // resolves once everything we sent has been acknowledged
+    if let Some(code) = send.stopped().await? {
+      anyhow::bail!("server stopped the upload with code {code}");
+    }
+    anyhow::Ok(start.elapsed())
+  };
+  let receiving = async {
+    let (mut first, mut total) = (None, 0);
+    while let Some(chunk) = recv.read_chunk(usize::MAX, false).await? {
+      first.get_or_insert_with(|| start.elapsed());
+      total += chunk.bytes.len() as u64;
+    }
+    anyhow::Ok((total, first.unwrap_or_default(), start.elapsed()))
+  };
+  let (sent, received) = tokio::join!(sending, receiving);
+  let upload_took = sent?;
+  let (total, first_byte, download_took) = received?;
+  anyhow::ensure!(total == download, "asked for {download} bytes, got {total}");
+
+  Ok(DuplexRun {
+    upload,
+    upload_took,
+    download,
+    download_took,
+    overlap: upload_took.min(download_took).saturating_sub(first_byte),
+  })
+}
@@ -0,0 +1,88 @@
+//! Flow-control and stream-limit flags shared by both binaries.
+//!
+//! quinn's defaults size the per-stream receive window for roughly 100 Mbit/s
+//! at 100 ms RTT, which caps a single stream well below line rate on
+//! high bandwidth-delay paths. Each flag left unset keeps quinn's default.
+//!
+//! [`open_bi`] and [`open_uni`] open streams for the client and log when the
+//! peer's stream limit leaves it waiting for credit, to make stream
+//! starvation visible.
+
+use clap::Args;
+use quinn::{Connection, ConnectionError, RecvStream, SendStream, TransportConfig, VarInt};
+use std::{
+  future::Future,
+  time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct FlowControl {
+  /// Bytes the peer may send on one stream before we grant more (stream_receive_window)
+  #[clap(long, env = "QUIC_ECHO_STREAM_WINDOW", value_parser = parse_varint)]
+  pub stream_window: Option<VarInt>,
+  /// Bytes the peer may send across all streams before we grant more (receive_window)
+  #[clap(long, env = "QUIC_ECHO_CONN_WINDOW", value_parser = parse_varint)]
+  pub conn_window: Option<VarInt>,
+  /// Bidirectional streams the peer may have open at once
+  #[clap(long, alias = "max-bidi-streams", env = "QUIC_ECHO_MAX_CONCURRENT_BIDI", value_parser = parse_varint)]
+  pub max_concurrent_bidi: Option<VarInt>,
+  /// Unidirectional streams the peer may have open at once
+  #[clap(long, alias = "max-uni-streams", env = "QUIC_ECHO_MAX_CONCURRENT_UNI", value_parser = parse_varint)]
+  pub max_concurrent_uni: Option<VarInt>,
+}
+
+impl FlowControl {
+  /// Apply the flags that were given to `transport`.
+  pub fn apply(&self, transport: &mut TransportConfig) {
+    if let Some(window) = self.stream_window {
+      transport.stream_receive_window(window);
+    }
+    if let Some(window) = self.conn_window {
+      transport.receive_window(window);
+    }
+    if let Some(max) = self.max_concurrent_bidi {
+      transport.max_concurrent_bidi_streams(max);
+    }
+    if let Some(max) = self.max_concurrent_uni {
+      transport.max_concurrent_uni_streams(max);
+    }
+  }
+
+  /// A stream window larger than the connection window can never be used.
+  pub fn check(&self) -> anyhow::Result<()> {
+    if let (Some(stream), Some(conn)) = (self.stream_window, self.conn_window) {
+      anyhow::ensure!(stream <= conn, "--stream-window ({stream}) exceeds --conn-window ({conn})");
+    }
+    Ok(())
+  }
+}
+
+/// `Connection::open_bi`, logging if the peer's stream limit makes us wait.
+pub async fn open_bi(conn: &Connection) -> Result<(SendStream, RecvStream), ConnectionError> {
+  credit(conn.open_bi(), "bidirectional").await
+}
+
+/// `Connection::open_uni`, logging if the peer's stream limit makes us wait.
+pub async fn open_uni(conn: &Connection) -> Result<SendStream, ConnectionError> {
+  credit(conn.open_uni(), "unidirectional").await
+}
+
+/// Await `open`, which is only pending while the peer grants no more streams.
+async fn credit<T>(open: impl Future<Output = T>, kind: &str) -> T {
+  tokio::pin!(open);
+  // a zero timeout still polls the future once
+  if let Ok(opened) = tokio::time::timeout(Duration::ZERO, &mut open).await {
+    return opened;
+  }
+  let start = Instant::now();
+  warn!("blocked on {kind} stream credit: the peer's stream limit is reached");
+  let opened = open.await;
+  info!(waited = ?start.elapsed(), "got {kind} stream credit");
+  opened
+}
+
+fn parse_varint(s: &str) -> Result<VarInt, String> {
+  let n: u64 = s.parse().map_err(|e| format!("{e}"))?;
+  VarInt::from_u64(n).map_err(|_| format!("{n} is larger than the QUIC maximum {}", VarInt::MAX))
+}
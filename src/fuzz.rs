@@ -0,0 +1,194 @@
+//! Malformed-input mode for the client (`--fuzz`): a seeded random mix of
+//! what a well-behaved peer rarely does to the echo server — datagrams of
+//! random (including zero and maximum) size, streams written in
+//! pathological pieces (zero-length and one-byte writes, huge chunks),
+//! streams reset mid-write or stopped before their echo is read, streams
+//! finished without data, unidirectional streams the server never reads,
+//! and bursts of streams abandoned right after opening. Afterwards the
+//! server must still echo a ping on the same connection.
+//!
+//! The seed is logged and printed, and `--seed` replays the same sequence
+//! of actions (timing, and therefore the server's side, may differ).
+
+use crate::flow;
+use crate::verify;
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use quinn::{Connection, VarInt};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use std::time::Duration;
+use tracing::{debug, info};
+
+/// Largest stream written by one action.
+const MAX_STREAM: usize = 256 * 1024;
+/// Application error code of our resets and STOP_SENDINGs.
+const FUZZ_CODE: u32 = 0xf0;
+
+#[derive(Clone, Copy, Debug)]
+enum Action {
+  Datagram,
+  Stream,
+  EmptyStream,
+  ResetStream,
+  StopSending,
+  UniStream,
+  AbandonedStreams,
+}
+
+const ACTIONS: [Action; 7] = [
+  Action::Datagram,
+  Action::Stream,
+  Action::EmptyStream,
+  Action::ResetStream,
+  Action::StopSending,
+  Action::UniStream,
+  Action::AbandonedStreams,
+];
+
+/// What a fuzz run did.
+#[derive(Debug)]
+pub struct Fuzz {
+  pub seed: u64,
+  /// Count per action, by name
+  pub actions: Vec<(&'static str, u32)>,
+  /// Echoes compared byte for byte
+  pub verified: u32,
+}
+
+impl Action {
+  fn name(self) -> &'static str {
+    match self {
+      Action::Datagram => "datagram",
+      Action::Stream => "stream",
+      Action::EmptyStream => "empty stream",
+      Action::ResetStream => "reset stream",
+      Action::StopSending => "stop sending",
+      Action::UniStream => "uni stream",
+      Action::AbandonedStreams => "abandoned streams",
+    }
+  }
+}
+
+/// Run `iterations` random actions from `seed` against the echo server on
+/// `conn`, waiting up to `timeout` for each echo that is read.
+pub async fn run(conn: &Connection, seed: u64, iterations: u32, timeout: Duration) -> Result<Fuzz> {
+  info!(seed, iterations, "fuzzing");
+  let mut rng = StdRng::seed_from_u64(seed);
+  let mut counts = [0u32; ACTIONS.len()];
+  let mut verified = 0;
+
+  for n in 0..iterations {
+    let pick = rng.random_range(0..ACTIONS.len());
+    let action = ACTIONS[pick];
+    counts[pick] += 1;
+    debug!(n, action = action.name(), "fuzz action");
+    let res = async {
+      match action {
+        Action::Datagram => {
+          let max = conn.max_datagram_size().context("peer does not support datagrams")?;
+          let size = match rng.random_range(0..4) {
+            0 => 0,
+            1 => max,
+            _ => rng.random_range(0..=max),
+          };
+          conn.send_datagram(random_bytes(&mut rng, size))?;
+        }
+        Action::Stream => {
+          let size = rng.random_range(0..=MAX_STREAM);
+          let data = random_bytes(&mut rng, size);
+          let (mut send, mut recv) = open_bi(conn, timeout).await?;
+          let mut rest = &data[..];
+          while !rest.is_empty() {
+            // zero-length, single-byte and arbitrary writes
+            let len = match rng.random_range(0..4) {
+              0 => 0,
+              1 => 1,
+              _ => rng.random_range(1..=rest.len()),
+            };
+            send.write_all(&rest[..len]).await?;
+            rest = &rest[len..];
+          }
+          send.finish()?;
+          let echo = tokio::time::timeout(timeout, recv.read_to_end(MAX_STREAM))
+            .await
+            .context("no stream echo")??;
+          verify::compare("stream echo", &data, &echo)?;
+          verified += 1;
+        }
+        Action::EmptyStream => {
+          let (mut send, mut recv) = open_bi(conn, timeout).await?;
+          send.write_all(&[]).await?;
+          send.finish()?;
+          let echo = tokio::time::timeout(timeout, recv.read_to_end(1)).await.context("no stream echo")??;
+          verify::compare("empty stream echo", &[], &echo)?;
+          verified += 1;
+        }
+        Action::ResetStream => {
+          let (mut send, _recv) = open_bi(conn, timeout).await?;
+          let size = rng.random_range(0..=MAX_STREAM / 4);
+          send.write_all(&random_bytes(&mut rng, size)).await?;
+          send.reset(VarInt::from_u32(FUZZ_CODE))?;
+        }
+        Action::StopSending => {
+          let (mut send, mut recv) = open_bi(conn, timeout).await?;
+          recv.stop(VarInt::from_u32(FUZZ_CODE))?;
+          let size = rng.random_range(0..=MAX_STREAM / 4);
+          send.write_all(&random_bytes(&mut rng, size)).await?;
+          send.finish()?;
+        }
+        Action::UniStream => {
+          let mut send = tokio::time::timeout(timeout, flow::open_uni(conn))
+            .await
+            .context("server stopped granting unidirectional streams")??;
+          let size = rng.random_range(0..=4096);
+          send.write_all(&random_bytes(&mut rng, size)).await?;
+          send.finish()?;
+        }
+        Action::AbandonedStreams => {
+          for _ in 0..rng.random_range(1..=16) {
+            // dropping both halves finishes the send side and stops the receive side
+            let (mut send, _recv) = open_bi(conn, timeout).await?;
+            send.write_all(&[rng.random()]).await?;
+          }
+        }
+      }
+      anyhow::Ok(())
+    }
+    .await;
+    if let Err(e) = res {
+      if let Some(reason) = conn.close_reason() {
+        return Err(anyhow::Error::from(reason))
+          .with_context(|| format!("connection lost at action {n} ({}) with seed {seed}", action.name()));
+      }
+      return Err(e.context(format!("action {n} ({}) with seed {seed}", action.name())));
+    }
+  }
+
+  // the server must have survived all of it
+  let (mut send, mut recv) = open_bi(conn, timeout).await?;
+  send.write_all(b"ping").await?;
+  send.finish()?;
+  let echo = tokio::time::timeout(timeout, recv.read_to_end(4))
+    .await
+    .with_context(|| format!("server stopped echoing after fuzzing with seed {seed}"))??;
+  verify::compare("final ping", b"ping", &echo)?;
+
+  let actions = ACTIONS.iter().zip(counts).map(|(a, count)| (a.name(), count)).collect();
+  Ok(Fuzz { seed, actions, verified })
+}
+
+/// Open a bidirectional stream, failing instead of waiting forever if the
+/// server stops granting them.
+async fn open_bi(conn: &Connection, timeout: Duration) -> Result<(quinn::SendStream, quinn::RecvStream)> {
+  Ok(
+    tokio::time::timeout(timeout, flow::open_bi(conn))
+      .await
+      .context("server stopped granting bidirectional streams")??,
+  )
+}
+
+fn random_bytes(rng: &mut StdRng, len: usize) -> Bytes {
+  let mut data = vec![0; len];
+  rng.fill(&mut data[..]);
+  data.into()
+}
@@ -0,0 +1,99 @@
+//! Country/ASN annotation of client addresses for the server (`--geoip
+//! <mmdb>`, needs the `geoip` feature): MaxMind databases (GeoLite2/GeoIP2
+//! Country, City or ASN, or anything with the same fields) are loaded once
+//! and every connection's remote address is looked up, so a public server's
+//! logs can be grouped by origin network.
+//!
+//! Several databases can be given (typically a Country and an ASN one); each
+//! field comes from the first database that has it.
+
+use anyhow::Result;
+use serde::Serialize;
+use std::{fmt, net::IpAddr, path::PathBuf};
+
+/// Where an address is, as far as the databases know.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Origin {
+  /// ISO 3166-1 alpha-2 country code (the registered country if the
+  /// database has no location)
+  pub country: Option<String>,
+  pub asn: Option<u32>,
+  pub as_org: Option<String>,
+}
+
+/// The loaded databases.
+pub struct GeoIp {
+  #[cfg(feature = "geoip")]
+  readers: Vec<maxminddb::Reader<Vec<u8>>>,
+}
+
+impl fmt::Debug for GeoIp {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("GeoIp").finish_non_exhaustive()
+  }
+}
+
+#[cfg(feature = "geoip")]
+impl GeoIp {
+  pub fn open(paths: &[PathBuf]) -> Result<Self> {
+    use anyhow::Context;
+
+    let readers = paths
+      .iter()
+      .map(|path| {
+        let reader = maxminddb::Reader::open_readfile(path).with_context(|| format!("open {}", path.display()))?;
+        tracing::info!(
+          path = %path.display(),
+          database = reader.metadata.database_type,
+          build_epoch = reader.metadata.build_epoch,
+          "loaded GeoIP database"
+        );
+        Ok(reader)
+      })
+      .collect::<Result<_>>()?;
+    Ok(GeoIp { readers })
+  }
+
+  pub fn lookup(&self, ip: IpAddr) -> Origin {
+    // an IPv4 peer on a dual-stack socket shows up as ::ffff:a.b.c.d
+    let ip = ip.to_canonical();
+    let mut origin = Origin::default();
+    for reader in &self.readers {
+      let Ok(entry) = reader.lookup::<Entry>(ip) else {
+        continue;
+      };
+      let country = entry.country.or(entry.registered_country).and_then(|c| c.iso_code);
+      origin.country = origin.country.or(country);
+      origin.asn = origin.asn.or(entry.autonomous_system_number);
+      origin.as_org = origin.as_org.or(entry.autonomous_system_organization);
+    }
+    origin
+  }
+}
+
+#[cfg(not(feature = "geoip"))]
+impl GeoIp {
+  pub fn open(_paths: &[PathBuf]) -> Result<Self> {
+    anyhow::bail!("--geoip needs quic_echo built with `--features geoip`")
+  }
+
+  pub fn lookup(&self, _ip: IpAddr) -> Origin {
+    Origin::default()
+  }
+}
+
+/// The fields used, from any kind of database.
+#[cfg(feature = "geoip")]
+#[derive(serde::Deserialize)]
+struct Entry {
+  country: Option<Country>,
+  registered_country: Option<Country>,
+  autonomous_system_number: Option<u32>,
+  autonomous_system_organization: Option<String>,
+}
+
+#[cfg(feature = "geoip")]
+#[derive(serde::Deserialize)]
+struct Country {
+  iso_code: Option<String>,
+}
@@ -0,0 +1,270 @@
+//! TLS session details (`--tls-info`) for both binaries: the protocol
+//! version, cipher suite, key exchange group, the signature scheme of the
+//! certificate's CertificateVerify, and whether the session was resumed.
+//!
+//! quinn only surfaces the ALPN and SNI of the rustls session, so
+//! [`Sniffing`] wraps quinn's rustls configs and reads the details off the
+//! server's handshake flight as it goes through. QUIC carries TLS
+//! handshake messages in CRYPTO frames without the TLS record layer, so
+//! the server's ServerHello and CertificateVerify are in plain sight on
+//! both ends: the client reads them, the server writes them. A
+//! `pre_shared_key` extension in the ServerHello means the server accepted
+//! a resumption ticket.
+//!
+//! Connections set up through [`Sniffing`] return a [`Handshake`] from
+//! `Connection::handshake_data`; [`data`] and [`info`] work with either.
+
+use quinn::{Connection, crypto::rustls::HandshakeData};
+use quinn_proto::{
+  ConnectError, ConnectionId, Side, TransportError,
+  crypto::{self, ExportKeyingMaterialError, HeaderKey, KeyPair, Keys, PacketKey, Session, UnsupportedVersion},
+  transport_parameters::TransportParameters,
+};
+use rustls::{CipherSuite, NamedGroup, ProtocolVersion, SignatureScheme};
+use std::{any::Any, fmt, sync::Arc};
+
+const SERVER_HELLO: u8 = 2;
+const CERTIFICATE_VERIFY: u8 = 15;
+const FINISHED: u8 = 20;
+const EXT_PRE_SHARED_KEY: u16 = 41;
+const EXT_SUPPORTED_VERSIONS: u16 = 43;
+const EXT_KEY_SHARE: u16 = 51;
+/// The ServerHello random that marks a HelloRetryRequest (RFC 8446, 4.1.3)
+const HELLO_RETRY_RANDOM: [u8; 32] = [
+  0xcf, 0x21, 0xad, 0x74, 0xe5, 0x9a, 0x61, 0x11, 0xbe, 0x1d, 0x8c, 0x02, 0x1e, 0x65, 0xb8, 0x91, 0xc2, 0xa2, 0x11,
+  0x16, 0x7a, 0xbb, 0x8c, 0x5e, 0x07, 0x9e, 0x09, 0xe2, 0xc8, 0xa8, 0x33, 0x9c,
+];
+
+/// What the handshake negotiated, as far as it got.
+#[derive(Clone, Debug, Default)]
+pub struct TlsInfo {
+  pub version: Option<ProtocolVersion>,
+  pub cipher_suite: Option<CipherSuite>,
+  pub key_exchange: Option<NamedGroup>,
+  /// Scheme of the server's CertificateVerify; `None` on resumption,
+  /// which skips the certificate
+  pub signature: Option<SignatureScheme>,
+  pub resumed: bool,
+  /// The server asked for another key share first
+  pub hello_retry: bool,
+}
+
+impl fmt::Display for TlsInfo {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    fn or_dash<T: fmt::Debug>(value: &Option<T>) -> String {
+      value.as_ref().map_or_else(|| "-".into(), |v| format!("{v:?}"))
+    }
+    write!(
+      f,
+      "version {}, cipher {}, key exchange {}{}, signature {}, resumed {}",
+      or_dash(&self.version),
+      or_dash(&self.cipher_suite),
+      or_dash(&self.key_exchange),
+      if self.hello_retry { " (after HelloRetryRequest)" } else { "" },
+      or_dash(&self.signature),
+      if self.resumed { "yes" } else { "no" }
+    )
+  }
+}
+
+/// `Connection::handshake_data` of a [`Sniffing`] connection.
+pub struct Handshake {
+  pub data: HandshakeData,
+  pub info: TlsInfo,
+}
+
+/// quinn's handshake data of `conn`, sniffed or not.
+pub fn data(conn: &Connection) -> Option<HandshakeData> {
+  let any = conn.handshake_data()?;
+  match any.downcast::<HandshakeData>() {
+    Ok(data) => Some(*data),
+    Err(any) => any.downcast::<Handshake>().ok().map(|hs| hs.data),
+  }
+}
+
+/// The session details of `conn`; `None` unless it was set up through
+/// [`Sniffing`].
+pub fn info(conn: &Connection) -> Option<TlsInfo> {
+  conn.handshake_data()?.downcast::<Handshake>().ok().map(|hs| hs.info)
+}
+
+/// A quinn client or server crypto config whose sessions report
+/// [`TlsInfo`].
+pub struct Sniffing<C: ?Sized>(Arc<C>);
+
+impl<C: ?Sized> Sniffing<C> {
+  pub fn new(inner: Arc<C>) -> Self {
+    Self(inner)
+  }
+}
+
+impl<C: crypto::ClientConfig + ?Sized + 'static> crypto::ClientConfig for Sniffing<C> {
+  fn start_session(
+    self: Arc<Self>,
+    version: u32,
+    server_name: &str,
+    params: &TransportParameters,
+  ) -> Result<Box<dyn Session>, ConnectError> {
+    let inner = self.0.clone().start_session(version, server_name, params)?;
+    Ok(Box::new(Sniffer::new(inner, Side::Client)))
+  }
+}
+
+impl<C: crypto::ServerConfig + ?Sized + 'static> crypto::ServerConfig for Sniffing<C> {
+  fn initial_keys(&self, version: u32, dst_cid: &ConnectionId) -> Result<Keys, UnsupportedVersion> {
+    self.0.initial_keys(version, dst_cid)
+  }
+
+  fn retry_tag(&self, version: u32, orig_dst_cid: &ConnectionId, packet: &[u8]) -> [u8; 16] {
+    self.0.retry_tag(version, orig_dst_cid, packet)
+  }
+
+  fn start_session(self: Arc<Self>, version: u32, params: &TransportParameters) -> Box<dyn Session> {
+    Box::new(Sniffer::new(self.0.clone().start_session(version, params), Side::Server))
+  }
+}
+
+/// Passes everything through to the real session, reading the server's
+/// handshake messages on the way.
+struct Sniffer {
+  inner: Box<dyn Session>,
+  side: Side,
+  /// Server handshake bytes not yet parsed; dropped after its Finished
+  flight: Vec<u8>,
+  done: bool,
+  info: TlsInfo,
+}
+
+impl Sniffer {
+  fn new(inner: Box<dyn Session>, side: Side) -> Self {
+    Self { inner, side, flight: Vec::new(), done: false, info: TlsInfo::default() }
+  }
+
+  /// Take in more of the server's flight and parse the messages it completes.
+  fn server_sent(&mut self, bytes: &[u8]) {
+    if self.done {
+      return;
+    }
+    self.flight.extend_from_slice(bytes);
+    let mut at = 0;
+    while let Some(header) = self.flight.get(at..at + 4) {
+      let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+      let Some(body) = self.flight.get(at + 4..at + 4 + len) else {
+        break;
+      };
+      match header[0] {
+        SERVER_HELLO => server_hello(body, &mut self.info),
+        CERTIFICATE_VERIFY => self.info.signature = read_u16(body, 0).map(SignatureScheme::from),
+        FINISHED => {
+          self.done = true;
+          self.flight = Vec::new();
+          return;
+        }
+        _ => {}
+      }
+      at += 4 + len;
+    }
+    self.flight.drain(..at);
+  }
+}
+
+/// Fill `info` from a ServerHello body; a HelloRetryRequest only notes
+/// the retry, the real ServerHello follows.
+fn server_hello(body: &[u8], info: &mut TlsInfo) {
+  let parse = || -> Option<TlsInfo> {
+    let mut hello = TlsInfo { version: Some(ProtocolVersion::from(read_u16(body, 0)?)), ..TlsInfo::default() };
+    if body.get(2..34)? == HELLO_RETRY_RANDOM {
+      return None;
+    }
+    let mut at = 35 + usize::from(*body.get(34)?);
+    hello.cipher_suite = Some(CipherSuite::from(read_u16(body, at)?));
+    // the suite, then legacy_compression_method and the extensions' length
+    at += 5;
+    while let (Some(kind), Some(len)) = (read_u16(body, at), read_u16(body, at + 2)) {
+      let data = body.get(at + 4..at + 4 + usize::from(len))?;
+      match kind {
+        EXT_SUPPORTED_VERSIONS => hello.version = Some(ProtocolVersion::from(read_u16(data, 0)?)),
+        EXT_KEY_SHARE => hello.key_exchange = Some(NamedGroup::from(read_u16(data, 0)?)),
+        EXT_PRE_SHARED_KEY => hello.resumed = true,
+        _ => {}
+      }
+      at += 4 + usize::from(len);
+    }
+    Some(hello)
+  };
+  match parse() {
+    Some(hello) => *info = TlsInfo { hello_retry: info.hello_retry, ..hello },
+    None => info.hello_retry = true,
+  }
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> Option<u16> {
+  Some(u16::from_be_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+impl Session for Sniffer {
+  fn initial_keys(&self, dst_cid: &ConnectionId, side: Side) -> Keys {
+    self.inner.initial_keys(dst_cid, side)
+  }
+
+  fn handshake_data(&self) -> Option<Box<dyn Any>> {
+    let data = self.inner.handshake_data()?;
+    match data.downcast::<HandshakeData>() {
+      Ok(data) => Some(Box::new(Handshake { data: *data, info: self.info.clone() })),
+      Err(data) => Some(data),
+    }
+  }
+
+  fn peer_identity(&self) -> Option<Box<dyn Any>> {
+    self.inner.peer_identity()
+  }
+
+  fn early_crypto(&self) -> Option<(Box<dyn HeaderKey>, Box<dyn PacketKey>)> {
+    self.inner.early_crypto()
+  }
+
+  fn early_data_accepted(&self) -> Option<bool> {
+    self.inner.early_data_accepted()
+  }
+
+  fn is_handshaking(&self) -> bool {
+    self.inner.is_handshaking()
+  }
+
+  fn read_handshake(&mut self, buf: &[u8]) -> Result<bool, TransportError> {
+    if self.side == Side::Client {
+      self.server_sent(buf);
+    }
+    self.inner.read_handshake(buf)
+  }
+
+  fn transport_parameters(&self) -> Result<Option<TransportParameters>, TransportError> {
+    self.inner.transport_parameters()
+  }
+
+  fn write_handshake(&mut self, buf: &mut Vec<u8>) -> Option<Keys> {
+    let start = buf.len();
+    let keys = self.inner.write_handshake(buf);
+    if self.side == Side::Server {
+      self.server_sent(&buf[start..]);
+    }
+    keys
+  }
+
+  fn next_1rtt_keys(&mut self) -> Option<KeyPair<Box<dyn PacketKey>>> {
+    self.inner.next_1rtt_keys()
+  }
+
+  fn is_valid_retry(&self, orig_dst_cid: &ConnectionId, header: &[u8], payload: &[u8]) -> bool {
+    self.inner.is_valid_retry(orig_dst_cid, header, payload)
+  }
+
+  fn export_keying_material(
+    &self,
+    output: &mut [u8],
+    label: &[u8],
+    context: &[u8],
+  ) -> Result<(), ExportKeyingMaterialError> {
+    self.inner.export_keying_material(output, label, context)
+  }
+}
@@ -0,0 +1,84 @@
+//! Latency histograms for the client (`--hdr-out`): every echoed request's
+//! round trip goes into an HdrHistogram, written at the end of the run as
+//! the percentile distribution HdrHistogram's Java `outputPercentileDistribution`
+//! prints (the `.hgrm` text wrk2 and friends produce), so runs can be
+//! compared and plotted with the existing HdrHistogram tooling.
+//!
+//! Values are recorded in microseconds (three significant digits, up to an
+//! hour) and written in milliseconds.
+
+use anyhow::{Context, Result};
+use hdrhistogram::Histogram;
+use std::{
+  fs::File,
+  io::{BufWriter, Write},
+  path::Path,
+  sync::Mutex,
+  time::Duration,
+};
+
+const SIGFIG: u8 = 3;
+/// Longest recordable latency; longer ones count as this
+const HIGHEST_US: u64 = 3_600_000_000;
+/// Percentile rows per halving of the distance to 100%, as the Java default
+const TICKS_PER_HALF_DISTANCE: u32 = 5;
+/// Microseconds per written unit (milliseconds)
+const SCALE: f64 = 1000.0;
+
+/// Round trips of one run, shared by all its connections.
+pub struct Latencies(Mutex<Histogram<u64>>);
+
+impl Default for Latencies {
+  fn default() -> Self {
+    Self(Mutex::new(Histogram::new_with_bounds(1, HIGHEST_US, SIGFIG).expect("valid histogram bounds")))
+  }
+}
+
+impl Latencies {
+  pub fn record(&self, rtt: Duration) {
+    let us = u64::try_from(rtt.as_micros()).unwrap_or(u64::MAX).max(1);
+    self.0.lock().unwrap().saturating_record(us);
+  }
+
+  /// Latencies recorded so far.
+  pub fn len(&self) -> u64 {
+    self.0.lock().unwrap().len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Write the percentile distribution to `path`.
+  pub fn write(&self, path: &Path) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("create {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    self.percentiles(&mut out)?;
+    out.flush()?;
+    Ok(())
+  }
+
+  /// The `.hgrm` text: a row per percentile step, then mean, deviation,
+  /// max, count and the histogram's shape.
+  pub fn percentiles(&self, out: &mut impl Write) -> Result<()> {
+    let hist = self.0.lock().unwrap();
+    let digits = usize::from(SIGFIG);
+    writeln!(out, "{:>12} {:>14} {:>10} {:>14}\n", "Value", "Percentile", "TotalCount", "1/(1-Percentile)")?;
+    let mut total = 0;
+    for step in hist.iter_quantiles(TICKS_PER_HALF_DISTANCE) {
+      total += step.count_since_last_iteration();
+      let value = step.value_iterated_to() as f64 / SCALE;
+      let quantile = step.quantile_iterated_to();
+      if quantile < 1.0 {
+        writeln!(out, "{value:>12.digits$} {quantile:>2.12} {total:>10} {:>14.2}", 1.0 / (1.0 - quantile))?;
+      } else {
+        writeln!(out, "{value:>12.digits$} {quantile:>2.12} {total:>10}")?;
+      }
+    }
+    let sub_buckets = (2 * 10u64.pow(SIGFIG.into())).next_power_of_two();
+    writeln!(out, "#[Mean    = {:>12.digits$}, StdDeviation   = {:>12.digits$}]", hist.mean() / SCALE, hist.stdev() / SCALE)?;
+    writeln!(out, "#[Max     = {:>12.digits$}, Total count    = {:>12}]", hist.max() as f64 / SCALE, hist.len())?;
+    writeln!(out, "#[Buckets = {:>12}, SubBuckets     = {:>12}]", hist.buckets(), sub_buckets)?;
+    Ok(())
+  }
+}
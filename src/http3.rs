@@ -0,0 +1,141 @@
+//! HTTP/3 echo (`--h3`): the server answers `POST /echo` with the request
+//! body, so the client can be pointed at real HTTP/3 stacks too. An extended
+//! CONNECT for `webtransport` turns the connection into a WebTransport echo
+//! session (see [`crate::webtransport`]).
+
+use anyhow::{Context, Result};
+use bytes::{Buf, Bytes, BytesMut};
+use h3::ext::Protocol;
+use http::{Method, Request, Response, StatusCode};
+use tracing::{info, warn, Instrument};
+
+pub const ALPN: &[u8] = b"h3";
+pub const ECHO_PATH: &str = "/echo";
+
+/// H3_NO_ERROR, the application close code for a clean HTTP/3 shutdown.
+pub const NO_ERROR: u32 = 0x100;
+
+pub(crate) type ServerConn = h3::server::Connection<h3_quinn::Connection, Bytes>;
+pub(crate) type ServerStream = h3::server::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>;
+
+/// Serve HTTP/3 requests on `conn` until the client goes away. The first
+/// WebTransport CONNECT hands the connection over to a session echo.
+pub async fn serve(conn: quinn::Connection) -> Result<()> {
+  let mut h3: ServerConn = h3::server::builder()
+    .enable_webtransport(true)
+    .enable_extended_connect(true)
+    .enable_datagram(true)
+    .max_webtransport_sessions(1)
+    .build(h3_quinn::Connection::new(conn))
+    .await
+    .context("HTTP/3 setup")?;
+
+  loop {
+    let resolver = match h3.accept().await {
+      Ok(Some(resolver)) => resolver,
+      Ok(None) => return Ok(()),
+      Err(e) if e.is_h3_no_error() => return Ok(()),
+      Err(e) => return Err(e.into()),
+    };
+    // headers only, the body is handled in the spawned task
+    let (req, stream) = match resolver.resolve_request().await {
+      Ok(r) => r,
+      Err(e) => {
+        warn!("HTTP/3 request failed: {e}");
+        continue;
+      }
+    };
+
+    if req.method() == Method::CONNECT
+      && req.extensions().get::<Protocol>() == Some(&Protocol::WEB_TRANSPORT)
+    {
+      return crate::webtransport::serve_session(req, stream, h3).await;
+    }
+
+    tokio::spawn(
+      async move {
+        if let Err(e) = respond(req, stream).await {
+          warn!("HTTP/3 request failed: {e:#}");
+        }
+      }
+      .in_current_span(),
+    );
+  }
+}
+
+/// `POST /echo` streams the body straight back; anything else is refused.
+pub(crate) async fn respond(req: Request<()>, mut stream: ServerStream) -> Result<()> {
+  info!(method = %req.method(), path = %req.uri().path(), "HTTP/3 request");
+
+  let status = match (req.method(), req.uri().path()) {
+    (&Method::POST, ECHO_PATH) => StatusCode::OK,
+    (_, ECHO_PATH) => StatusCode::METHOD_NOT_ALLOWED,
+    _ => StatusCode::NOT_FOUND,
+  };
+  stream.send_response(Response::builder().status(status).body(())?).await?;
+
+  if status == StatusCode::OK {
+    while let Some(mut chunk) = stream.recv_data().await? {
+      let data = chunk.copy_to_bytes(chunk.remaining());
+      stream.send_data(data).await?;
+    }
+  }
+  stream.finish().await?;
+  Ok(())
+}
+
+/// `POST body` to `https://{host}/echo` on `conn` and return the response body.
+pub async fn echo(conn: quinn::Connection, host: &str, body: Bytes) -> Result<Bytes> {
+  let (mut driver, mut sender) = h3::client::new(h3_quinn::Connection::new(conn))
+    .await
+    .context("HTTP/3 setup")?;
+  let drive = tokio::spawn(
+    async move {
+      let e = std::future::poll_fn(|cx| driver.poll_close(cx)).await;
+      if !e.is_h3_no_error() {
+        warn!("HTTP/3 connection closed: {e}");
+      }
+    }
+    .in_current_span(),
+  );
+
+  let req = Request::builder()
+    .method(Method::POST)
+    .uri(format!("https://{host}{ECHO_PATH}"))
+    .body(())?;
+  let mut stream = sender.send_request(req).await?;
+  stream.send_data(body).await?;
+  stream.finish().await?;
+
+  let resp = stream.recv_response().await?;
+  anyhow::ensure!(resp.status() == StatusCode::OK, "POST {ECHO_PATH}: HTTP {}", resp.status());
+
+  let mut out = BytesMut::new();
+  while let Some(mut chunk) = stream.recv_data().await? {
+    out.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+  }
+
+  drop(sender);
+  drive.abort();
+  Ok(out.freeze())
+}
+
+/// QUIC variable-length integer (RFC 9000, section 16).
+pub(crate) fn put_varint(buf: &mut Vec<u8>, v: u64) {
+  if v < 1 << 6 {
+    buf.push(v as u8);
+  } else if v < 1 << 14 {
+    buf.extend_from_slice(&(v as u16 | 0x4000).to_be_bytes());
+  } else if v < 1 << 30 {
+    buf.extend_from_slice(&(v as u32 | 0x8000_0000).to_be_bytes());
+  } else {
+    buf.extend_from_slice(&(v | 0xc000_0000_0000_0000).to_be_bytes());
+  }
+}
+
+pub(crate) fn get_varint(buf: &[u8]) -> Option<(u64, usize)> {
+  let len = 1 << (buf.first()? >> 6);
+  let bytes = buf.get(..len)?;
+  let v = bytes[1..].iter().fold(u64::from(bytes[0] & 0x3f), |v, &b| v << 8 | u64::from(b));
+  Some((v, len))
+}
@@ -0,0 +1,141 @@
+//! Interactive client (`--interactive`): every line read from stdin is sent
+//! to the echo server and the echoes are printed as they arrive, so the
+//! protocol can be poked at by hand.
+//!
+//! A line goes out on its own bidirectional stream, or as a datagram in
+//! datagram mode; a leading `=` forces a stream and a leading `~` a
+//! datagram. Lines starting with `/` are commands, see [`HELP`].
+
+use crate::flow;
+use crate::replay::Recorder;
+use anyhow::Result;
+use bytes::Bytes;
+use quinn::VarInt;
+use std::{net::SocketAddr, time::Instant};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tracing::Instrument;
+
+const MAX_ECHO: usize = 64 * 1024;
+
+pub const HELP: &str = "\
+commands:
+  /stats                 connection statistics
+  /migrate               move to a new local UDP port (client migration)
+  /close [code] [reason] close the connection with an application error code
+  /help                  this help
+lines starting with `=` go over a stream, `~` as a datagram, anything else per --datagram";
+
+/// Run the REPL on `conn` until stdin ends, `/close` or the connection goes
+/// away. `rebind` moves the endpoint to a fresh socket and returns its
+/// address; sent lines also go to `record` if given.
+pub async fn run(
+  conn: &quinn::Connection,
+  datagram: bool,
+  rebind: impl Fn() -> Result<SocketAddr>,
+  record: Option<&Recorder>,
+) -> Result<()> {
+  // echoed datagrams can arrive at any time, print them as they come
+  let reader = tokio::spawn(
+    {
+      let conn = conn.clone();
+      async move {
+        while let Ok(data) = conn.read_datagram().await {
+          println!("< dgram {:?}", String::from_utf8_lossy(&data));
+        }
+      }
+    }
+    .in_current_span(),
+  );
+
+  println!("connected to {}; /help for commands", conn.remote_address());
+  let mut lines = BufReader::new(tokio::io::stdin()).lines();
+  let res = loop {
+    let line = tokio::select! {
+      line = lines.next_line() => line?,
+      reason = conn.closed() => break Err(anyhow::Error::from(reason).context("connection closed")),
+    };
+    let Some(line) = line else {
+      conn.close(VarInt::from_u32(0), b"stdin closed");
+      break Ok(());
+    };
+
+    if let Some(cmd) = line.strip_prefix('/') {
+      let mut args = cmd.split_whitespace();
+      match args.next() {
+        Some("stats") => print_stats(conn),
+        Some("migrate") => match rebind() {
+          Ok(local) => println!("migrated to local address {local}"),
+          Err(e) => println!("migration failed: {e:#}"),
+        },
+        Some("close") => {
+          let code = match args.next().map(str::parse::<u32>).transpose() {
+            Ok(code) => code.unwrap_or(0),
+            Err(e) => {
+              println!("bad close code: {e}");
+              continue;
+            }
+          };
+          let reason = args.collect::<Vec<_>>().join(" ");
+          conn.close(VarInt::from_u32(code), reason.as_bytes());
+          println!("closed with code {code}");
+          break Ok(());
+        }
+        Some("help") => println!("{HELP}"),
+        _ => println!("unknown command {line:?}, /help for a list"),
+      }
+      continue;
+    }
+
+    let (as_datagram, text) = match line.chars().next() {
+      Some('=') => (false, &line[1..]),
+      Some('~') => (true, &line[1..]),
+      _ => (datagram, &line[..]),
+    };
+    let payload = Bytes::copy_from_slice(text.as_bytes());
+    if as_datagram {
+      if let Some(record) = record {
+        record.datagram(&payload);
+      }
+      if let Err(e) = conn.send_datagram(payload) {
+        println!("datagram not sent: {e}");
+      }
+    } else {
+      if let Some(record) = record {
+        record.stream(record.open_stream(), &payload, true);
+      }
+      let conn = conn.clone();
+      tokio::spawn(
+        async move {
+          let start = Instant::now();
+          let echoed = async {
+            let (mut send, mut recv) = flow::open_bi(&conn).await?;
+            send.write_all(&payload).await?;
+            send.finish()?;
+            anyhow::Ok(recv.read_to_end(MAX_ECHO).await?)
+          };
+          match echoed.await {
+            Ok(data) => println!("< stream {:?} ({:?})", String::from_utf8_lossy(&data), start.elapsed()),
+            Err(e) => println!("stream failed: {e:#}"),
+          }
+        }
+        .in_current_span(),
+      );
+    }
+  };
+  reader.abort();
+  res
+}
+
+fn print_stats(conn: &quinn::Connection) {
+  let stats = conn.stats();
+  let path = &stats.path;
+  println!("remote   {}", conn.remote_address());
+  println!("rtt      {:?}", conn.rtt());
+  println!("cwnd     {} B, mtu {} B", path.cwnd, path.current_mtu);
+  println!("sent     {} datagrams, {} B", stats.udp_tx.datagrams, stats.udp_tx.bytes);
+  println!("received {} datagrams, {} B", stats.udp_rx.datagrams, stats.udp_rx.bytes);
+  println!("lost     {} packets, {} congestion events", path.lost_packets, path.congestion_events);
+  if let Some(max) = conn.max_datagram_size() {
+    println!("max dgram {max} B");
+  }
+}
@@ -0,0 +1,36 @@
+//! TLS key logging (`--keylog`, or the usual `SSLKEYLOGFILE`) for both
+//! binaries: every TLS secret is appended to a file in the NSS key log
+//! format, which Wireshark uses to decrypt captured QUIC and TLS traffic.
+
+use crate::transfer::hex;
+use anyhow::{Context, Result};
+use std::{
+  fs::{File, OpenOptions},
+  io::Write,
+  path::Path,
+  sync::{Arc, Mutex},
+};
+use tracing::warn;
+
+#[derive(Debug)]
+pub struct KeyLogWriter(Mutex<File>);
+
+impl rustls::KeyLog for KeyLogWriter {
+  fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+    let line = format!("{label} {} {}\n", hex(client_random), hex(secret));
+    if let Err(e) = self.0.lock().unwrap().write_all(line.as_bytes()) {
+      warn!("cannot write TLS key log: {e}");
+    }
+  }
+}
+
+/// Open `path` for appending and return it as a rustls key log.
+pub fn open(path: &Path) -> Result<Arc<dyn rustls::KeyLog>> {
+  let file = OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(path)
+    .with_context(|| format!("open key log {}", path.display()))?;
+  warn!(path = %path.display(), "logging TLS secrets; anyone with this file can decrypt captured traffic");
+  Ok(Arc::new(KeyLogWriter(Mutex::new(file))))
+}
@@ -0,0 +1,57 @@
+//! Shared pieces of the `quic_echo_server` and `quic_echo_client` binaries.
+
+pub mod access;
+pub mod acl;
+pub mod acme;
+pub mod amplification;
+pub mod auth;
+pub mod bufferbloat;
+pub mod certs;
+pub mod cid;
+pub mod client;
+pub mod config;
+pub mod crypto;
+pub mod daemon;
+pub mod doq;
+pub mod dscp;
+pub mod duplex;
+pub mod flow;
+pub mod fuzz;
+pub mod geoip;
+pub mod handshake;
+pub mod hdr;
+pub mod http3;
+pub mod interactive;
+pub mod keylog;
+pub mod logging;
+pub mod loss;
+pub mod masque;
+pub mod matrix;
+pub mod offload;
+pub mod pace;
+pub mod pcap;
+pub mod perf;
+pub mod preferred;
+pub mod push;
+pub mod reaper;
+pub mod reliable;
+pub mod replay;
+pub mod resolve;
+pub mod route;
+pub mod samples;
+pub mod scenario;
+pub mod selftest;
+pub mod sim;
+pub mod sni;
+pub mod soak;
+pub mod socket;
+pub mod tcp;
+pub mod transfer;
+pub mod transform;
+pub mod ttl;
+pub mod tui;
+pub mod tunnel;
+pub mod uring;
+pub mod verify;
+pub mod versions;
+pub mod webtransport;
@@ -0,0 +1,77 @@
+//! `tracing` setup shared by both binaries.
+//!
+//! Filtering follows `RUST_LOG` (default `info`) and applies to quinn's own
+//! events too, e.g. `RUST_LOG=info,quinn_proto=trace`. Logs go to stderr so
+//! stdout only carries results. [`init_reloadable`] takes the filter from a
+//! flag instead and can replace it while running.
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::io::IsTerminal;
+use tracing_subscriber::{EnvFilter, Layer, Registry, layer::SubscriberExt, reload, util::SubscriberInitExt};
+
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum LogFormat {
+  /// Single-line human readable output
+  #[default]
+  Text,
+  /// Multi-line human readable output
+  Pretty,
+  /// One JSON object per event, including the current span fields
+  Json,
+}
+
+/// Install the global subscriber.
+pub fn init(format: LogFormat) {
+  let filter = default_filter();
+  let builder = tracing_subscriber::fmt()
+    .with_env_filter(filter)
+    .with_writer(std::io::stderr)
+    .with_ansi(std::io::stderr().is_terminal());
+
+  match format {
+    LogFormat::Text => builder.init(),
+    LogFormat::Pretty => builder.pretty().init(),
+    LogFormat::Json => builder.json().init(),
+  }
+}
+
+/// Handle to the filter installed by [`init_reloadable`].
+pub struct LogLevel(reload::Handle<EnvFilter, Registry>);
+
+impl LogLevel {
+  /// Replace the filter with `directives` (`RUST_LOG` syntax), or with the
+  /// default one for `None`.
+  pub fn set(&self, directives: Option<&str>) -> Result<()> {
+    let filter = parse(directives)?;
+    self.0.reload(filter).context("replace the log filter")
+  }
+}
+
+/// Install the global subscriber with `directives` (`RUST_LOG` syntax) as
+/// the filter, or the default one for `None`, and return a handle to change
+/// it later.
+pub fn init_reloadable(format: LogFormat, directives: Option<&str>) -> Result<LogLevel> {
+  let (filter, handle) = reload::Layer::new(parse(directives)?);
+  let output = tracing_subscriber::fmt::layer()
+    .with_writer(std::io::stderr)
+    .with_ansi(std::io::stderr().is_terminal());
+  let output = match format {
+    LogFormat::Text => output.boxed(),
+    LogFormat::Pretty => output.pretty().boxed(),
+    LogFormat::Json => output.json().boxed(),
+  };
+  tracing_subscriber::registry().with(filter).with(output).init();
+  Ok(LogLevel(handle))
+}
+
+fn parse(directives: Option<&str>) -> Result<EnvFilter> {
+  match directives {
+    Some(directives) => EnvFilter::try_new(directives).with_context(|| format!("parse log level {directives:?}")),
+    None => Ok(default_filter()),
+  }
+}
+
+fn default_filter() -> EnvFilter {
+  EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"))
+}
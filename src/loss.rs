@@ -0,0 +1,97 @@
+//! End-of-connection loss report shared by both binaries: packets and bytes
+//! lost, congestion events and flow-control stalls from
+//! `Connection::stats()`, logged by the client after its run and by the
+//! server when each connection closes, with a guess at what limited it.
+//!
+//! quinn does not count retransmitted bytes; `lost_bytes` is the closest
+//! figure, since lost stream and control data is sent again (lost datagrams
+//! are not).
+
+use quinn::ConnectionStats;
+use std::fmt;
+use tracing::info;
+
+/// What most likely held a connection back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+  /// No losses and no flow-control stalls
+  None,
+  /// Packets were lost and the congestion window shrank
+  Loss,
+  /// The sender ran out of stream or connection credit
+  FlowControl,
+  /// Both of the above
+  Both,
+}
+
+impl fmt::Display for Limit {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Limit::None => "none",
+      Limit::Loss => "loss",
+      Limit::FlowControl => "flow control",
+      Limit::Both => "loss and flow control",
+    })
+  }
+}
+
+/// The loss and flow-control counters of one connection.
+#[derive(Clone, Copy, Debug)]
+pub struct LossReport {
+  pub sent_packets: u64,
+  pub lost_packets: u64,
+  pub lost_bytes: u64,
+  pub congestion_events: u64,
+  pub black_holes: u64,
+  /// STREAM_DATA_BLOCKED/DATA_BLOCKED frames we sent: we were out of credit
+  pub blocked_tx: u64,
+  /// STREAM_DATA_BLOCKED/DATA_BLOCKED frames the peer sent
+  pub blocked_rx: u64,
+}
+
+impl LossReport {
+  pub fn new(stats: &ConnectionStats) -> Self {
+    let path = &stats.path;
+    Self {
+      sent_packets: path.sent_packets,
+      lost_packets: path.lost_packets,
+      lost_bytes: path.lost_bytes,
+      congestion_events: path.congestion_events,
+      black_holes: path.black_holes_detected,
+      blocked_tx: stats.frame_tx.stream_data_blocked + stats.frame_tx.data_blocked,
+      blocked_rx: stats.frame_rx.stream_data_blocked + stats.frame_rx.data_blocked,
+    }
+  }
+
+  /// Lost packets as a percentage of those sent.
+  pub fn loss_percent(&self) -> f64 {
+    self.lost_packets as f64 * 100.0 / self.sent_packets.max(1) as f64
+  }
+
+  pub fn limit(&self) -> Limit {
+    let lossy = self.lost_packets > 0 || self.congestion_events > 0;
+    let blocked = self.blocked_tx > 0 || self.blocked_rx > 0;
+    match (lossy, blocked) {
+      (false, false) => Limit::None,
+      (true, false) => Limit::Loss,
+      (false, true) => Limit::FlowControl,
+      (true, true) => Limit::Both,
+    }
+  }
+
+  /// Log the report in the current span.
+  pub fn log(&self) {
+    info!(
+      sent_packets = self.sent_packets,
+      lost_packets = self.lost_packets,
+      loss = format_args!("{:.2}%", self.loss_percent()),
+      lost_bytes = self.lost_bytes,
+      congestion_events = self.congestion_events,
+      black_holes = self.black_holes,
+      blocked_tx = self.blocked_tx,
+      blocked_rx = self.blocked_rx,
+      limit = %self.limit(),
+      "loss report"
+    );
+  }
+}
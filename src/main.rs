@@ -1,12 +1,108 @@
-fn main() {
+use clap::{Parser, Subcommand};
+use quic_echo::{
+    matrix::{self, Matrix, ReportFormat},
+    selftest,
+};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+struct Opt {
+    #[clap(subcommand)]
+    cmd: Option<Cmd>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Cmd {
+    /// Run an echo server and a client against it in this process, and fail if any check fails
+    Selftest {
+        /// Only run checks whose name contains this
+        #[clap(long, env = "QUIC_ECHO_FILTER")]
+        filter: Option<String>,
+    },
+    /// Start quic_echo_server per server variant of a matrix file and run quic_echo_client against it per combination
+    Test {
+        /// TOML file with [[server]] variants and client [[axis]] values
+        #[clap(long, env = "QUIC_ECHO_MATRIX")]
+        matrix: PathBuf,
+        /// Write a report of every case to this file
+        #[clap(long, env = "QUIC_ECHO_REPORT")]
+        report: Option<PathBuf>,
+        /// Report format (default: from the extension, `.xml` is JUnit, anything else JSON)
+        #[clap(long, env = "QUIC_ECHO_REPORT_FORMAT", value_enum, requires = "report")]
+        report_format: Option<ReportFormat>,
+        /// Directory holding quic_echo_server and quic_echo_client (default: next to this executable)
+        #[clap(long, env = "QUIC_ECHO_BIN_DIR")]
+        bin_dir: Option<PathBuf>,
+    },
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
     let _ = rustls::crypto::ring::default_provider().install_default();
 
-    eprintln!(
-        "Use one of the binaries:\n\
-         - quic_echo_server\n\
-         - quic_echo_client\n\n\
-         Examples:\n\
-         cargo run --bin quic_echo_server -- --help\n\
-         cargo run --bin quic_echo_client -- --help"
-    );
+    let filter = match Opt::parse().cmd {
+        Some(Cmd::Selftest { filter }) => filter,
+        Some(Cmd::Test { matrix, report, report_format, bin_dir }) => {
+            return test(&matrix, report, report_format, bin_dir).await;
+        }
+        None => {
+            eprintln!(
+                "Use one of the binaries:\n\
+                 - quic_echo_server\n\
+                 - quic_echo_client\n\n\
+                 Examples:\n\
+                 cargo run --bin quic_echo_server -- --help\n\
+                 cargo run --bin quic_echo_client -- --help\n\n\
+                 Or check the whole crate in one process, or run a CI matrix:\n\
+                 cargo run --bin quic_echo -- selftest\n\
+                 cargo run --bin quic_echo -- test --matrix ci.toml --report report.xml"
+            );
+            return Ok(());
+        }
+    };
+
+    let outcomes = selftest::run(filter.as_deref()).await?;
+    let width = outcomes.iter().map(|o| o.name.len()).max().unwrap_or(0);
+    let mut failed = 0;
+    for outcome in &outcomes {
+        match &outcome.result {
+            Ok(took) => println!("ok    {:width$}  {took:.1?}", outcome.name),
+            Err(e) => {
+                failed += 1;
+                println!("FAIL  {:width$}  {e:#}", outcome.name);
+            }
+        }
+    }
+    anyhow::ensure!(!outcomes.is_empty(), "no self-test check matches the filter");
+    anyhow::ensure!(failed == 0, "{failed} of {} self-test checks failed", outcomes.len());
+    println!("all {} self-test checks passed", outcomes.len());
+    Ok(())
+}
+
+async fn test(
+    path: &std::path::Path,
+    report: Option<PathBuf>,
+    format: Option<ReportFormat>,
+    bin_dir: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let matrix = Matrix::load(path)?;
+    let bin_dir = match bin_dir {
+        Some(dir) => dir,
+        None => std::env::current_exe()?
+            .parent()
+            .expect("an executable lives in a directory")
+            .to_path_buf(),
+    };
+    let results = matrix::run(&matrix, &bin_dir).await?;
+    if let Some(report) = &report {
+        let format = format.unwrap_or_else(|| ReportFormat::from_path(report));
+        matrix::write_report(report, format, &results)?;
+    }
+    let failed = results.iter().filter(|r| !r.passed).count();
+    for result in results.iter().filter(|r| !r.passed) {
+        println!("\n--- {} / {}\n{}", result.server, result.name, result.output);
+    }
+    anyhow::ensure!(failed == 0, "{failed} of {} matrix cases failed", results.len());
+    println!("all {} matrix cases passed", results.len());
+    Ok(())
 }
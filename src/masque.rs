@@ -0,0 +1,227 @@
+//! MASQUE CONNECT-UDP (RFC 9298) over HTTP/3: the server relays the HTTP
+//! datagrams of each session to a UDP target and back, the client pushes
+//! one datagram through a session.
+
+use crate::http3::{self, get_varint, ServerConn, ServerStream};
+use anyhow::{Context, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use h3::{ext::Protocol, quic::StreamId};
+use h3_datagram::datagram_handler::{DatagramSender, HandleDatagramsExt};
+use http::{Method, Request, Response, StatusCode};
+use std::{
+  collections::HashMap,
+  net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+  sync::{Arc, Mutex},
+};
+use tokio::net::UdpSocket;
+use tracing::{info, warn, Instrument};
+
+const PATH_PREFIX: &str = "/.well-known/masque/udp/";
+/// Context ID 0 carries a plain UDP payload.
+const CONTEXT_UDP: u8 = 0;
+
+type Sender = DatagramSender<h3_quinn::datagram::SendDatagramHandler, Bytes>;
+type Sessions = Arc<Mutex<HashMap<StreamId, Arc<UdpSocket>>>>;
+
+/// Serve CONNECT-UDP requests on `conn` until the client goes away.
+pub async fn serve(conn: quinn::Connection) -> Result<()> {
+  let mut h3: ServerConn = h3::server::builder()
+    .enable_extended_connect(true)
+    .enable_datagram(true)
+    .build(h3_quinn::Connection::new(conn))
+    .await
+    .context("HTTP/3 setup")?;
+
+  // one datagram reader per connection, dispatched by the session's stream id
+  let sessions = Sessions::default();
+  let mut reader = h3.get_datagram_reader();
+  let dispatch = tokio::spawn({
+    let sessions = sessions.clone();
+    async move {
+      while let Ok(dgram) = reader.read_datagram().await {
+        let socket = sessions.lock().unwrap().get(&dgram.stream_id()).cloned();
+        let payload = dgram.into_payload();
+        // unknown sessions and context ids are dropped silently (RFC 9298, section 4)
+        if let (Some(socket), Some((0, n))) = (socket, get_varint(&payload)) {
+          let _ = socket.send(&payload[n..]).await;
+        }
+      }
+    }
+    .in_current_span()
+  });
+
+  let res = loop {
+    let resolver = match h3.accept().await {
+      Ok(Some(resolver)) => resolver,
+      Ok(None) => break Ok(()),
+      Err(e) if e.is_h3_no_error() => break Ok(()),
+      Err(e) => break Err(e.into()),
+    };
+    let (req, mut stream) = match resolver.resolve_request().await {
+      Ok(r) => r,
+      Err(e) => {
+        warn!("HTTP/3 request failed: {e}");
+        continue;
+      }
+    };
+
+    let sender = h3.get_datagram_sender(stream.id());
+    let sessions = sessions.clone();
+    tokio::spawn(
+      async move {
+        if let Err(e) = relay(req, &mut stream, sender, &sessions).await {
+          warn!("CONNECT-UDP session failed: {e:#}");
+        }
+        let _ = stream.finish().await;
+      }
+      .in_current_span(),
+    );
+  };
+  dispatch.abort();
+  res
+}
+
+/// Answer one request; a valid CONNECT-UDP relays until its stream ends.
+async fn relay(req: Request<()>, stream: &mut ServerStream, mut sender: Sender, sessions: &Sessions) -> Result<()> {
+  let target = match parse_target(&req) {
+    Some(target) => target,
+    None => {
+      info!(method = %req.method(), path = %req.uri().path(), "not a CONNECT-UDP request");
+      let status = if req.method() == Method::CONNECT { StatusCode::BAD_REQUEST } else { StatusCode::NOT_FOUND };
+      stream.send_response(Response::builder().status(status).body(())?).await?;
+      return Ok(());
+    }
+  };
+
+  let connected = async {
+    let addr = tokio::net::lookup_host(&target).await?.next().context("no address")?;
+    let bind = match addr {
+      SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+      SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(addr).await?;
+    anyhow::Ok((socket, addr))
+  };
+  let (socket, addr) = match connected.await {
+    Ok(s) => s,
+    Err(e) => {
+      stream.send_response(Response::builder().status(StatusCode::BAD_GATEWAY).body(())?).await?;
+      return Err(e.context(format!("target {target}")));
+    }
+  };
+  stream
+    .send_response(Response::builder().status(StatusCode::OK).header("capsule-protocol", "?1").body(())?)
+    .await?;
+  info!(%target, %addr, "CONNECT-UDP session established");
+
+  let id = stream.id();
+  let socket = Arc::new(socket);
+  sessions.lock().unwrap().insert(id, socket.clone());
+
+  let mut buf = vec![0u8; 65_535];
+  let res = loop {
+    tokio::select! {
+      n = socket.recv(&mut buf) => {
+        // ICMP errors surface here on a connected socket; keep relaying
+        let Ok(n) = n else { continue };
+        let mut dgram = BytesMut::with_capacity(n + 1);
+        dgram.put_u8(CONTEXT_UDP);
+        dgram.extend_from_slice(&buf[..n]);
+        if let Err(e) = sender.send_datagram(dgram.freeze()) {
+          warn!("CONNECT-UDP datagram send failed: {e}");
+        }
+      }
+      data = stream.recv_data() => match data {
+        // capsules are not used, skip them
+        Ok(Some(_)) => {}
+        Ok(None) => break Ok(()),
+        Err(e) if e.is_h3_no_error() => break Ok(()),
+        Err(e) => break Err(e.into()),
+      }
+    }
+  };
+  sessions.lock().unwrap().remove(&id);
+  info!(%target, "CONNECT-UDP session closed");
+  res
+}
+
+/// `host:port` from `/.well-known/masque/udp/{host}/{port}/`.
+fn parse_target(req: &Request<()>) -> Option<String> {
+  if req.method() != Method::CONNECT || req.extensions().get::<Protocol>() != Some(&Protocol::CONNECT_UDP) {
+    return None;
+  }
+  let rest = req.uri().path().strip_prefix(PATH_PREFIX)?;
+  let mut parts = rest.trim_end_matches('/').split('/');
+  let (host, port) = (parts.next()?, parts.next()?);
+  if parts.next().is_some() {
+    return None;
+  }
+  // IPv6 literals come percent-encoded
+  let host = host.replace("%3A", ":").replace("%3a", ":");
+  let port: u16 = port.parse().ok()?;
+  Some(if host.contains(':') { format!("[{host}]:{port}") } else { format!("{host}:{port}") })
+}
+
+/// Open a CONNECT-UDP session to `target` (`host:port`) through the proxy on
+/// `conn`, send `payload` as one UDP datagram and return the first reply.
+/// Closes the connection when done.
+pub async fn proxy_echo(conn: &quinn::Connection, proxy_host: &str, target: &str, payload: Bytes) -> Result<Bytes> {
+  let (host, port) = target
+    .rsplit_once(':')
+    .context("--proxy-target must be host:port")?;
+  let host = host.trim_start_matches('[').trim_end_matches(']').replace(':', "%3A");
+
+  let (mut driver, mut sender) = h3::client::builder()
+    .enable_extended_connect(true)
+    .enable_datagram(true)
+    .build::<_, _, Bytes>(h3_quinn::Connection::new(conn.clone()))
+    .await
+    .context("HTTP/3 setup")?;
+  let mut reader = driver.get_datagram_reader();
+
+  let mut req = Request::builder()
+    .method(Method::CONNECT)
+    .uri(format!("https://{proxy_host}{PATH_PREFIX}{host}/{port}/"))
+    .header("capsule-protocol", "?1")
+    .body(())?;
+  req.extensions_mut().insert(Protocol::CONNECT_UDP);
+  let mut stream = sender.send_request(req).await?;
+  let mut dgrams = driver.get_datagram_sender(stream.id());
+
+  let drive = tokio::spawn(
+    async move {
+      let e = std::future::poll_fn(|cx| driver.poll_close(cx)).await;
+      if !e.is_h3_no_error() {
+        warn!("HTTP/3 connection closed: {e}");
+      }
+    }
+    .in_current_span(),
+  );
+
+  let resp = stream.recv_response().await?;
+  anyhow::ensure!(resp.status() == StatusCode::OK, "CONNECT-UDP {target}: HTTP {}", resp.status());
+  info!(target, "CONNECT-UDP session established");
+
+  let mut dgram = BytesMut::with_capacity(payload.len() + 1);
+  dgram.put_u8(CONTEXT_UDP);
+  dgram.extend_from_slice(&payload);
+  dgrams.send_datagram(dgram.freeze())?;
+
+  let reply = loop {
+    let dgram = reader.read_datagram().await?;
+    if dgram.stream_id() != stream.id() {
+      continue;
+    }
+    let mut payload = dgram.into_payload();
+    if let Some((0, n)) = get_varint(&payload) {
+      payload.advance(n);
+      break payload;
+    }
+  };
+
+  // close before the driver goes away, or the peer sees its control stream end first
+  conn.close(http3::NO_ERROR.into(), b"");
+  drive.abort();
+  Ok(reply)
+}
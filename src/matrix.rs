@@ -0,0 +1,412 @@
+//! Test matrix for CI (`quic_echo test --matrix <file>`): starts
+//! `quic_echo_server` once per server variant and runs `quic_echo_client`
+//! against it for every combination of the client axes, then writes a
+//! JUnit or JSON report.
+//!
+//! ```toml
+//! timeout = 30                  # seconds per client run, default 30
+//!
+//! [[server]]
+//! name = "echo"
+//! args = ["--0rtt"]
+//!
+//! [[server]]
+//! name = "perf-small-window"
+//! args = ["--mode", "perf", "--stream-window", "65536"]
+//! client_args = ["--perf"]      # added to every client run against it
+//! axes = ["payload"]            # default: every axis
+//!
+//! [[axis]]
+//! name = "transport"
+//! values = [{ name = "stream" }, { name = "datagram", args = ["--datagram"] }]
+//!
+//! [[axis]]
+//! name = "payload"
+//! values = [
+//!   { name = "1KiB", args = ["--perf-upload", "1024", "--perf-download", "1024"] },
+//!   { name = "16MiB", args = ["--perf-upload", "16777216", "--perf-download", "16777216"] },
+//! ]
+//! ```
+//!
+//! Both binaries are taken from `--bin-dir` (default: next to the running
+//! executable) and talk over 127.0.0.1 on a free port. Unless a server
+//! variant brings its own certificate (`--cert`, `--pkcs12`, `--cert-dir`,
+//! `--acme`), it gets one generated for `localhost`. A case passes when the
+//! client exits with status 0.
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::HashSet,
+  fmt::Write as _,
+  net::{Ipv4Addr, UdpSocket},
+  path::{Path, PathBuf},
+  process::Stdio,
+  time::{Duration, Instant},
+};
+use tokio::{
+  io::{AsyncBufReadExt, BufReader},
+  process::{Child, Command},
+};
+
+/// How long a server gets to start listening.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Lines of a failed client's output kept in the report.
+const OUTPUT_LINES: usize = 20;
+/// Server flags that bring a certificate.
+const CERT_FLAGS: [&str; 4] = ["--cert", "--pkcs12", "--cert-dir", "--acme"];
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Matrix {
+  /// Seconds one client run may take
+  #[serde(default = "default_timeout")]
+  pub timeout: f64,
+  #[serde(rename = "server")]
+  pub servers: Vec<Server>,
+  #[serde(rename = "axis", default)]
+  pub axes: Vec<Axis>,
+}
+
+fn default_timeout() -> f64 {
+  30.0
+}
+
+/// One server configuration.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Server {
+  pub name: String,
+  #[serde(default)]
+  pub args: Vec<String>,
+  /// Client arguments every run against this server gets
+  #[serde(default)]
+  pub client_args: Vec<String>,
+  /// Names of the axes to combine against this server (default: all)
+  pub axes: Option<Vec<String>>,
+}
+
+/// One dimension of client arguments.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Axis {
+  pub name: String,
+  pub values: Vec<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Value {
+  pub name: String,
+  #[serde(default)]
+  pub args: Vec<String>,
+}
+
+impl Matrix {
+  pub fn load(path: &Path) -> Result<Self> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+    let matrix: Matrix = toml::from_str(&text).with_context(|| format!("parse {}", path.display()))?;
+    anyhow::ensure!(!matrix.servers.is_empty(), "the matrix needs at least one [[server]]");
+    anyhow::ensure!(matrix.timeout.is_finite() && matrix.timeout > 0.0, "timeout must be positive");
+    let mut names = HashSet::new();
+    for axis in &matrix.axes {
+      anyhow::ensure!(names.insert(axis.name.as_str()), "axis {:?} is defined twice", axis.name);
+      anyhow::ensure!(!axis.values.is_empty(), "axis {:?} has no values", axis.name);
+    }
+    for server in &matrix.servers {
+      for axis in server.axes.iter().flatten() {
+        anyhow::ensure!(names.contains(axis.as_str()), "server {:?} uses unknown axis {axis:?}", server.name);
+      }
+    }
+    Ok(matrix)
+  }
+
+  /// The client runs against `server`: a name and the arguments of each.
+  fn cases(&self, server: &Server) -> Vec<(String, Vec<String>)> {
+    let axes = self
+      .axes
+      .iter()
+      .filter(|axis| server.axes.as_ref().is_none_or(|names| names.contains(&axis.name)));
+    let mut cases = vec![(Vec::new(), server.client_args.clone())];
+    for axis in axes {
+      cases = cases
+        .into_iter()
+        .flat_map(|(names, args)| {
+          axis.values.iter().map(move |value| {
+            let mut names = names.clone();
+            names.push(format!("{}={}", axis.name, value.name));
+            (names, [args.clone(), value.args.clone()].concat())
+          })
+        })
+        .collect();
+    }
+    cases
+      .into_iter()
+      .map(|(names, args)| (if names.is_empty() { "default".into() } else { names.join(" ") }, args))
+      .collect()
+  }
+}
+
+/// How one client run went.
+#[derive(Debug, Serialize)]
+pub struct CaseResult {
+  pub server: String,
+  pub name: String,
+  pub passed: bool,
+  pub seconds: f64,
+  /// Why it failed, and the end of the client's output
+  pub output: String,
+}
+
+/// Run every case of `matrix` with the binaries in `bin_dir`, printing a
+/// line per case.
+pub async fn run(matrix: &Matrix, bin_dir: &Path) -> Result<Vec<CaseResult>> {
+  let server_bin = binary(bin_dir, "quic_echo_server")?;
+  let client_bin = binary(bin_dir, "quic_echo_client")?;
+  let certs = Certs::generate()?;
+  let timeout = Duration::from_secs_f64(matrix.timeout);
+
+  let mut results = Vec::new();
+  for server in &matrix.servers {
+    let cases = matrix.cases(server);
+    let port = free_port()?;
+    let mut args = vec!["--host".to_string(), Ipv4Addr::LOCALHOST.to_string(), "--port".into(), port.to_string()];
+    if !server.args.iter().any(|arg| CERT_FLAGS.contains(&arg.split('=').next().unwrap_or_default())) {
+      args.extend(["--cert".into(), certs.cert.display().to_string(), "--key".into(), certs.key.display().to_string()]);
+    }
+    args.extend(server.args.iter().cloned());
+
+    let mut child = match start_server(&server_bin, &args).await {
+      Ok(child) => child,
+      Err(e) => {
+        // every case of a server that did not start fails with its reason
+        for (name, _) in cases {
+          println!("FAIL  {} / {name}: server did not start", server.name);
+          results.push(CaseResult { server: server.name.clone(), name, passed: false, seconds: 0.0, output: format!("{e:#}") });
+        }
+        continue;
+      }
+    };
+    for (name, client_args) in cases {
+      let mut args = vec!["--host".to_string(), Ipv4Addr::LOCALHOST.to_string(), "--port".into(), port.to_string()];
+      args.extend(client_args);
+      let result = run_client(&client_bin, &args, timeout, &server.name, name).await;
+      println!(
+        "{}  {} / {}  {:.2}s",
+        if result.passed { "ok  " } else { "FAIL" },
+        result.server,
+        result.name,
+        result.seconds
+      );
+      results.push(result);
+    }
+    let _ = child.kill().await;
+  }
+  Ok(results)
+}
+
+fn binary(dir: &Path, name: &str) -> Result<PathBuf> {
+  let path = dir.join(format!("{name}{}", std::env::consts::EXE_SUFFIX));
+  anyhow::ensure!(path.is_file(), "{} not found (build it, or point --bin-dir at it)", path.display());
+  Ok(path)
+}
+
+/// A UDP port on 127.0.0.1 that was free a moment ago.
+fn free_port() -> Result<u16> {
+  Ok(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?.local_addr()?.port())
+}
+
+/// Start the server and wait until it logs that it is listening.
+async fn start_server(bin: &Path, args: &[String]) -> Result<Child> {
+  let mut child = Command::new(bin)
+    .args(args)
+    .env("NO_COLOR", "1")
+    .stdout(Stdio::null())
+    .stderr(Stdio::piped())
+    .kill_on_drop(true)
+    .spawn()
+    .with_context(|| format!("start {}", bin.display()))?;
+  let mut lines = BufReader::new(child.stderr.take().unwrap()).lines();
+  let mut seen = Vec::new();
+  let ready = tokio::time::timeout(STARTUP_TIMEOUT, async {
+    while let Some(line) = lines.next_line().await? {
+      if line.contains("listening on") {
+        return anyhow::Ok(true);
+      }
+      seen.push(line);
+    }
+    anyhow::Ok(false)
+  })
+  .await;
+  match ready {
+    Ok(Ok(true)) => {
+      // keep draining the log so the server never blocks on a full pipe
+      tokio::spawn(async move { while let Ok(Some(_)) = lines.next_line().await {} });
+      Ok(child)
+    }
+    Ok(Ok(false)) => anyhow::bail!("server exited: {}", last_lines(&seen.join("\n"))),
+    Ok(Err(e)) => Err(e),
+    Err(_) => anyhow::bail!("server not listening after {STARTUP_TIMEOUT:?}: {}", last_lines(&seen.join("\n"))),
+  }
+}
+
+async fn run_client(bin: &Path, args: &[String], timeout: Duration, server: &str, name: String) -> CaseResult {
+  let start = Instant::now();
+  let output = Command::new(bin)
+    .args(args)
+    .env("NO_COLOR", "1")
+    .env("RUST_BACKTRACE", "0")
+    .stdin(Stdio::null())
+    .kill_on_drop(true)
+    .output();
+  let (passed, output) = match tokio::time::timeout(timeout, output).await {
+    Ok(Ok(out)) => {
+      let text = format!("{}{}", String::from_utf8_lossy(&out.stdout), String::from_utf8_lossy(&out.stderr));
+      if out.status.success() {
+        (true, String::new())
+      } else {
+        (false, format!("client {}\n{}", out.status, last_lines(&text)))
+      }
+    }
+    Ok(Err(e)) => (false, format!("cannot run {}: {e}", bin.display())),
+    Err(_) => (false, format!("client timed out after {timeout:?}")),
+  };
+  CaseResult { server: server.into(), name, passed, seconds: start.elapsed().as_secs_f64(), output }
+}
+
+fn last_lines(text: &str) -> String {
+  let lines: Vec<_> = text.lines().collect();
+  lines[lines.len().saturating_sub(OUTPUT_LINES)..].join("\n")
+}
+
+/// A certificate for `localhost` in a temporary directory, removed on drop.
+struct Certs {
+  dir: PathBuf,
+  cert: PathBuf,
+  key: PathBuf,
+}
+
+impl Certs {
+  fn generate() -> Result<Self> {
+    let generated = rcgen::generate_simple_self_signed(vec!["localhost".into()]).context("generate a certificate")?;
+    let dir = std::env::temp_dir().join(format!("quic_echo_matrix_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).with_context(|| format!("create {}", dir.display()))?;
+    let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+    std::fs::write(&cert, generated.cert.pem())?;
+    std::fs::write(&key, generated.signing_key.serialize_pem())?;
+    Ok(Self { dir, cert, key })
+  }
+}
+
+impl Drop for Certs {
+  fn drop(&mut self) {
+    let _ = std::fs::remove_dir_all(&self.dir);
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+  /// JUnit XML, one test suite per server
+  Junit,
+  /// One JSON document with every case
+  Json,
+}
+
+impl ReportFormat {
+  /// `.xml` files get JUnit, anything else JSON.
+  pub fn from_path(path: &Path) -> Self {
+    match path.extension().and_then(|e| e.to_str()) {
+      Some("xml") => ReportFormat::Junit,
+      _ => ReportFormat::Json,
+    }
+  }
+}
+
+/// Write `results` to `path`.
+pub fn write_report(path: &Path, format: ReportFormat, results: &[CaseResult]) -> Result<()> {
+  let text = match format {
+    ReportFormat::Json => {
+      let failed = results.iter().filter(|r| !r.passed).count();
+      let report = serde_json::json!({
+        "passed": results.len() - failed,
+        "failed": failed,
+        "cases": results,
+      });
+      serde_json::to_string_pretty(&report)?
+    }
+    ReportFormat::Junit => junit(results),
+  };
+  std::fs::write(path, text).with_context(|| format!("write {}", path.display()))
+}
+
+fn junit(results: &[CaseResult]) -> String {
+  let failures = |cases: &[&CaseResult]| cases.iter().filter(|r| !r.passed).count();
+  let seconds = |cases: &[&CaseResult]| cases.iter().map(|r| r.seconds).sum::<f64>();
+  let all: Vec<_> = results.iter().collect();
+  let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+  let _ = writeln!(
+    xml,
+    "<testsuites name=\"quic_echo\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">",
+    all.len(),
+    failures(&all),
+    seconds(&all)
+  );
+  let mut servers: Vec<&str> = Vec::new();
+  for result in results {
+    if !servers.contains(&result.server.as_str()) {
+      servers.push(&result.server);
+    }
+  }
+  for server in servers {
+    let cases: Vec<_> = results.iter().filter(|r| r.server == server).collect();
+    let _ = writeln!(
+      xml,
+      "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">",
+      escape(server),
+      cases.len(),
+      failures(&cases),
+      seconds(&cases)
+    );
+    for case in cases {
+      let _ = write!(
+        xml,
+        "    <testcase classname=\"quic_echo.{}\" name=\"{}\" time=\"{:.3}\"",
+        escape(server),
+        escape(&case.name),
+        case.seconds
+      );
+      if case.passed {
+        xml.push_str("/>\n");
+      } else {
+        let message = case.output.lines().next().unwrap_or("failed");
+        let _ = writeln!(
+          xml,
+          ">\n      <failure message=\"{}\">{}</failure>\n    </testcase>",
+          escape(message),
+          escape(&case.output)
+        );
+      }
+    }
+    xml.push_str("  </testsuite>\n");
+  }
+  xml.push_str("</testsuites>\n");
+  xml
+}
+
+fn escape(text: &str) -> String {
+  text
+    .chars()
+    .filter(|&c| c == '\n' || c == '\t' || !c.is_control())
+    .fold(String::new(), |mut out, c| {
+      match c {
+        '&' => out.push_str("&amp;"),
+        '<' => out.push_str("&lt;"),
+        '>' => out.push_str("&gt;"),
+        '"' => out.push_str("&quot;"),
+        '\'' => out.push_str("&apos;"),
+        c => out.push(c),
+      }
+      out
+    })
+}
@@ -0,0 +1,234 @@
+//! UDP segmentation offload control for both binaries. quinn batches
+//! datagrams into one `sendmsg` with GSO and takes coalesced batches from
+//! one `recvmsg` with GRO when the OS supports them; `--gso` and `--gro`
+//! (`auto`, `on`, `off`) pin that down: `on` fails unless the socket has
+//! it, `off` keeps quinn at one datagram per call (and turns `UDP_GRO` off
+//! on Linux).
+//!
+//! The endpoint's socket is wrapped so every send and receive call is
+//! counted; the average datagrams per call at the end of a run show whether
+//! batching actually happened, which is what tends to differ between hosts.
+//! `--dscp` marks it too (see [`dscp`]), and the markings of what arrived
+//! are logged with the counts.
+
+use crate::{
+  dscp::{self, Marks},
+  pcap::{self, Pcap},
+  uring::IoBackend,
+};
+use anyhow::Result;
+use clap::{Args, ValueEnum};
+use quinn::{
+  AsyncUdpSocket, UdpPoller,
+  udp::{RecvMeta, Transmit},
+};
+use std::{
+  fmt,
+  io::{self, IoSliceMut},
+  net::SocketAddr,
+  pin::Pin,
+  sync::{
+    Arc,
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+  },
+  task::{Context, Poll},
+};
+use tracing::info;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Offload {
+  /// Use it if the OS supports it
+  #[default]
+  Auto,
+  /// Fail unless the socket supports it
+  On,
+  /// Never batch
+  Off,
+}
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct OffloadOptions {
+  /// UDP generic segmentation offload (batched sends)
+  #[clap(long, env = "QUIC_ECHO_GSO", value_enum, default_value_t)]
+  pub gso: Offload,
+  /// UDP generic receive offload (batched receives)
+  #[clap(long, env = "QUIC_ECHO_GRO", value_enum, default_value_t)]
+  pub gro: Offload,
+  /// How the socket's I/O is driven
+  #[clap(long, env = "QUIC_ECHO_IO_BACKEND", value_enum, default_value_t)]
+  pub io_backend: IoBackend,
+  /// DSCP to mark outgoing packets with: 0-63 or a name like ef, af41, cs1 (Linux)
+  #[clap(long, env = "QUIC_ECHO_DSCP", value_parser = dscp::parse)]
+  pub dscp: Option<u8>,
+}
+
+impl OffloadOptions {
+  /// Hand `socket` to quinn through the chosen I/O backend, capturing it
+  /// like [`pcap::wrap`], with offload set up as asked and its calls counted
+  /// in `stats`.
+  pub fn wrap(
+    &self,
+    socket: std::net::UdpSocket,
+    capture: Option<&Arc<Pcap>>,
+    stats: &Arc<OffloadStats>,
+  ) -> Result<Arc<dyn AsyncUdpSocket>> {
+    // quinn enables GRO while wrapping the socket; keep a handle to undo that
+    let raw = socket.try_clone()?;
+    let mut backend = self.io_backend.wrap(socket)?;
+    if let Some(dscp) = self.dscp {
+      backend = dscp::mark(backend, raw.try_clone()?, dscp, self.io_backend, &stats.marks)?;
+    }
+    let inner = pcap::wrap(backend, capture)?;
+    let (gso, gro) = (inner.max_transmit_segments(), inner.max_receive_segments());
+    anyhow::ensure!(self.gso != Offload::On || gso > 1, "--gso on: the OS does not offer UDP segmentation offload");
+    anyhow::ensure!(self.gro != Offload::On || gro > 1, "--gro on: the OS does not offer UDP receive offload");
+    if self.gro == Offload::Off && gro > 1 {
+      disable_gro(&raw)?;
+    }
+    drop(raw);
+
+    let counted = Counted { inner, gso_off: self.gso == Offload::Off, gro_off: self.gro == Offload::Off, stats: stats.clone() };
+    info!(
+      local = %counted.local_addr()?,
+      gso_segments = counted.max_transmit_segments(),
+      gro_segments = counted.max_receive_segments(),
+      "UDP offload"
+    );
+    Ok(Arc::new(counted))
+  }
+}
+
+#[cfg(target_os = "linux")]
+fn disable_gro(socket: &std::net::UdpSocket) -> io::Result<()> {
+  use std::os::fd::AsRawFd;
+  let off: libc::c_int = 0;
+  // SAFETY: the fd is open for the duration of the call and `off` outlives it
+  let rc = unsafe {
+    libc::setsockopt(
+      socket.as_raw_fd(),
+      libc::SOL_UDP,
+      libc::UDP_GRO,
+      (&off as *const libc::c_int).cast(),
+      size_of::<libc::c_int>() as libc::socklen_t,
+    )
+  };
+  if rc == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn disable_gro(_socket: &std::net::UdpSocket) -> io::Result<()> {
+  // elsewhere quinn only batches receives that we also report as unbatched
+  Ok(())
+}
+
+/// Send and receive calls of an endpoint's sockets, the datagrams they
+/// carried and, with `--dscp`, the markings of those received.
+#[derive(Debug, Default)]
+pub struct OffloadStats {
+  send_calls: AtomicU64,
+  sent: AtomicU64,
+  recv_calls: AtomicU64,
+  received: AtomicU64,
+  /// Largest GSO and GRO batch seen
+  max_sent: AtomicUsize,
+  max_received: AtomicUsize,
+  marks: Arc<Marks>,
+}
+
+impl OffloadStats {
+  fn per_call(datagrams: u64, calls: u64) -> f64 {
+    datagrams as f64 / calls.max(1) as f64
+  }
+
+  /// Log the counters in the current span.
+  pub fn log(&self) {
+    let (send_calls, sent) = (self.send_calls.load(Ordering::Relaxed), self.sent.load(Ordering::Relaxed));
+    let (recv_calls, received) = (self.recv_calls.load(Ordering::Relaxed), self.received.load(Ordering::Relaxed));
+    info!(
+      sent,
+      send_calls,
+      sent_per_call = format_args!("{:.2}", Self::per_call(sent, send_calls)),
+      largest_gso_batch = self.max_sent.load(Ordering::Relaxed),
+      received,
+      recv_calls,
+      received_per_call = format_args!("{:.2}", Self::per_call(received, recv_calls)),
+      largest_gro_batch = self.max_received.load(Ordering::Relaxed),
+      "UDP offload stats"
+    );
+    self.marks.log();
+  }
+
+  /// [`log`](Self::log) once the returned guard is dropped.
+  pub fn report_on_drop(self: &Arc<Self>) -> Report {
+    Report(self.clone())
+  }
+}
+
+/// Logs the stats when dropped, however the run ends.
+pub struct Report(Arc<OffloadStats>);
+
+impl Drop for Report {
+  fn drop(&mut self) {
+    self.0.log();
+  }
+}
+
+/// A socket whose calls are counted, and whose batching may be turned off.
+struct Counted {
+  inner: Arc<dyn AsyncUdpSocket>,
+  gso_off: bool,
+  gro_off: bool,
+  stats: Arc<OffloadStats>,
+}
+
+impl fmt::Debug for Counted {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Counted").field("inner", &self.inner).finish_non_exhaustive()
+  }
+}
+
+impl AsyncUdpSocket for Counted {
+  fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+    self.inner.clone().create_io_poller()
+  }
+
+  fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+    self.inner.try_send(transmit)?;
+    let segment = transmit.segment_size.unwrap_or(transmit.contents.len()).max(1);
+    let datagrams = transmit.contents.len().div_ceil(segment);
+    self.stats.send_calls.fetch_add(1, Ordering::Relaxed);
+    self.stats.sent.fetch_add(datagrams as u64, Ordering::Relaxed);
+    self.stats.max_sent.fetch_max(datagrams, Ordering::Relaxed);
+    Ok(())
+  }
+
+  fn poll_recv(&self, cx: &mut Context, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) -> Poll<io::Result<usize>> {
+    let n = std::task::ready!(self.inner.poll_recv(cx, bufs, meta))?;
+    let mut datagrams = 0;
+    for meta in &meta[..n] {
+      let stride = if meta.stride == 0 { meta.len } else { meta.stride };
+      let batch = meta.len.div_ceil(stride.max(1));
+      self.stats.max_received.fetch_max(batch, Ordering::Relaxed);
+      datagrams += batch;
+    }
+    self.stats.recv_calls.fetch_add(1, Ordering::Relaxed);
+    self.stats.received.fetch_add(datagrams as u64, Ordering::Relaxed);
+    Poll::Ready(Ok(n))
+  }
+
+  fn local_addr(&self) -> io::Result<SocketAddr> {
+    self.inner.local_addr()
+  }
+
+  fn max_transmit_segments(&self) -> usize {
+    if self.gso_off { 1 } else { self.inner.max_transmit_segments() }
+  }
+
+  fn max_receive_segments(&self) -> usize {
+    if self.gro_off { 1 } else { self.inner.max_receive_segments() }
+  }
+
+  fn may_fragment(&self) -> bool {
+    self.inner.may_fragment()
+  }
+}
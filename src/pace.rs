@@ -0,0 +1,180 @@
+//! Token-bucket pacing shared by both binaries: `--read-rate` throttles how
+//! fast stream data is taken from quinn, so the receive window fills up and
+//! the sender runs into flow control, as behind a slow reader.
+//!
+//! On the sending side, the client's `--rate-limit` caps what one connection
+//! offers: a [`RateLimit`] is shared by all its stream writes and datagram
+//! sends, which wait for tokens before handing data to quinn.
+//!
+//! A [`Spacer`] keeps a fixed send schedule (the server's `--spacing` for
+//! pushed datagrams) to well under a millisecond, which tokio's timer
+//! wheel cannot. It blocks its thread, sleeping until shortly before each
+//! deadline and spinning for the rest, so it belongs on a blocking task
+//! rather than a runtime worker.
+
+use bytes::Bytes;
+use quinn::{ReadError, ReadToEndError, RecvStream, SendStream, WriteError};
+use std::{
+  sync::Arc,
+  time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+/// Most bytes read in one go while paced.
+const MAX_SLICE: usize = 64 * 1024;
+/// How long before a [`Spacer`] deadline sleeping hands over to spinning;
+/// thread sleeps overshoot by the kernel's timer slack (50 µs on Linux)
+const SPIN: Duration = Duration::from_micros(200);
+
+/// A token bucket refilled at `rate` bytes per second, holding up to a
+/// tenth of a second's worth.
+#[derive(Debug)]
+pub struct Pacer {
+  rate: f64,
+  burst: f64,
+  tokens: f64,
+  last: Instant,
+}
+
+impl Pacer {
+  pub fn new(rate: u64) -> Self {
+    let rate = rate.max(1) as f64;
+    let burst = (rate / 10.0).max(1.0);
+    Self { rate, burst, tokens: burst, last: Instant::now() }
+  }
+
+  /// Bytes worth asking for at once: a burst, within sane bounds.
+  pub fn slice(&self) -> usize {
+    (self.burst as usize).clamp(1, MAX_SLICE)
+  }
+
+  /// Account for `n` bytes, sleeping while the bucket is in debt.
+  pub async fn take(&mut self, n: usize) {
+    let now = Instant::now();
+    self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.burst);
+    self.last = now;
+    self.tokens -= n as f64;
+    if self.tokens < 0.0 {
+      tokio::time::sleep(Duration::from_secs_f64(-self.tokens / self.rate)).await;
+    }
+  }
+}
+
+/// Read into `chunks` like `RecvStream::read_chunks`, or, with a `pacer`,
+/// one slice at a time at the pacer's rate.
+pub async fn read_chunks(
+  recv: &mut RecvStream,
+  chunks: &mut [Bytes],
+  pacer: Option<&mut Pacer>,
+) -> Result<Option<usize>, ReadError> {
+  let Some(pacer) = pacer else {
+    return recv.read_chunks(chunks).await;
+  };
+  match recv.read_chunk(pacer.slice(), true).await? {
+    Some(chunk) => {
+      pacer.take(chunk.bytes.len()).await;
+      chunks[0] = chunk.bytes;
+      Ok(Some(1))
+    }
+    None => Ok(None),
+  }
+}
+
+/// `RecvStream::read_to_end`, paced by `pacer` if there is one.
+pub async fn read_to_end(
+  recv: &mut RecvStream,
+  size_limit: usize,
+  pacer: Option<&mut Pacer>,
+) -> Result<Vec<u8>, ReadToEndError> {
+  let Some(pacer) = pacer else {
+    return recv.read_to_end(size_limit).await;
+  };
+  let mut data = Vec::new();
+  while let Some(chunk) = recv.read_chunk(pacer.slice(), true).await? {
+    if data.len() + chunk.bytes.len() > size_limit {
+      return Err(ReadToEndError::TooLong);
+    }
+    pacer.take(chunk.bytes.len()).await;
+    data.extend_from_slice(&chunk.bytes);
+  }
+  Ok(data)
+}
+
+/// A send-rate cap shared by everything one connection sends.
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+  pacer: Arc<Mutex<Pacer>>,
+  slice: usize,
+}
+
+impl RateLimit {
+  /// A cap of `mbps` megabits per second.
+  pub fn mbps(mbps: f64) -> Self {
+    let pacer = Pacer::new((mbps * 1e6 / 8.0) as u64);
+    Self { slice: pacer.slice(), pacer: Arc::new(Mutex::new(pacer)) }
+  }
+
+  /// Bytes worth sending at once under the cap.
+  pub fn slice(&self) -> usize {
+    self.slice
+  }
+
+  /// Wait until `n` more bytes fit under the cap. Senders queue up on the
+  /// bucket, so they share the rate.
+  pub async fn take(&self, n: usize) {
+    self.pacer.lock().await.take(n).await;
+  }
+}
+
+/// `SendStream::write_all`, a slice at a time under `limit` if there is one.
+pub async fn write_all(send: &mut SendStream, data: &[u8], limit: Option<&RateLimit>) -> Result<(), WriteError> {
+  let Some(limit) = limit else {
+    return send.write_all(data).await;
+  };
+  for slice in data.chunks(limit.slice) {
+    limit.take(slice.len()).await;
+    send.write_all(slice).await?;
+  }
+  Ok(())
+}
+
+/// Deadlines every `interval` from the start, met by sleeping the thread
+/// until [`SPIN`] before each one and spinning the rest of the way.
+/// Deadlines that have passed by a whole interval are counted as skipped
+/// and the schedule restarts, rather than making them up in a burst.
+#[derive(Debug)]
+pub struct Spacer {
+  interval: Duration,
+  next: Instant,
+  skipped: u64,
+}
+
+impl Spacer {
+  /// A schedule whose first deadline is now.
+  pub fn new(interval: Duration) -> Self {
+    Self { interval: interval.max(Duration::from_nanos(1)), next: Instant::now(), skipped: 0 }
+  }
+
+  /// Block until the next deadline.
+  pub fn wait(&mut self) {
+    let now = Instant::now();
+    if now >= self.next + self.interval {
+      self.skipped += ((now - self.next).as_nanos() / self.interval.as_nanos()) as u64;
+      self.next = now;
+    }
+    if let Some(coarse) = self.next.checked_sub(SPIN)
+      && coarse > now
+    {
+      std::thread::sleep(coarse - now);
+    }
+    while Instant::now() < self.next {
+      std::hint::spin_loop();
+    }
+    self.next += self.interval;
+  }
+
+  /// Deadlines skipped because the sender fell behind.
+  pub fn skipped(&self) -> u64 {
+    self.skipped
+  }
+}
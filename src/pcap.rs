@@ -0,0 +1,223 @@
+//! Built-in packet capture (`--pcap <file>`) for both binaries: the
+//! endpoint's UDP socket is wrapped so every datagram it sends or receives is
+//! also written to a classic pcap file, no root or tcpdump needed.
+//!
+//! Only UDP payloads are visible at the socket, so each packet gets a
+//! synthesized IPv4 or IPv6 and UDP header (LINKTYPE_RAW) from the socket's
+//! addresses; Wireshark then dissects the QUIC inside, and decrypts it with
+//! a `--keylog` file. GSO/GRO batches are split into single datagrams.
+
+use anyhow::{Context as _, Result};
+use quinn::{
+  AsyncUdpSocket, UdpPoller,
+  udp::{RecvMeta, Transmit},
+};
+use std::{
+  fmt,
+  fs::File,
+  io::{self, BufWriter, IoSliceMut, Write},
+  net::{IpAddr, SocketAddr},
+  path::Path,
+  pin::Pin,
+  sync::{Arc, Mutex},
+  task::{Context, Poll},
+  time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+const MAGIC: u32 = 0xa1b2_c3d4;
+const SNAPLEN: u32 = 262_144;
+const LINKTYPE_RAW: u32 = 101;
+const UDP: u8 = 17;
+
+/// A capture file shared by every socket of the process.
+pub struct Pcap {
+  out: Mutex<BufWriter<File>>,
+}
+
+impl Pcap {
+  pub fn create(path: &Path) -> Result<Arc<Self>> {
+    let file = File::create(path).with_context(|| format!("create {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    out.write_all(&MAGIC.to_le_bytes())?;
+    out.write_all(&2u16.to_le_bytes())?;
+    out.write_all(&4u16.to_le_bytes())?;
+    out.write_all(&[0; 8])?; // thiszone, sigfigs
+    out.write_all(&SNAPLEN.to_le_bytes())?;
+    out.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+    out.flush()?;
+    Ok(Arc::new(Self { out: Mutex::new(out) }))
+  }
+
+  fn write(&self, src: SocketAddr, dst: SocketAddr, payload: &[u8]) {
+    let packet = packet(src, dst, payload);
+    let ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let res = (|| {
+      let mut out = self.out.lock().unwrap();
+      out.write_all(&(ts.as_secs() as u32).to_le_bytes())?;
+      out.write_all(&ts.subsec_micros().to_le_bytes())?;
+      out.write_all(&(packet.len() as u32).to_le_bytes())?;
+      out.write_all(&(packet.len() as u32).to_le_bytes())?;
+      out.write_all(&packet)?;
+      // flush per packet so the capture is complete whenever the process stops
+      out.flush()
+    })();
+    if let Err(e) = res {
+      warn!("cannot write packet capture: {e}");
+    }
+  }
+}
+
+/// Capture the traffic of `inner` to `pcap`, if given.
+pub fn wrap(inner: Arc<dyn AsyncUdpSocket>, pcap: Option<&Arc<Pcap>>) -> io::Result<Arc<dyn AsyncUdpSocket>> {
+  Ok(match pcap {
+    Some(pcap) => {
+      let local = inner.local_addr()?;
+      Arc::new(Capture { inner, pcap: pcap.clone(), local })
+    }
+    None => inner,
+  })
+}
+
+struct Capture {
+  inner: Arc<dyn AsyncUdpSocket>,
+  pcap: Arc<Pcap>,
+  local: SocketAddr,
+}
+
+impl fmt::Debug for Capture {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Capture").field("inner", &self.inner).finish_non_exhaustive()
+  }
+}
+
+impl AsyncUdpSocket for Capture {
+  fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+    self.inner.clone().create_io_poller()
+  }
+
+  fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+    self.inner.try_send(transmit)?;
+    let src = SocketAddr::new(transmit.src_ip.unwrap_or(self.local.ip()), self.local.port());
+    let segment = transmit.segment_size.unwrap_or(transmit.contents.len()).max(1);
+    for datagram in transmit.contents.chunks(segment) {
+      self.pcap.write(src, transmit.destination, datagram);
+    }
+    Ok(())
+  }
+
+  fn poll_recv(&self, cx: &mut Context, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) -> Poll<io::Result<usize>> {
+    let n = std::task::ready!(self.inner.poll_recv(cx, bufs, meta))?;
+    for (buf, meta) in bufs.iter().zip(meta.iter()).take(n) {
+      let dst = SocketAddr::new(meta.dst_ip.unwrap_or(self.local.ip()), self.local.port());
+      let stride = if meta.stride == 0 { meta.len } else { meta.stride };
+      for datagram in buf[..meta.len].chunks(stride.max(1)) {
+        self.pcap.write(meta.addr, dst, datagram);
+      }
+    }
+    Poll::Ready(Ok(n))
+  }
+
+  fn local_addr(&self) -> io::Result<SocketAddr> {
+    self.inner.local_addr()
+  }
+
+  fn max_transmit_segments(&self) -> usize {
+    self.inner.max_transmit_segments()
+  }
+
+  fn max_receive_segments(&self) -> usize {
+    self.inner.max_receive_segments()
+  }
+
+  fn may_fragment(&self) -> bool {
+    self.inner.may_fragment()
+  }
+}
+
+/// IP + UDP headers around `payload`; IPv4 if both ends are (or map to)
+/// IPv4, IPv6 otherwise.
+fn packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+  let udp_len = (8 + payload.len()) as u16;
+  let mut udp = Vec::with_capacity(udp_len as usize);
+  udp.extend_from_slice(&src.port().to_be_bytes());
+  udp.extend_from_slice(&dst.port().to_be_bytes());
+  udp.extend_from_slice(&udp_len.to_be_bytes());
+  udp.extend_from_slice(&[0, 0]);
+  udp.extend_from_slice(payload);
+
+  let mut out = Vec::with_capacity(40 + udp.len());
+  match (src.ip().to_canonical(), dst.ip().to_canonical()) {
+    (IpAddr::V4(s), IpAddr::V4(d)) => {
+      let mut pseudo = [0u8; 12];
+      pseudo[..4].copy_from_slice(&s.octets());
+      pseudo[4..8].copy_from_slice(&d.octets());
+      pseudo[9] = UDP;
+      pseudo[10..].copy_from_slice(&udp_len.to_be_bytes());
+      set_udp_checksum(&mut udp, &pseudo);
+
+      let mut ip = [0u8; 20];
+      ip[0] = 0x45;
+      ip[2..4].copy_from_slice(&(20 + udp_len).to_be_bytes());
+      ip[6] = 0x40; // don't fragment
+      ip[8] = 64;
+      ip[9] = UDP;
+      ip[12..16].copy_from_slice(&s.octets());
+      ip[16..20].copy_from_slice(&d.octets());
+      let sum = checksum(&[&ip]);
+      ip[10..12].copy_from_slice(&sum.to_be_bytes());
+      out.extend_from_slice(&ip);
+    }
+    (s, d) => {
+      let v6 = |ip: IpAddr| match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+      };
+      let (s, d) = (v6(s), v6(d));
+      let mut pseudo = [0u8; 40];
+      pseudo[..16].copy_from_slice(&s.octets());
+      pseudo[16..32].copy_from_slice(&d.octets());
+      pseudo[34..36].copy_from_slice(&udp_len.to_be_bytes());
+      pseudo[39] = UDP;
+      set_udp_checksum(&mut udp, &pseudo);
+
+      let mut ip = [0u8; 40];
+      ip[0] = 0x60;
+      ip[4..6].copy_from_slice(&udp_len.to_be_bytes());
+      ip[6] = UDP;
+      ip[7] = 64;
+      ip[8..24].copy_from_slice(&s.octets());
+      ip[24..40].copy_from_slice(&d.octets());
+      out.extend_from_slice(&ip);
+    }
+  }
+  out.extend_from_slice(&udp);
+  out
+}
+
+fn set_udp_checksum(udp: &mut [u8], pseudo: &[u8]) {
+  let sum = match checksum(&[pseudo, udp]) {
+    0 => 0xffff,
+    sum => sum,
+  };
+  udp[6..8].copy_from_slice(&sum.to_be_bytes());
+}
+
+/// Internet checksum over the concatenation of `parts` (each of even length
+/// except the last).
+fn checksum(parts: &[&[u8]]) -> u16 {
+  let mut sum = 0u32;
+  for part in parts {
+    let mut words = part.chunks_exact(2);
+    for word in &mut words {
+      sum += u32::from(u16::from_be_bytes([word[0], word[1]]));
+    }
+    if let [last] = words.remainder() {
+      sum += u32::from(*last) << 8;
+    }
+  }
+  while sum > 0xffff {
+    sum = (sum & 0xffff) + (sum >> 16);
+  }
+  !(sum as u16)
+}
@@ -0,0 +1,158 @@
+//! The `quinn/perf` protocol (ALPN "perf"): on each bidirectional stream the
+//! client sends the response size it wants as a big-endian u64, then its
+//! upload; the server drains the upload and answers with that many bytes.
+//! Unidirectional streams are upload-only and simply drained.
+
+use crate::{flow, pace::RateLimit};
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use quinn::ConnectionError;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn, Instrument};
+
+pub const ALPN: &[u8] = b"perf";
+
+const CHUNK: usize = 64 * 1024;
+/// Largest upload or download of one warmup request.
+const WARMUP_ROUND: u64 = 4 * 1024 * 1024;
+static ZEROS: [u8; CHUNK] = [0; CHUNK];
+
+/// Serve perf requests on `conn` until the client goes away.
+pub async fn serve(conn: quinn::Connection) -> Result<()> {
+  loop {
+    tokio::select! {
+      bi = conn.accept_bi() => {
+        let (send, recv) = match bi {
+          Ok(s) => s,
+          Err(e) => return closed(e),
+        };
+        tokio::spawn(
+          async move {
+            if let Err(e) = respond(send, recv).await {
+              warn!("perf stream failed: {e:#}");
+            }
+          }
+          .in_current_span(),
+        );
+      }
+      uni = conn.accept_uni() => {
+        let mut recv = match uni {
+          Ok(s) => s,
+          Err(e) => return closed(e),
+        };
+        tokio::spawn(
+          async move {
+            if let Err(e) = drain(&mut recv).await {
+              warn!("perf upload failed: {e:#}");
+            }
+          }
+          .in_current_span(),
+        );
+      }
+    }
+  }
+}
+
+fn closed(e: ConnectionError) -> Result<()> {
+  match e {
+    ConnectionError::ApplicationClosed { .. } | ConnectionError::LocallyClosed => Ok(()),
+    e => Err(e.into()),
+  }
+}
+
+async fn respond(mut send: quinn::SendStream, mut recv: quinn::RecvStream) -> Result<()> {
+  let mut header = [0u8; 8];
+  recv.read_exact(&mut header).await.context("read perf request header")?;
+  let size = u64::from_be_bytes(header);
+  let uploaded = drain(&mut recv).await?;
+  debug!(uploaded, size, "perf request");
+  send_zeros(&mut send, size, None).await?;
+  send.finish()?;
+  Ok(())
+}
+
+async fn drain(recv: &mut quinn::RecvStream) -> Result<u64> {
+  let mut total = 0;
+  while let Some(chunk) = recv.read_chunk(usize::MAX, false).await? {
+    total += chunk.bytes.len() as u64;
+  }
+  Ok(total)
+}
+
+/// Write `size` zero bytes to `send`, under `limit` if given.
+pub async fn send_zeros(send: &mut quinn::SendStream, mut size: u64, limit: Option<&RateLimit>) -> Result<()> {
+  let chunk = limit.map_or(CHUNK, |l| l.slice().min(CHUNK));
+  while size > 0 {
+    let n = size.min(chunk as u64) as usize;
+    if let Some(limit) = limit {
+      limit.take(n).await;
+    }
+    send.write_chunk(Bytes::from_static(&ZEROS[..n])).await?;
+    size -= n as u64;
+  }
+  Ok(())
+}
+
+/// Outcome of one perf request.
+pub struct PerfRun {
+  pub upload: u64,
+  pub download: u64,
+  pub ttfb: Option<Duration>,
+  pub elapsed: Duration,
+}
+
+impl PerfRun {
+  /// Throughput in Mbit/s over the whole request.
+  pub fn mbps(&self, bytes: u64) -> f64 {
+    bytes as f64 * 8.0 / self.elapsed.as_secs_f64().max(f64::EPSILON) / 1e6
+  }
+}
+
+/// Run requests shaped like a measured one, with at most [`WARMUP_ROUND`]
+/// bytes each way, until `duration` has passed, so congestion control has
+/// left slow start when measurement begins. Returns the totals moved.
+pub async fn warmup(
+  conn: &quinn::Connection,
+  upload: u64,
+  download: u64,
+  duration: Duration,
+  limit: Option<&RateLimit>,
+) -> Result<PerfRun> {
+  let start = Instant::now();
+  let (mut up, mut down) = (0, 0);
+  while start.elapsed() < duration {
+    let run = request(conn, upload.min(WARMUP_ROUND), download.min(WARMUP_ROUND), limit).await?;
+    up += run.upload;
+    down += run.download;
+  }
+  Ok(PerfRun { upload: up, download: down, ttfb: None, elapsed: start.elapsed() })
+}
+
+/// Upload `upload` bytes (under `limit`, if given) and ask for `download`
+/// bytes back on one stream.
+pub async fn request(conn: &quinn::Connection, upload: u64, download: u64, limit: Option<&RateLimit>) -> Result<PerfRun> {
+  let start = Instant::now();
+  let (mut send, mut recv) = flow::open_bi(conn).await?;
+
+  let sending = async {
+    send.write_all(&download.to_be_bytes()).await?;
+    send_zeros(&mut send, upload, limit).await?;
+    send.finish()?;
+    anyhow::Ok(())
+  };
+  let receiving = async {
+    let mut ttfb = None;
+    let mut total = 0;
+    while let Some(chunk) = recv.read_chunk(usize::MAX, false).await? {
+      ttfb.get_or_insert_with(|| start.elapsed());
+      total += chunk.bytes.len() as u64;
+    }
+    anyhow::Ok((total, ttfb))
+  };
+  let (sent, received) = tokio::join!(sending, receiving);
+  sent?;
+  let (total, ttfb) = received?;
+  anyhow::ensure!(total == download, "asked for {download} bytes, got {total}");
+
+  Ok(PerfRun { upload, download, ttfb, elapsed: start.elapsed() })
+}
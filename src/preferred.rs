@@ -0,0 +1,136 @@
+//! Server preferred address (`--preferred-addr`): the server advertises a
+//! second address in its transport parameters, and clients that support it
+//! move their connection there once the handshake is confirmed (RFC 9000,
+//! section 9.6).
+//!
+//! Packets sent to either address must reach the same quinn endpoint, so
+//! [`Dual`] presents the main socket and one bound to the preferred address
+//! as a single socket: it receives from both, and replies to each peer from
+//! whichever socket that peer last sent to. The first packet of a peer on
+//! the preferred socket is logged, which is how a migration shows up.
+
+use quinn::{
+  AsyncUdpSocket, UdpPoller,
+  udp::{RecvMeta, Transmit},
+};
+use std::{
+  collections::HashSet,
+  fmt,
+  io::{self, IoSliceMut},
+  net::SocketAddr,
+  pin::Pin,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+  },
+  task::{Context, Poll},
+};
+use tracing::info;
+
+/// The main socket and the preferred-address socket as one.
+pub struct Dual {
+  main: Arc<dyn AsyncUdpSocket>,
+  preferred: Arc<dyn AsyncUdpSocket>,
+  preferred_addr: SocketAddr,
+  /// Peers whose latest packet arrived on the preferred socket
+  moved: Mutex<HashSet<SocketAddr>>,
+  /// Which socket to poll first, alternated so neither starves the other
+  preferred_first: AtomicBool,
+}
+
+impl Dual {
+  pub fn new(main: Arc<dyn AsyncUdpSocket>, preferred: Arc<dyn AsyncUdpSocket>) -> io::Result<Arc<Self>> {
+    let preferred_addr = preferred.local_addr()?;
+    Ok(Arc::new(Self {
+      main,
+      preferred,
+      preferred_addr,
+      moved: Mutex::new(HashSet::new()),
+      preferred_first: AtomicBool::new(false),
+    }))
+  }
+
+  /// Note which socket the peers in `meta` used.
+  fn track(&self, meta: &[RecvMeta], preferred: bool) {
+    let mut moved = self.moved.lock().unwrap();
+    for meta in meta {
+      if preferred && moved.insert(meta.addr) {
+        info!(peer = %meta.addr, preferred = %self.preferred_addr, "peer moved to the preferred address");
+      } else if !preferred && moved.remove(&meta.addr) {
+        info!(peer = %meta.addr, "peer is back on the main address");
+      }
+    }
+  }
+}
+
+impl fmt::Debug for Dual {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Dual")
+      .field("main", &self.main)
+      .field("preferred", &self.preferred)
+      .finish_non_exhaustive()
+  }
+}
+
+impl AsyncUdpSocket for Dual {
+  fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+    Box::pin(DualPoller { main: self.main.clone().create_io_poller(), preferred: self.preferred.clone().create_io_poller() })
+  }
+
+  fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+    if self.moved.lock().unwrap().contains(&transmit.destination) {
+      self.preferred.try_send(transmit)
+    } else {
+      self.main.try_send(transmit)
+    }
+  }
+
+  fn poll_recv(&self, cx: &mut Context, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) -> Poll<io::Result<usize>> {
+    let preferred_first = !self.preferred_first.fetch_xor(true, Ordering::Relaxed);
+    let order = if preferred_first { [true, false] } else { [false, true] };
+    for preferred in order {
+      let socket = if preferred { &self.preferred } else { &self.main };
+      // a pending poll leaves the waker registered with that socket
+      if let Poll::Ready(res) = socket.poll_recv(cx, bufs, meta) {
+        let n = res?;
+        self.track(&meta[..n], preferred);
+        return Poll::Ready(Ok(n));
+      }
+    }
+    Poll::Pending
+  }
+
+  fn local_addr(&self) -> io::Result<SocketAddr> {
+    self.main.local_addr()
+  }
+
+  fn max_transmit_segments(&self) -> usize {
+    self.main.max_transmit_segments().min(self.preferred.max_transmit_segments())
+  }
+
+  fn max_receive_segments(&self) -> usize {
+    self.main.max_receive_segments().min(self.preferred.max_receive_segments())
+  }
+
+  fn may_fragment(&self) -> bool {
+    self.main.may_fragment() || self.preferred.may_fragment()
+  }
+}
+
+/// Writable once both sockets are.
+#[derive(Debug)]
+struct DualPoller {
+  main: Pin<Box<dyn UdpPoller>>,
+  preferred: Pin<Box<dyn UdpPoller>>,
+}
+
+impl UdpPoller for DualPoller {
+  fn poll_writable(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+    let main = self.main.as_mut().poll_writable(cx)?;
+    let preferred = self.preferred.as_mut().poll_writable(cx)?;
+    match (main, preferred) {
+      (Poll::Ready(()), Poll::Ready(())) => Poll::Ready(Ok(())),
+      _ => Poll::Pending,
+    }
+  }
+}
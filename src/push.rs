@@ -0,0 +1,332 @@
+//! Server-initiated datagrams: with `--push-dgrams <rate>` the echo server
+//! sends every client a stream of numbered datagrams it never asked for,
+//! and the client's `--listen` mode receives them and reports what
+//! arrived, to test downstream-only unreliable delivery such as telemetry
+//! fan-out.
+//!
+//! ```text
+//! PUSH  seq (u64) | microseconds since the push started (u64) | padding
+//! ```
+//!
+//! The timestamp is on the server's clock, so only differences between
+//! datagrams mean anything: the client uses them for the interarrival
+//! jitter (RFC 3550, 6.4.1) without the clocks having to agree.
+//!
+//! `--push-stream bi|uni` does the same for streams: right after the
+//! handshake the server opens one stream of the given kind and sends
+//! `--push-stream-size` bytes of a fixed pattern, which the client's
+//! `--accept-stream` accepts and verifies. On a bidirectional stream the
+//! client echoes the data back and the server verifies that in turn.
+
+use crate::{pace::Spacer, verify};
+use anyhow::{Context, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use clap::ValueEnum;
+use quinn::{Connection, ConnectionError, SendDatagramError};
+use std::{
+  collections::HashSet,
+  fmt,
+  time::{Duration, Instant},
+};
+use tokio::time::MissedTickBehavior;
+use tracing::debug;
+
+/// Sequence number and timestamp.
+pub const HEADER: usize = 16;
+/// Largest stream `--accept-stream` takes in.
+const MAX_STREAM: usize = 64 * 1024 * 1024;
+
+/// When pushed datagrams leave.
+#[derive(Clone, Copy, Debug)]
+pub enum Pacing {
+  /// `rate` per second off tokio's millisecond timer, catching up in a
+  /// burst after a stall so the average holds
+  Rate(f64),
+  /// `burst` datagrams back to back every `spacing`, kept to well under a
+  /// millisecond by a [`Spacer`]
+  Spaced { spacing: Duration, burst: u32 },
+}
+
+impl Pacing {
+  /// Datagrams per second.
+  pub fn rate(&self) -> f64 {
+    match *self {
+      Pacing::Rate(rate) => rate,
+      Pacing::Spaced { spacing, burst } => f64::from(burst) / spacing.as_secs_f64(),
+    }
+  }
+}
+
+/// What [`send`] pushed before the connection closed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Pushed {
+  pub sent: u64,
+  /// Bursts a [`Pacing::Spaced`] schedule skipped because the sender fell
+  /// behind
+  pub skipped: u64,
+}
+
+/// Send datagrams of `size` bytes (at least [`HEADER`]) as `pacing` says
+/// until the connection closes.
+pub async fn send(conn: &Connection, pacing: Pacing, size: usize) -> Result<Pushed> {
+  let max = conn.max_datagram_size().context("peer does not support datagrams")?;
+  anyhow::ensure!(size <= max, "--push-size {size} does not fit a {max} B datagram");
+  let start = Instant::now();
+  let mut pushed = Pushed::default();
+  let frame = move |seq: u64| {
+    let mut frame = BytesMut::with_capacity(size);
+    frame.put_u64(seq);
+    frame.put_u64(start.elapsed().as_micros() as u64);
+    frame.resize(size, 0);
+    frame.freeze()
+  };
+  match pacing {
+    Pacing::Rate(rate) => {
+      let mut pace = tokio::time::interval(Duration::from_secs_f64(1.0 / rate));
+      // after a stall, catch up in a burst so the average rate holds
+      pace.set_missed_tick_behavior(MissedTickBehavior::Burst);
+      loop {
+        tokio::select! {
+          _ = pace.tick() => {}
+          _ = conn.closed() => return Ok(pushed),
+        }
+        if !push(conn, frame(pushed.sent))? {
+          return Ok(pushed);
+        }
+        pushed.sent += 1;
+      }
+    }
+    Pacing::Spaced { spacing, burst } => {
+      // send_datagram does not block, so the whole schedule can run on a
+      // thread of its own instead of spinning on a runtime worker
+      let conn = conn.clone();
+      tokio::task::spawn_blocking(move || {
+        let mut spacer = Spacer::new(spacing);
+        while conn.close_reason().is_none() {
+          spacer.wait();
+          for _ in 0..burst {
+            if !push(&conn, frame(pushed.sent))? {
+              break;
+            }
+            pushed.sent += 1;
+          }
+        }
+        pushed.skipped = spacer.skipped();
+        Ok(pushed)
+      })
+      .await?
+    }
+  }
+}
+
+/// Send one datagram; `false` once the connection is gone.
+fn push(conn: &Connection, frame: Bytes) -> Result<bool> {
+  match conn.send_datagram(frame) {
+    Ok(()) => Ok(true),
+    Err(SendDatagramError::ConnectionLost(_)) => Ok(false),
+    Err(e) => Err(e.into()),
+  }
+}
+
+/// What a [`listen`] run received.
+#[derive(Debug, Default)]
+pub struct Listen {
+  pub received: u64,
+  /// Sequence numbers below the highest one seen that never arrived
+  pub lost: u64,
+  /// Datagrams that arrived after one with a higher sequence number
+  pub reordered: u64,
+  pub duplicates: u64,
+  /// Datagrams that were not push datagrams (e.g. too short)
+  pub malformed: u64,
+  pub bytes: u64,
+  /// Interarrival jitter, smoothed as in RTP
+  pub jitter: Duration,
+  /// Time from the first datagram to the last
+  pub elapsed: Duration,
+}
+
+impl Listen {
+  /// Lost datagrams as a percentage of those the server sent (as far as we
+  /// can tell: up to the highest sequence number seen).
+  pub fn loss_percent(&self) -> f64 {
+    let arrived = self.received - self.duplicates - self.malformed;
+    self.lost as f64 * 100.0 / (arrived + self.lost).max(1) as f64
+  }
+
+  /// Datagrams received per second.
+  pub fn rate(&self) -> f64 {
+    self.received as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+  }
+}
+
+impl fmt::Display for Listen {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{} datagrams ({} B) in {:.1?} ({:.0}/s), {} lost ({:.2}%), {} reordered, {} duplicates, jitter {:.1?}",
+      self.received,
+      self.bytes,
+      self.elapsed,
+      self.rate(),
+      self.lost,
+      self.loss_percent(),
+      self.reordered,
+      self.duplicates,
+      self.jitter
+    )?;
+    if self.malformed > 0 {
+      write!(f, ", {} malformed", self.malformed)?;
+    }
+    Ok(())
+  }
+}
+
+/// Receive pushed datagrams on `conn` for `duration` (or until the
+/// connection closes), without sending anything.
+pub async fn listen(conn: &Connection, duration: Option<Duration>) -> Result<Listen> {
+  let mut run = Listen::default();
+  let mut seen = HashSet::new();
+  let mut highest: Option<u64> = None;
+  let mut first: Option<Instant> = None;
+  // (arrival, server timestamp) of the previous datagram
+  let mut previous: Option<(Instant, u64)> = None;
+  let mut jitter = 0.0f64;
+  let deadline = duration.map(|d| tokio::time::Instant::now() + d);
+
+  loop {
+    let frame = tokio::select! {
+      frame = conn.read_datagram() => frame,
+      _ = tokio::time::sleep_until(deadline.unwrap_or_else(tokio::time::Instant::now)), if deadline.is_some() => break,
+    };
+    let frame = match frame {
+      Ok(frame) => frame,
+      Err(ConnectionError::ApplicationClosed(_) | ConnectionError::LocallyClosed) => break,
+      Err(e) => return Err(e.into()),
+    };
+    let now = Instant::now();
+    first.get_or_insert(now);
+    run.elapsed = now - first.unwrap();
+    run.received += 1;
+    run.bytes += frame.len() as u64;
+    let Some(header) = frame.get(..HEADER) else {
+      run.malformed += 1;
+      continue;
+    };
+    let seq = u64::from_be_bytes(header[..8].try_into().unwrap());
+    let sent_us = u64::from_be_bytes(header[8..].try_into().unwrap());
+    if !seen.insert(seq) {
+      run.duplicates += 1;
+      continue;
+    }
+    match highest {
+      Some(top) if seq < top => {
+        run.reordered += 1;
+        // it was counted as lost when the gap opened
+        run.lost -= 1;
+      }
+      _ => {
+        run.lost += seq - highest.map_or(0, |top| top + 1);
+        highest = Some(seq);
+      }
+    }
+    if let Some((arrived, sent)) = previous {
+      let transit = (now - arrived).as_secs_f64() - (sent_us as f64 - sent as f64) / 1e6;
+      jitter += (transit.abs() - jitter) / 16.0;
+    }
+    previous = Some((now, sent_us));
+    debug!(seq, len = frame.len(), "push datagram");
+  }
+
+  run.jitter = Duration::from_secs_f64(jitter);
+  Ok(run)
+}
+
+/// Which stream the server opens with `--push-stream`.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamKind {
+  /// Bidirectional: the client echoes the data back
+  Bi,
+  /// Unidirectional: server to client only
+  Uni,
+}
+
+impl StreamKind {
+  pub fn name(self) -> &'static str {
+    match self {
+      StreamKind::Bi => "bidirectional",
+      StreamKind::Uni => "unidirectional",
+    }
+  }
+}
+
+/// `len` bytes of the pattern a pushed stream carries.
+fn pattern(len: usize) -> Bytes {
+  (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+/// Open a `kind` stream to the client, send `size` bytes on it and, on a
+/// bidirectional stream, verify the client's echo. Returns the time until
+/// the data was acknowledged or echoed.
+pub async fn open_stream(conn: &Connection, kind: StreamKind, size: usize) -> Result<Duration> {
+  let start = Instant::now();
+  let data = pattern(size);
+  match kind {
+    StreamKind::Uni => {
+      let mut send = conn.open_uni().await?;
+      send.write_chunk(data).await?;
+      send.finish()?;
+      // resolves once the client has acknowledged everything, or stopped the stream
+      if let Some(code) = send.stopped().await? {
+        anyhow::bail!("client stopped the stream with code {code}");
+      }
+    }
+    StreamKind::Bi => {
+      let (mut send, mut recv) = conn.open_bi().await?;
+      send.write_chunk(data.clone()).await?;
+      send.finish()?;
+      let echo = recv.read_to_end(size).await?;
+      verify::compare("pushed stream echo", &data, &echo)?;
+    }
+  }
+  Ok(start.elapsed())
+}
+
+/// A stream the server opened, as [`accept_stream`] saw it.
+#[derive(Debug)]
+pub struct Accepted {
+  pub kind: StreamKind,
+  pub size: usize,
+  /// From accepting the stream until its data (and our echo) was through
+  pub elapsed: Duration,
+}
+
+/// Wait up to `timeout` for the server to open a stream, read and verify
+/// it, and echo it back if it is bidirectional.
+pub async fn accept_stream(conn: &Connection, timeout: Duration) -> Result<Accepted> {
+  let accepted = tokio::time::timeout(timeout, async {
+    tokio::select! {
+      bi = conn.accept_bi() => bi.map(|(send, recv)| (Some(send), recv)),
+      uni = conn.accept_uni() => uni.map(|recv| (None, recv)),
+    }
+  })
+  .await
+  .with_context(|| format!("the server opened no stream within {timeout:?} (is it running with --push-stream?)"))??;
+  let (send, mut recv) = accepted;
+  let kind = if send.is_some() { StreamKind::Bi } else { StreamKind::Uni };
+  debug!(kind = kind.name(), id = %recv.id(), "accepted a stream from the server");
+  let start = Instant::now();
+  let data = tokio::time::timeout(timeout, recv.read_to_end(MAX_STREAM))
+    .await
+    .context("the pushed stream was not finished")??;
+  verify::compare("pushed stream", &pattern(data.len()), &data)?;
+  if let Some(mut send) = send {
+    send.write_all(&data).await?;
+    send.finish()?;
+    // the server needs the whole echo before we close the connection
+    if let Some(code) = send.stopped().await? {
+      anyhow::bail!("server stopped the echo with code {code}");
+    }
+  }
+  Ok(Accepted { kind, size: data.len(), elapsed: start.elapsed() })
+}
@@ -0,0 +1,80 @@
+//! Closing connections that have gone quiet at the application level
+//! (`--reap-idle`): every tracked connection's [`Counters`] record when the
+//! echo loops last saw a stream or datagram, and a sweep closes those idle
+//! past the threshold with [`IDLE_REAPED`].
+//!
+//! This is separate from the QUIC idle timeout, which keep-alive PINGs
+//! reset: a client that keeps its connection alive but never sends anything
+//! is still reaped, so soak tests with many clients leave no connections
+//! lingering on the server.
+
+use crate::access::Counters;
+use quinn::{Connection, VarInt};
+use std::{
+  collections::HashMap,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  time::Duration,
+};
+use tracing::info;
+
+/// Application close code for a connection closed as idle.
+pub const IDLE_REAPED: u32 = 408;
+
+/// The tracked connections.
+#[derive(Debug)]
+pub struct Reaper {
+  idle: Duration,
+  conns: Mutex<HashMap<usize, (Connection, Arc<Counters>)>>,
+  reaped_total: AtomicU64,
+}
+
+impl Reaper {
+  pub fn new(idle: Duration) -> Arc<Self> {
+    Arc::new(Reaper { idle, conns: Mutex::new(HashMap::new()), reaped_total: AtomicU64::new(0) })
+  }
+
+  /// Watch `conn`, whose echo loops update `counters`; it counts as active
+  /// from now.
+  pub fn track(&self, conn: &Connection, counters: Arc<Counters>) {
+    counters.touch();
+    self.conns.lock().unwrap().insert(conn.stable_id(), (conn.clone(), counters));
+  }
+
+  /// Stop watching a closed connection.
+  pub fn forget(&self, conn: &Connection) {
+    self.conns.lock().unwrap().remove(&conn.stable_id());
+  }
+
+  /// Sweep a few times per threshold, until the process exits.
+  pub fn run(self: Arc<Self>) {
+    let period = (self.idle / 4).clamp(Duration::from_millis(100), Duration::from_secs(5));
+    tokio::spawn(async move {
+      let mut tick = tokio::time::interval(period);
+      tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+      loop {
+        tick.tick().await;
+        self.sweep();
+      }
+    });
+  }
+
+  fn sweep(&self) {
+    let mut conns = self.conns.lock().unwrap();
+    let before = conns.len();
+    conns.retain(|_, (conn, counters)| {
+      if counters.idle() < self.idle {
+        return true;
+      }
+      conn.close(VarInt::from_u32(IDLE_REAPED), b"idle");
+      false
+    });
+    let reaped = (before - conns.len()) as u64;
+    if reaped > 0 {
+      let total = self.reaped_total.fetch_add(reaped, Ordering::Relaxed) + reaped;
+      info!(reaped, reaped_total = total, tracked = conns.len(), idle = ?self.idle, "reaped idle connections");
+    }
+  }
+}
@@ -0,0 +1,236 @@
+//! Reliable datagrams for the client (`--reliable-dgram`): sequence numbers,
+//! selective ACKs and bounded retransmission on top of QUIC datagrams, to
+//! prototype unreliable-with-recovery messaging against the echo server.
+//!
+//! The echo server needs no support for it: the client is the sender and,
+//! one echo later, the receiver of its own messages. Each message goes out
+//! as a `DATA` datagram; when its echo arrives it counts as delivered and
+//! the receiving half answers with an `ACK` datagram, whose echo in turn
+//! acknowledges the message to the sending half. A loss on any of the four
+//! legs (e.g. the server's `--drop-rate`) leads to a retransmission.
+//!
+//! ```text
+//! DATA  0x01 | seq (u32) | payload
+//! ACK   0x02 | base (u32) | top (u32) | bitmap (u64)
+//! ```
+//!
+//! An `ACK` covers every sequence number below `base` (the first one not
+//! received yet), `top` (the one that triggered it) and `top - 1 - i` for
+//! each bit `i` set in the bitmap, so messages far beyond a gap the sender
+//! gave up on are still acknowledged.
+
+use anyhow::{Context, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use quinn::Connection;
+use std::{
+  collections::BTreeSet,
+  time::{Duration, Instant},
+};
+
+const DATA: u8 = 0x01;
+const ACK: u8 = 0x02;
+const DATA_HEADER: usize = 5;
+const ACK_LEN: usize = 17;
+/// Floor for the retransmission timeout on very short paths.
+const MIN_RTO: Duration = Duration::from_millis(20);
+
+/// What to send with [`run`].
+#[derive(Clone, Copy, Debug)]
+pub struct Params {
+  pub count: u32,
+  /// Messages per second
+  pub rate: f64,
+  /// Payload bytes per message
+  pub size: usize,
+  /// Retransmissions of one message before giving up on it
+  pub retries: u32,
+}
+
+/// Outcome of a [`run`].
+#[derive(Debug)]
+pub struct Reliable {
+  pub sent: u32,
+  pub delivered: u32,
+  /// Delivered by the first transmission
+  pub first_try: u32,
+  pub retransmissions: u64,
+  pub acks: u64,
+  /// Messages whose retries ran out before they were acknowledged
+  pub gave_up: u32,
+  pub elapsed: Duration,
+  /// Time from the first transmission to delivery, sorted
+  pub latencies: Vec<Duration>,
+}
+
+impl Reliable {
+  /// Delivered messages as a percentage of those sent.
+  pub fn delivery_rate(&self) -> f64 {
+    self.delivered as f64 * 100.0 / self.sent.max(1) as f64
+  }
+
+  /// Delivered messages per second.
+  pub fn goodput(&self) -> f64 {
+    self.delivered as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+  }
+
+  /// The `p`th percentile (0-100) delivery latency.
+  pub fn latency(&self, p: f64) -> Duration {
+    let Some(last) = self.latencies.len().checked_sub(1) else {
+      return Duration::ZERO;
+    };
+    self.latencies[(last as f64 * p / 100.0).round() as usize]
+  }
+
+  /// Latency added by recovery at the `p`th percentile: the excess over
+  /// the fastest delivery.
+  pub fn added(&self, p: f64) -> Duration {
+    self.latency(p).saturating_sub(self.latency(0.0))
+  }
+}
+
+struct Message {
+  first_sent: Instant,
+  last_sent: Instant,
+  retries: u32,
+  delivered: bool,
+}
+
+/// The receiving half: which sequence numbers have arrived.
+#[derive(Default)]
+struct Received {
+  base: u32,
+  above: BTreeSet<u32>,
+}
+
+impl Received {
+  /// Note `seq`; false if it is a duplicate.
+  fn insert(&mut self, seq: u32) -> bool {
+    if seq < self.base || !self.above.insert(seq) {
+      return false;
+    }
+    while self.above.remove(&self.base) {
+      self.base += 1;
+    }
+    true
+  }
+
+  /// Acknowledge `top` and what arrived in the 64 sequence numbers below.
+  fn ack(&self, top: u32) -> Bytes {
+    let bitmap = (0..64)
+      .filter(|i| top.checked_sub(i + 1).is_some_and(|seq| self.contains(seq)))
+      .fold(0u64, |bits, i| bits | 1 << i);
+    let mut frame = BytesMut::with_capacity(ACK_LEN);
+    frame.put_u8(ACK);
+    frame.put_u32(self.base);
+    frame.put_u32(top);
+    frame.put_u64(bitmap);
+    frame.freeze()
+  }
+
+  fn contains(&self, seq: u32) -> bool {
+    seq < self.base || self.above.contains(&seq)
+  }
+}
+
+/// Send `params.count` messages reliably over `conn`'s datagrams and wait
+/// until each is acknowledged or out of retries.
+pub async fn run(conn: &Connection, params: Params) -> Result<Reliable> {
+  let max = conn.max_datagram_size().context("peer does not support datagrams")?;
+  anyhow::ensure!(
+    DATA_HEADER + params.size <= max,
+    "--reliable-size {} does not fit a {max} B datagram",
+    params.size
+  );
+  anyhow::ensure!(params.rate > 0.0, "--reliable-rate must be positive");
+
+  let start = Instant::now();
+  let mut messages: Vec<Message> = Vec::with_capacity(params.count as usize);
+  // sent, neither acknowledged nor given up
+  let mut pending = BTreeSet::new();
+  let mut received = Received::default();
+  let mut latencies = Vec::new();
+  let (mut first_try, mut retransmissions, mut acks, mut gave_up) = (0, 0, 0, 0);
+  let payload = vec![0x5a; params.size];
+  let data = |seq: u32| {
+    let mut frame = BytesMut::with_capacity(DATA_HEADER + payload.len());
+    frame.put_u8(DATA);
+    frame.put_u32(seq);
+    frame.put_slice(&payload);
+    frame.freeze()
+  };
+  let mut pace = tokio::time::interval(Duration::from_secs_f64(1.0 / params.rate));
+
+  while messages.len() < params.count as usize || !pending.is_empty() {
+    // quinn's RTT is one leg pair; a message needs two (DATA and its ACK)
+    let rto = (conn.rtt() * 3).max(MIN_RTO);
+    let timeout = |m: &Message| m.last_sent + rto * (1 << m.retries.min(16));
+    let next_timeout = pending.iter().map(|&seq| timeout(&messages[seq as usize])).min();
+
+    tokio::select! {
+      _ = pace.tick(), if messages.len() < params.count as usize => {
+        let seq = messages.len() as u32;
+        conn.send_datagram(data(seq))?;
+        let now = Instant::now();
+        messages.push(Message { first_sent: now, last_sent: now, retries: 0, delivered: false });
+        pending.insert(seq);
+      }
+      _ = tokio::time::sleep_until(next_timeout.unwrap_or(start + Duration::from_secs(86_400)).into()),
+        if next_timeout.is_some() => {
+        let now = Instant::now();
+        let due: Vec<u32> = pending.iter().copied().filter(|&seq| timeout(&messages[seq as usize]) <= now).collect();
+        for seq in due {
+          let m = &mut messages[seq as usize];
+          if m.retries == params.retries {
+            pending.remove(&seq);
+            gave_up += 1;
+            continue;
+          }
+          conn.send_datagram(data(seq))?;
+          m.retries += 1;
+          m.last_sent = now;
+          retransmissions += 1;
+        }
+      }
+      frame = conn.read_datagram() => {
+        let frame = frame?;
+        match (frame.first(), frame.get(1..5)) {
+          (Some(&DATA), Some(seq)) => {
+            let seq = u32::from_be_bytes(seq.try_into().unwrap());
+            let Some(m) = messages.get_mut(seq as usize) else { continue };
+            if received.insert(seq) && !m.delivered {
+              m.delivered = true;
+              latencies.push(m.first_sent.elapsed());
+              if m.retries == 0 {
+                first_try += 1;
+              }
+            }
+            // (re-)acknowledge duplicates too: the earlier ACK may be lost
+            conn.send_datagram(received.ack(seq))?;
+            acks += 1;
+          }
+          (Some(&ACK), Some(base)) if frame.len() == ACK_LEN => {
+            let base = u32::from_be_bytes(base.try_into().unwrap());
+            let top = u32::from_be_bytes(frame[5..9].try_into().unwrap());
+            let bitmap = u64::from_be_bytes(frame[9..17].try_into().unwrap());
+            let acked = |seq: u32| seq < base || seq == top || seq < top && top - seq <= 64 && bitmap & 1 << (top - seq - 1) != 0;
+            pending.retain(|&seq| !acked(seq));
+          }
+          // not ours (e.g. a late echo of something else)
+          _ => {}
+        }
+      }
+    }
+  }
+
+  latencies.sort();
+  Ok(Reliable {
+    sent: params.count,
+    delivered: latencies.len() as u32,
+    first_try,
+    retransmissions,
+    acks,
+    gave_up,
+    elapsed: start.elapsed(),
+    latencies,
+  })
+}
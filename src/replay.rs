@@ -0,0 +1,247 @@
+//! Traffic record and replay for the client: `--record <file>` writes every
+//! application payload the client sends, with its time since the start of
+//! the run, and `--replay <file>` sends the same payloads to the echo server
+//! again with the original spacing.
+//!
+//! The file is JSON Lines, one object per payload:
+//!
+//! ```text
+//! {"t_us":0,"kind":"dgram","data":"70696e67"}
+//! {"t_us":1520,"kind":"stream","stream":0,"data":"70696e67","fin":true}
+//! ```
+//!
+//! `data` is hex; records with the same `stream` number go to the same
+//! bidirectional stream, which is finished at the record with `fin`.
+
+use crate::flow;
+use crate::transfer::hex;
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use quinn::{Connection, SendStream};
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::HashMap,
+  fs::File,
+  io::{BufRead, BufReader, BufWriter, Write},
+  path::Path,
+  sync::{
+    Mutex,
+    atomic::{AtomicU64, Ordering},
+  },
+  time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+use tracing::warn;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum Record {
+  Dgram {
+    t_us: u64,
+    data: String,
+  },
+  Stream {
+    t_us: u64,
+    stream: u64,
+    data: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    fin: bool,
+  },
+}
+
+impl Record {
+  fn at(&self) -> Duration {
+    match self {
+      Record::Dgram { t_us, .. } | Record::Stream { t_us, .. } => Duration::from_micros(*t_us),
+    }
+  }
+}
+
+/// Writes the payloads of a run to a record file.
+pub struct Recorder {
+  out: Mutex<BufWriter<File>>,
+  start: Instant,
+  streams: AtomicU64,
+}
+
+impl Recorder {
+  pub fn create(path: &Path) -> Result<Self> {
+    let file = File::create(path).with_context(|| format!("create {}", path.display()))?;
+    Ok(Self { out: Mutex::new(BufWriter::new(file)), start: Instant::now(), streams: AtomicU64::new(0) })
+  }
+
+  /// Record a datagram about to be sent.
+  pub fn datagram(&self, data: &[u8]) {
+    self.write(&Record::Dgram { t_us: self.now(), data: hex(data) });
+  }
+
+  /// Number a newly opened stream for [`Recorder::stream`].
+  pub fn open_stream(&self) -> u64 {
+    self.streams.fetch_add(1, Ordering::Relaxed)
+  }
+
+  /// Record data about to be written to stream `id`; `fin` if the stream is
+  /// finished after it.
+  pub fn stream(&self, id: u64, data: &[u8], fin: bool) {
+    self.write(&Record::Stream { t_us: self.now(), stream: id, data: hex(data), fin });
+  }
+
+  fn now(&self) -> u64 {
+    self.start.elapsed().as_micros() as u64
+  }
+
+  fn write(&self, record: &Record) {
+    let res = (|| {
+      let mut out = self.out.lock().unwrap();
+      serde_json::to_writer(&mut *out, record)?;
+      writeln!(out)?;
+      // flush per record so an interrupted run still leaves a usable file
+      out.flush()?;
+      anyhow::Ok(())
+    })();
+    if let Err(e) = res {
+      warn!("cannot write record: {e:#}");
+    }
+  }
+}
+
+/// A record file loaded for replay.
+pub struct Recording(Vec<Record>);
+
+impl Recording {
+  pub fn load(path: &Path) -> Result<Self> {
+    let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let mut records = Vec::new();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+      let line = line?;
+      if line.trim().is_empty() {
+        continue;
+      }
+      let record: Record =
+        serde_json::from_str(&line).with_context(|| format!("{}:{}: bad record", path.display(), n + 1))?;
+      let data = match &record {
+        Record::Dgram { data, .. } | Record::Stream { data, .. } => data,
+      };
+      unhex(data).with_context(|| format!("{}:{}: bad hex data", path.display(), n + 1))?;
+      records.push(record);
+    }
+    records.sort_by_key(Record::at);
+    Ok(Self(records))
+  }
+
+  pub fn len(&self) -> usize {
+    self.0.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+
+  /// Length of the recorded run, up to its last payload.
+  pub fn duration(&self) -> Duration {
+    self.0.last().map_or(Duration::ZERO, Record::at)
+  }
+}
+
+/// What came back from a replay.
+#[derive(Debug, Default)]
+pub struct Replay {
+  pub datagrams: u64,
+  pub datagrams_echoed: u64,
+  pub streams: u64,
+  pub stream_bytes: u64,
+  pub stream_bytes_echoed: u64,
+  /// Largest delay of a payload behind its recorded time
+  pub max_late: Duration,
+  pub elapsed: Duration,
+}
+
+/// Send every payload of `recording` on `conn` at its recorded offset, then
+/// wait up to `timeout` for the echoes.
+pub async fn replay(conn: &Connection, recording: &Recording, timeout: Duration) -> Result<Replay> {
+  let echoed = AtomicU64::new(0);
+  let echo = Notify::new();
+  let reading = async {
+    while conn.read_datagram().await.is_ok() {
+      echoed.fetch_add(1, Ordering::Relaxed);
+      echo.notify_one();
+    }
+  };
+
+  let work = async {
+    let mut report = Replay::default();
+    let start = tokio::time::Instant::now();
+    let mut streams: HashMap<u64, SendStream> = HashMap::new();
+    let mut echoes = tokio::task::JoinSet::new();
+    for record in &recording.0 {
+      let at = start + record.at();
+      tokio::time::sleep_until(at).await;
+      report.max_late = report.max_late.max(at.elapsed());
+      match record {
+        Record::Dgram { data, .. } => {
+          conn.send_datagram(Bytes::from(unhex(data)?))?;
+          report.datagrams += 1;
+        }
+        Record::Stream { stream, data, fin, .. } => {
+          if !streams.contains_key(stream) {
+            let (send, mut recv) = flow::open_bi(conn).await?;
+            echoes.spawn(async move {
+              let mut n = 0u64;
+              while let Some(chunk) = recv.read_chunk(usize::MAX, true).await? {
+                n += chunk.bytes.len() as u64;
+              }
+              anyhow::Ok(n)
+            });
+            streams.insert(*stream, send);
+            report.streams += 1;
+          }
+          let data = unhex(data)?;
+          report.stream_bytes += data.len() as u64;
+          let send = streams.get_mut(stream).unwrap();
+          send.write_all(&data).await?;
+          if *fin {
+            send.finish()?;
+            streams.remove(stream);
+          }
+        }
+      }
+    }
+    // the recording may have been cut off before some streams finished
+    for (_, mut send) in streams {
+      send.finish()?;
+    }
+
+    let deadline = tokio::time::Instant::now() + timeout;
+    while let Ok(Some(joined)) = tokio::time::timeout_at(deadline, echoes.join_next()).await {
+      match joined? {
+        Ok(n) => report.stream_bytes_echoed += n,
+        Err(e) => warn!("stream echo failed: {e:#}"),
+      }
+    }
+    while echoed.load(Ordering::Relaxed) < report.datagrams {
+      if tokio::time::timeout_at(deadline, echo.notified()).await.is_err() {
+        break;
+      }
+    }
+    report.datagrams_echoed = echoed.load(Ordering::Relaxed);
+    report.elapsed = start.elapsed();
+    anyhow::Ok(report)
+  };
+
+  tokio::select! {
+    report = work => report,
+    () = reading => Err(conn.closed().await).context("connection closed during replay"),
+  }
+}
+
+/// Decode the hex written by [`crate::transfer::hex`].
+pub fn unhex(s: &str) -> Result<Vec<u8>> {
+  anyhow::ensure!(s.len().is_multiple_of(2), "odd number of hex digits");
+  s.as_bytes()
+    .chunks(2)
+    .map(|pair| {
+      let pair = std::str::from_utf8(pair).ok();
+      pair.and_then(|p| u8::from_str_radix(p, 16).ok()).context("not a hex digit")
+    })
+    .collect()
+}
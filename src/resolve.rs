@@ -0,0 +1,137 @@
+//! Name resolution for the client: the system resolver by default, a
+//! specific DNS server (`--resolver`, optionally over TLS or HTTPS), and
+//! curl-style `--resolve host:port:addr` overrides that bypass DNS entirely.
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use hickory_resolver::{
+  config::{NameServerConfig, ResolverConfig},
+  name_server::TokioConnectionProvider,
+  proto::xfer::Protocol,
+  TokioResolver,
+};
+use std::{
+  net::{IpAddr, SocketAddr},
+  str::FromStr,
+};
+
+/// `host:port:addr[,addr...]`, IPv6 addresses optionally in brackets.
+#[derive(Clone, Debug)]
+pub struct ResolveOverride {
+  host: String,
+  port: u16,
+  addrs: Vec<IpAddr>,
+}
+
+impl FromStr for ResolveOverride {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let err = || format!("expected `host:port:addr[,addr...]`, got {s:?}");
+    let mut parts = s.splitn(3, ':');
+    let (Some(host), Some(port), Some(addrs)) = (parts.next(), parts.next(), parts.next()) else {
+      return Err(err());
+    };
+    let port = port.parse().map_err(|_| err())?;
+    let addrs = addrs
+      .split(',')
+      .map(|a| a.trim_start_matches('[').trim_end_matches(']').parse())
+      .collect::<Result<Vec<IpAddr>, _>>()
+      .map_err(|_| err())?;
+    Ok(Self { host: host.to_string(), port, addrs })
+  }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ResolverProto {
+  /// Plain DNS over UDP (falling back to TCP), port 53
+  #[default]
+  Udp,
+  /// DNS over TLS, port 853 (needs the `doh` feature)
+  Tls,
+  /// DNS over HTTPS, port 443 (needs the `doh` feature)
+  Https,
+}
+
+pub struct Resolver {
+  overrides: Vec<ResolveOverride>,
+  dns: Option<TokioResolver>,
+}
+
+impl Resolver {
+  /// `server` is `ip` or `ip:port`; `tls_name` is the server's certificate
+  /// name, required for TLS and HTTPS.
+  pub fn new(
+    overrides: Vec<ResolveOverride>,
+    server: Option<&str>,
+    proto: ResolverProto,
+    tls_name: Option<&str>,
+  ) -> Result<Self> {
+    let dns = server
+      .map(|server| {
+        let default_port = match proto {
+          ResolverProto::Udp => 53,
+          ResolverProto::Tls => 853,
+          ResolverProto::Https => 443,
+        };
+        let addr = server
+          .parse::<SocketAddr>()
+          .or_else(|_| server.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, default_port)))
+          .with_context(|| format!("resolver {server:?}: expected `ip` or `ip:port`"))?;
+
+        let mut config = ResolverConfig::new();
+        match proto {
+          ResolverProto::Udp => {
+            config.add_name_server(NameServerConfig::new(addr, Protocol::Udp));
+            config.add_name_server(NameServerConfig::new(addr, Protocol::Tcp));
+          }
+          ResolverProto::Tls | ResolverProto::Https => {
+            let name = tls_name.context("--resolver-name is required for TLS/HTTPS resolvers")?;
+            config.add_name_server(encrypted_name_server(addr, proto, name)?);
+          }
+        }
+        anyhow::Ok(
+          TokioResolver::builder_with_config(config, TokioConnectionProvider::default()).build(),
+        )
+      })
+      .transpose()?;
+
+    Ok(Self { overrides, dns })
+  }
+
+  /// All addresses for `host:port`, in resolver order.
+  pub async fn lookup(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    if let Some(o) = self.overrides.iter().find(|o| o.host == host && o.port == port) {
+      return Ok(o.addrs.iter().map(|&ip| SocketAddr::new(ip, port)).collect());
+    }
+    if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+      return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+
+    match &self.dns {
+      Some(dns) => {
+        let ips = dns.lookup_ip(host).await.with_context(|| format!("resolve {host}"))?;
+        Ok(ips.iter().map(|ip| SocketAddr::new(ip, port)).collect())
+      }
+      None => Ok(
+        tokio::net::lookup_host((host, port))
+          .await
+          .with_context(|| format!("resolve {host}"))?
+          .collect(),
+      ),
+    }
+  }
+}
+
+#[cfg(feature = "doh")]
+fn encrypted_name_server(addr: SocketAddr, proto: ResolverProto, name: &str) -> Result<NameServerConfig> {
+  let protocol = if proto == ResolverProto::Tls { Protocol::Tls } else { Protocol::Https };
+  let mut ns = NameServerConfig::new(addr, protocol);
+  ns.tls_dns_name = Some(name.to_string());
+  Ok(ns)
+}
+
+#[cfg(not(feature = "doh"))]
+fn encrypted_name_server(_addr: SocketAddr, proto: ResolverProto, _name: &str) -> Result<NameServerConfig> {
+  anyhow::bail!("{proto:?} resolvers need quic_echo built with `--features doh`")
+}
@@ -0,0 +1,41 @@
+//! Route probe: which source address and interface the OS would use to
+//! reach a remote address, determined in-process.
+//!
+//! Connecting a UDP socket sends nothing but makes the kernel pick a route
+//! and source address, which `local_addr()` then reports. The interface is
+//! whichever local interface owns that address. Purely informational; every
+//! failure maps to `None`.
+
+use crate::socket::bind_udp;
+use std::net::{IpAddr, SocketAddr};
+
+/// Returns `(source ip, interface name)` for packets sent to `remote` from a
+/// socket bound like the real endpoint (`local` IP, optional `interface`).
+pub fn route_get(
+  remote: SocketAddr,
+  local: IpAddr,
+  interface: Option<&str>,
+) -> (Option<IpAddr>, Option<String>) {
+  // a dual-stack IPv6 socket reaches IPv4 peers via mapped addresses
+  let remote = match (local, remote) {
+    (IpAddr::V6(_), SocketAddr::V4(v4)) => SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()),
+    _ => remote,
+  };
+  let src = bind_udp(SocketAddr::new(local, 0), interface)
+    .and_then(|sock| {
+      sock.connect(remote)?;
+      sock.local_addr()
+    })
+    .ok()
+    .map(|addr| addr.ip().to_canonical());
+
+  let iface = src.and_then(|ip| {
+    if_addrs::get_if_addrs()
+      .ok()?
+      .into_iter()
+      .find(|iface| iface.ip() == ip)
+      .map(|iface| iface.name)
+  });
+
+  (src, iface)
+}
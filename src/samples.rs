@@ -0,0 +1,191 @@
+//! Time-series export for the client (`--samples-out`): one row per ping and
+//! one per `--sample-interval` (default a second) of a run, as CSV or JSON
+//! Lines, so results can be plotted without scraping stdout.
+//!
+//! Columns: `ts_ms` (Unix time), `kind` (`ping` or `interval`), `rtt_ms`
+//! (measured round trip for pings, quinn's smoothed path RTT for intervals),
+//! `bytes_tx`/`bytes_rx` and `lost` (UDP bytes and lost packets since the
+//! previous row of the connection), `cwnd` (current congestion window) and
+//! `phase` (`warmup` for rows taken during `--warmup`, `measure` after), and
+//! `jitter_ms`, the smoothed interarrival jitter of datagram echoes in
+//! `--soak --datagram` (empty, or `null` in JSON, everywhere else).
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::{
+  fs::File,
+  io::{BufWriter, Write},
+  path::Path,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+  },
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{warn, Instrument};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SampleFormat {
+  /// Comma-separated values with a header line
+  Csv,
+  /// One JSON object per line
+  Jsonl,
+}
+
+impl SampleFormat {
+  /// `.jsonl`/`.json` files get JSON Lines, anything else CSV.
+  pub fn from_path(path: &Path) -> Self {
+    match path.extension().and_then(|e| e.to_str()) {
+      Some("jsonl" | "json") => SampleFormat::Jsonl,
+      _ => SampleFormat::Csv,
+    }
+  }
+}
+
+/// The output file, shared by every connection of a run.
+pub struct SampleWriter {
+  out: Mutex<BufWriter<File>>,
+  format: SampleFormat,
+}
+
+impl SampleWriter {
+  pub fn create(path: &Path, format: SampleFormat) -> Result<Self> {
+    let file = File::create(path).with_context(|| format!("create {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    if format == SampleFormat::Csv {
+      writeln!(out, "ts_ms,kind,rtt_ms,bytes_tx,bytes_rx,lost,cwnd,phase,jitter_ms")?;
+    }
+    Ok(Self { out: Mutex::new(out), format })
+  }
+
+  fn write(&self, kind: &str, rtt: Duration, jitter: Option<Duration>, delta: &Totals, cwnd: u64, phase: &str) -> Result<()> {
+    let ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+    let rtt = rtt.as_secs_f64() * 1000.0;
+    let jitter = jitter.map(|j| format!("{:.3}", j.as_secs_f64() * 1000.0));
+    let Totals { tx, rx, lost } = delta;
+    let mut out = self.out.lock().unwrap();
+    match self.format {
+      SampleFormat::Csv => writeln!(out, "{ts},{kind},{rtt:.3},{tx},{rx},{lost},{cwnd},{phase},{}", jitter.as_deref().unwrap_or(""))?,
+      SampleFormat::Jsonl => writeln!(
+        out,
+        r#"{{"ts_ms":{ts},"kind":"{kind}","rtt_ms":{rtt:.3},"bytes_tx":{tx},"bytes_rx":{rx},"lost":{lost},"cwnd":{cwnd},"phase":"{phase}","jitter_ms":{}}}"#,
+        jitter.as_deref().unwrap_or("null")
+      )?,
+    }
+    // flush per row so the file can be tailed while a test runs
+    out.flush()?;
+    Ok(())
+  }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Totals {
+  tx: u64,
+  rx: u64,
+  lost: u64,
+}
+
+/// Stops the interval sampling when dropped.
+pub struct Intervals(tokio::task::JoinHandle<()>);
+
+impl Drop for Intervals {
+  fn drop(&mut self) {
+    self.0.abort();
+  }
+}
+
+/// Samples one connection into a [`SampleWriter`].
+#[derive(Clone)]
+pub struct Sampler {
+  writer: Arc<SampleWriter>,
+  conn: quinn::Connection,
+  last: Arc<Mutex<Totals>>,
+  drift: Arc<Mutex<Option<Drift>>>,
+  warmup: Arc<AtomicBool>,
+}
+
+/// Path RTT over the `interval` rows of a connection, independent of the
+/// application's own round trips.
+#[derive(Clone, Copy, Debug)]
+pub struct Drift {
+  pub samples: u64,
+  pub first: Duration,
+  pub last: Duration,
+  pub min: Duration,
+  pub max: Duration,
+}
+
+impl Sampler {
+  pub fn new(writer: Arc<SampleWriter>, conn: quinn::Connection) -> Self {
+    Self { writer, conn, last: Default::default(), drift: Default::default(), warmup: Default::default() }
+  }
+
+  /// Label the rows that follow as warmup (`true`) or measured (`false`).
+  pub fn set_warmup(&self, warmup: bool) {
+    self.warmup.store(warmup, Ordering::Relaxed);
+  }
+
+  /// Record a ping that took `rtt` to come back.
+  pub fn ping(&self, rtt: Duration) {
+    self.record("ping", rtt, None);
+  }
+
+  /// Record a datagram ping along with the jitter of the echoes so far.
+  pub fn ping_with_jitter(&self, rtt: Duration, jitter: Option<Duration>) {
+    self.record("ping", rtt, jitter);
+  }
+
+  /// Record an `interval` row with the path RTT every `every` until the
+  /// returned guard is dropped.
+  pub fn spawn_intervals(&self, every: Duration) -> Intervals {
+    let this = self.clone();
+    Intervals(tokio::spawn(
+      async move {
+        let mut tick = tokio::time::interval(every.max(Duration::from_millis(1)));
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        tick.tick().await;
+        loop {
+          tick.tick().await;
+          let rtt = this.conn.rtt();
+          this.track(rtt);
+          this.record("interval", rtt, None);
+        }
+      }
+      .in_current_span(),
+    ))
+  }
+
+  /// How the path RTT moved over the `interval` rows so far; `None` before
+  /// the first one.
+  pub fn drift(&self) -> Option<Drift> {
+    *self.drift.lock().unwrap()
+  }
+
+  fn track(&self, rtt: Duration) {
+    let mut drift = self.drift.lock().unwrap();
+    match &mut *drift {
+      Some(d) => {
+        d.samples += 1;
+        d.last = rtt;
+        d.min = d.min.min(rtt);
+        d.max = d.max.max(rtt);
+      }
+      None => *drift = Some(Drift { samples: 1, first: rtt, last: rtt, min: rtt, max: rtt }),
+    }
+  }
+
+  fn record(&self, kind: &str, rtt: Duration, jitter: Option<Duration>) {
+    let stats = self.conn.stats();
+    let now = Totals { tx: stats.udp_tx.bytes, rx: stats.udp_rx.bytes, lost: stats.path.lost_packets };
+    let delta = {
+      let mut last = self.last.lock().unwrap();
+      let delta = Totals { tx: now.tx - last.tx, rx: now.rx - last.rx, lost: now.lost - last.lost };
+      *last = now;
+      delta
+    };
+    let phase = if self.warmup.load(Ordering::Relaxed) { "warmup" } else { "measure" };
+    if let Err(e) = self.writer.write(kind, rtt, jitter, &delta, stats.path.cwnd, phase) {
+      warn!("cannot write sample: {e:#}");
+    }
+  }
+}
@@ -0,0 +1,277 @@
+//! Scenario runner for the client (`--scenario <file>`): a TOML list of
+//! steps run in order against the echo server, with a result line per step,
+//! so server behavior can be regression-tested the same way every time.
+//!
+//! ```toml
+//! [[step]]
+//! action = "connect"
+//!
+//! [[step]]
+//! action = "datagrams"
+//! count = 200
+//! rate = 100.0      # per second, default: as fast as possible
+//! size = 64         # bytes, default 32 (at least 8)
+//! min_echoed = 190  # fail the step below this, default 0
+//!
+//! [[step]]
+//! action = "stream"
+//! size = 1048576
+//!
+//! [[step]]
+//! action = "migrate"
+//!
+//! [[step]]
+//! action = "sleep"
+//! secs = 0.5
+//!
+//! [[step]]
+//! action = "close"
+//! code = 7
+//! reason = "done"
+//! ```
+//!
+//! Every step runs even if an earlier one failed; steps that need a
+//! connection fail until a `connect` step succeeds.
+
+use crate::flow;
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use quinn::{Connection, VarInt};
+use serde::Deserialize;
+use std::{
+  collections::HashSet,
+  net::SocketAddr,
+  path::Path,
+  time::{Duration, Instant},
+};
+
+const CHUNK: usize = 64 * 1024;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+  #[serde(rename = "step")]
+  pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Step {
+  /// Connect (again) to the server; an open connection is closed first
+  Connect,
+  /// Send `count` datagrams, `rate` per second, and count the echoes
+  Datagrams {
+    count: u32,
+    rate: Option<f64>,
+    #[serde(default = "default_datagram_size")]
+    size: usize,
+    #[serde(default)]
+    min_echoed: u32,
+  },
+  /// Echo `size` bytes through one bidirectional stream and verify them
+  Stream { size: u64 },
+  /// Move the connection to a new local port
+  Migrate,
+  /// Do nothing for `secs` seconds
+  Sleep { secs: f64 },
+  /// Close the connection with an application error code
+  Close {
+    #[serde(default)]
+    code: u32,
+    #[serde(default)]
+    reason: String,
+  },
+}
+
+fn default_datagram_size() -> usize {
+  32
+}
+
+impl Scenario {
+  pub fn load(path: &Path) -> Result<Self> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+    let scenario: Scenario = toml::from_str(&text).with_context(|| format!("parse {}", path.display()))?;
+    for step in &scenario.steps {
+      match step {
+        Step::Datagrams { size, rate, .. } => {
+          anyhow::ensure!(*size >= 8, "datagram size must be at least 8 bytes");
+          anyhow::ensure!(rate.is_none_or(|r| r > 0.0), "datagram rate must be positive");
+        }
+        Step::Sleep { secs } => anyhow::ensure!(secs.is_finite() && *secs >= 0.0, "sleep needs secs >= 0"),
+        _ => {}
+      }
+    }
+    Ok(scenario)
+  }
+}
+
+impl Step {
+  fn name(&self) -> &'static str {
+    match self {
+      Step::Connect => "connect",
+      Step::Datagrams { .. } => "datagrams",
+      Step::Stream { .. } => "stream",
+      Step::Migrate => "migrate",
+      Step::Sleep { .. } => "sleep",
+      Step::Close { .. } => "close",
+    }
+  }
+}
+
+/// Run every step, print one result line each and fail if any step failed.
+/// `connect` opens a new connection to the server, `rebind` moves the
+/// endpoint to a fresh socket and `timeout` bounds the wait for datagram
+/// echoes after the last one went out.
+pub async fn run(
+  scenario: &Scenario,
+  connect: impl AsyncFn() -> Result<Connection>,
+  rebind: impl Fn() -> Result<SocketAddr>,
+  timeout: Duration,
+) -> Result<()> {
+  let mut conn: Option<Connection> = None;
+  let mut failed = 0;
+  for (n, step) in scenario.steps.iter().enumerate() {
+    let start = Instant::now();
+    let res = async {
+      match step {
+        Step::Connect => {
+          if let Some(old) = conn.take() {
+            old.close(VarInt::from_u32(0), b"reconnecting");
+          }
+          let new = connect().await?;
+          let detail = format!("connected to {}", new.remote_address());
+          conn = Some(new);
+          Ok(detail)
+        }
+        Step::Datagrams { count, rate, size, min_echoed } => {
+          let conn = connected(&conn)?;
+          let echoed = datagrams(conn, n as u32, *count, *rate, *size, timeout).await?;
+          let lost = count - echoed;
+          anyhow::ensure!(echoed >= *min_echoed, "{echoed}/{count} echoed, wanted at least {min_echoed}");
+          Ok(format!("{echoed}/{count} echoed, {lost} lost"))
+        }
+        Step::Stream { size } => {
+          let conn = connected(&conn)?;
+          let took = stream(conn, *size).await?;
+          let mbps = *size as f64 * 8.0 / took.as_secs_f64() / 1e6;
+          Ok(format!("{size} B echoed, {mbps:.1} Mbit/s"))
+        }
+        Step::Migrate => {
+          connected(&conn)?;
+          Ok(format!("now on local {}", rebind()?))
+        }
+        Step::Sleep { secs } => {
+          tokio::time::sleep(Duration::from_secs_f64(*secs)).await;
+          Ok(String::new())
+        }
+        Step::Close { code, reason } => {
+          let conn = conn.take().context("no connection; add a connect step first")?;
+          conn.close(VarInt::from_u32(*code), reason.as_bytes());
+          Ok(format!("closed with code {code}"))
+        }
+      }
+    }
+    .await;
+
+    let ms = start.elapsed().as_secs_f64() * 1000.0;
+    match res {
+      Ok(detail) => println!("  {:>3} {:<10} OK    {ms:>8.1} ms  {detail}", n + 1, step.name()),
+      Err(e) => {
+        failed += 1;
+        println!("  {:>3} {:<10} FAIL  {ms:>8.1} ms  {e:#}", n + 1, step.name());
+      }
+    }
+  }
+  if let Some(conn) = conn {
+    conn.close(VarInt::from_u32(0), b"scenario done");
+  }
+  println!("{} ok, {failed} failed", scenario.steps.len() - failed);
+  anyhow::ensure!(failed == 0, "{failed} scenario step(s) failed");
+  Ok(())
+}
+
+fn connected(conn: &Option<Connection>) -> Result<&Connection> {
+  let conn = conn.as_ref().context("no connection; add a connect step first")?;
+  if let Some(reason) = conn.close_reason() {
+    return Err(anyhow::Error::from(reason).context("connection closed"));
+  }
+  Ok(conn)
+}
+
+/// Send `count` datagrams tagged with the step number and a sequence number
+/// and return how many distinct ones came back.
+async fn datagrams(
+  conn: &Connection,
+  step: u32,
+  count: u32,
+  rate: Option<f64>,
+  size: usize,
+  timeout: Duration,
+) -> Result<u32> {
+  let mut seen = HashSet::new();
+  let reading = async {
+    while seen.len() < count as usize {
+      let data = conn.read_datagram().await?;
+      // skip late echoes of earlier steps
+      if data.len() == size && data[..4] == step.to_be_bytes() {
+        let seq = u32::from_be_bytes(data[4..8].try_into().unwrap());
+        if seq < count {
+          seen.insert(seq);
+        }
+      }
+    }
+    anyhow::Ok(())
+  };
+  let sending = async {
+    let mut tick = rate.map(|rate| tokio::time::interval(Duration::from_secs_f64(1.0 / rate)));
+    for seq in 0..count {
+      if let Some(tick) = &mut tick {
+        tick.tick().await;
+      }
+      let mut payload = vec![0x5a; size];
+      payload[..4].copy_from_slice(&step.to_be_bytes());
+      payload[4..8].copy_from_slice(&seq.to_be_bytes());
+      conn.send_datagram(Bytes::from(payload))?;
+    }
+    tokio::time::sleep(timeout).await;
+    anyhow::Ok(())
+  };
+  tokio::select! {
+    res = reading => res?,
+    res = sending => res?,
+  }
+  Ok(seen.len() as u32)
+}
+
+/// Echo `size` bytes of a known pattern through one stream, checking every
+/// byte that comes back; returns the time until the last one did.
+async fn stream(conn: &Connection, size: u64) -> Result<Duration> {
+  let start = Instant::now();
+  let (mut send, mut recv) = flow::open_bi(conn).await?;
+  let pattern: Vec<u8> = (0..CHUNK).map(|i| (i % 251) as u8).collect();
+  let writing = async {
+    let mut left = size;
+    while left > 0 {
+      let n = left.min(CHUNK as u64) as usize;
+      send.write_all(&pattern[..n]).await?;
+      left -= n as u64;
+    }
+    send.finish()?;
+    anyhow::Ok(())
+  };
+  let reading = async {
+    let mut pos = 0u64;
+    while let Some(chunk) = recv.read_chunk(CHUNK, true).await? {
+      for (i, byte) in chunk.bytes.iter().enumerate() {
+        let at = pos + i as u64;
+        anyhow::ensure!(at < size, "echo longer than the {size} bytes sent");
+        anyhow::ensure!(*byte == (at % CHUNK as u64 % 251) as u8, "echo mismatch at byte {at}");
+      }
+      pos += chunk.bytes.len() as u64;
+    }
+    anyhow::ensure!(pos == size, "echo ended after {pos} of {size} bytes");
+    anyhow::Ok(())
+  };
+  tokio::try_join!(writing, reading)?;
+  Ok(start.elapsed())
+}
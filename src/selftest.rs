@@ -0,0 +1,335 @@
+//! One-command smoke test (`quic_echo selftest`): a server on a loopback
+//! port with a freshly generated certificate for `localhost`, and a client
+//! in the same process that runs a matrix of stream, datagram and protocol
+//! checks against it, each on its own connection.
+//!
+//! The server here speaks the plain echo protocol (streams and datagrams
+//! echoed back, unidirectional streams drained) plus the library's perf,
+//! duplex, file, DoQ and HTTP/3 handlers, chosen by ALPN like the real
+//! server's modes. The client verifies the certificate properly, so the
+//! TLS setup is checked too.
+
+use crate::{
+  client::{EchoClient, Mode},
+  doq, duplex, http3, perf, reliable, transfer, verify,
+};
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use futures_core::Stream;
+use hickory_resolver::proto::rr::RecordType;
+use quinn::{
+  ClientConfig, Connection, ConnectionError, Endpoint, ServerConfig, TransportConfig,
+  crypto::rustls::{QuicClientConfig, QuicServerConfig},
+};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use std::{
+  future::Future,
+  net::{Ipv4Addr, SocketAddr},
+  sync::Arc,
+  time::{Duration, Instant},
+};
+use tokio::task::JoinSet;
+use tracing::{debug, warn, Instrument};
+
+/// The echo protocol's ALPN, as in both binaries.
+const ECHO_ALPN: &[u8] = b"freven-quic-test";
+const SERVER_NAME: &str = "localhost";
+/// How long one check may take before it counts as failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// One check and how it went.
+#[derive(Debug)]
+pub struct Outcome {
+  pub name: String,
+  /// How long the check took
+  pub result: Result<Duration>,
+}
+
+/// Start the server and run every check whose name contains `filter` (all
+/// of them without one), in order.
+pub async fn run(filter: Option<&str>) -> Result<Vec<Outcome>> {
+  let cert = rcgen::generate_simple_self_signed(vec![SERVER_NAME.into()]).context("generate a certificate")?;
+  let der = cert.cert.der().clone();
+  let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der()));
+
+  let server = Endpoint::server(server_config(der.clone(), key)?, (Ipv4Addr::LOCALHOST, 0).into())?;
+  let addr = server.local_addr()?;
+  debug!(%addr, "self-test server listening");
+  tokio::spawn(serve(server.clone()));
+  let client = Endpoint::client((Ipv4Addr::LOCALHOST, 0).into())?;
+  let checker = Checker { client, addr, cert: der };
+
+  let mut outcomes = Vec::new();
+  for size in [0, 1, 1200, 64 * 1024, 4 * 1024 * 1024] {
+    let name = format!("stream echo {size} B");
+    checker.run(&mut outcomes, filter, &name, ECHO_ALPN, |conn| stream_echo(conn, size)).await;
+  }
+  checker.run(&mut outcomes, filter, "16 concurrent streams", ECHO_ALPN, concurrent_streams).await;
+  checker.run(&mut outcomes, filter, "unidirectional stream", ECHO_ALPN, uni_stream).await;
+  for size in [Some(1), Some(512), None] {
+    let name = size.map_or("datagram echo max size".into(), |size| format!("datagram echo {size} B"));
+    checker.run(&mut outcomes, filter, &name, ECHO_ALPN, |conn| datagram_echo(conn, size)).await;
+  }
+  checker.run(&mut outcomes, filter, "datagram burst", ECHO_ALPN, datagram_burst).await;
+  checker.run(&mut outcomes, filter, "reliable datagrams", ECHO_ALPN, reliable_datagrams).await;
+  checker.run(&mut outcomes, filter, "perf", perf::ALPN, perf_request).await;
+  checker.run(&mut outcomes, filter, "duplex", duplex::ALPN, duplex_run).await;
+  checker.run(&mut outcomes, filter, "file echo", transfer::ALPN, file_echo).await;
+  checker.run(&mut outcomes, filter, "DNS-over-QUIC", doq::ALPN, doq_query).await;
+  checker.run(&mut outcomes, filter, "HTTP/3 echo", http3::ALPN, h3_echo).await;
+  record(&mut outcomes, filter, "EchoClient API", echo_client(addr, checker.cert.clone())).await;
+
+  checker.client.close(0u32.into(), b"self-test done");
+  server.close(0u32.into(), b"self-test done");
+  Ok(outcomes)
+}
+
+fn server_config(cert: CertificateDer<'static>, key: PrivateKeyDer<'static>) -> Result<ServerConfig> {
+  let mut tls = rustls::ServerConfig::builder().with_no_client_auth().with_single_cert(vec![cert], key)?;
+  tls.alpn_protocols =
+    [ECHO_ALPN, perf::ALPN, duplex::ALPN, transfer::ALPN, doq::ALPN, http3::ALPN].map(<[u8]>::to_vec).to_vec();
+  let mut config = ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?));
+  let transport: &mut TransportConfig = Arc::get_mut(&mut config.transport).unwrap();
+  transport.datagram_receive_buffer_size(Some(65_536));
+  transport.datagram_send_buffer_size(2 * 1024 * 1024);
+  Ok(config)
+}
+
+/// Accept connections and hand each to the handler for its ALPN.
+async fn serve(endpoint: Endpoint) {
+  while let Some(incoming) = endpoint.accept().await {
+    tokio::spawn(
+      async move {
+        if let Err(e) = handle(incoming).await {
+          warn!("self-test server connection failed: {e:#}");
+        }
+      }
+      .in_current_span(),
+    );
+  }
+}
+
+async fn handle(incoming: quinn::Incoming) -> Result<()> {
+  let conn = incoming.await?;
+  let alpn = conn
+    .handshake_data()
+    .and_then(|hd| hd.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+    .and_then(|hd| hd.protocol);
+  match alpn.as_deref() {
+    Some(perf::ALPN) => perf::serve(conn).await,
+    Some(duplex::ALPN) => duplex::serve(conn).await,
+    Some(transfer::ALPN) => transfer::serve(conn, None).await,
+    Some(doq::ALPN) => doq::serve(conn).await,
+    Some(http3::ALPN) => http3::serve(conn).await,
+    _ => echo(conn).await,
+  }
+}
+
+/// Echo streams and datagrams back and drain unidirectional streams.
+async fn echo(conn: Connection) -> Result<()> {
+  let dgram_conn = conn.clone();
+  tokio::spawn(async move {
+    while let Ok(data) = dgram_conn.read_datagram().await {
+      let _ = dgram_conn.send_datagram(data);
+    }
+  });
+  let uni_conn = conn.clone();
+  tokio::spawn(async move {
+    while let Ok(mut recv) = uni_conn.accept_uni().await {
+      tokio::spawn(async move { while let Ok(Some(_)) = recv.read_chunk(usize::MAX, false).await {} });
+    }
+  });
+  loop {
+    let (mut send, mut recv) = match conn.accept_bi().await {
+      Ok(s) => s,
+      Err(ConnectionError::ApplicationClosed { .. } | ConnectionError::LocallyClosed) => return Ok(()),
+      Err(e) => return Err(e.into()),
+    };
+    tokio::spawn(async move {
+      while let Ok(Some(chunk)) = recv.read_chunk(usize::MAX, true).await {
+        if send.write_chunk(chunk.bytes).await.is_err() {
+          return;
+        }
+      }
+      let _ = send.finish();
+    });
+  }
+}
+
+struct Checker {
+  client: Endpoint,
+  addr: SocketAddr,
+  cert: CertificateDer<'static>,
+}
+
+impl Checker {
+  /// Run `check` on a new `alpn` connection unless `filter` excludes it.
+  async fn run<F, Fut>(&self, outcomes: &mut Vec<Outcome>, filter: Option<&str>, name: &str, alpn: &[u8], check: F)
+  where
+    F: FnOnce(Connection) -> Fut,
+    Fut: Future<Output = Result<()>>,
+  {
+    let check = async {
+      let conn = self.connect(alpn).await?;
+      check(conn.clone()).await?;
+      conn.close(0u32.into(), b"check done");
+      Ok(())
+    };
+    record(outcomes, filter, name, check).await;
+  }
+
+  async fn connect(&self, alpn: &[u8]) -> Result<Connection> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(self.cert.clone())?;
+    let mut tls = rustls::ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+    tls.alpn_protocols = vec![alpn.to_vec()];
+    let config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?));
+    Ok(self.client.connect_with(config, self.addr, SERVER_NAME)?.await?)
+  }
+}
+
+/// Run `check` unless `filter` excludes it, timing it.
+async fn record(outcomes: &mut Vec<Outcome>, filter: Option<&str>, name: &str, check: impl Future<Output = Result<()>>) {
+  if filter.is_some_and(|filter| !name.contains(filter)) {
+    return;
+  }
+  let start = Instant::now();
+  let result = tokio::time::timeout(CHECK_TIMEOUT, check)
+    .await
+    .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out after {CHECK_TIMEOUT:?}")))
+    .map(|()| start.elapsed());
+  outcomes.push(Outcome { name: name.into(), result });
+}
+
+/// `len` bytes that differ from one offset to the next.
+fn pattern(len: usize) -> Vec<u8> {
+  (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+/// The library's [`EchoClient`]: single pings both ways, then a few from
+/// [`EchoClient::ping_stream`], all on one connection.
+async fn echo_client(addr: SocketAddr, cert: CertificateDer<'static>) -> Result<()> {
+  let mut roots = rustls::RootCertStore::empty();
+  roots.add(cert)?;
+  let tls = rustls::ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+  let client = Arc::new(EchoClient::new(addr, SERVER_NAME, tls)?);
+  let first = client.ping(b"ping", Mode::Stream).await?;
+  anyhow::ensure!(first.bytes == 4 && first.handshake.is_some(), "unexpected first ping {first:?}");
+  let second = client.ping(&pattern(1000), Mode::Datagram).await?;
+  anyhow::ensure!(second.handshake.is_none(), "the second ping did not reuse the connection");
+  let mut pings = client.clone().ping_stream(Bytes::from_static(b"tick"), Mode::Stream, Duration::from_millis(10));
+  for _ in 0..3 {
+    let next = std::future::poll_fn(|cx| std::pin::Pin::new(&mut pings).poll_next(cx)).await;
+    next.context("the ping stream ended")??;
+  }
+  drop(pings);
+  client.close().await;
+  Ok(())
+}
+
+async fn stream_echo(conn: Connection, size: usize) -> Result<()> {
+  let data = pattern(size);
+  let (mut send, mut recv) = conn.open_bi().await?;
+  let (sent, echo) = tokio::join!(
+    async {
+      send.write_all(&data).await?;
+      anyhow::Ok(send.finish()?)
+    },
+    recv.read_to_end(size.max(1))
+  );
+  sent?;
+  verify::compare("stream echo", &data, &echo?)
+}
+
+async fn concurrent_streams(conn: Connection) -> Result<()> {
+  let mut streams = JoinSet::new();
+  for _ in 0..16 {
+    streams.spawn(stream_echo(conn.clone(), 16 * 1024));
+  }
+  while let Some(result) = streams.join_next().await {
+    result??;
+  }
+  Ok(())
+}
+
+async fn uni_stream(conn: Connection) -> Result<()> {
+  let mut send = conn.open_uni().await?;
+  send.write_all(&pattern(256 * 1024)).await?;
+  send.finish()?;
+  // resolves once the server has acknowledged everything
+  if let Some(code) = send.stopped().await? {
+    anyhow::bail!("server stopped the stream with code {code}");
+  }
+  Ok(())
+}
+
+async fn datagram_echo(conn: Connection, size: Option<usize>) -> Result<()> {
+  let max = conn.max_datagram_size().context("server does not support datagrams")?;
+  let data = Bytes::from(pattern(size.unwrap_or(max)));
+  // datagrams may be lost even on loopback; a few tries are enough
+  for _ in 0..5 {
+    conn.send_datagram(data.clone())?;
+    if let Ok(echo) = tokio::time::timeout(Duration::from_millis(500), conn.read_datagram()).await {
+      return verify::compare("datagram echo", &data, &echo?);
+    }
+  }
+  anyhow::bail!("no datagram echo after 5 tries")
+}
+
+async fn datagram_burst(conn: Connection) -> Result<()> {
+  const COUNT: usize = 100;
+  for i in 0..COUNT {
+    conn.send_datagram(Bytes::from((i as u32).to_be_bytes().to_vec()))?;
+  }
+  let mut received = 0;
+  while received < COUNT {
+    match tokio::time::timeout(Duration::from_secs(1), conn.read_datagram()).await {
+      Ok(echo) => {
+        echo?;
+        received += 1;
+      }
+      Err(_) => break,
+    }
+  }
+  anyhow::ensure!(received * 100 >= COUNT * 95, "only {received} of {COUNT} datagrams came back");
+  Ok(())
+}
+
+async fn reliable_datagrams(conn: Connection) -> Result<()> {
+  let params = reliable::Params { count: 200, rate: 2000.0, size: 64, retries: 10 };
+  let run = reliable::run(&conn, params).await?;
+  anyhow::ensure!(run.delivered == run.sent, "{} of {} messages delivered", run.delivered, run.sent);
+  Ok(())
+}
+
+async fn perf_request(conn: Connection) -> Result<()> {
+  let size = 4 * 1024 * 1024;
+  let run = perf::request(&conn, size, size, None).await?;
+  anyhow::ensure!(run.upload == size && run.download == size, "moved {} up and {} down", run.upload, run.download);
+  Ok(())
+}
+
+async fn duplex_run(conn: Connection) -> Result<()> {
+  duplex::run(&conn, 4 * 1024 * 1024, 4 * 1024 * 1024, None).await?;
+  Ok(())
+}
+
+async fn file_echo(conn: Connection) -> Result<()> {
+  let path = std::env::temp_dir().join(format!("quic_echo_selftest_{}", std::process::id()));
+  std::fs::write(&path, pattern(1024 * 1024)).with_context(|| format!("write {}", path.display()))?;
+  let result = transfer::send_file(&conn, &path, transfer::OP_ECHO, None).await;
+  let _ = std::fs::remove_file(&path);
+  result.map(drop)
+}
+
+async fn doq_query(conn: Connection) -> Result<()> {
+  let response = doq::query(&conn, "selftest.example.", RecordType::A).await?;
+  anyhow::ensure!(!response.answers().is_empty(), "no answer in the DoQ response");
+  Ok(())
+}
+
+async fn h3_echo(conn: Connection) -> Result<()> {
+  let body = Bytes::from(pattern(32 * 1024));
+  let echo = http3::echo(conn, SERVER_NAME, body.clone()).await?;
+  verify::compare("HTTP/3 echo", &body, &echo)
+}
@@ -0,0 +1,318 @@
+//! In-process simulated network for tests: [`Network`] hands out sockets
+//! (or whole quinn endpoints) that exchange datagrams in memory, with
+//! configurable latency, jitter, loss, reordering and MTU, so client and
+//! server can run in one process without touching a real socket.
+//!
+//! Delivery is driven by tokio's clock and the fate of each datagram by an
+//! RNG seeded from [`SimConfig::seed`], so a test run under paused time
+//! (`#[tokio::test(start_paused = true)]`) sees the same losses and the same
+//! order every time.
+//!
+//! Every socket is an [`AsyncUdpSocket`] with one datagram per call (no
+//! GSO/GRO) and no ECN; a datagram to an address nobody has bound is
+//! dropped, like on a real network.
+
+use quinn::{
+  AsyncUdpSocket, Endpoint, EndpointConfig, ServerConfig, TokioRuntime, UdpPoller,
+  udp::{RecvMeta, Transmit},
+};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use std::{
+  cmp::Reverse,
+  collections::{BinaryHeap, HashMap},
+  fmt,
+  future::Future,
+  io::{self, IoSliceMut},
+  net::SocketAddr,
+  pin::Pin,
+  sync::{Arc, Mutex, Weak},
+  task::{Context, Poll, Waker},
+  time::Duration,
+};
+use tokio::time::{Instant, Sleep};
+
+/// First port handed out for sockets bound to port 0.
+const EPHEMERAL_PORTS: u16 = 49152;
+
+/// Conditions of a [`Network`], applied to every datagram.
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+  /// One-way delay
+  pub latency: Duration,
+  /// Extra delay, uniformly random up to this much
+  pub jitter: Duration,
+  /// Probability (0 to 1) that a datagram is dropped
+  pub loss: f64,
+  /// Probability (0 to 1) that a datagram is held back long enough for
+  /// later ones to overtake it
+  pub reorder: f64,
+  /// Largest UDP payload delivered; bigger datagrams are dropped
+  pub mtu: usize,
+  pub seed: u64,
+}
+
+impl Default for SimConfig {
+  fn default() -> Self {
+    Self { latency: Duration::from_millis(10), jitter: Duration::ZERO, loss: 0.0, reorder: 0.0, mtu: 1472, seed: 0 }
+  }
+}
+
+/// What happened to the datagrams sent on a [`Network`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SimStats {
+  pub sent: u64,
+  /// Queued for a bound address (counting the reordered ones)
+  pub delivered: u64,
+  pub lost: u64,
+  pub reordered: u64,
+  /// Dropped for exceeding [`SimConfig::mtu`]
+  pub too_big: u64,
+  /// Sent to an address no socket is bound to
+  pub unroutable: u64,
+}
+
+/// A simulated network; clones share it.
+#[derive(Clone)]
+pub struct Network(Arc<Mutex<Shared>>);
+
+struct Shared {
+  config: SimConfig,
+  rng: StdRng,
+  sockets: HashMap<SocketAddr, Weak<SimSocket>>,
+  stats: SimStats,
+  next_port: u16,
+  /// Orders datagrams due at the same instant by when they were sent
+  seq: u64,
+}
+
+impl fmt::Debug for Network {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let shared = self.0.lock().unwrap();
+    f.debug_struct("Network")
+      .field("config", &shared.config)
+      .field("sockets", &shared.sockets.len())
+      .finish_non_exhaustive()
+  }
+}
+
+impl Network {
+  pub fn new(config: SimConfig) -> Self {
+    Network(Arc::new(Mutex::new(Shared {
+      rng: StdRng::seed_from_u64(config.seed),
+      config,
+      sockets: HashMap::new(),
+      stats: SimStats::default(),
+      next_port: EPHEMERAL_PORTS,
+      seq: 0,
+    })))
+  }
+
+  /// Change the conditions for datagrams sent from now on (e.g. `loss: 1.0`
+  /// for a partition); the RNG keeps its state.
+  pub fn set_config(&self, config: SimConfig) {
+    self.0.lock().unwrap().config = config;
+  }
+
+  pub fn stats(&self) -> SimStats {
+    self.0.lock().unwrap().stats
+  }
+
+  /// Bind a socket to `addr`; port 0 picks a free port.
+  pub fn socket(&self, mut addr: SocketAddr) -> io::Result<Arc<SimSocket>> {
+    let mut shared = self.0.lock().unwrap();
+    shared.sockets.retain(|_, socket| socket.strong_count() > 0);
+    if addr.port() == 0 {
+      let start = shared.next_port;
+      loop {
+        addr.set_port(shared.next_port);
+        shared.next_port = shared.next_port.checked_add(1).unwrap_or(EPHEMERAL_PORTS);
+        if !shared.sockets.contains_key(&addr) {
+          break;
+        }
+        if shared.next_port == start {
+          return Err(io::Error::new(io::ErrorKind::AddrInUse, "no free simulated port"));
+        }
+      }
+    } else if shared.sockets.contains_key(&addr) {
+      return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{addr} is already bound")));
+    }
+    let socket = Arc::new(SimSocket { network: self.clone(), local: addr, inbox: Mutex::new(Inbox::default()) });
+    shared.sockets.insert(addr, Arc::downgrade(&socket));
+    Ok(socket)
+  }
+
+  /// A quinn endpoint on a socket bound to `addr`, accepting connections if
+  /// `server_config` is given. Its RNG (connection IDs, tokens, ...) is
+  /// seeded from the network's, so runs repeat exactly.
+  pub fn endpoint(&self, addr: SocketAddr, server_config: Option<ServerConfig>) -> io::Result<Endpoint> {
+    let socket = self.socket(addr)?;
+    let mut config = EndpointConfig::default();
+    config.rng_seed(Some(self.0.lock().unwrap().rng.random()));
+    Endpoint::new_with_abstract_socket(config, server_config, socket, Arc::new(TokioRuntime))
+  }
+}
+
+/// A datagram on its way, ordered by when it is due.
+struct Queued {
+  at: Instant,
+  seq: u64,
+  from: SocketAddr,
+  data: Vec<u8>,
+}
+
+impl PartialEq for Queued {
+  fn eq(&self, other: &Self) -> bool {
+    (self.at, self.seq) == (other.at, other.seq)
+  }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+  fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for Queued {
+  fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+    (self.at, self.seq).cmp(&(other.at, other.seq))
+  }
+}
+
+#[derive(Default)]
+struct Inbox {
+  queue: BinaryHeap<Reverse<Queued>>,
+  waker: Option<Waker>,
+  /// Fires when the earliest queued datagram is due
+  timer: Option<Pin<Box<Sleep>>>,
+}
+
+/// A socket on a [`Network`].
+pub struct SimSocket {
+  network: Network,
+  local: SocketAddr,
+  inbox: Mutex<Inbox>,
+}
+
+impl fmt::Debug for SimSocket {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("SimSocket").field("local", &self.local).finish_non_exhaustive()
+  }
+}
+
+impl SimSocket {
+  fn deliver(&self, datagram: Queued) {
+    let mut inbox = self.inbox.lock().unwrap();
+    inbox.queue.push(Reverse(datagram));
+    if let Some(waker) = inbox.waker.take() {
+      waker.wake();
+    }
+  }
+}
+
+impl Drop for SimSocket {
+  fn drop(&mut self) {
+    let mut shared = self.network.0.lock().unwrap();
+    if shared.sockets.get(&self.local).is_some_and(|socket| socket.strong_count() == 0) {
+      shared.sockets.remove(&self.local);
+    }
+  }
+}
+
+impl AsyncUdpSocket for SimSocket {
+  fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+    Box::pin(AlwaysWritable)
+  }
+
+  fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+    let segment = transmit.segment_size.unwrap_or(transmit.contents.len()).max(1);
+    let now = Instant::now();
+    let mut deliveries = Vec::new();
+    {
+      let mut shared = self.network.0.lock().unwrap();
+      let Shared { config, rng, sockets, stats, seq, .. } = &mut *shared;
+      for data in transmit.contents.chunks(segment) {
+        stats.sent += 1;
+        if data.len() > config.mtu {
+          stats.too_big += 1;
+          continue;
+        }
+        if rng.random_bool(config.loss.clamp(0.0, 1.0)) {
+          stats.lost += 1;
+          continue;
+        }
+        let mut delay = config.latency + config.jitter.mul_f64(rng.random::<f64>());
+        if rng.random_bool(config.reorder.clamp(0.0, 1.0)) {
+          delay += (config.latency + config.jitter).max(Duration::from_millis(1));
+          stats.reordered += 1;
+        }
+        let Some(target) = sockets.get(&transmit.destination).and_then(Weak::upgrade) else {
+          stats.unroutable += 1;
+          continue;
+        };
+        stats.delivered += 1;
+        *seq += 1;
+        deliveries.push((target, Queued { at: now + delay, seq: *seq, from: self.local, data: data.to_vec() }));
+      }
+    }
+    // outside the network lock: dropping the last handle to a target locks it again
+    for (target, datagram) in deliveries {
+      target.deliver(datagram);
+    }
+    Ok(())
+  }
+
+  fn poll_recv(&self, cx: &mut Context, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) -> Poll<io::Result<usize>> {
+    let mut inbox = self.inbox.lock().unwrap();
+    loop {
+      let now = Instant::now();
+      let mut n = 0;
+      while n < bufs.len().min(meta.len()) {
+        match inbox.queue.peek() {
+          Some(Reverse(next)) if next.at <= now => {}
+          _ => break,
+        }
+        let Reverse(datagram) = inbox.queue.pop().unwrap();
+        let len = datagram.data.len().min(bufs[n].len());
+        bufs[n][..len].copy_from_slice(&datagram.data[..len]);
+        meta[n] = RecvMeta { addr: datagram.from, len, stride: len, ecn: None, dst_ip: None };
+        n += 1;
+      }
+      if n > 0 {
+        return Poll::Ready(Ok(n));
+      }
+
+      inbox.waker = Some(cx.waker().clone());
+      let Some(due) = inbox.queue.peek().map(|Reverse(next)| next.at) else {
+        return Poll::Pending;
+      };
+      let timer = match inbox.timer.take() {
+        Some(timer) if timer.deadline() == due => timer,
+        _ => Box::pin(tokio::time::sleep_until(due)),
+      };
+      let timer = inbox.timer.insert(timer);
+      if timer.as_mut().poll(cx).is_pending() {
+        return Poll::Pending;
+      }
+    }
+  }
+
+  fn local_addr(&self) -> io::Result<SocketAddr> {
+    Ok(self.local)
+  }
+
+  fn may_fragment(&self) -> bool {
+    false
+  }
+}
+
+/// Sends never block: they only queue.
+#[derive(Debug)]
+struct AlwaysWritable;
+
+impl UdpPoller for AlwaysWritable {
+  fn poll_writable(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+    Poll::Ready(Ok(()))
+  }
+}
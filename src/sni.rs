@@ -0,0 +1,53 @@
+//! SNI-based certificate selection for the server: with several `--cert`
+//! pairs (or a `--cert-dir`) each handshake gets the first certificate whose
+//! subject alternative names cover the client's SNI, checked like a client
+//! would (wildcards included). Handshakes without SNI, or with a name no
+//! certificate covers, get the first one.
+
+use rustls::{
+  pki_types::{DnsName, ServerName},
+  server::{ClientHello, ResolvesServerCert},
+  sign::CertifiedKey,
+};
+use std::sync::Arc;
+use tracing::{debug, info};
+
+#[derive(Debug)]
+pub struct SniResolver {
+  /// Label (the cert's file name) and key; the first one is the default
+  certs: Vec<(String, Arc<CertifiedKey>)>,
+}
+
+impl SniResolver {
+  /// `certs` must not be empty.
+  pub fn new(certs: Vec<(String, CertifiedKey)>) -> Self {
+    assert!(!certs.is_empty(), "SniResolver needs at least one certificate");
+    Self { certs: certs.into_iter().map(|(label, key)| (label, Arc::new(key))).collect() }
+  }
+
+  fn find(&self, sni: &str) -> Option<&(String, Arc<CertifiedKey>)> {
+    let name = ServerName::DnsName(DnsName::try_from(sni).ok()?.to_owned());
+    self.certs.iter().find(|(_, key)| {
+      let Ok(der) = key.end_entity_cert() else {
+        return false;
+      };
+      webpki::EndEntityCert::try_from(der).is_ok_and(|ee| ee.verify_is_valid_for_subject_name(&name).is_ok())
+    })
+  }
+}
+
+impl ResolvesServerCert for SniResolver {
+  fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+    let sni = hello.server_name();
+    let (label, key, matched) = match sni.and_then(|sni| self.find(sni)) {
+      Some((label, key)) => (label, key, true),
+      None => (&self.certs[0].0, &self.certs[0].1, false),
+    };
+    if self.certs.len() > 1 {
+      info!(sni = sni.unwrap_or("<none>"), cert = %label, matched, "serving certificate");
+    } else {
+      debug!(sni = sni.unwrap_or("<none>"), cert = %label, "serving certificate");
+    }
+    Some(key.clone())
+  }
+}
@@ -0,0 +1,309 @@
+//! Soak test for the client (`--soak`): keep a connection to the echo
+//! server for hours, ping it at a fixed interval and reconnect whenever it
+//! is lost, so a flaky link can be watched overnight.
+//!
+//! Every `report` interval (hourly by default) one summary line is printed:
+//! availability (the share of pings echoed in time, counting pings that
+//! could not be sent while disconnected as failed), RTT percentiles of the
+//! successful pings, reconnects with the reasons the connections were lost,
+//! and the longest outage. The same summary over the whole run follows at
+//! the end.
+//!
+//! Datagram pings also track the interarrival jitter of the echoes (RFC
+//! 3550, 6.4.1). The pings leave at a fixed interval and both timestamps
+//! are on our clock, so the transit difference between two echoes is just
+//! the difference of their round trips. The estimate restarts its history
+//! on every new connection, since the path may have changed.
+
+use crate::{
+  flow,
+  hdr::Latencies,
+  samples::{Intervals, SampleWriter, Sampler},
+  transform::Transform,
+  verify,
+};
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use quinn::{Connection, ConnectionError, VarInt};
+use std::{
+  collections::BTreeMap,
+  fmt,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, info, warn};
+
+/// How a soak run pings the server.
+#[derive(Clone, Copy, Debug)]
+pub struct Params {
+  /// How long to keep going
+  pub duration: Duration,
+  /// Time between pings
+  pub interval: Duration,
+  /// Time between summaries
+  pub report: Duration,
+  /// How long one echo may take before the ping counts as failed
+  pub timeout: Duration,
+  /// Ping with datagrams instead of streams
+  pub datagram: bool,
+  /// The server's --transform
+  pub transform: Transform,
+  /// Time between `interval` rows with --samples-out
+  pub sample_interval: Duration,
+}
+
+/// Pings, reconnects and outages over one report interval, or the whole
+/// run.
+#[derive(Clone, Debug, Default)]
+pub struct Window {
+  pub pings: u32,
+  pub ok: u32,
+  /// Round trips of the successful pings
+  rtts: Vec<Duration>,
+  /// Connections established after the first one
+  pub reconnects: u32,
+  /// Connection attempts that failed
+  pub connect_failures: u32,
+  /// Why connections were lost, with counts
+  pub reasons: BTreeMap<&'static str, u32>,
+  pub longest_outage: Duration,
+  /// Smoothed interarrival jitter of the datagram echoes at the end of the
+  /// window; `None` for stream pings
+  pub jitter: Option<Duration>,
+  pub max_jitter: Duration,
+}
+
+impl Window {
+  /// Echoed pings as a percentage of all pings.
+  pub fn availability(&self) -> f64 {
+    f64::from(self.ok) * 100.0 / f64::from(self.pings.max(1))
+  }
+
+  /// RTT at the `p`th percentile of the successful pings.
+  pub fn rtt(&self, p: f64) -> Duration {
+    let Some(last) = self.rtts.len().checked_sub(1) else {
+      return Duration::ZERO;
+    };
+    let mut sorted = self.rtts.clone();
+    sorted.sort_unstable();
+    sorted[(last as f64 * p / 100.0).round() as usize]
+  }
+
+  fn merge(&mut self, other: &Window) {
+    self.pings += other.pings;
+    self.ok += other.ok;
+    self.rtts.extend_from_slice(&other.rtts);
+    self.reconnects += other.reconnects;
+    self.connect_failures += other.connect_failures;
+    for (reason, n) in &other.reasons {
+      *self.reasons.entry(reason).or_default() += n;
+    }
+    self.longest_outage = self.longest_outage.max(other.longest_outage);
+    self.jitter = other.jitter.or(self.jitter);
+    self.max_jitter = self.max_jitter.max(other.max_jitter);
+  }
+}
+
+impl fmt::Display for Window {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "availability {:.2}% ({}/{} pings)", self.availability(), self.ok, self.pings)?;
+    if !self.rtts.is_empty() {
+      write!(
+        f,
+        ", rtt p50/p90/p99/max {:.1?}/{:.1?}/{:.1?}/{:.1?}",
+        self.rtt(50.0),
+        self.rtt(90.0),
+        self.rtt(99.0),
+        self.rtt(100.0)
+      )?;
+    }
+    if let Some(jitter) = self.jitter {
+      write!(f, ", jitter {jitter:.1?} (max {:.1?})", self.max_jitter)?;
+    }
+    write!(f, ", reconnects {}", self.reconnects)?;
+    if !self.reasons.is_empty() {
+      let reasons: Vec<String> = self.reasons.iter().map(|(reason, n)| format!("{reason}: {n}")).collect();
+      write!(f, " ({})", reasons.join(", "))?;
+    }
+    if self.connect_failures > 0 {
+      write!(f, ", {} failed connects", self.connect_failures)?;
+    }
+    if !self.longest_outage.is_zero() {
+      write!(f, ", longest outage {:.1?}", self.longest_outage)?;
+    }
+    Ok(())
+  }
+}
+
+/// Ping the server over connections from `connect` for `params.duration`,
+/// printing a summary every `params.report`, and return the summary of the
+/// whole run. With `samples`, every echoed ping becomes a `ping` row of
+/// the connection it went over; with `latencies`, its round trip is
+/// recorded there too.
+pub async fn run(
+  connect: impl AsyncFn() -> Result<Connection>,
+  params: &Params,
+  samples: Option<&Arc<SampleWriter>>,
+  latencies: Option<&Latencies>,
+) -> Result<Window> {
+  info!(duration = ?params.duration, interval = ?params.interval, "soak test");
+  let start = Instant::now();
+  let mut ticker = tokio::time::interval(params.interval.max(Duration::from_millis(1)));
+  ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+  let (mut window, mut total) = (Window::default(), Window::default());
+  let mut window_start = start;
+  let mut conn: Option<Connection> = None;
+  let mut connected_once = false;
+  let mut down_since: Option<Instant> = None;
+  let mut seq = 0u64;
+  let mut sampler: Option<(Sampler, Intervals)> = None;
+  // round trip of the previous echo on this connection, and the estimate
+  let mut previous: Option<Duration> = None;
+  let mut jitter = 0.0f64;
+
+  while start.elapsed() < params.duration {
+    ticker.tick().await;
+    if let Some(reason) = conn.as_ref().and_then(Connection::close_reason) {
+      warn!(%reason, "connection lost");
+      *window.reasons.entry(describe(&reason)).or_default() += 1;
+      down_since.get_or_insert_with(Instant::now);
+      conn = None;
+      sampler = None;
+    }
+    if conn.is_none() {
+      match connect().await {
+        Ok(new) => {
+          if connected_once {
+            window.reconnects += 1;
+            info!(remote = %new.remote_address(), "reconnected");
+          }
+          connected_once = true;
+          previous = None;
+          sampler = samples.map(|w| {
+            let sampler = Sampler::new(w.clone(), new.clone());
+            let intervals = sampler.spawn_intervals(params.sample_interval);
+            (sampler, intervals)
+          });
+          conn = Some(new);
+        }
+        Err(e) => {
+          window.connect_failures += 1;
+          down_since.get_or_insert_with(Instant::now);
+          warn!("connect failed: {e:#}");
+        }
+      }
+    }
+
+    // pings that were due while a handshake or a slow echo held us up failed too
+    let due = (start.elapsed().as_secs_f64() / params.interval.as_secs_f64()) as u64;
+    if due > seq {
+      window.pings += (due - seq) as u32;
+      seq = due;
+      down_since.get_or_insert_with(Instant::now);
+    }
+    window.pings += 1;
+    seq += 1;
+    let res = match &conn {
+      Some(conn) => ping(conn, seq, params).await,
+      None => Err(anyhow::anyhow!("not connected")),
+    };
+    match res {
+      Ok(rtt) => {
+        window.ok += 1;
+        window.rtts.push(rtt);
+        if let Some(latencies) = latencies {
+          latencies.record(rtt);
+        }
+        let mut echo_jitter = None;
+        if params.datagram {
+          if let Some(previous) = previous {
+            let transit = rtt.as_secs_f64() - previous.as_secs_f64();
+            jitter += (transit.abs() - jitter) / 16.0;
+          }
+          let current = Duration::from_secs_f64(jitter);
+          window.jitter = Some(current);
+          window.max_jitter = window.max_jitter.max(current);
+          echo_jitter = Some(current);
+        }
+        previous = Some(rtt);
+        if let Some((sampler, _)) = &sampler {
+          sampler.ping_with_jitter(rtt, echo_jitter);
+        }
+        if let Some(since) = down_since.take() {
+          let outage = since.elapsed();
+          window.longest_outage = window.longest_outage.max(outage);
+          info!(outage = ?outage, "echoes are back");
+        }
+      }
+      Err(e) => {
+        debug!(seq, "ping failed: {e:#}");
+        down_since.get_or_insert_with(Instant::now);
+      }
+    }
+
+    if window_start.elapsed() >= params.report {
+      println!("soak [{}] last {}: {window}", hms(start.elapsed()), hms(window_start.elapsed()));
+      total.merge(&window);
+      window = Window::default();
+      window_start = Instant::now();
+    }
+  }
+
+  if window.pings > 0 {
+    total.merge(&window);
+  }
+  if let Some(conn) = conn {
+    conn.close(VarInt::from_u32(0), b"soak done");
+  }
+  Ok(total)
+}
+
+/// One echo of a numbered ping, returning its round trip.
+async fn ping(conn: &Connection, seq: u64, params: &Params) -> Result<Duration> {
+  let payload = Bytes::from(format!("soak {seq}"));
+  let expected = params.transform.apply(payload.clone());
+  let sent = Instant::now();
+  tokio::time::timeout(params.timeout, async {
+    if params.datagram {
+      conn.send_datagram(payload)?;
+      // skip late echoes of pings that already timed out
+      loop {
+        let echo = conn.read_datagram().await?;
+        if echo == expected {
+          return anyhow::Ok(());
+        }
+        debug!(len = echo.len(), "stale datagram echo");
+      }
+    } else {
+      let (mut send, mut recv) = flow::open_bi(conn).await?;
+      send.write_all(&payload).await?;
+      send.finish()?;
+      let echo = recv.read_to_end(expected.len().max(64)).await?;
+      verify::compare("soak echo", &expected, &echo)
+    }
+  })
+  .await
+  .with_context(|| format!("no echo within {:?}", params.timeout))??;
+  Ok(sent.elapsed())
+}
+
+/// A short, groupable name for why a connection was lost.
+fn describe(reason: &ConnectionError) -> &'static str {
+  match reason {
+    ConnectionError::TimedOut => "idle timeout",
+    ConnectionError::Reset => "stateless reset",
+    ConnectionError::ApplicationClosed(_) => "closed by server",
+    ConnectionError::ConnectionClosed(_) => "transport error from server",
+    ConnectionError::TransportError(_) => "local transport error",
+    ConnectionError::VersionMismatch => "version mismatch",
+    ConnectionError::LocallyClosed => "closed locally",
+    ConnectionError::CidsExhausted => "connection IDs exhausted",
+  }
+}
+
+/// `d` as hours:minutes:seconds.
+fn hms(d: Duration) -> String {
+  let secs = d.as_secs();
+  format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
@@ -0,0 +1,111 @@
+//! UDP socket setup for quinn endpoints, for when `Endpoint::client` /
+//! `Endpoint::server` (plain bind) is not enough.
+
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{io, net::SocketAddr};
+
+/// Bind a UDP socket to `addr`, optionally pinned to a network interface.
+/// An IPv6 wildcard bind is made dual-stack where the OS allows it, so it
+/// can reach IPv4 peers too (quinn maps them to `::ffff:a.b.c.d`).
+pub fn bind_udp(addr: SocketAddr, interface: Option<&str>) -> io::Result<std::net::UdpSocket> {
+  let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+  if addr.is_ipv6() && addr.ip().is_unspecified() {
+    let _ = socket.set_only_v6(false);
+  }
+  if let Some(name) = interface {
+    bind_device(&socket, addr, name)?;
+  }
+  socket.bind(&addr.into())?;
+  Ok(socket.into())
+}
+
+/// Bind a UDP socket to `addr` with SO_REUSEPORT, so several sockets (one
+/// per endpoint) can share the port and the kernel spreads flows across them.
+#[cfg(unix)]
+pub fn bind_udp_reuseport(addr: SocketAddr) -> io::Result<std::net::UdpSocket> {
+  let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+  if addr.is_ipv6() && addr.ip().is_unspecified() {
+    let _ = socket.set_only_v6(false);
+  }
+  socket.set_reuse_port(true)?;
+  socket.bind(&addr.into())?;
+  Ok(socket.into())
+}
+
+#[cfg(not(unix))]
+pub fn bind_udp_reuseport(_addr: SocketAddr) -> io::Result<std::net::UdpSocket> {
+  Err(io::Error::new(io::ErrorKind::Unsupported, "SO_REUSEPORT is not supported on this platform"))
+}
+
+/// First descriptor of a socket activation (`SD_LISTEN_FDS_START`).
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// The UDP sockets systemd passed by socket activation, read from
+/// `LISTEN_PID`/`LISTEN_FDS` like `sd_listen_fds()` does; empty when this
+/// process was not socket-activated. Any other kind of descriptor is an
+/// error, since the unit's sockets are then not what the server expects.
+#[cfg(unix)]
+pub fn activated_udp() -> io::Result<Vec<std::net::UdpSocket>> {
+  use std::os::fd::FromRawFd;
+
+  // a LISTEN_PID of another process means the variables were inherited, not meant for us
+  let pid = std::env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+  let count = match std::env::var("LISTEN_FDS") {
+    Ok(count) if pid == Some(std::process::id()) => count
+      .parse::<i32>()
+      .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("LISTEN_FDS={count:?} is not a number")))?,
+    _ => return Ok(Vec::new()),
+  };
+  (LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count))
+    .map(|fd| {
+      // SAFETY: systemd passed these descriptors to this process, and nothing else here owns them
+      let socket = unsafe { Socket::from_raw_fd(fd) };
+      socket.set_cloexec(true)?;
+      if socket.r#type()? != Type::DGRAM {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("socket-activated fd {fd} is not a UDP socket")));
+      }
+      Ok(socket.into())
+    })
+    .collect()
+}
+
+#[cfg(not(unix))]
+pub fn activated_udp() -> io::Result<Vec<std::net::UdpSocket>> {
+  Ok(Vec::new())
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &Socket, _addr: SocketAddr, name: &str) -> io::Result<()> {
+  // SO_BINDTODEVICE
+  socket.bind_device(Some(name.as_bytes()))
+}
+
+#[cfg(any(target_os = "ios", target_os = "macos"))]
+fn bind_device(socket: &Socket, addr: SocketAddr, name: &str) -> io::Result<()> {
+  // IP_BOUND_IF / IPV6_BOUND_IF
+  let index = if_addrs::get_if_addrs()?
+    .into_iter()
+    .find(|iface| iface.name == name)
+    .and_then(|iface| iface.index)
+    .and_then(std::num::NonZeroU32::new)
+    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no interface named {name}")))?;
+  match addr {
+    SocketAddr::V4(_) => socket.bind_device_by_index_v4(Some(index)),
+    SocketAddr::V6(_) => socket.bind_device_by_index_v6(Some(index)),
+  }
+}
+
+#[cfg(not(any(
+  target_os = "android",
+  target_os = "fuchsia",
+  target_os = "linux",
+  target_os = "ios",
+  target_os = "macos"
+)))]
+fn bind_device(_socket: &Socket, _addr: SocketAddr, _name: &str) -> io::Result<()> {
+  Err(io::Error::new(
+    io::ErrorKind::Unsupported,
+    "binding to an interface is not supported on this platform",
+  ))
+}
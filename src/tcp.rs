@@ -0,0 +1,131 @@
+//! TLS-over-TCP echo, the baseline for `--compare-tcp`: the server's
+//! companion listener echoes every byte back like the QUIC stream echo, and
+//! the measurements below run unchanged over a TLS stream or a QUIC stream.
+
+use anyhow::{Context, Result};
+use rustls::pki_types::ServerName;
+use std::{
+  net::SocketAddr,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+use tokio::{
+  io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+  net::{TcpListener, TcpStream},
+};
+use tokio_rustls::{client::TlsStream, TlsAcceptor, TlsConnector};
+use tracing::{debug, info, info_span, Instrument};
+
+const CHUNK: usize = 64 * 1024;
+/// Bytes per echo while warming up.
+const WARMUP_ROUND: usize = 1024 * 1024;
+
+/// Echo every TLS connection accepted on `listener` until the task is dropped.
+pub async fn serve(listener: TcpListener, tls: Arc<rustls::ServerConfig>) -> Result<()> {
+  let acceptor = TlsAcceptor::from(tls);
+  info!("TLS-over-TCP echo listening on {} (TCP)", listener.local_addr()?);
+  loop {
+    let (tcp, remote) = listener.accept().await?;
+    let acceptor = acceptor.clone();
+    tokio::spawn(
+      async move {
+        let res = async {
+          tcp.set_nodelay(true)?;
+          let tls = acceptor.accept(tcp).await.context("TLS handshake")?;
+          let (mut recv, mut send) = tokio::io::split(tls);
+          let n = tokio::io::copy(&mut recv, &mut send).await?;
+          send.shutdown().await?;
+          anyhow::Ok(n)
+        };
+        match res.await {
+          Ok(n) => debug!(bytes = n, "TCP connection closed"),
+          Err(e) => debug!("TCP connection failed: {e:#}"),
+        }
+      }
+      .instrument(info_span!("tcp", %remote)),
+    );
+  }
+}
+
+/// TCP connect plus TLS handshake to `addr`; returns the stream and how
+/// long both took together.
+pub async fn connect(
+  addr: SocketAddr,
+  host: &str,
+  tls: Arc<rustls::ClientConfig>,
+) -> Result<(TlsStream<TcpStream>, Duration)> {
+  let name = ServerName::try_from(host.to_string()).with_context(|| format!("invalid TLS name {host:?}"))?;
+  let start = Instant::now();
+  let tcp = TcpStream::connect(addr).await.with_context(|| format!("connect {addr} (TCP)"))?;
+  tcp.set_nodelay(true)?;
+  let stream = TlsConnector::from(tls).connect(name, tcp).await.context("TLS handshake")?;
+  Ok((stream, start.elapsed()))
+}
+
+/// `count` sequential 4-byte round trips over an echoing byte stream.
+pub async fn ping<R, W>(recv: &mut R, send: &mut W, count: usize) -> Result<Vec<Duration>>
+where
+  R: AsyncRead + Unpin,
+  W: AsyncWrite + Unpin,
+{
+  let mut rtts = Vec::with_capacity(count);
+  let mut buf = [0u8; 4];
+  for _ in 0..count {
+    let start = Instant::now();
+    send.write_all(b"ping").await?;
+    send.flush().await?;
+    recv.read_exact(&mut buf).await.context("read ping echo")?;
+    anyhow::ensure!(&buf == b"ping", "ping echo mismatch: {buf:?}");
+    rtts.push(start.elapsed());
+  }
+  Ok(rtts)
+}
+
+/// Push `bytes` through the echo while reading them back; returns the time
+/// until the last byte came back.
+pub async fn bulk<R, W>(recv: &mut R, send: &mut W, bytes: usize) -> Result<Duration>
+where
+  R: AsyncRead + Unpin,
+  W: AsyncWrite + Unpin,
+{
+  let start = Instant::now();
+  let chunk = vec![0x5a; CHUNK];
+  let writing = async {
+    let mut left = bytes;
+    while left > 0 {
+      let n = left.min(CHUNK);
+      send.write_all(&chunk[..n]).await?;
+      left -= n;
+    }
+    send.flush().await?;
+    anyhow::Ok(())
+  };
+  let reading = async {
+    let mut buf = vec![0; CHUNK];
+    let mut left = bytes;
+    while left > 0 {
+      let n = recv.read(&mut buf[..left.min(CHUNK)]).await?;
+      anyhow::ensure!(n > 0, "echo ended with {left} bytes outstanding");
+      left -= n;
+    }
+    anyhow::Ok(())
+  };
+  tokio::try_join!(writing, reading)?;
+  Ok(start.elapsed())
+}
+
+/// Echo [`WARMUP_ROUND`]-byte rounds until `duration` has passed, so the
+/// measured echo does not start in slow start. Returns the bytes echoed.
+pub async fn warmup<R, W>(recv: &mut R, send: &mut W, duration: Duration) -> Result<u64>
+where
+  R: AsyncRead + Unpin,
+  W: AsyncWrite + Unpin,
+{
+  let start = Instant::now();
+  let mut echoed = 0;
+  while start.elapsed() < duration {
+    bulk(recv, send, WARMUP_ROUND).await?;
+    echoed += WARMUP_ROUND as u64;
+  }
+  Ok(echoed)
+}
@@ -0,0 +1,203 @@
+//! File transfer with end-to-end SHA-256 verification (ALPN
+//! "quic-echo-file"). Each file travels on its own bidirectional stream:
+//!
+//! ```text
+//! request:  op (u8) | name length (u16) | name | size (u64) | file bytes
+//! response: file bytes (echo only) | SHA-256 of what the server received (32 bytes)
+//! ```
+//!
+//! Integers are big endian. With [`OP_ECHO`] the server streams the file back,
+//! with [`OP_STORE`] it writes it to its store directory instead.
+
+use crate::{flow, pace::{self, RateLimit}};
+use anyhow::{Context, Result};
+use quinn::{ConnectionError, VarInt};
+use ring::digest::{self, SHA256, SHA256_OUTPUT_LEN};
+use std::{
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::{Duration, Instant},
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{info, warn, Instrument};
+
+pub const ALPN: &[u8] = b"quic-echo-file";
+
+pub const OP_ECHO: u8 = 0;
+pub const OP_STORE: u8 = 1;
+
+/// Stream reset code for refused or malformed transfers.
+const REFUSED: u32 = 1;
+const CHUNK: usize = 64 * 1024;
+
+/// Handle transfers on `conn` until the client goes away. Stores are refused
+/// without a `store_dir`.
+pub async fn serve(conn: quinn::Connection, store_dir: Option<Arc<PathBuf>>) -> Result<()> {
+  loop {
+    let (mut send, recv) = match conn.accept_bi().await {
+      Ok(s) => s,
+      Err(ConnectionError::ApplicationClosed { .. } | ConnectionError::LocallyClosed) => {
+        return Ok(())
+      }
+      Err(e) => return Err(e.into()),
+    };
+
+    let store_dir = store_dir.clone();
+    tokio::spawn(
+      async move {
+        if let Err(e) = receive(&mut send, recv, store_dir.as_ref().map(|d| d.as_path())).await {
+          warn!("file transfer failed: {e:#}");
+          let _ = send.reset(VarInt::from_u32(REFUSED));
+        }
+      }
+      .in_current_span(),
+    );
+  }
+}
+
+async fn receive(send: &mut quinn::SendStream, mut recv: quinn::RecvStream, store_dir: Option<&Path>) -> Result<()> {
+  let start = Instant::now();
+  let op = recv.read_u8().await.context("read transfer header")?;
+  let mut name = vec![0; usize::from(recv.read_u16().await?)];
+  recv.read_exact(&mut name).await?;
+  let name = String::from_utf8(name).context("file name is not UTF-8")?;
+  let size = recv.read_u64().await?;
+
+  let mut file = match op {
+    OP_ECHO => None,
+    OP_STORE => {
+      let dir = store_dir.context("storing is disabled (no --store-dir)")?;
+      // keep only the last path component so clients can't escape the store directory
+      let file_name = Path::new(&name).file_name().context("invalid file name")?;
+      let path = dir.join(file_name);
+      Some(tokio::fs::File::create(&path).await.with_context(|| format!("create {}", path.display()))?)
+    }
+    op => anyhow::bail!("unknown transfer op {op}"),
+  };
+
+  let mut ctx = digest::Context::new(&SHA256);
+  let mut received = 0;
+  while let Some(chunk) = recv.read_chunk(CHUNK, true).await? {
+    ctx.update(&chunk.bytes);
+    received += chunk.bytes.len() as u64;
+    match file.as_mut() {
+      Some(file) => file.write_all(&chunk.bytes).await?,
+      None => send.write_chunk(chunk.bytes).await?,
+    }
+  }
+  anyhow::ensure!(received == size, "{name}: announced {size} bytes, got {received}");
+  if let Some(file) = file.as_mut() {
+    file.flush().await?;
+  }
+
+  let sha256 = ctx.finish();
+  send.write_all(sha256.as_ref()).await?;
+  send.finish()?;
+  let elapsed = start.elapsed();
+  info!(
+    name,
+    size,
+    op = if op == OP_STORE { "store" } else { "echo" },
+    sha256 = hex(sha256.as_ref()),
+    mbps = format_args!("{:.2}", mbps(size, elapsed)),
+    "file received"
+  );
+  Ok(())
+}
+
+/// Outcome of one verified transfer.
+pub struct Transfer {
+  pub size: u64,
+  pub sha256: [u8; SHA256_OUTPUT_LEN],
+  pub elapsed: Duration,
+}
+
+impl Transfer {
+  /// Throughput in Mbit/s over the whole transfer (one direction).
+  pub fn mbps(&self) -> f64 {
+    mbps(self.size, self.elapsed)
+  }
+}
+
+/// Send the file at `path` (under `limit`, if given) and check that the
+/// server's SHA-256 (and, for echoes, the SHA-256 of the data that came
+/// back) matches the local one.
+pub async fn send_file(conn: &quinn::Connection, path: &Path, op: u8, limit: Option<&RateLimit>) -> Result<Transfer> {
+  let mut file = tokio::fs::File::open(path).await.with_context(|| format!("open {}", path.display()))?;
+  let size = file.metadata().await?.len();
+  let name = path.file_name().context("not a file path")?.to_string_lossy();
+  let name_len = u16::try_from(name.len()).context("file name too long")?;
+
+  let start = Instant::now();
+  let (mut send, mut recv) = flow::open_bi(conn).await?;
+  let sending = async {
+    let mut header = vec![op];
+    header.extend_from_slice(&name_len.to_be_bytes());
+    header.extend_from_slice(name.as_bytes());
+    header.extend_from_slice(&size.to_be_bytes());
+    send.write_all(&header).await?;
+
+    let mut ctx = digest::Context::new(&SHA256);
+    let mut buf = vec![0; CHUNK];
+    let mut sent = 0;
+    loop {
+      let n = file.read(&mut buf).await?;
+      if n == 0 {
+        break;
+      }
+      ctx.update(&buf[..n]);
+      pace::write_all(&mut send, &buf[..n], limit).await?;
+      sent += n as u64;
+    }
+    anyhow::ensure!(sent == size, "{} changed size while sending", path.display());
+    send.finish()?;
+    anyhow::Ok(ctx.finish())
+  };
+  let receiving = async {
+    let echoed = if op == OP_ECHO {
+      let mut ctx = digest::Context::new(&SHA256);
+      let mut left = size;
+      let mut buf = vec![0; CHUNK];
+      while left > 0 {
+        let n = left.min(CHUNK as u64) as usize;
+        recv.read_exact(&mut buf[..n]).await.context("read echoed file")?;
+        ctx.update(&buf[..n]);
+        left -= n as u64;
+      }
+      Some(ctx.finish())
+    } else {
+      None
+    };
+    let mut remote = [0; SHA256_OUTPUT_LEN];
+    recv.read_exact(&mut remote).await.context("read server SHA-256")?;
+    anyhow::Ok((echoed, remote))
+  };
+  let (local, received) = tokio::join!(sending, receiving);
+  let local = local?;
+  let (echoed, remote) = received?;
+
+  anyhow::ensure!(
+    local.as_ref() == remote,
+    "SHA-256 mismatch: sent {}, server received {}",
+    hex(local.as_ref()),
+    hex(&remote)
+  );
+  if let Some(echoed) = echoed {
+    anyhow::ensure!(
+      local.as_ref() == echoed.as_ref(),
+      "SHA-256 mismatch: sent {}, echoed back {}",
+      hex(local.as_ref()),
+      hex(echoed.as_ref())
+    );
+  }
+
+  Ok(Transfer { size, sha256: remote, elapsed: start.elapsed() })
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn mbps(bytes: u64, elapsed: Duration) -> f64 {
+  bytes as f64 * 8.0 / elapsed.as_secs_f64().max(f64::EPSILON) / 1e6
+}
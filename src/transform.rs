@@ -0,0 +1,54 @@
+//! Response transforms of the echo mode (`--transform` on both binaries):
+//! instead of reflecting the request, the server answers with something
+//! derived from it, which a middlebox reflecting packets cannot fake. The
+//! client applies the same transform to what it sent and checks the answer.
+//!
+//! `upper` works chunk by chunk, so streams are still echoed as they
+//! arrive; `reverse` and `sha256` answer once the stream is finished (up to
+//! [`STREAM_LIMIT`] bytes). Datagrams are transformed one by one.
+
+use bytes::Bytes;
+use clap::ValueEnum;
+use ring::digest::{self, SHA256};
+
+/// Largest stream `reverse` and `sha256` buffer before answering.
+pub const STREAM_LIMIT: usize = 64 * 1024 * 1024;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transform {
+  /// Send the request back unchanged
+  #[default]
+  Echo,
+  /// ASCII uppercase
+  Upper,
+  /// The bytes in reverse order
+  Reverse,
+  /// Lowercase hex SHA-256 of the request (64 bytes)
+  Sha256,
+}
+
+impl Transform {
+  pub fn name(self) -> &'static str {
+    match self {
+      Transform::Echo => "echo",
+      Transform::Upper => "upper",
+      Transform::Reverse => "reverse",
+      Transform::Sha256 => "sha256",
+    }
+  }
+
+  /// Whether each chunk of a stream can be transformed on its own.
+  pub fn per_chunk(self) -> bool {
+    matches!(self, Transform::Echo | Transform::Upper)
+  }
+
+  /// The response to `data`.
+  pub fn apply(self, data: Bytes) -> Bytes {
+    match self {
+      Transform::Echo => data,
+      Transform::Upper => data.to_ascii_uppercase().into(),
+      Transform::Reverse => data.iter().rev().copied().collect::<Vec<_>>().into(),
+      Transform::Sha256 => crate::transfer::hex(digest::digest(&SHA256, &data).as_ref()).into(),
+    }
+  }
+}
@@ -0,0 +1,291 @@
+//! Hop limit of the client's packets (`--ttl`): IP_TTL / IPV6_UNICAST_HOPS
+//! on the endpoint's socket, to keep probes within a few hops and see how
+//! the routers on the way answer.
+//!
+//! A router that drops a packet for its TTL sends ICMP time exceeded, which
+//! Linux only reports on an unconnected UDP socket with IP_RECVERR /
+//! IPV6_RECVERR. Those also make the next send or receive fail with the
+//! error, which would stop quinn's endpoint on a receive, so [`limit`] wraps
+//! the socket: before each send and receive it drains the socket's error
+//! queue into a process-wide record (logged as it comes in), and receive
+//! errors an ICMP message explains are retried instead of passed on. Nothing
+//! wakes the endpoint for an ICMP message alone, so while a handshake goes
+//! unanswered the record grows with quinn's retransmissions. [`feedback`]
+//! sums the record up for a failed handshake. Elsewhere only the hop limit
+//! is set.
+
+use quinn::AsyncUdpSocket;
+use std::{
+  fmt, io,
+  net::IpAddr,
+  sync::{Arc, Mutex},
+};
+
+/// Distinct ICMP errors received so far, with how often each came.
+static FEEDBACK: Mutex<Vec<(Icmp, u64)>> = Mutex::new(Vec::new());
+
+/// An ICMP error about one of our packets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Icmp {
+  pub kind: IcmpKind,
+  /// The router or host that sent it
+  pub from: Option<IpAddr>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IcmpKind {
+  /// The hop limit ran out on the way
+  TimeExceeded,
+  /// Destination unreachable, with its code
+  Unreachable(u8),
+  /// Packet too big (ICMPv6) or fragmentation needed, with the next hop MTU
+  TooBig(u32),
+  Other { kind: u8, code: u8 },
+}
+
+impl fmt::Display for Icmp {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.kind {
+      IcmpKind::TimeExceeded => write!(f, "time exceeded")?,
+      IcmpKind::Unreachable(code) => write!(f, "destination unreachable (code {code})")?,
+      IcmpKind::TooBig(mtu) => write!(f, "packet too big (MTU {mtu})")?,
+      IcmpKind::Other { kind, code } => write!(f, "type {kind} code {code}")?,
+    }
+    match self.from {
+      Some(from) => write!(f, " from {from}"),
+      None => Ok(()),
+    }
+  }
+}
+
+/// The ICMP errors received so far, e.g. `ICMP time exceeded from 10.0.0.1
+/// x 3 (--ttl too small to reach the server)`; `None` if there were none.
+pub fn feedback() -> Option<String> {
+  let feedback = FEEDBACK.lock().unwrap();
+  if feedback.is_empty() {
+    return None;
+  }
+  let seen: Vec<String> = feedback.iter().map(|(icmp, count)| format!("{icmp} x {count}")).collect();
+  let hint = if feedback.iter().any(|(icmp, _)| icmp.kind == IcmpKind::TimeExceeded) {
+    " (--ttl too small to reach the server)"
+  } else {
+    ""
+  };
+  Some(format!("ICMP {}{hint}", seen.join(", ")))
+}
+
+#[cfg(target_os = "linux")]
+fn record(icmp: Icmp) {
+  let mut feedback = FEEDBACK.lock().unwrap();
+  match feedback.iter_mut().find(|(seen, _)| *seen == icmp) {
+    Some((_, count)) => *count += 1,
+    None => {
+      tracing::info!(%icmp, "ICMP error");
+      feedback.push((icmp, 1));
+    }
+  }
+}
+
+/// Send everything `inner` sends with at most `ttl` hops; `socket` is
+/// another handle to the socket behind it.
+pub fn limit(inner: Arc<dyn AsyncUdpSocket>, socket: std::net::UdpSocket, ttl: u8) -> io::Result<Arc<dyn AsyncUdpSocket>> {
+  let sock = socket2::SockRef::from(&socket);
+  if socket.local_addr()?.is_ipv6() {
+    sock.set_unicast_hops_v6(ttl.into())?;
+    // IPv4 peers of a dual-stack socket; fails on an IPv6-only one
+    let _ = sock.set_ttl_v4(ttl.into());
+  } else {
+    sock.set_ttl_v4(ttl.into())?;
+  }
+  tracing::info!(ttl, "hop limit");
+  #[cfg(target_os = "linux")]
+  {
+    linux::receive_errors(&socket)?;
+    Ok(Arc::new(linux::Watched { inner, socket }))
+  }
+  #[cfg(not(target_os = "linux"))]
+  Ok(inner)
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+  use super::{Icmp, IcmpKind, record};
+  use quinn::{
+    AsyncUdpSocket, UdpPoller,
+    udp::{RecvMeta, Transmit},
+  };
+  use std::{
+    fmt,
+    io::{self, IoSliceMut},
+    mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::fd::AsRawFd,
+    pin::Pin,
+    ptr,
+    sync::Arc,
+    task::{Context, Poll},
+  };
+
+  const ICMP_UNREACHABLE: u8 = 3;
+  const ICMP_TIME_EXCEEDED: u8 = 11;
+  /// Code of ICMP unreachable asking for fragmentation
+  const ICMP_FRAG_NEEDED: u8 = 4;
+  const ICMP6_UNREACHABLE: u8 = 1;
+  const ICMP6_TOO_BIG: u8 = 2;
+  const ICMP6_TIME_EXCEEDED: u8 = 3;
+  /// Errors a receive reports for an ICMP message
+  const ICMP_ERRNOS: [i32; 6] =
+    [libc::EHOSTUNREACH, libc::ENETUNREACH, libc::ECONNREFUSED, libc::EHOSTDOWN, libc::EPROTO, libc::EMSGSIZE];
+
+  #[repr(align(8))]
+  struct Control([u8; 256]);
+
+  /// Turn on IP_RECVERR, and IPV6_RECVERR on an IPv6 socket.
+  pub fn receive_errors(socket: &std::net::UdpSocket) -> io::Result<()> {
+    let set = |level, name| {
+      let on: libc::c_int = 1;
+      // SAFETY: an int option on a socket we own
+      let rc = unsafe {
+        libc::setsockopt(
+          socket.as_raw_fd(),
+          level,
+          name,
+          (&raw const on).cast(),
+          mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+      };
+      if rc == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+    };
+    if socket.local_addr()?.is_ipv6() {
+      set(libc::IPPROTO_IPV6, libc::IPV6_RECVERR)?;
+      // IPv4 peers of a dual-stack socket; fails on an IPv6-only one
+      let _ = set(libc::IPPROTO_IP, libc::IP_RECVERR);
+      Ok(())
+    } else {
+      set(libc::IPPROTO_IP, libc::IP_RECVERR)
+    }
+  }
+
+  /// Record every ICMP error queued on `socket`.
+  fn drain(socket: &std::net::UdpSocket) {
+    loop {
+      let mut control = Control([0; 256]);
+      // SAFETY: all-zero is a valid msghdr
+      let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+      hdr.msg_control = control.0.as_mut_ptr().cast();
+      hdr.msg_controllen = control.0.len() as _;
+      // only the extended error matters, not the returned packet
+      // SAFETY: `hdr` points at the control buffer, alive for the call
+      if unsafe { libc::recvmsg(socket.as_raw_fd(), &mut hdr, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) } < 0 {
+        return;
+      }
+      // SAFETY: the kernel filled in `msg_controllen` bytes of control messages
+      unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&hdr);
+        while !cmsg.is_null() {
+          if matches!(
+            ((*cmsg).cmsg_level, (*cmsg).cmsg_type),
+            (libc::IPPROTO_IP, libc::IP_RECVERR) | (libc::IPPROTO_IPV6, libc::IPV6_RECVERR)
+          ) {
+            let err = libc::CMSG_DATA(cmsg).cast::<libc::sock_extended_err>();
+            if let Some(icmp) = icmp(&ptr::read_unaligned(err), offender(err)) {
+              record(icmp);
+            }
+          }
+          cmsg = libc::CMSG_NXTHDR(&hdr, cmsg);
+        }
+      }
+    }
+  }
+
+  fn icmp(err: &libc::sock_extended_err, from: Option<IpAddr>) -> Option<Icmp> {
+    let kind = match (err.ee_origin, err.ee_type, err.ee_code) {
+      (libc::SO_EE_ORIGIN_ICMP, ICMP_TIME_EXCEEDED, _) | (libc::SO_EE_ORIGIN_ICMP6, ICMP6_TIME_EXCEEDED, _) => {
+        IcmpKind::TimeExceeded
+      }
+      (libc::SO_EE_ORIGIN_ICMP, ICMP_UNREACHABLE, ICMP_FRAG_NEEDED) | (libc::SO_EE_ORIGIN_ICMP6, ICMP6_TOO_BIG, _) => {
+        IcmpKind::TooBig(err.ee_info)
+      }
+      (libc::SO_EE_ORIGIN_ICMP, ICMP_UNREACHABLE, code) | (libc::SO_EE_ORIGIN_ICMP6, ICMP6_UNREACHABLE, code) => {
+        IcmpKind::Unreachable(code)
+      }
+      (libc::SO_EE_ORIGIN_ICMP | libc::SO_EE_ORIGIN_ICMP6, kind, code) => IcmpKind::Other { kind, code },
+      // a local error, not from the network
+      _ => return None,
+    };
+    Some(Icmp { kind, from })
+  }
+
+  /// The address of whoever sent the ICMP message, stored after the error.
+  ///
+  /// # Safety
+  ///
+  /// `err` must point into an IP_RECVERR / IPV6_RECVERR control message.
+  unsafe fn offender(err: *const libc::sock_extended_err) -> Option<IpAddr> {
+    // SAFETY: the kernel stores a sockaddr_in or sockaddr_in6 right after the error
+    unsafe {
+      let addr = libc::SO_EE_OFFENDER(err);
+      match i32::from(ptr::read_unaligned(&raw const (*addr).sa_family)) {
+        libc::AF_INET => {
+          let addr = ptr::read_unaligned(addr.cast::<libc::sockaddr_in>());
+          Some(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)).into())
+        }
+        libc::AF_INET6 => {
+          let addr = ptr::read_unaligned(addr.cast::<libc::sockaddr_in6>());
+          Some(Ipv6Addr::from(addr.sin6_addr.s6_addr).to_canonical())
+        }
+        _ => None,
+      }
+    }
+  }
+
+  /// quinn's socket with its ICMP errors taken off the error queue.
+  pub struct Watched {
+    pub inner: Arc<dyn AsyncUdpSocket>,
+    pub socket: std::net::UdpSocket,
+  }
+
+  impl fmt::Debug for Watched {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      f.debug_struct("Watched").field("inner", &self.inner).finish_non_exhaustive()
+    }
+  }
+
+  impl AsyncUdpSocket for Watched {
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+      self.inner.clone().create_io_poller()
+    }
+
+    fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+      drain(&self.socket);
+      self.inner.try_send(transmit)
+    }
+
+    fn poll_recv(&self, cx: &mut Context, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) -> Poll<io::Result<usize>> {
+      loop {
+        drain(&self.socket);
+        match self.inner.poll_recv(cx, bufs, meta) {
+          // an ICMP message that came in after the drain
+          Poll::Ready(Err(e)) if e.raw_os_error().is_some_and(|errno| ICMP_ERRNOS.contains(&errno)) => continue,
+          polled => return polled,
+        }
+      }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+      self.inner.local_addr()
+    }
+
+    fn max_transmit_segments(&self) -> usize {
+      self.inner.max_transmit_segments()
+    }
+
+    fn max_receive_segments(&self) -> usize {
+      self.inner.max_receive_segments()
+    }
+
+    fn may_fragment(&self) -> bool {
+      self.inner.may_fragment()
+    }
+  }
+}
@@ -0,0 +1,258 @@
+//! Live dashboard for long-running client tests (`--tui`, needs the `tui`
+//! feature): pings the echo server every `interval` over a fresh stream (or
+//! a datagram) and shows an RTT sparkline, ping/loss counters and the
+//! connection's own stats until `q`, Esc or Ctrl-C.
+
+use anyhow::Result;
+use std::time::Duration;
+
+/// Ping on `conn` and draw the dashboard until the user quits or the
+/// connection goes away.
+#[cfg(feature = "tui")]
+pub async fn run(conn: &quinn::Connection, datagram: bool, interval: Duration, timeout: Duration) -> Result<()> {
+  dashboard::run(conn, datagram, interval, timeout).await
+}
+
+#[cfg(not(feature = "tui"))]
+pub async fn run(_conn: &quinn::Connection, _datagram: bool, _interval: Duration, _timeout: Duration) -> Result<()> {
+  anyhow::bail!("--tui needs quic_echo built with `--features tui`")
+}
+
+#[cfg(feature = "tui")]
+mod dashboard {
+  use crate::flow;
+  use anyhow::Result;
+  use bytes::Bytes;
+  use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Color, Style},
+    text::Line,
+    widgets::{Block, Paragraph, Sparkline},
+    Frame,
+  };
+  use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+  };
+  use tokio::sync::mpsc;
+  use tracing::Instrument;
+
+  const FRAME: Duration = Duration::from_millis(100);
+  const HISTORY: usize = 1024;
+
+  /// Everything the dashboard shows, updated from ping results and stats.
+  struct State {
+    mode: &'static str,
+    started: Instant,
+    /// RTT in microseconds, `None` for a lost ping
+    rtts: VecDeque<Option<u64>>,
+    sent: u64,
+    lost: u64,
+    min: Duration,
+    max: Duration,
+    sum: Duration,
+    rate: (f64, f64),
+    last_bytes: (u64, u64, Instant),
+    stats: quinn::ConnectionStats,
+    rtt: Duration,
+  }
+
+  pub(super) async fn run(conn: &quinn::Connection, datagram: bool, interval: Duration, timeout: Duration) -> Result<()> {
+    let (tx, mut results) = mpsc::unbounded_channel();
+    let pinger = tokio::spawn(
+      {
+        let conn = conn.clone();
+        async move {
+          let mut tick = tokio::time::interval(interval);
+          for seq in 0u64.. {
+            tick.tick().await;
+            let rtt = ping(&conn, seq, datagram, timeout).await;
+            if tx.send(rtt).is_err() {
+              return;
+            }
+          }
+        }
+      }
+      .in_current_span(),
+    );
+
+    let stats = conn.stats();
+    let mut state = State {
+      mode: if datagram { "datagram" } else { "stream" },
+      started: Instant::now(),
+      rtts: VecDeque::with_capacity(HISTORY),
+      sent: 0,
+      lost: 0,
+      min: Duration::MAX,
+      max: Duration::ZERO,
+      sum: Duration::ZERO,
+      rate: (0.0, 0.0),
+      last_bytes: (stats.udp_tx.bytes, stats.udp_rx.bytes, Instant::now()),
+      stats,
+      rtt: conn.rtt(),
+    };
+
+    let mut terminal = ratatui::init();
+    let res = async {
+      loop {
+        while let Ok(rtt) = results.try_recv() {
+          state.record(rtt);
+        }
+        state.refresh(conn);
+        terminal.draw(|f| draw(f, &state, conn.remote_address()))?;
+
+        if let Some(reason) = conn.close_reason() {
+          return Err(anyhow::Error::from(reason).context("connection closed"));
+        }
+        if event::poll(Duration::ZERO)?
+          && let Event::Key(key) = event::read()?
+          && key.kind == KeyEventKind::Press
+          && (matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)
+            || (key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL)))
+        {
+          return Ok(());
+        }
+        tokio::time::sleep(FRAME).await;
+      }
+    }
+    .await;
+    ratatui::restore();
+    pinger.abort();
+
+    let ok = state.sent - state.lost;
+    println!(
+      "{} pings, {ok} echoed, {} lost; rtt min/avg/max {:?}/{:?}/{:?}",
+      state.sent,
+      state.lost,
+      if ok > 0 { state.min } else { Duration::ZERO },
+      state.avg(),
+      state.max
+    );
+    res
+  }
+
+  /// One ping with an 8-byte sequence number; `None` if nothing matching
+  /// came back within `timeout`.
+  async fn ping(conn: &quinn::Connection, seq: u64, datagram: bool, timeout: Duration) -> Option<Duration> {
+    let start = Instant::now();
+    let payload = Bytes::copy_from_slice(&seq.to_be_bytes());
+    let echoed = async {
+      if datagram {
+        conn.send_datagram(payload.clone())?;
+        // skip late echoes of earlier pings
+        while conn.read_datagram().await? != payload {}
+      } else {
+        let (mut send, mut recv) = flow::open_bi(conn).await?;
+        send.write_all(&payload).await?;
+        send.finish()?;
+        let data = recv.read_to_end(payload.len()).await?;
+        anyhow::ensure!(data == payload, "echo mismatch");
+      }
+      anyhow::Ok(())
+    };
+    match tokio::time::timeout(timeout, echoed).await {
+      Ok(Ok(())) => Some(start.elapsed()),
+      _ => None,
+    }
+  }
+
+  impl State {
+    fn record(&mut self, rtt: Option<Duration>) {
+      self.sent += 1;
+      match rtt {
+        Some(rtt) => {
+          self.min = self.min.min(rtt);
+          self.max = self.max.max(rtt);
+          self.sum += rtt;
+        }
+        None => self.lost += 1,
+      }
+      if self.rtts.len() == HISTORY {
+        self.rtts.pop_front();
+      }
+      self.rtts.push_back(rtt.map(|r| r.as_micros() as u64));
+    }
+
+    /// Pull the connection stats and recompute throughput about once a second.
+    fn refresh(&mut self, conn: &quinn::Connection) {
+      self.stats = conn.stats();
+      self.rtt = conn.rtt();
+      let (tx, rx, at) = self.last_bytes;
+      let elapsed = at.elapsed();
+      if elapsed >= Duration::from_secs(1) {
+        let secs = elapsed.as_secs_f64();
+        self.rate = (
+          (self.stats.udp_tx.bytes - tx) as f64 * 8.0 / secs / 1e3,
+          (self.stats.udp_rx.bytes - rx) as f64 * 8.0 / secs / 1e3,
+        );
+        self.last_bytes = (self.stats.udp_tx.bytes, self.stats.udp_rx.bytes, Instant::now());
+      }
+    }
+
+    fn avg(&self) -> Duration {
+      let ok = (self.sent - self.lost) as u32;
+      if ok == 0 { Duration::ZERO } else { self.sum / ok }
+    }
+  }
+
+  fn draw(f: &mut Frame, state: &State, remote: std::net::SocketAddr) {
+    let [header, spark, counters] =
+      Layout::vertical([Constraint::Length(3), Constraint::Min(6), Constraint::Length(8)]).areas(f.area());
+    let [pings, transport] = Layout::horizontal([Constraint::Percentage(50); 2]).areas(counters);
+
+    f.render_widget(
+      Paragraph::new(format!(
+        "{remote}  {} pings  up {:.0?}  (q to quit)",
+        state.mode,
+        state.started.elapsed()
+      ))
+      .block(Block::bordered().title(" quic_echo ")),
+      header,
+    );
+
+    let width = usize::from(spark.width.saturating_sub(2));
+    let shown: Vec<_> = state.rtts.iter().skip(state.rtts.len().saturating_sub(width)).collect();
+    f.render_widget(
+      Sparkline::default()
+        .block(Block::bordered().title(" RTT (gaps are lost pings) "))
+        .data(shown)
+        .style(Style::default().fg(Color::Cyan)),
+      spark,
+    );
+
+    let ok = state.sent - state.lost;
+    let loss = if state.sent > 0 { 100.0 * state.lost as f64 / state.sent as f64 } else { 0.0 };
+    let last = state.rtts.back().copied().flatten().map_or_else(|| "-".into(), |us| format!("{us} µs"));
+    f.render_widget(
+      Paragraph::new(vec![
+        Line::from(format!("sent     {}", state.sent)),
+        Line::from(format!("echoed   {ok}")),
+        Line::from(format!("lost     {} ({loss:.1}%)", state.lost)),
+        Line::from(format!("last     {last}")),
+        Line::from(format!(
+          "min/avg  {:?} / {:?}",
+          if ok > 0 { state.min } else { Duration::ZERO },
+          state.avg()
+        )),
+        Line::from(format!("max      {:?}", state.max)),
+      ])
+      .block(Block::bordered().title(" pings ")),
+      pings,
+    );
+
+    let path = &state.stats.path;
+    f.render_widget(
+      Paragraph::new(vec![
+        Line::from(format!("rtt      {:?}", state.rtt)),
+        Line::from(format!("cwnd     {} B", path.cwnd)),
+        Line::from(format!("mtu      {} B", path.current_mtu)),
+        Line::from(format!("tx/rx    {:.1} / {:.1} kbit/s", state.rate.0, state.rate.1)),
+        Line::from(format!("lost     {} packets", path.lost_packets)),
+        Line::from(format!("cong.    {} events", path.congestion_events)),
+      ])
+      .block(Block::bordered().title(" connection ")),
+      transport,
+    );
+  }
+}
@@ -0,0 +1,233 @@
+//! Tiny UDP tunnel over QUIC datagrams: the client listens on a local UDP
+//! port and sends every packet it gets as one datagram, the server relays
+//! the datagrams of each connection to its `--forward-to` target from a
+//! socket of their own and sends the replies back the same way.
+//!
+//! One connection carries one flow: replies go to whichever local peer sent
+//! the last packet.
+//!
+//! With a [`Failover`] the client keeps a second, already established
+//! connection to a standby server and checks the primary every
+//! [`HEALTH_CHECK`]. Once the primary's RTT estimate or its loss over the
+//! last [`LOSS_WINDOW`] passes the thresholds, or it closes, the tunnel
+//! moves to the standby on the spot and logs why. A primary that stops
+//! answering altogether updates neither (nothing is acknowledged), so
+//! sending it datagrams or pings and hearing nothing back for longer than
+//! the RTT threshold plus its [`ACK_DELAY`] counts as well. There is one
+//! standby: after the switch the tunnel stays on it.
+
+use crate::{pace::RateLimit, replay::Recorder};
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use quinn::{Connection, ConnectionError, SendDatagramError};
+use std::{
+  collections::VecDeque,
+  net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+  time::Duration,
+};
+use tokio::{
+  net::UdpSocket,
+  time::{Instant, MissedTickBehavior},
+};
+use tracing::{debug, info, warn};
+
+const MAX_PACKET: usize = 65_535;
+/// How often the primary is checked while a standby is ready
+pub const HEALTH_CHECK: Duration = Duration::from_millis(20);
+/// Span over which the primary's loss is measured
+pub const LOSS_WINDOW: Duration = Duration::from_secs(1);
+/// Fewest packets sent within [`LOSS_WINDOW`] for its loss to count
+const MIN_LOSS_SAMPLE: u64 = 20;
+/// How long the server may hold back an ACK (QUIC's default max_ack_delay)
+pub const ACK_DELAY: Duration = Duration::from_millis(25);
+
+/// A warm standby connection for [`listen`] and when to move to it.
+#[derive(Debug, Clone)]
+pub struct Failover {
+  pub standby: Connection,
+  /// Largest acceptable RTT estimate of the primary
+  pub max_rtt: Duration,
+  /// Largest acceptable fraction (0 to 1) of the primary's packets lost
+  /// within [`LOSS_WINDOW`]
+  pub max_loss: f64,
+}
+
+/// Server side: relay the datagrams of `conn` to `target` and back until the
+/// connection closes.
+pub async fn relay(conn: &quinn::Connection, target: SocketAddr) -> Result<()> {
+  let bind = match target {
+    SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+    SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+  };
+  let socket = UdpSocket::bind(bind).await?;
+  socket.connect(target).await.with_context(|| format!("connect {target}"))?;
+  info!(%target, local = %socket.local_addr()?, "forwarding datagrams");
+
+  let mut buf = vec![0u8; MAX_PACKET];
+  loop {
+    tokio::select! {
+      dgram = conn.read_datagram() => match dgram {
+        Ok(data) => {
+          if let Err(e) = socket.send(&data).await {
+            debug!("send to {target} failed: {e}");
+          }
+        }
+        Err(e) => return closed(e),
+      },
+      // ICMP errors surface here on a connected socket; keep relaying
+      n = socket.recv(&mut buf) => if let Ok(n) = n {
+        send(conn, Bytes::copy_from_slice(&buf[..n]));
+      },
+    }
+  }
+}
+
+/// Client side: tunnel packets arriving on the local UDP `listen` address
+/// through `conn` until the connection closes, recording them to `record`
+/// and shaping them to `limit` if given. With `failover` the tunnel moves
+/// to its standby when `conn` degrades.
+pub async fn listen(
+  conn: &quinn::Connection,
+  listen: SocketAddr,
+  record: Option<&Recorder>,
+  limit: Option<&RateLimit>,
+  mut failover: Option<Failover>,
+) -> Result<()> {
+  let socket = UdpSocket::bind(listen).await.with_context(|| format!("bind {listen}"))?;
+  info!(local = %socket.local_addr()?, remote = %conn.remote_address(), "tunnel listening");
+  if let Some(failover) = &failover {
+    info!(
+      standby = %failover.standby.remote_address(),
+      max_rtt = ?failover.max_rtt,
+      max_loss = format!("{:.1}%", failover.max_loss * 100.0),
+      "warm standby ready"
+    );
+  }
+
+  let mut conn = conn.clone();
+  let mut health = Health::default();
+  let mut check = tokio::time::interval(HEALTH_CHECK);
+  check.set_missed_tick_behavior(MissedTickBehavior::Skip);
+  let mut peer = None;
+  let mut buf = vec![0u8; MAX_PACKET];
+  loop {
+    tokio::select! {
+      res = socket.recv_from(&mut buf) => {
+        let (n, from) = res?;
+        if peer != Some(from) {
+          info!(%from, "tunnel peer");
+          peer = Some(from);
+        }
+        if let Some(record) = record {
+          record.datagram(&buf[..n]);
+        }
+        let data = Bytes::copy_from_slice(&buf[..n]);
+        if let Some(limit) = limit {
+          limit.take(data.len()).await;
+        }
+        send(&conn, data);
+      }
+      dgram = conn.read_datagram() => match dgram {
+        Ok(data) => match peer {
+          Some(peer) => {
+            if let Err(e) = socket.send_to(&data, peer).await {
+              debug!("send to {peer} failed: {e}");
+            }
+          }
+          None => debug!("reply before any local packet, dropped"),
+        },
+        Err(e) => match failover.take() {
+          Some(standby) if standby.standby.close_reason().is_none() => {
+            conn = fail_over(&conn, standby, &format!("primary closed: {e}"));
+          }
+          _ => return closed(e),
+        },
+      },
+      _ = check.tick(), if failover.is_some() => {
+        let standby = failover.as_ref().unwrap();
+        if let Some(reason) = standby.standby.close_reason() {
+          warn!(standby = %standby.standby.remote_address(), "standby connection lost, no failover left: {reason}");
+          failover = None;
+        } else if let Some(reason) = health.check(&conn, standby) {
+          conn = fail_over(&conn, failover.take().unwrap(), &reason);
+        }
+      },
+    }
+  }
+}
+
+/// Move the tunnel from `primary` to the standby of `failover`, closing
+/// the primary.
+fn fail_over(primary: &Connection, failover: Failover, reason: &str) -> Connection {
+  warn!(
+    from = %primary.remote_address(),
+    to = %failover.standby.remote_address(),
+    rtt = ?primary.rtt(),
+    "failing over to the standby: {reason}"
+  );
+  primary.close(0u32.into(), b"failed over");
+  failover.standby
+}
+
+/// The primary's recent packet counts, to judge it against a [`Failover`].
+#[derive(Default)]
+struct Health {
+  /// `(when, sent, lost)` over the last [`LOSS_WINDOW`]
+  samples: VecDeque<(Instant, u64, u64)>,
+  /// Datagrams received, and frames sent that the server must acknowledge,
+  /// as of the last time a datagram came in
+  heard: (u64, u64),
+  /// Since when frames to acknowledge have gone unanswered
+  unanswered: Option<Instant>,
+}
+
+impl Health {
+  /// Why the tunnel should leave `conn`, if it should.
+  fn check(&mut self, conn: &Connection, failover: &Failover) -> Option<String> {
+    let rtt = conn.rtt();
+    if rtt > failover.max_rtt {
+      return Some(format!("RTT {rtt:.1?} over {:?}", failover.max_rtt));
+    }
+    let stats = conn.stats();
+    let now = Instant::now();
+    // tunneled packets go as datagrams, and keep-alives as pings
+    let eliciting = stats.frame_tx.datagram + stats.frame_tx.ping + stats.frame_tx.stream;
+    if stats.udp_rx.datagrams != self.heard.0 {
+      self.heard = (stats.udp_rx.datagrams, eliciting);
+      self.unanswered = None;
+    } else if eliciting != self.heard.1 {
+      let since = *self.unanswered.get_or_insert(now);
+      if now - since > failover.max_rtt + ACK_DELAY {
+        return Some(format!("no answer for {:.1?}", now - since));
+      }
+    }
+    let path = stats.path;
+    self.samples.push_back((now, path.sent_packets, path.lost_packets));
+    while self.samples.front().is_some_and(|(at, _, _)| now - *at > LOSS_WINDOW) {
+      self.samples.pop_front();
+    }
+    let (_, sent_before, lost_before) = self.samples.front()?;
+    let (sent, lost) = (path.sent_packets - sent_before, path.lost_packets - lost_before);
+    let loss = lost as f64 / sent.max(1) as f64;
+    (sent >= MIN_LOSS_SAMPLE && loss > failover.max_loss)
+      .then(|| format!("{:.1}% of {sent} packets lost within {LOSS_WINDOW:?}", loss * 100.0))
+  }
+}
+
+/// Datagrams are best effort, so are tunneled packets: log and move on.
+fn send(conn: &quinn::Connection, data: Bytes) {
+  match conn.send_datagram(data) {
+    Ok(()) => {}
+    Err(SendDatagramError::TooLarge) => {
+      debug!(max = ?conn.max_datagram_size(), "packet too large for a datagram, dropped")
+    }
+    Err(e) => warn!("datagram send failed: {e}"),
+  }
+}
+
+fn closed(e: ConnectionError) -> Result<()> {
+  match e {
+    ConnectionError::ApplicationClosed { .. } | ConnectionError::LocallyClosed => Ok(()),
+    e => Err(e.into()),
+  }
+}
@@ -0,0 +1,405 @@
+//! UDP I/O backend of the endpoint (`--io-backend tokio|uring`). `tokio`
+//! is quinn's own socket (quinn-udp on tokio, with GSO/GRO, ECN and packet
+//! info). `uring` (Linux, needs the `io-uring` feature) drives the socket
+//! through an io_uring instead: receives stay posted in the ring, sends are
+//! queued into it, and completions are reaped from shared memory, so a busy
+//! flood or benchmark makes far fewer syscalls per packet. On hosts with
+//! more than two cores a kernel submission thread (SQPOLL) is tried first,
+//! and then sending needs no syscalls at all.
+//!
+//! The uring socket sends and receives one datagram per operation (no
+//! GSO/GRO) and carries no ECN marks or destination address; replies from a
+//! wildcard-bound server leave by the kernel's route.
+
+use anyhow::Result;
+use clap::ValueEnum;
+use quinn::{AsyncUdpSocket, Runtime, TokioRuntime};
+use std::sync::Arc;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IoBackend {
+  /// quinn's tokio socket
+  #[default]
+  Tokio,
+  /// io_uring (Linux, needs the `io-uring` feature)
+  Uring,
+}
+
+impl IoBackend {
+  /// Hand `socket` to quinn through this backend.
+  pub fn wrap(self, socket: std::net::UdpSocket) -> Result<Arc<dyn AsyncUdpSocket>> {
+    match self {
+      IoBackend::Tokio => Ok(TokioRuntime.wrap_udp_socket(socket)?),
+      #[cfg(all(feature = "io-uring", target_os = "linux"))]
+      IoBackend::Uring => Ok(ring::UringSocket::new(socket)?),
+      #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+      IoBackend::Uring => anyhow::bail!("--io-backend uring needs quic_echo built with `--features io-uring` (Linux only)"),
+    }
+  }
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod ring {
+  //! The uring socket on the `io-uring` crate: `RecvMsg` operations stay
+  //! posted on fixed slots and `SendMsg` operations take a free send slot
+  //! each. Completions signal an eventfd that tokio watches.
+
+  use io_uring::{IoUring, opcode, squeue, types};
+  use quinn::{
+    UdpPoller,
+    udp::{RecvMeta, Transmit},
+  };
+  use socket2::{SockAddr, SockAddrStorage};
+  use std::{
+    collections::VecDeque,
+    fmt,
+    fs::File,
+    io::{self, IoSliceMut, Read},
+    mem,
+    net::SocketAddr,
+    os::fd::{AsRawFd, FromRawFd},
+    pin::Pin,
+    ptr,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+  };
+  use tokio::io::unix::AsyncFd;
+  use tracing::{debug, info};
+
+  const ENTRIES: u32 = 256;
+  /// Receives kept posted; each slot holds one datagram of up to [`RECV_BUF`].
+  const RECV_SLOTS: usize = 32;
+  const RECV_BUF: usize = 64 * 1024;
+  /// Sends in flight before `try_send` reports `WouldBlock`.
+  const SEND_SLOTS: usize = 128;
+  /// Marks the user data of send completions.
+  const SEND_TAG: u64 = 1 << 63;
+  /// Marks the user data of cancellations, whose completions are ignored.
+  const CANCEL_TAG: u64 = 1 << 62;
+  /// How long the kernel submission thread spins before it sleeps.
+  const SQ_THREAD_IDLE_MS: u32 = 1000;
+
+  /// One operation's message: header, vector, peer address and data.
+  struct Slot {
+    hdr: libc::msghdr,
+    iov: libc::iovec,
+    addr: SockAddrStorage,
+    buf: Vec<u8>,
+  }
+
+  impl Slot {
+    fn new(capacity: usize) -> Self {
+      Slot {
+        // SAFETY: a plain C struct for which all zeroes is valid
+        hdr: unsafe { mem::zeroed() },
+        iov: libc::iovec { iov_base: ptr::null_mut(), iov_len: 0 },
+        addr: SockAddrStorage::zeroed(),
+        buf: vec![0; capacity],
+      }
+    }
+
+    /// Point the header at this slot's own address and data, which stay put
+    /// while the operation is in flight (the slot vectors are never resized,
+    /// and a send slot's buffer only while it is free).
+    fn prepare(&mut self, name_len: libc::socklen_t, data_len: usize) -> *mut libc::msghdr {
+      self.iov = libc::iovec { iov_base: self.buf.as_mut_ptr().cast(), iov_len: data_len };
+      self.hdr.msg_name = (&raw mut self.addr).cast();
+      self.hdr.msg_namelen = name_len;
+      self.hdr.msg_iov = &raw mut self.iov;
+      self.hdr.msg_iovlen = 1;
+      self.hdr.msg_control = ptr::null_mut();
+      self.hdr.msg_controllen = 0;
+      self.hdr.msg_flags = 0;
+      &raw mut self.hdr
+    }
+  }
+
+  /// The ring and everything in flight.
+  struct Ring {
+    ring: IoUring,
+    socket: std::net::UdpSocket,
+    ipv6: bool,
+    sqpoll: bool,
+    recv: Vec<Slot>,
+    send: Vec<Slot>,
+    free_send: Vec<usize>,
+    /// Receives and sends the kernel may still write or read
+    in_flight: usize,
+    /// Set while `Drop` cancels; failed receives are no longer reposted
+    closing: bool,
+    /// Completed receives: slot and length
+    ready: VecDeque<(usize, usize)>,
+    /// Pollers waiting for a free send slot
+    writers: Vec<Waker>,
+  }
+
+  // SAFETY: the raw pointers in the slots' headers point into the slots
+  // themselves, which the `Ring` owns and only uses behind a mutex
+  unsafe impl Send for Ring {}
+
+  impl Ring {
+    fn new(socket: std::net::UdpSocket, eventfd: &File) -> io::Result<Self> {
+      // the submission thread spins on a core of its own, which only pays
+      // off if there are cores to spare
+      let spare_cores = std::thread::available_parallelism().is_ok_and(|n| n.get() > 2);
+      let ring = match spare_cores.then(|| IoUring::builder().setup_sqpoll(SQ_THREAD_IDLE_MS).build(ENTRIES)) {
+        Some(Ok(ring)) => ring,
+        // an unprivileged SQPOLL needs Linux 5.11
+        Some(Err(e)) => {
+          debug!("io_uring SQPOLL unavailable ({e}), submitting with syscalls");
+          IoUring::new(ENTRIES)?
+        }
+        None => IoUring::new(ENTRIES)?,
+      };
+      ring.submitter().register_eventfd(eventfd.as_raw_fd())?;
+      let mut ring = Ring {
+        sqpoll: ring.params().is_setup_sqpoll(),
+        ring,
+        ipv6: socket.local_addr()?.is_ipv6(),
+        socket,
+        recv: (0..RECV_SLOTS).map(|_| Slot::new(RECV_BUF)).collect(),
+        send: (0..SEND_SLOTS).map(|_| Slot::new(0)).collect(),
+        free_send: (0..SEND_SLOTS).collect(),
+        in_flight: 0,
+        closing: false,
+        ready: VecDeque::new(),
+        writers: Vec::new(),
+      };
+      for slot in 0..RECV_SLOTS {
+        ring.post_recv(slot);
+      }
+      ring.submit()?;
+      Ok(ring)
+    }
+
+    /// Queue one operation.
+    fn push(&mut self, entry: squeue::Entry) {
+      // SAFETY: the entries' headers point into slots of this ring, which
+      // stay put until the operation's completion is reaped (see `Drop`)
+      let pushed = unsafe { self.ring.submission().push(&entry) };
+      // in-flight operations are bounded by the slots, well below ENTRIES
+      pushed.expect("io_uring submission queue full");
+    }
+
+    fn post_recv(&mut self, slot: usize) {
+      let hdr = self.recv[slot].prepare(mem::size_of::<SockAddrStorage>() as libc::socklen_t, RECV_BUF);
+      self.push(opcode::RecvMsg::new(types::Fd(self.socket.as_raw_fd()), hdr).build().user_data(slot as u64));
+      self.in_flight += 1;
+    }
+
+    /// Hand queued operations to the kernel; with SQPOLL this only wakes
+    /// the submission thread if it sleeps.
+    fn submit(&mut self) -> io::Result<()> {
+      if !self.ring.submission().is_empty() {
+        self.ring.submit()?;
+      }
+      Ok(())
+    }
+
+    /// Take every completion: free send slots, queue received datagrams and
+    /// repost failed receives.
+    fn reap(&mut self) {
+      let mut freed = false;
+      let completions: Vec<_> = self.ring.completion().map(|cqe| (cqe.user_data(), cqe.result())).collect();
+      for (user_data, res) in completions {
+        if user_data & CANCEL_TAG != 0 {
+          continue;
+        }
+        self.in_flight -= 1;
+        if user_data & SEND_TAG != 0 {
+          if res < 0 {
+            debug!("io_uring send failed: {}", io::Error::from_raw_os_error(-res));
+          }
+          self.free_send.push((user_data & !SEND_TAG) as usize);
+          freed = true;
+        } else if res >= 0 && self.recv[user_data as usize].hdr.msg_flags & libc::MSG_TRUNC == 0 {
+          self.ready.push_back((user_data as usize, res as usize));
+        } else if !self.closing {
+          if res < 0 {
+            debug!("io_uring receive failed: {}", io::Error::from_raw_os_error(-res));
+          }
+          self.post_recv(user_data as usize);
+        }
+      }
+      if freed {
+        self.writers.drain(..).for_each(Waker::wake);
+      }
+    }
+  }
+
+  impl Drop for Ring {
+    fn drop(&mut self) {
+      // the kernel fills posted slots until their operations complete, so
+      // cancel what is still in flight and wait for it before the slots go
+      self.closing = true;
+      self.reap();
+      let posted = (0..RECV_SLOTS).filter(|slot| !self.ready.iter().any(|(ready, _)| ready == slot));
+      let sending = (0..SEND_SLOTS).filter(|slot| !self.free_send.contains(slot));
+      let cancels: Vec<_> = posted.map(|slot| slot as u64).chain(sending.map(|slot| SEND_TAG | slot as u64)).collect();
+      for user_data in cancels {
+        self.push(opcode::AsyncCancel::new(user_data).build().user_data(CANCEL_TAG));
+      }
+      while self.in_flight > 0 {
+        match self.ring.submit_and_wait(1) {
+          Ok(_) => self.reap(),
+          Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+          Err(e) => {
+            // the slots may still be in use; leak them rather than free them
+            // under the kernel
+            debug!("io_uring cancel failed: {e}");
+            mem::forget(mem::take(&mut self.recv));
+            mem::forget(mem::take(&mut self.send));
+            return;
+          }
+        }
+      }
+    }
+  }
+
+  /// Set the don't-fragment bit the way quinn-udp does, so quinn can run
+  /// path MTU discovery; false if the OS refused.
+  fn set_dont_fragment(socket: &std::net::UdpSocket, ipv6: bool) -> bool {
+    let set = |level, name, value: libc::c_int| {
+      // SAFETY: an int option on a socket we own
+      unsafe {
+        libc::setsockopt(
+          socket.as_raw_fd(),
+          level,
+          name,
+          (&raw const value).cast(),
+          mem::size_of::<libc::c_int>() as libc::socklen_t,
+        ) == 0
+      }
+    };
+    let v4 = set(libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_PROBE);
+    if ipv6 {
+      set(libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, libc::IPV6_PMTUDISC_PROBE)
+    } else {
+      v4
+    }
+  }
+
+  /// A UDP socket whose I/O goes through an io_uring.
+  pub struct UringSocket {
+    ring: Mutex<Ring>,
+    eventfd: AsyncFd<File>,
+    local: SocketAddr,
+    may_fragment: bool,
+  }
+
+  impl fmt::Debug for UringSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      f.debug_struct("UringSocket").field("local", &self.local).finish_non_exhaustive()
+    }
+  }
+
+  impl UringSocket {
+    pub fn new(socket: std::net::UdpSocket) -> io::Result<Arc<Self>> {
+      // the ring waits for the socket, so it can stay in blocking mode
+      socket.set_nonblocking(false)?;
+      let local = socket.local_addr()?;
+      let may_fragment = !set_dont_fragment(&socket, local.is_ipv6());
+      // SAFETY: plain syscall; the result is checked before use
+      let eventfd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+      if eventfd < 0 {
+        return Err(io::Error::last_os_error());
+      }
+      // SAFETY: a fresh, valid fd that nothing else owns
+      let eventfd = unsafe { File::from_raw_fd(eventfd) };
+      let ring = Ring::new(socket, &eventfd)?;
+      info!(%local, sqpoll = ring.sqpoll, "io_uring UDP backend");
+      Ok(Arc::new(Self { ring: Mutex::new(ring), eventfd: AsyncFd::new(eventfd)?, local, may_fragment }))
+    }
+  }
+
+  impl quinn::AsyncUdpSocket for UringSocket {
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+      Box::pin(UringPoller { socket: self })
+    }
+
+    fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+      let mut ring = self.ring.lock().unwrap();
+      ring.reap();
+      let Some(slot) = ring.free_send.pop() else {
+        return Err(io::ErrorKind::WouldBlock.into());
+      };
+      let destination = match transmit.destination {
+        SocketAddr::V4(v4) if ring.ipv6 => SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()),
+        destination => destination,
+      };
+      let addr = SockAddr::from(destination);
+      let hdr = {
+        let send = &mut ring.send[slot];
+        send.buf.clear();
+        send.buf.extend_from_slice(transmit.contents);
+        let len = addr.len();
+        send.addr = addr.as_storage();
+        send.prepare(len, transmit.contents.len())
+      };
+      let fd = types::Fd(ring.socket.as_raw_fd());
+      ring.push(opcode::SendMsg::new(fd, hdr).build().user_data(SEND_TAG | slot as u64));
+      ring.in_flight += 1;
+      ring.submit()
+    }
+
+    fn poll_recv(&self, cx: &mut Context, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) -> Poll<io::Result<usize>> {
+      loop {
+        {
+          let mut ring = self.ring.lock().unwrap();
+          ring.reap();
+          let mut n = 0;
+          while n < bufs.len().min(meta.len()) {
+            let Some((slot, len)) = ring.ready.pop_front() else { break };
+            let recv = &mut ring.recv[slot];
+            let copied = len.min(bufs[n].len());
+            bufs[n][..copied].copy_from_slice(&recv.buf[..copied]);
+            let storage = mem::replace(&mut recv.addr, SockAddrStorage::zeroed());
+            // SAFETY: the kernel filled in `msg_namelen` bytes of the storage
+            let addr = unsafe { SockAddr::new(storage, recv.hdr.msg_namelen) }.as_socket();
+            ring.post_recv(slot);
+            let Some(addr) = addr else { continue };
+            meta[n] = RecvMeta { addr, len: copied, stride: copied, ecn: None, dst_ip: None };
+            n += 1;
+          }
+          ring.submit()?;
+          if n > 0 {
+            return Poll::Ready(Ok(n));
+          }
+        }
+        // completions bump the eventfd; wait for that, reset its counter,
+        // then look again
+        let mut guard = std::task::ready!(self.eventfd.poll_read_ready(cx))?;
+        let _ = guard.get_inner().read(&mut [0; 8]);
+        guard.clear_ready();
+      }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+      Ok(self.local)
+    }
+
+    fn may_fragment(&self) -> bool {
+      self.may_fragment
+    }
+  }
+
+  /// Writable while a send slot is free; waiters are woken when a send
+  /// completes, which the receive path notices through the eventfd.
+  #[derive(Debug)]
+  struct UringPoller {
+    socket: Arc<UringSocket>,
+  }
+
+  impl UdpPoller for UringPoller {
+    fn poll_writable(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+      let mut ring = self.socket.ring.lock().unwrap();
+      ring.reap();
+      if ring.free_send.is_empty() {
+        ring.writers.push(cx.waker().clone());
+        Poll::Pending
+      } else {
+        Poll::Ready(Ok(()))
+      }
+    }
+  }
+}
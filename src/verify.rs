@@ -0,0 +1,83 @@
+//! End-to-end payload checks for the client: echoed bytes are compared with
+//! what was sent byte for byte, and a mismatch fails the run with the offset
+//! of the first differing byte and a hex excerpt of both sides from there.
+//!
+//! [`EchoCheck`] does the same for a stream whose response arrives while it
+//! is still being sent (`--pipe`): sent bytes are kept only until their echo
+//! has been compared, so memory stays bounded by what is in flight.
+
+use crate::transfer::hex;
+use anyhow::Result;
+use std::collections::VecDeque;
+
+/// Bytes of each side shown after the first difference.
+const EXCERPT: usize = 16;
+
+/// Fail unless `got` equals `expected`.
+pub fn compare(what: &str, expected: &[u8], got: &[u8]) -> Result<()> {
+  match first_diff(expected, got) {
+    None => Ok(()),
+    Some(offset) => Err(mismatch(what, offset as u64, &expected[offset.min(expected.len())..], &got[offset.min(got.len())..])),
+  }
+}
+
+/// Offset of the first byte where `a` and `b` differ, counting a missing
+/// tail on either side as a difference.
+pub fn first_diff(a: &[u8], b: &[u8]) -> Option<usize> {
+  a.iter()
+    .zip(b)
+    .position(|(x, y)| x != y)
+    .or_else(|| (a.len() != b.len()).then(|| a.len().min(b.len())))
+}
+
+fn mismatch(what: &str, offset: u64, expected: &[u8], got: &[u8]) -> anyhow::Error {
+  let excerpt = |data: &[u8]| match data {
+    [] => "(end of data)".to_string(),
+    data => hex(&data[..data.len().min(EXCERPT)]),
+  };
+  anyhow::anyhow!(
+    "{what} mismatch at byte {offset}: expected {}, got {}",
+    excerpt(expected),
+    excerpt(got)
+  )
+}
+
+/// Streaming comparison of an echo against what was sent.
+#[derive(Debug, Default)]
+pub struct EchoCheck {
+  /// Sent and not compared yet
+  pending: VecDeque<u8>,
+  /// Bytes compared so far
+  matched: u64,
+}
+
+impl EchoCheck {
+  /// Note bytes sent.
+  pub fn sent(&mut self, data: &[u8]) {
+    self.pending.extend(data);
+  }
+
+  /// Compare bytes received against the oldest unmatched sent bytes.
+  pub fn received(&mut self, data: &[u8]) -> Result<()> {
+    let (front, back) = self.pending.as_slices();
+    let expected: Vec<u8> = front.iter().chain(back).take(data.len()).copied().collect();
+    if let Some(offset) = first_diff(&expected, data) {
+      let at = self.matched + offset as u64;
+      return Err(mismatch("echo", at, &expected[offset.min(expected.len())..], &data[offset..]));
+    }
+    self.pending.drain(..data.len());
+    self.matched += data.len() as u64;
+    Ok(())
+  }
+
+  /// Fail if part of what was sent never came back.
+  pub fn finish(&self) -> Result<()> {
+    anyhow::ensure!(
+      self.pending.is_empty(),
+      "echo ended at byte {} with {} sent bytes not echoed",
+      self.matched,
+      self.pending.len()
+    );
+    Ok(())
+  }
+}
@@ -0,0 +1,104 @@
+//! Version negotiation probe for the client (`version-negotiation`): a
+//! padded Initial with a version the server cannot support is sent over a
+//! plain UDP socket, which a conforming server answers with a Version
+//! Negotiation packet listing its versions (RFC 9000 section 6). Middleboxes
+//! that drop or mangle unknown versions show up as a timeout or a bad reply.
+
+use anyhow::{Context, Result};
+use rand::Rng;
+use std::{
+  net::SocketAddr,
+  time::{Duration, Instant},
+};
+use tokio::net::UdpSocket;
+
+/// Smallest datagram a server answers to (RFC 9000 section 14.1).
+const MIN_INITIAL: usize = 1200;
+const CID_LEN: usize = 8;
+
+/// A random version from the reserved `0x?a?a?a?a` space, which no
+/// implementation supports.
+pub fn greased() -> u32 {
+  rand::rng().random::<u32>() & 0xf0f0_f0f0 | 0x0a0a_0a0a
+}
+
+/// Human-readable name of a QUIC version.
+pub fn name(version: u32) -> String {
+  match version {
+    0x0000_0001 => "QUIC v1".into(),
+    0x6b33_43cf => "QUIC v2".into(),
+    0xff00_0000..=0xff00_00ff => format!("draft-{}", version & 0xff),
+    v if v & 0x0f0f_0f0f == 0x0a0a_0a0a => "reserved (greased)".into(),
+    _ => "unknown".into(),
+  }
+}
+
+/// The server's answer to one probe.
+#[derive(Debug)]
+pub struct Negotiation {
+  pub versions: Vec<u32>,
+  pub rtt: Duration,
+  pub attempts: u32,
+}
+
+/// Offer `version` to `server` from `socket`, up to `attempts` times with
+/// `timeout` each, and return the versions the server advertised.
+pub async fn probe(
+  socket: &UdpSocket,
+  server: SocketAddr,
+  version: u32,
+  attempts: u32,
+  timeout: Duration,
+) -> Result<Negotiation> {
+  let mut rng = rand::rng();
+  let dcid: [u8; CID_LEN] = rng.random();
+  let scid: [u8; CID_LEN] = rng.random();
+  let mut packet = Vec::with_capacity(MIN_INITIAL);
+  packet.push(0xc0 | rng.random::<u8>() & 0x0f); // long header, Initial
+  packet.extend_from_slice(&version.to_be_bytes());
+  packet.push(CID_LEN as u8);
+  packet.extend_from_slice(&dcid);
+  packet.push(CID_LEN as u8);
+  packet.extend_from_slice(&scid);
+  // the rest is opaque to a server that does not know the version
+  packet.resize(MIN_INITIAL, 0);
+  rng.fill(&mut packet[1 + 4 + 2 + 2 * CID_LEN..]);
+
+  let mut buf = vec![0u8; 65_536];
+  for attempt in 1..=attempts {
+    let sent = Instant::now();
+    socket.send_to(&packet, server).await.with_context(|| format!("send to {server}"))?;
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+      let Ok(res) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await else {
+        break;
+      };
+      let (n, from) = res?;
+      if from != server {
+        continue;
+      }
+      let versions = parse(&buf[..n], &dcid, &scid).with_context(|| format!("bad reply from {server}"))?;
+      return Ok(Negotiation { versions, rtt: sent.elapsed(), attempts: attempt });
+    }
+  }
+  anyhow::bail!("no Version Negotiation from {server} after {attempts} attempts")
+}
+
+/// The versions in a Version Negotiation packet answering an Initial sent
+/// with `dcid`/`scid`, whose connection IDs it must echo swapped.
+fn parse(packet: &[u8], dcid: &[u8], scid: &[u8]) -> Result<Vec<u32>> {
+  let (&first, rest) = packet.split_first().context("empty datagram")?;
+  anyhow::ensure!(first & 0x80 != 0, "short header packet");
+  let (version, rest) = rest.split_at_checked(4).context("truncated")?;
+  anyhow::ensure!(version == [0; 4], "version {:#010x} instead of Version Negotiation", u32::from_be_bytes(version.try_into()?));
+  let (echoed_dcid, rest) = cid(rest)?;
+  let (echoed_scid, rest) = cid(rest)?;
+  anyhow::ensure!(echoed_dcid == scid && echoed_scid == dcid, "connection IDs not echoed");
+  anyhow::ensure!(!rest.is_empty() && rest.len().is_multiple_of(4), "version list of {} bytes", rest.len());
+  Ok(rest.chunks_exact(4).map(|v| u32::from_be_bytes(v.try_into().unwrap())).collect())
+}
+
+fn cid(data: &[u8]) -> Result<(&[u8], &[u8])> {
+  let (&len, rest) = data.split_first().context("truncated")?;
+  rest.split_at_checked(usize::from(len)).context("truncated connection ID")
+}
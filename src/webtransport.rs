@@ -0,0 +1,225 @@
+//! WebTransport echo over HTTP/3.
+//!
+//! Server side: `h3-webtransport` sessions, echoing every bidirectional
+//! stream and datagram of the session. Client side: a minimal hand-rolled
+//! session (the `h3` client cannot announce `SETTINGS_ENABLE_WEBTRANSPORT`),
+//! just enough to open one session and echo through it.
+
+use crate::flow;
+use crate::http3::{self, get_varint, put_varint, ServerConn, ServerStream};
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use h3_webtransport::server::{AcceptedBi, WebTransportSession};
+use http::Request;
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn, Instrument};
+
+pub const PATH: &str = "/webtransport";
+
+// HTTP/3 and WebTransport code points
+const STREAM_CONTROL: u64 = 0x00;
+const FRAME_HEADERS: u64 = 0x01;
+const FRAME_SETTINGS: u64 = 0x04;
+const FRAME_WEBTRANSPORT_STREAM: u64 = 0x41;
+const SETTING_ENABLE_CONNECT_PROTOCOL: u64 = 0x08;
+const SETTING_H3_DATAGRAM: u64 = 0x33;
+const SETTING_ENABLE_WEBTRANSPORT: u64 = 0x2b60_3742;
+
+type Session = WebTransportSession<h3_quinn::Connection, Bytes>;
+
+/// Accept the CONNECT and echo the session's streams and datagrams until it ends.
+pub(crate) async fn serve_session(req: Request<()>, stream: ServerStream, conn: ServerConn) -> Result<()> {
+  let session: Session = WebTransportSession::accept(req, stream, conn)
+    .await
+    .context("WebTransport CONNECT")?;
+  info!(session = ?session.session_id(), "WebTransport session established");
+
+  let mut reader = session.datagram_reader();
+  let mut sender = session.datagram_sender();
+  let datagrams = tokio::spawn(
+    async move {
+      while let Ok(dgram) = reader.read_datagram().await {
+        if let Err(e) = sender.send_datagram(dgram.into_payload()) {
+          warn!("WebTransport datagram send failed: {e}");
+        }
+      }
+    }
+    .in_current_span(),
+  );
+
+  loop {
+    match session.accept_bi().await {
+      Ok(Some(AcceptedBi::BidiStream(_, stream))) => {
+        tokio::spawn(
+          async move {
+            let (mut recv, mut send) = tokio::io::split(stream);
+            if tokio::io::copy(&mut recv, &mut send).await.is_ok() {
+              let _ = send.shutdown().await;
+            }
+          }
+          .in_current_span(),
+        );
+      }
+      Ok(Some(AcceptedBi::Request(req, stream))) => {
+        tokio::spawn(
+          async move {
+            if let Err(e) = http3::respond(req, stream).await {
+              warn!("HTTP/3 request failed: {e:#}");
+            }
+          }
+          .in_current_span(),
+        );
+      }
+      Ok(None) => break,
+      Err(e) if e.is_h3_no_error() => break,
+      Err(e) => {
+        datagrams.abort();
+        return Err(e.into());
+      }
+    }
+  }
+  datagrams.abort();
+  Ok(())
+}
+
+/// Open a WebTransport session to `https://{host}/webtransport`, echo
+/// `payload` through a session stream (or a session datagram) and return
+/// what came back. Closes the connection when done.
+pub async fn echo(conn: &quinn::Connection, host: &str, payload: Bytes, datagram: bool) -> Result<Bytes> {
+  // the control stream must stay open for the whole connection
+  let mut control = flow::open_uni(conn).await?;
+  let mut settings = Vec::new();
+  for (id, value) in [
+    (SETTING_ENABLE_CONNECT_PROTOCOL, 1),
+    (SETTING_H3_DATAGRAM, 1),
+    (SETTING_ENABLE_WEBTRANSPORT, 1),
+  ] {
+    put_varint(&mut settings, id);
+    put_varint(&mut settings, value);
+  }
+  let mut buf = Vec::new();
+  put_varint(&mut buf, STREAM_CONTROL);
+  put_frame(&mut buf, FRAME_SETTINGS, &settings);
+  control.write_all(&buf).await?;
+
+  let (mut connect_send, mut connect_recv) = flow::open_bi(conn).await?;
+  let session_id = u64::from(connect_send.id());
+  let mut buf = Vec::new();
+  put_frame(&mut buf, FRAME_HEADERS, &connect_headers(host));
+  connect_send.write_all(&buf).await?;
+
+  let status = loop {
+    let ty = read_varint(&mut connect_recv).await.context("read CONNECT response")?;
+    let len = read_varint(&mut connect_recv).await?;
+    let mut frame = vec![0; usize::try_from(len)?];
+    connect_recv.read_exact(&mut frame).await?;
+    if ty == FRAME_HEADERS {
+      break response_status(&frame)?;
+    }
+  };
+  anyhow::ensure!(status == 200, "WebTransport CONNECT: HTTP {status}");
+  info!(session_id, "WebTransport session established");
+
+  let echoed = if datagram {
+    let quarter_id = session_id / 4;
+    let mut dgram = Vec::new();
+    put_varint(&mut dgram, quarter_id);
+    dgram.extend_from_slice(&payload);
+    conn.send_datagram(dgram.into())?;
+    loop {
+      let dgram = conn.read_datagram().await?;
+      if let Some((id, n)) = get_varint(&dgram)
+        && id == quarter_id
+      {
+        break dgram.slice(n..);
+      }
+    }
+  } else {
+    let (mut send, mut recv) = flow::open_bi(conn).await?;
+    let mut buf = Vec::new();
+    put_varint(&mut buf, FRAME_WEBTRANSPORT_STREAM);
+    put_varint(&mut buf, session_id);
+    buf.extend_from_slice(&payload);
+    send.write_all(&buf).await?;
+    send.finish()?;
+    Bytes::from(recv.read_to_end(payload.len() + 1).await?)
+  };
+
+  conn.close(http3::NO_ERROR.into(), b"");
+  Ok(echoed)
+}
+
+/// QPACK field section for the extended CONNECT, static table only.
+fn connect_headers(host: &str) -> Vec<u8> {
+  // required insert count 0, base 0
+  let mut out = vec![0x00, 0x00];
+  out.push(0xc0 | 15); // :method CONNECT
+  out.push(0xc0 | 23); // :scheme https
+  for (name_idx, value) in [(0u8, host), (1, PATH)] {
+    // literal with static name reference (:authority, :path)
+    out.push(0x50 | name_idx);
+    put_prefixed(&mut out, value.len() as u64, 7, 0x00);
+    out.extend_from_slice(value.as_bytes());
+  }
+  // literal with literal name
+  put_prefixed(&mut out, ":protocol".len() as u64, 3, 0x20);
+  out.extend_from_slice(b":protocol");
+  put_prefixed(&mut out, "webtransport".len() as u64, 7, 0x00);
+  out.extend_from_slice(b"webtransport");
+  out
+}
+
+/// `:status` of a response field section, which servers put first.
+fn response_status(block: &[u8]) -> Result<u16> {
+  let first = *block.get(2).context("empty response headers")?;
+  let status = if first & 0xc0 == 0xc0 {
+    // indexed field line from the static table
+    match first & 0x3f {
+      24 => 103,
+      25 => 200,
+      26 => 304,
+      27 => 404,
+      28 => 503,
+      _ => anyhow::bail!("unexpected first response header (static index {})", first & 0x3f),
+    }
+  } else if first & 0xf0 == 0x50 && (first & 0x0f) == 0x0f && block.get(3) == Some(&9) {
+    // literal with static name reference to :status (index 24), plain string
+    let len = *block.get(4).context("truncated :status")? as usize;
+    anyhow::ensure!(len & 0x80 == 0, "Huffman-coded :status is not supported");
+    let value = block.get(5..5 + len).context("truncated :status")?;
+    std::str::from_utf8(value)?.parse()?
+  } else {
+    anyhow::bail!("unsupported encoding of :status");
+  };
+  Ok(status)
+}
+
+fn put_frame(buf: &mut Vec<u8>, ty: u64, payload: &[u8]) {
+  put_varint(buf, ty);
+  put_varint(buf, payload.len() as u64);
+  buf.extend_from_slice(payload);
+}
+
+async fn read_varint(recv: &mut quinn::RecvStream) -> Result<u64> {
+  let mut buf = [0u8; 8];
+  recv.read_exact(&mut buf[..1]).await?;
+  let len = 1 << (buf[0] >> 6);
+  recv.read_exact(&mut buf[1..len]).await?;
+  Ok(get_varint(&buf[..len]).unwrap().0)
+}
+
+/// HPACK/QPACK prefixed integer (RFC 7541, section 5.1).
+fn put_prefixed(buf: &mut Vec<u8>, v: u64, prefix_bits: u8, flags: u8) {
+  let max = (1u64 << prefix_bits) - 1;
+  if v < max {
+    buf.push(flags | v as u8);
+    return;
+  }
+  buf.push(flags | max as u8);
+  let mut rest = v - max;
+  while rest >= 0x80 {
+    buf.push((rest & 0x7f) as u8 | 0x80);
+    rest >>= 7;
+  }
+  buf.push(rest as u8);
+}
@@ -0,0 +1,98 @@
+//! The simulated network repeats a lossy echo run exactly under paused time.
+
+use anyhow::Result;
+use quic_echo::sim::{Network, SimConfig, SimStats};
+use quinn::{
+  ClientConfig, ServerConfig,
+  crypto::rustls::{QuicClientConfig, QuicServerConfig},
+};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use std::{
+  net::{Ipv4Addr, SocketAddr},
+  sync::Arc,
+  time::Duration,
+};
+
+const ALPN: &[u8] = b"freven-quic-test";
+const SERVER: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 4433);
+const CLIENT: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 0);
+
+/// An Ed25519 certificate, so signatures and with them the handshake
+/// packets keep their size from run to run.
+fn certificate() -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+  let key = rcgen::KeyPair::generate_for(&rcgen::PKCS_ED25519).unwrap();
+  let cert = rcgen::CertificateParams::new(vec!["localhost".into()]).unwrap().self_signed(&key).unwrap();
+  (cert.der().clone(), PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())))
+}
+
+/// Echo `rounds` streams over a lossy, reordering network seeded with
+/// `seed` and return what the network saw.
+async fn echo_run(
+  seed: u64,
+  cert: CertificateDer<'static>,
+  key: PrivateKeyDer<'static>,
+  rounds: usize,
+) -> Result<SimStats> {
+  let network = Network::new(SimConfig {
+    latency: Duration::from_millis(20),
+    jitter: Duration::from_millis(5),
+    loss: 0.05,
+    reorder: 0.05,
+    seed,
+    ..SimConfig::default()
+  });
+
+  let mut tls = rustls::ServerConfig::builder().with_no_client_auth().with_single_cert(vec![cert.clone()], key)?;
+  tls.alpn_protocols = vec![ALPN.to_vec()];
+  let server = network.endpoint(SERVER, Some(ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?))))?;
+  let serving = server.clone();
+  tokio::spawn(async move {
+    while let Some(incoming) = serving.accept().await {
+      tokio::spawn(async move {
+        let Ok(conn) = incoming.await else {
+          return;
+        };
+        while let Ok((mut send, mut recv)) = conn.accept_bi().await {
+          tokio::spawn(async move {
+            while let Ok(Some(chunk)) = recv.read_chunk(usize::MAX, true).await {
+              if send.write_chunk(chunk.bytes).await.is_err() {
+                return;
+              }
+            }
+            let _ = send.finish();
+          });
+        }
+      });
+    }
+  });
+
+  let mut roots = rustls::RootCertStore::empty();
+  roots.add(cert)?;
+  let mut tls = rustls::ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+  tls.alpn_protocols = vec![ALPN.to_vec()];
+  let client = network.endpoint(CLIENT, None)?;
+  let config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?));
+  let conn = client.connect_with(config, SERVER, "localhost")?.await?;
+  for round in 0..rounds {
+    let payload = vec![round as u8; 16 * 1024];
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send.write_all(&payload).await?;
+    send.finish()?;
+    assert_eq!(recv.read_to_end(usize::MAX).await?, payload, "echo of round {round}");
+  }
+  conn.close(0u32.into(), b"done");
+  client.wait_idle().await;
+  server.close(0u32.into(), b"done");
+  server.wait_idle().await;
+  Ok(network.stats())
+}
+
+#[tokio::test(start_paused = true)]
+async fn same_seed_same_run() -> Result<()> {
+  let (cert, key) = certificate();
+  let first = echo_run(7, cert.clone(), key.clone_key(), 20).await?;
+  let second = echo_run(7, cert, key, 20).await?;
+  assert_eq!(first, second);
+  assert!(first.lost > 0 && first.reordered > 0, "the run should see loss and reordering: {first:?}");
+  Ok(())
+}
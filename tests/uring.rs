@@ -0,0 +1,60 @@
+//! An echo over loopback with both ends on `--io-backend uring`.
+#![cfg(all(feature = "io-uring", target_os = "linux"))]
+
+use std::{
+  net::UdpSocket,
+  process::{Child, Command, Stdio},
+  time::{Duration, Instant},
+};
+
+/// Kills the server when the test ends, however it ends.
+struct Server(Child);
+
+impl Drop for Server {
+  fn drop(&mut self) {
+    let _ = self.0.kill();
+    let _ = self.0.wait();
+  }
+}
+
+#[test]
+fn echo_over_uring() {
+  let dir = std::env::temp_dir().join(format!("quic_echo_uring_{}", std::process::id()));
+  std::fs::create_dir_all(&dir).unwrap();
+  let key = rcgen::KeyPair::generate().unwrap();
+  let cert = rcgen::CertificateParams::new(vec!["localhost".into()]).unwrap().self_signed(&key).unwrap();
+  std::fs::write(dir.join("cert.pem"), cert.pem()).unwrap();
+  std::fs::write(dir.join("key.pem"), key.serialize_pem()).unwrap();
+  let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port().to_string();
+
+  let _server = Server(
+    Command::new(env!("CARGO_BIN_EXE_quic_echo_server"))
+      .args(["--host", "127.0.0.1", "--port", &port, "--io-backend", "uring"])
+      .arg("--cert")
+      .arg(dir.join("cert.pem"))
+      .arg("--key")
+      .arg(dir.join("key.pem"))
+      .stdout(Stdio::null())
+      .stderr(Stdio::null())
+      .spawn()
+      .unwrap(),
+  );
+
+  // the server may still be starting; retry until it answers
+  let deadline = Instant::now() + Duration::from_secs(20);
+  let output = loop {
+    let output = Command::new(env!("CARGO_BIN_EXE_quic_echo_client"))
+      .args(["--host", "127.0.0.1", "--port", &port, "--io-backend", "uring", "--connect-timeout", "2"])
+      .output()
+      .unwrap();
+    if output.status.success() || Instant::now() > deadline {
+      break output;
+    }
+    std::thread::sleep(Duration::from_millis(200));
+  };
+  let _ = std::fs::remove_dir_all(&dir);
+  let log = String::from_utf8_lossy(&output.stderr);
+  assert!(output.status.success(), "client failed: {log}");
+  assert!(log.contains("io_uring UDP backend"), "the client did not use the uring socket: {log}");
+  assert_eq!(String::from_utf8_lossy(&output.stdout), "recv: [112, 105, 110, 103]\n");
+}
@@ -0,0 +1,221 @@
+//! Browser client for the server's WebTransport endpoint (`--h3`): the same
+//! ping tests as the native `--webtransport` client, but through the
+//! browser's own `WebTransport` API, so what is checked is whether an end
+//! user's browser can reach the server - its QUIC stack, its certificate
+//! checks and whatever sits between it and the server.
+//!
+//! [`run`] opens a session, sends `count` pings on fresh bidirectional
+//! streams and `count` as datagrams, and reports every result to the
+//! console and, if the page has one, to the `#log` element. It resolves to a
+//! summary object, or rejects if the session cannot be opened.
+//!
+//! Built with `wasm-pack build --target web` from this directory (see
+//! `index.html`); `.cargo/config.toml` sets the wasm target and enables
+//! web-sys's unstable WebTransport bindings.
+
+use js_sys::{Object, Promise, Reflect, Uint8Array};
+use std::{
+  future::{Future, IntoFuture, poll_fn},
+  pin::{Pin, pin},
+  task::Poll,
+};
+use wasm_bindgen::{JsCast, prelude::*};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+  ReadableStream, ReadableStreamDefaultReader, WebTransport, WebTransportBidirectionalStream, WebTransportCloseInfo,
+  WebTransportHash, WebTransportOptions, WritableStream,
+};
+
+/// How long one ping may take before it counts as lost, in ms.
+const PING_TIMEOUT_MS: i32 = 3000;
+/// How long the session may take to open, in ms.
+const CONNECT_TIMEOUT_MS: i32 = 10_000;
+
+/// Open a WebTransport session to `url` (e.g.
+/// `https://echo.example.com:12806/webtransport`) and run `count` stream and
+/// `count` datagram pings. `cert_hash` is the hex SHA-256 of a self-signed
+/// server certificate's DER, passed as `serverCertificateHashes`; leave it
+/// empty for a certificate the browser trusts.
+#[wasm_bindgen]
+pub async fn run(url: String, count: u32, cert_hash: Option<String>) -> Result<JsValue, JsValue> {
+  let options = WebTransportOptions::new();
+  if let Some(hash) = cert_hash.as_deref().map(str::trim).filter(|hash| !hash.is_empty()) {
+    let value = Uint8Array::from(parse_hex(hash)?.as_slice());
+    let entry = WebTransportHash::new();
+    entry.set_algorithm("sha-256");
+    entry.set_value(&value);
+    options.set_server_certificate_hashes(&[entry]);
+  }
+
+  report(&format!("connecting to {url}"));
+  let started = now();
+  let transport = WebTransport::new_with_options(&url, &options)?;
+  timeout(transport.ready(), CONNECT_TIMEOUT_MS, "session not ready").await?;
+  let handshake = now() - started;
+  report(&format!("session ready after {handshake:.1} ms"));
+
+  let (mut stream_ok, mut stream_rtts) = (0, Vec::new());
+  for n in 0..count {
+    let payload = format!("ping {n}").into_bytes();
+    let started = now();
+    match timeout(stream_ping(&transport, &payload), PING_TIMEOUT_MS, "no stream echo").await {
+      Ok(()) => {
+        let rtt = now() - started;
+        report(&format!("stream   #{n}: {} B echoed in {rtt:.1} ms", payload.len()));
+        stream_ok += 1;
+        stream_rtts.push(rtt);
+      }
+      Err(e) => report(&format!("stream   #{n}: FAIL {}", describe(&e))),
+    }
+  }
+
+  let datagrams = transport.datagrams();
+  let writer = datagrams.writable().get_writer()?;
+  let reader: ReadableStreamDefaultReader = datagrams.readable().get_reader().unchecked_into();
+  let (mut dgram_ok, mut dgram_rtts) = (0, Vec::new());
+  // a read cut off by the timeout stays queued on the reader and takes the
+  // next datagram, so it is kept for the next ping rather than dropped
+  let mut pending: Option<JsFuture> = None;
+  for n in 0..count {
+    let payload = format!("dgram {n}").into_bytes();
+    let started = now();
+    let ping = async {
+      JsFuture::from(writer.write_with_chunk(&Uint8Array::from(payload.as_slice()))).await?;
+      // an echo of an earlier datagram that timed out may still arrive
+      loop {
+        let read = pending.get_or_insert_with(|| JsFuture::from(reader.read()));
+        let result = read.await;
+        pending = None;
+        let echo = chunk(result?)?.ok_or("datagrams closed")?;
+        if echo == payload {
+          return Ok(JsValue::UNDEFINED);
+        }
+      }
+    };
+    match timeout(ping, PING_TIMEOUT_MS, "no datagram echo").await {
+      Ok(_) => {
+        let rtt = now() - started;
+        report(&format!("datagram #{n}: {} B echoed in {rtt:.1} ms", payload.len()));
+        dgram_ok += 1;
+        dgram_rtts.push(rtt);
+      }
+      Err(e) => report(&format!("datagram #{n}: FAIL {}", describe(&e))),
+    }
+  }
+  writer.release_lock();
+  reader.release_lock();
+
+  let close = WebTransportCloseInfo::new();
+  close.set_close_code(0);
+  close.set_reason("browser test done");
+  transport.close_with_close_info(&close);
+
+  report(&format!(
+    "streams {stream_ok}/{count} ok (avg {}), datagrams {dgram_ok}/{count} ok (avg {})",
+    average(&stream_rtts),
+    average(&dgram_rtts)
+  ));
+  let summary = Object::new();
+  for (key, value) in [
+    ("handshake_ms", handshake),
+    ("streams_ok", f64::from(stream_ok)),
+    ("datagrams_ok", f64::from(dgram_ok)),
+    ("count", f64::from(count)),
+  ] {
+    Reflect::set(&summary, &key.into(), &value.into())?;
+  }
+  Ok(summary.into())
+}
+
+/// One ping on a new bidirectional stream: write and finish, then read the
+/// echo to the end and compare.
+async fn stream_ping(transport: &WebTransport, payload: &[u8]) -> Result<JsValue, JsValue> {
+  let stream: WebTransportBidirectionalStream =
+    JsFuture::from(transport.create_bidirectional_stream()).await?.unchecked_into();
+  let writable: WritableStream = stream.writable().unchecked_into();
+  let writer = writable.get_writer()?;
+  JsFuture::from(writer.write_with_chunk(&Uint8Array::from(payload))).await?;
+  JsFuture::from(writer.close()).await?;
+
+  let readable: ReadableStream = stream.readable().unchecked_into();
+  let reader: ReadableStreamDefaultReader = readable.get_reader().unchecked_into();
+  let mut echo = Vec::new();
+  while let Some(chunk) = read_chunk(&reader).await? {
+    echo.extend_from_slice(&chunk);
+  }
+  if echo != payload {
+    let at = echo.iter().zip(payload).position(|(a, b)| a != b).unwrap_or(echo.len().min(payload.len()));
+    return Err(format!("echo differs at byte {at} ({} B sent, {} B back)", payload.len(), echo.len()).into());
+  }
+  Ok(JsValue::UNDEFINED)
+}
+
+/// The next chunk, or `None` once the stream is done.
+async fn read_chunk(reader: &ReadableStreamDefaultReader) -> Result<Option<Vec<u8>>, JsValue> {
+  chunk(JsFuture::from(reader.read()).await?)
+}
+
+/// The chunk of a `read()` result, or `None` if it says the stream is done.
+fn chunk(result: JsValue) -> Result<Option<Vec<u8>>, JsValue> {
+  if Reflect::get(&result, &"done".into())?.as_bool().unwrap_or(false) {
+    return Ok(None);
+  }
+  Ok(Some(Uint8Array::new(&Reflect::get(&result, &"value".into())?).to_vec()))
+}
+
+/// `future`, or an error saying `what` after `ms`.
+async fn timeout<T>(future: impl IntoFuture<Output = Result<T, JsValue>>, ms: i32, what: &str) -> Result<(), JsValue> {
+  let expired = Promise::new(&mut |resolve, _| {
+    let window = web_sys::window().expect("runs in a browser window");
+    let _ = window.set_timeout_with_callback_and_timeout_and_arguments_0(&resolve, ms);
+  });
+  let mut expired = JsFuture::from(expired);
+  let mut future = pin!(future.into_future());
+  poll_fn(|cx| {
+    if let Poll::Ready(result) = future.as_mut().poll(cx) {
+      return Poll::Ready(result.map(drop));
+    }
+    match Pin::new(&mut expired).poll(cx) {
+      Poll::Ready(_) => Poll::Ready(Err(format!("{what} within {ms} ms").into())),
+      Poll::Pending => Poll::Pending,
+    }
+  })
+  .await
+}
+
+/// Log `line` to the console and append it to `#log`, if there is one.
+fn report(line: &str) {
+  web_sys::console::log_1(&line.into());
+  let log = web_sys::window().and_then(|w| w.document()).and_then(|d| d.get_element_by_id("log"));
+  if let Some(log) = log {
+    let text = log.text_content().unwrap_or_default();
+    log.set_text_content(Some(&format!("{text}{line}\n")));
+  }
+}
+
+fn now() -> f64 {
+  web_sys::window().and_then(|w| w.performance()).map_or(0.0, |p| p.now())
+}
+
+fn average(rtts: &[f64]) -> String {
+  if rtts.is_empty() {
+    return "-".into();
+  }
+  format!("{:.1} ms", rtts.iter().sum::<f64>() / rtts.len() as f64)
+}
+
+fn describe(error: &JsValue) -> String {
+  error.as_string().unwrap_or_else(|| format!("{error:?}"))
+}
+
+fn parse_hex(hex: &str) -> Result<Vec<u8>, JsValue> {
+  let hex: String = hex.chars().filter(|c| *c != ':').collect();
+  if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+    return Err("the certificate hash is not hex".into());
+  }
+  if hex.len() != 64 {
+    return Err("the certificate hash must be 32 bytes of hex (SHA-256)".into());
+  }
+  // all ASCII, so every two bytes are two hex digits
+  (0..hex.len()).step_by(2).map(|i| Ok(u8::from_str_radix(&hex[i..i + 2], 16).expect("hex digits"))).collect()
+}